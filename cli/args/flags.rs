@@ -152,11 +152,13 @@ pub struct InfoFlags {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct InstallFlags {
-  pub module_url: String,
+  pub module_url: Option<String>,
   pub args: Vec<String>,
   pub name: Option<String>,
   pub root: Option<PathBuf>,
   pub force: bool,
+  pub update: bool,
+  pub list: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -168,8 +170,9 @@ pub struct JupyterFlags {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct UninstallFlags {
-  pub name: String,
+  pub name: Option<String>,
   pub root: Option<PathBuf>,
+  pub all: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -195,6 +198,8 @@ impl LintFlags {
 pub struct ReplFlags {
   pub eval_files: Option<Vec<String>>,
   pub eval: Option<String>,
+  /// Maximum time in milliseconds a single line may take to evaluate.
+  pub eval_timeout: Option<u64>,
   pub is_default_command: bool,
 }
 
@@ -219,6 +224,7 @@ pub struct WatchFlags {
 pub struct WatchFlagsWithPaths {
   pub paths: Vec<PathBuf>,
   pub no_clear_screen: bool,
+  pub restart_on_enter: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -302,6 +308,7 @@ impl Default for DenoSubcommand {
     DenoSubcommand::Repl(ReplFlags {
       eval_files: None,
       eval: None,
+      eval_timeout: None,
       is_default_command: true,
     })
   }
@@ -761,6 +768,10 @@ static ENV_VARIABLES_HELP: &str = r#"ENVIRONMENT VARIABLES:
     DENO_JOBS            Number of parallel workers used for the --parallel
                          flag with the test subcommand. Defaults to number
                          of available CPUs.
+    DENO_WATCH_POLL      Poll for file changes in --watch mode every given
+                         number of milliseconds instead of relying on file
+                         system events, which some network file systems and
+                         bind mounts don't deliver
     HTTP_PROXY           Proxy address for HTTP requests
                          (module downloads, fetch)
     HTTPS_PROXY          Proxy address for HTTPS requests
@@ -853,6 +864,7 @@ pub fn flags_from_vec(args: Vec<String>) -> clap::error::Result<Flags> {
       ReplFlags {
         eval_files: None,
         eval: None,
+        eval_timeout: None,
         is_default_command: true,
       },
     )
@@ -1625,8 +1637,19 @@ The installation root is determined, in order of precedence:
   - DENO_INSTALL_ROOT environment variable
   - $HOME/.deno
 
-These must be added to the path manually if required.")
-    .defer(|cmd| runtime_args(cmd, true, true).arg(Arg::new("cmd").required(true).num_args(1..).value_hint(ValueHint::FilePath))
+These must be added to the path manually if required.
+
+To change the flags of an existing installation, use --update:
+
+  deno install --update --allow-net --allow-read -n serve
+
+The module URL and its arguments are kept from the existing installation
+unless a new module URL is given.
+
+To list the installed scripts and their module URLs, use --list:
+
+  deno install --list")
+    .defer(|cmd| runtime_args(cmd, true, true).arg(Arg::new("cmd").required_unless_present_any(["update", "list"]).num_args(1..).value_hint(ValueHint::FilePath))
       .arg(check_arg(true))
       .arg(
         Arg::new("name")
@@ -1646,6 +1669,18 @@ These must be added to the path manually if required.")
           .short('f')
           .help("Forcefully overwrite existing installation")
           .action(ArgAction::SetTrue))
+      .arg(
+        Arg::new("update")
+          .long("update")
+          .help("Update the flags of an existing installation")
+          .conflicts_with("force")
+          .action(ArgAction::SetTrue))
+      .arg(
+        Arg::new("list")
+          .long("list")
+          .help("List installed scripts")
+          .conflicts_with_all(["cmd", "name", "force", "update"])
+          .action(ArgAction::SetTrue))
       )
 }
 
@@ -1691,14 +1726,24 @@ To change the installation root, use --root:
 The installation root is determined, in order of precedence:
   - --root option
   - DENO_INSTALL_ROOT environment variable
-  - $HOME/.deno")
-    .defer(|cmd| cmd.arg(Arg::new("name").required(true))
+  - $HOME/.deno
+
+To uninstall all scripts in the installation root, use --all:
+
+  deno uninstall --all")
+    .defer(|cmd| cmd.arg(Arg::new("name").required_unless_present("all"))
       .arg(
         Arg::new("root")
           .long("root")
           .help("Installation root")
           .value_parser(value_parser!(PathBuf))
           .value_hint(ValueHint::DirPath))
+      .arg(
+        Arg::new("all")
+          .long("all")
+          .help("Uninstall all installed scripts")
+          .conflicts_with("name")
+          .action(ArgAction::SetTrue))
 )
 }
 
@@ -1847,6 +1892,14 @@ fn repl_subcommand() -> Command {
           .long("eval")
           .help("Evaluates the provided code when the REPL starts.")
           .value_name("code"),
+      )
+      .arg(
+        Arg::new("repl-eval-timeout")
+          .long("repl-eval-timeout")
+          .value_name("MILLISECONDS")
+          .help("Abort evaluation of a line after the given number of milliseconds.")
+          .require_equals(true)
+          .value_parser(value_parser!(u64).range(1..)),
       ))
 }
 
@@ -1855,6 +1908,7 @@ fn run_subcommand() -> Command {
     .arg(check_arg(false))
     .arg(watch_arg(true))
     .arg(no_clear_screen_arg())
+    .arg(restart_on_enter_arg())
     .arg(executable_ext_arg())
     .arg(
       script_arg()
@@ -2756,6 +2810,19 @@ fn no_clear_screen_arg() -> Arg {
     .help("Do not clear terminal screen when under watch mode")
 }
 
+fn restart_on_enter_arg() -> Arg {
+  Arg::new("restart-on-enter")
+    .requires("watch")
+    .long("restart-on-enter")
+    .action(ArgAction::SetTrue)
+    .help("Restart the process when Enter is pressed under watch mode")
+    .long_help(
+      "Restart the process when Enter is pressed under watch mode. Lines read
+from stdin while watching are consumed by the watcher and don't reach the
+process.",
+    )
+}
+
 fn no_check_arg() -> Arg {
   Arg::new("no-check")
     .num_args(0..=1)
@@ -3204,15 +3271,32 @@ fn info_parse(flags: &mut Flags, matches: &mut ArgMatches) {
 }
 
 fn install_parse(flags: &mut Flags, matches: &mut ArgMatches) {
-  runtime_args_parse(flags, matches, true, true);
-
   let root = matches.remove_one::<PathBuf>("root");
+  if matches.get_flag("list") {
+    flags.subcommand = DenoSubcommand::Install(InstallFlags {
+      name: None,
+      module_url: None,
+      args: vec![],
+      root,
+      force: false,
+      update: false,
+      list: true,
+    });
+    return;
+  }
+
+  runtime_args_parse(flags, matches, true, true);
 
   let force = matches.get_flag("force");
+  let update = matches.get_flag("update");
   let name = matches.remove_one::<String>("name");
-  let mut cmd_values = matches.remove_many::<String>("cmd").unwrap();
+  let mut cmd_values = matches
+    .remove_many::<String>("cmd")
+    .map(|values| values.collect::<Vec<_>>())
+    .unwrap_or_default()
+    .into_iter();
 
-  let module_url = cmd_values.next().unwrap();
+  let module_url = cmd_values.next();
   let args = cmd_values.collect();
 
   flags.subcommand = DenoSubcommand::Install(InstallFlags {
@@ -3221,6 +3305,8 @@ fn install_parse(flags: &mut Flags, matches: &mut ArgMatches) {
     args,
     root,
     force,
+    update,
+    list: false,
   });
 }
 
@@ -3239,8 +3325,10 @@ fn jupyter_parse(flags: &mut Flags, matches: &mut ArgMatches) {
 fn uninstall_parse(flags: &mut Flags, matches: &mut ArgMatches) {
   let root = matches.remove_one::<PathBuf>("root");
 
-  let name = matches.remove_one::<String>("name").unwrap();
-  flags.subcommand = DenoSubcommand::Uninstall(UninstallFlags { name, root });
+  let name = matches.remove_one::<String>("name");
+  let all = matches.get_flag("all");
+  flags.subcommand =
+    DenoSubcommand::Uninstall(UninstallFlags { name, root, all });
 }
 
 fn lsp_parse(flags: &mut Flags, _matches: &mut ArgMatches) {
@@ -3300,6 +3388,7 @@ fn repl_parse(flags: &mut Flags, matches: &mut ArgMatches) {
     ReplFlags {
       eval_files,
       eval: matches.remove_one::<String>("eval"),
+      eval_timeout: matches.remove_one::<u64>("repl-eval-timeout"),
       is_default_command: false,
     },
   );
@@ -3850,6 +3939,7 @@ fn watch_arg_parse_with_paths(
     .map(|f| WatchFlagsWithPaths {
       paths: f.collect(),
       no_clear_screen: matches.get_flag("no-clear-screen"),
+      restart_on_enter: matches.get_flag("restart-on-enter"),
     })
 }
 
@@ -3968,6 +4058,7 @@ mod tests {
           watch: Some(WatchFlagsWithPaths {
             paths: vec![],
             no_clear_screen: false,
+            restart_on_enter: false,
           }),
         }),
         ..Flags::default()
@@ -3988,6 +4079,7 @@ mod tests {
           watch: Some(WatchFlagsWithPaths {
             paths: vec![PathBuf::from("file1"), PathBuf::from("file2")],
             no_clear_screen: false,
+            restart_on_enter: false,
           }),
         }),
         ..Flags::default()
@@ -4014,6 +4106,7 @@ mod tests {
           watch: Some(WatchFlagsWithPaths {
             paths: vec![],
             no_clear_screen: true,
+            restart_on_enter: false,
           })
         }),
         ..Flags::default()
@@ -4021,6 +4114,37 @@ mod tests {
     );
   }
 
+  #[test]
+  fn run_watch_with_restart_on_enter() {
+    let r = flags_from_vec(svec![
+      "deno",
+      "run",
+      "--watch",
+      "--restart-on-enter",
+      "script.ts"
+    ]);
+
+    let flags = r.unwrap();
+    assert_eq!(
+      flags,
+      Flags {
+        subcommand: DenoSubcommand::Run(RunFlags {
+          script: "script.ts".to_string(),
+          watch: Some(WatchFlagsWithPaths {
+            paths: vec![],
+            no_clear_screen: false,
+            restart_on_enter: true,
+          })
+        }),
+        ..Flags::default()
+      }
+    );
+
+    let r =
+      flags_from_vec(svec!["deno", "run", "--restart-on-enter", "script.ts"]);
+    assert!(r.is_err());
+  }
+
   #[test]
   fn run_reload_allow_write() {
     let r =
@@ -5110,6 +5234,7 @@ mod tests {
         subcommand: DenoSubcommand::Repl(ReplFlags {
           eval_files: None,
           eval: None,
+          eval_timeout: None,
           is_default_command: true,
         }),
         allow_net: Some(vec![]),
@@ -5142,6 +5267,7 @@ mod tests {
         subcommand: DenoSubcommand::Repl(ReplFlags {
           eval_files: None,
           eval: None,
+          eval_timeout: None,
           is_default_command: false,
         }),
         import_map_path: Some("import_map.json".to_string()),
@@ -5172,6 +5298,30 @@ mod tests {
     );
   }
 
+  #[test]
+  fn repl_with_eval_timeout_flag() {
+    let r = flags_from_vec(svec!["deno", "repl", "--repl-eval-timeout=500"]);
+    assert_eq!(
+      r.unwrap(),
+      Flags {
+        subcommand: DenoSubcommand::Repl(ReplFlags {
+          eval_files: None,
+          eval: None,
+          eval_timeout: Some(500),
+          is_default_command: false,
+        }),
+        type_check_mode: TypeCheckMode::None,
+        ..Flags::default()
+      }
+    );
+
+    let r = flags_from_vec(svec!["deno", "repl", "--repl-eval-timeout=soon"]);
+    assert!(r.is_err());
+
+    let r = flags_from_vec(svec!["deno", "repl", "--repl-eval-timeout=0"]);
+    assert!(r.is_err());
+  }
+
   #[test]
   fn repl_with_eval_flag() {
     #[rustfmt::skip]
@@ -5182,6 +5332,7 @@ mod tests {
         subcommand: DenoSubcommand::Repl(ReplFlags {
           eval_files: None,
           eval: Some("console.log('hello');".to_string()),
+          eval_timeout: None,
           is_default_command: false,
         }),
         allow_write: Some(vec![]),
@@ -5205,6 +5356,7 @@ mod tests {
             "https://examples.deno.land/hello-world.ts".to_string()
           ]),
           eval: None,
+          eval_timeout: None,
           is_default_command: false,
         }),
         type_check_mode: TypeCheckMode::None,
@@ -5997,10 +6149,14 @@ mod tests {
       Flags {
         subcommand: DenoSubcommand::Install(InstallFlags {
           name: None,
-          module_url: "https://deno.land/std/examples/colors.ts".to_string(),
+          module_url: Some(
+            "https://deno.land/std/examples/colors.ts".to_string()
+          ),
           args: vec![],
           root: None,
           force: false,
+          update: false,
+          list: false,
         }),
         ..Flags::default()
       }
@@ -6016,10 +6172,14 @@ mod tests {
       Flags {
         subcommand: DenoSubcommand::Install(InstallFlags {
           name: Some("file_server".to_string()),
-          module_url: "https://deno.land/std/http/file_server.ts".to_string(),
+          module_url: Some(
+            "https://deno.land/std/http/file_server.ts".to_string()
+          ),
           args: svec!["foo", "bar"],
           root: Some(PathBuf::from("/foo")),
           force: true,
+          update: false,
+          list: false,
         }),
         import_map_path: Some("import_map.json".to_string()),
         no_remote: true,
@@ -6041,6 +6201,89 @@ mod tests {
     );
   }
 
+  #[test]
+  fn install_update() {
+    let r = flags_from_vec(svec![
+      "deno",
+      "install",
+      "--update",
+      "--allow-read",
+      "-n",
+      "file_server"
+    ]);
+    assert_eq!(
+      r.unwrap(),
+      Flags {
+        subcommand: DenoSubcommand::Install(InstallFlags {
+          name: Some("file_server".to_string()),
+          module_url: None,
+          args: vec![],
+          root: None,
+          force: false,
+          update: true,
+          list: false,
+        }),
+        allow_read: Some(vec![]),
+        ..Flags::default()
+      }
+    );
+
+    let r = flags_from_vec(svec!["deno", "install", "--allow-read"]);
+    assert!(r.is_err());
+
+    let r = flags_from_vec(svec![
+      "deno",
+      "install",
+      "--update",
+      "--force",
+      "-n",
+      "file_server"
+    ]);
+    assert!(r.is_err());
+  }
+
+  #[test]
+  fn install_list() {
+    let r =
+      flags_from_vec(svec!["deno", "install", "--list", "--root", "/foo"]);
+    assert_eq!(
+      r.unwrap(),
+      Flags {
+        subcommand: DenoSubcommand::Install(InstallFlags {
+          name: None,
+          module_url: None,
+          args: vec![],
+          root: Some(PathBuf::from("/foo")),
+          force: false,
+          update: false,
+          list: true,
+        }),
+        ..Flags::default()
+      }
+    );
+
+    // a module named "list" can still be installed
+    let r = flags_from_vec(svec!["deno", "install", "list", "foo"]);
+    assert_eq!(
+      r.unwrap(),
+      Flags {
+        subcommand: DenoSubcommand::Install(InstallFlags {
+          name: None,
+          module_url: Some("list".to_string()),
+          args: svec!["foo"],
+          root: None,
+          force: false,
+          update: false,
+          list: false,
+        }),
+        ..Flags::default()
+      }
+    );
+
+    let r = flags_from_vec(svec!["deno", "install", "--list", "main.ts"]);
+    assert!(r.is_err());
+  }
+
   #[test]
   fn uninstall() {
     let r = flags_from_vec(svec!["deno", "uninstall", "file_server"]);
@@ -6048,14 +6291,37 @@ mod tests {
       r.unwrap(),
       Flags {
         subcommand: DenoSubcommand::Uninstall(UninstallFlags {
-          name: "file_server".to_string(),
+          name: Some("file_server".to_string()),
           root: None,
+          all: false,
         }),
         ..Flags::default()
       }
     );
   }
 
+  #[test]
+  fn uninstall_all() {
+    let r = flags_from_vec(svec!["deno", "uninstall", "--all"]);
+    assert_eq!(
+      r.unwrap(),
+      Flags {
+        subcommand: DenoSubcommand::Uninstall(UninstallFlags {
+          name: None,
+          root: None,
+          all: true,
+        }),
+        ..Flags::default()
+      }
+    );
+
+    let r = flags_from_vec(svec!["deno", "uninstall", "--all", "file_server"]);
+    assert!(r.is_err());
+
+    let r = flags_from_vec(svec!["deno", "uninstall"]);
+    assert!(r.is_err());
+  }
+
   #[test]
   fn uninstall_with_help_flag() {
     let r = flags_from_vec(svec!["deno", "uninstall", "--help"]);
@@ -6242,6 +6508,7 @@ mod tests {
         subcommand: DenoSubcommand::Repl(ReplFlags {
           eval_files: None,
           eval: Some("console.log('hello');".to_string()),
+          eval_timeout: None,
           is_default_command: false,
         }),
         unsafely_ignore_certificate_errors: Some(vec![]),
@@ -6312,6 +6579,7 @@ mod tests {
         subcommand: DenoSubcommand::Repl(ReplFlags {
           eval_files: None,
           eval: None,
+          eval_timeout: None,
           is_default_command: false,
         }),
         unsafely_ignore_certificate_errors: Some(svec![
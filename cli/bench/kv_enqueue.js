@@ -0,0 +1,13 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+// Run with `deno bench --unstable cli/bench/kv_enqueue.js`.
+
+const db = await Deno.openKv(":memory:");
+
+Deno.bench("kv_enqueue", async () => {
+  await db.enqueue("message");
+});
+
+Deno.bench("kv_atomic_enqueue", async () => {
+  await db.atomic().enqueue("message").commit();
+});
@@ -126,6 +126,11 @@ impl DenoDir {
     self.root.join("location_data")
   }
 
+  /// Path to the folder used to cache the metadata of remote KV databases.
+  pub fn kv_metadata_folder_path(&self) -> PathBuf {
+    self.root.join("kv_metadata")
+  }
+
   /// File used for the upgrade checker.
   pub fn upgrade_check_file_path(&self) -> PathBuf {
     self.root.join("latest.txt")
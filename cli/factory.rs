@@ -677,6 +677,7 @@ impl CliFactory {
         maybe_binary_command_name
       },
       origin_data_folder_path: Some(self.deno_dir()?.origin_data_folder_path()),
+      kv_metadata_folder_path: Some(self.deno_dir()?.kv_metadata_folder_path()),
       seed: self.options.seed(),
       unsafely_ignore_certificate_errors: self
         .options
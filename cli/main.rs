@@ -135,13 +135,23 @@ async fn run_subcommand(flags: Flags) -> Result<i32, AnyError> {
       spawn_subcommand(async { tools::info::info(flags, info_flags).await })
     }
     DenoSubcommand::Install(install_flags) => spawn_subcommand(async {
-      tools::installer::install_command(flags, install_flags).await
+      if install_flags.list {
+        tools::installer::list_installed(install_flags.root)
+      } else {
+        tools::installer::install_command(flags, install_flags).await
+      }
     }),
     DenoSubcommand::Jupyter(jupyter_flags) => spawn_subcommand(async {
       tools::jupyter::kernel(flags, jupyter_flags).await
     }),
     DenoSubcommand::Uninstall(uninstall_flags) => spawn_subcommand(async {
-      tools::installer::uninstall(uninstall_flags.name, uninstall_flags.root)
+      if uninstall_flags.all {
+        tools::installer::uninstall_all(uninstall_flags.root)
+      } else {
+        // the name is required unless --all is provided
+        let name = uninstall_flags.name.unwrap();
+        tools::installer::uninstall(name, uninstall_flags.root)
+      }
     }),
     DenoSubcommand::Lsp => spawn_subcommand(async { lsp::start().await }),
     DenoSubcommand::Lint(lint_flags) => spawn_subcommand(async {
@@ -6,6 +6,7 @@ use test_util as util;
 use test_util::assert_contains;
 use test_util::assert_ends_with;
 use test_util::TempDir;
+use test_util::TestContextBuilder;
 
 #[test]
 fn install_basic() {
@@ -83,6 +84,256 @@ fn install_basic() {
   assert!(!file_path.exists());
 }
 
+#[test]
+fn install_update() {
+  let _guard = util::http_server();
+  let temp_dir = TempDir::new();
+  let temp_dir_str = temp_dir.path().to_string();
+
+  // ensure a lockfile doesn't get created or updated locally
+  temp_dir.write("deno.json", "{}");
+
+  let status = util::deno_cmd()
+    .current_dir(temp_dir.path())
+    .arg("install")
+    .arg("--check")
+    .arg("--name")
+    .arg("echo_test")
+    .arg("http://localhost:4545/echo.ts")
+    .arg("hello")
+    .envs([
+      ("HOME", temp_dir_str.as_str()),
+      ("USERPROFILE", temp_dir_str.as_str()),
+      ("DENO_INSTALL_ROOT", ""),
+    ])
+    .spawn()
+    .unwrap()
+    .wait()
+    .unwrap();
+  assert!(status.success());
+
+  let mut file_path = temp_dir.path().join(".deno/bin/echo_test");
+  assert!(file_path.exists());
+
+  if cfg!(windows) {
+    file_path = file_path.with_extension("cmd");
+  }
+
+  // installing again without --update or --force fails
+  let output = util::deno_cmd()
+    .current_dir(temp_dir.path())
+    .arg("install")
+    .arg("--allow-read")
+    .arg("--name")
+    .arg("echo_test")
+    .arg("http://localhost:4545/echo.ts")
+    .envs([
+      ("HOME", temp_dir_str.as_str()),
+      ("USERPROFILE", temp_dir_str.as_str()),
+      ("DENO_INSTALL_ROOT", ""),
+    ])
+    .stderr(std::process::Stdio::piped())
+    .spawn()
+    .unwrap()
+    .wait_with_output()
+    .unwrap();
+  assert!(!output.status.success());
+  assert_contains!(
+    String::from_utf8(output.stderr).unwrap(),
+    "Existing installation found"
+  );
+
+  // update the flags without repeating the module URL
+  let output = util::deno_cmd()
+    .current_dir(temp_dir.path())
+    .arg("install")
+    .arg("--update")
+    .arg("--check")
+    .arg("--allow-read")
+    .arg("--name")
+    .arg("echo_test")
+    .envs([
+      ("HOME", temp_dir_str.as_str()),
+      ("USERPROFILE", temp_dir_str.as_str()),
+      ("DENO_INSTALL_ROOT", ""),
+    ])
+    .stderr(std::process::Stdio::piped())
+    .spawn()
+    .unwrap()
+    .wait_with_output()
+    .unwrap();
+  assert!(output.status.success());
+  assert_contains!(
+    String::from_utf8(output.stderr).unwrap(),
+    "Successfully updated echo_test"
+  );
+
+  let content = file_path.read_to_string();
+  if cfg!(windows) {
+    assert_contains!(
+      content,
+      r#""run" "--allow-read" "--check" "--no-config" "http://localhost:4545/echo.ts" "hello""#
+    );
+  } else {
+    assert_contains!(
+      content,
+      r#"run --allow-read --check --no-config 'http://localhost:4545/echo.ts' hello"#
+    );
+  }
+
+  // update with a new module URL
+  let status = util::deno_cmd()
+    .current_dir(temp_dir.path())
+    .arg("install")
+    .arg("--update")
+    .arg("--allow-read")
+    .arg("--name")
+    .arg("echo_test")
+    .arg("http://localhost:4545/cat.ts")
+    .envs([
+      ("HOME", temp_dir_str.as_str()),
+      ("USERPROFILE", temp_dir_str.as_str()),
+      ("DENO_INSTALL_ROOT", ""),
+    ])
+    .spawn()
+    .unwrap()
+    .wait()
+    .unwrap();
+  assert!(status.success());
+
+  let content = file_path.read_to_string();
+  if cfg!(windows) {
+    assert_contains!(
+      content,
+      r#""run" "--allow-read" "--no-config" "http://localhost:4545/cat.ts" %*"#
+    );
+  } else {
+    assert_contains!(
+      content,
+      r#"run --allow-read --no-config 'http://localhost:4545/cat.ts' "$@""#
+    );
+  }
+}
+
+#[test]
+fn install_list_and_uninstall_all() {
+  let _guard = util::http_server();
+  let temp_dir = TempDir::new();
+  let temp_dir_str = temp_dir.path().to_string();
+
+  for (name, module_url) in [
+    ("echo_test", "http://localhost:4545/echo.ts"),
+    ("cat_test", "http://localhost:4545/cat.ts"),
+  ] {
+    let status = util::deno_cmd()
+      .current_dir(util::root_path())
+      .arg("install")
+      .arg("--name")
+      .arg(name)
+      .arg(module_url)
+      .envs([
+        ("HOME", temp_dir_str.as_str()),
+        ("USERPROFILE", temp_dir_str.as_str()),
+        ("DENO_INSTALL_ROOT", temp_dir_str.as_str()),
+      ])
+      .spawn()
+      .unwrap()
+      .wait()
+      .unwrap();
+    assert!(status.success());
+  }
+
+  let output = util::deno_cmd()
+    .current_dir(util::root_path())
+    .arg("install")
+    .arg("--list")
+    .envs([
+      ("HOME", temp_dir_str.as_str()),
+      ("USERPROFILE", temp_dir_str.as_str()),
+      ("DENO_INSTALL_ROOT", temp_dir_str.as_str()),
+    ])
+    .stdout(std::process::Stdio::piped())
+    .spawn()
+    .unwrap()
+    .wait_with_output()
+    .unwrap();
+  assert!(output.status.success());
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  let lines = stdout.lines().collect::<Vec<_>>();
+  assert_eq!(
+    lines,
+    vec![
+      "cat_test   http://localhost:4545/cat.ts",
+      "echo_test  http://localhost:4545/echo.ts",
+    ]
+  );
+
+  let status = util::deno_cmd()
+    .current_dir(util::root_path())
+    .arg("uninstall")
+    .arg("--all")
+    .envs([
+      ("HOME", temp_dir_str.as_str()),
+      ("USERPROFILE", temp_dir_str.as_str()),
+      ("DENO_INSTALL_ROOT", temp_dir_str.as_str()),
+    ])
+    .spawn()
+    .unwrap()
+    .wait()
+    .unwrap();
+  assert!(status.success());
+
+  let bin_dir = temp_dir.path().join("bin");
+  assert_eq!(fs::read_dir(bin_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn install_pins_npm_version() {
+  let context = TestContextBuilder::for_npm().use_temp_cwd().build();
+  let temp_dir = context.temp_dir();
+  let temp_dir_str = temp_dir.path().to_string();
+
+  context
+    .new_command()
+    .args("install --name cowsay npm:cowsay@1")
+    .envs([("DENO_INSTALL_ROOT", temp_dir_str.as_str())])
+    .run()
+    .skip_output_check()
+    .assert_exit_code(0);
+
+  let mut file_path = temp_dir.path().join("bin/cowsay");
+  if cfg!(windows) {
+    file_path = file_path.with_extension("cmd");
+  }
+  let content = file_path.read_to_string();
+  // the version range is resolved to the version that was installed
+  assert_contains!(content, "npm:cowsay@1.5.0");
+}
+
+#[test]
+fn install_pins_jsr_version() {
+  let context = TestContextBuilder::for_jsr().use_temp_cwd().build();
+  let temp_dir = context.temp_dir();
+  let temp_dir_str = temp_dir.path().to_string();
+
+  context
+    .new_command()
+    .args(
+      "install --name no_module_graph jsr:@denotest/no_module_graph@0.1/mod.ts",
+    )
+    .envs([("DENO_INSTALL_ROOT", temp_dir_str.as_str())])
+    .run()
+    .skip_output_check()
+    .assert_exit_code(0);
+
+  let mut file_path = temp_dir.path().join("bin/no_module_graph");
+  if cfg!(windows) {
+    file_path = file_path.with_extension("cmd");
+  }
+  let content = file_path.read_to_string();
+  assert_contains!(content, "jsr:@denotest/no_module_graph@0.1.1/mod.ts");
+}
+
 #[test]
 fn install_custom_dir_env_var() {
   let _guard = util::http_server();
@@ -1059,3 +1059,190 @@ fn closed_file_pre_load_does_not_occur() {
       assert_contains!(console.all_output(), "Skipping document preload.",);
     });
 }
+
+#[test]
+fn language_command() {
+  util::with_pty(&["repl"], |mut console| {
+    console.write_line("const typed: number = 1; typed");
+    console.expect("1");
+
+    console.write_line(".language js");
+    console.expect("Evaluating input as JavaScript");
+    console.write_line("const annotated: number = 2;");
+    console.expect("parse error");
+    // the choice persists across lines
+    console.write_line("const plain = 3; plain");
+    console.expect("3");
+    console.write_line(".language");
+    console.expect("Evaluating input as JavaScript");
+
+    console.write_line(".language ts");
+    console.expect("Evaluating input as TypeScript");
+    console.write_line("const annotated: number = 4; annotated");
+    console.expect("4");
+
+    console.write_line(".language rust");
+    console.expect("unknown language \"rust\"");
+    console.write_line(".5");
+    console.expect("0.5");
+  });
+}
+
+#[test]
+fn eval_timeout() {
+  util::with_pty(&["repl", "--repl-eval-timeout=500"], |mut console| {
+    let start = std::time::Instant::now();
+    console.write_line("while (true) {}");
+    console.expect("evaluation timed out after 500 ms");
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+    // the session is still usable
+    console.write_line("1 + 1");
+    console.expect("2");
+
+    console.write_line("await new Promise(() => {})");
+    console.expect("evaluation timed out after 500 ms");
+    console.write_line(".timeout off");
+    console.expect("Evaluation timeout is off");
+    console.write_line("'still alive'");
+    console.expect("\"still alive\"");
+  });
+}
+
+#[test]
+fn save_history() {
+  let context = TestContextBuilder::new().use_temp_cwd().build();
+  let temp_dir = context.temp_dir();
+  context
+    .new_command()
+    .args_vec(["repl"])
+    .with_pty(|mut console| {
+      console.write_line("const a = 1;");
+      console.expect("undefined");
+      console.write_line("throw new Error('not saved');");
+      console.expect("Uncaught Error: not saved");
+      console.write_line("function double(n) { return n * 2; }");
+      console.expect("undefined");
+      console.write_line(".save session.js");
+      console.expect("Saved 2 evaluated lines to session.js");
+    });
+  assert_eq!(
+    temp_dir.read_to_string("session.js"),
+    "const a = 1;\nfunction double(n) { return n * 2; }\n"
+  );
+
+  // replaying the saved lines restores the declarations
+  context
+    .new_command()
+    .args_vec(["repl", "--eval-file=session.js"])
+    .with_pty(|mut console| {
+      console.write_line("double(a)");
+      console.expect("2");
+    });
+}
+
+#[test]
+fn uncaught_promise_rejection() {
+  util::with_pty(&["repl"], |mut console| {
+    console.write_line("Promise.reject(new Error('x'))");
+    console.expect("Uncaught (in promise) Error: x");
+    console.write_line("1 + 1");
+    console.expect("2");
+  });
+
+  // rejections are reported as errors, so they are printed for `--eval` too
+  util::with_pty(
+    &["repl", "--eval", "Promise.reject(new Error('from eval'))"],
+    |mut console| {
+      console.expect("Error in --eval flag:");
+      console.expect("Uncaught (in promise) Error: from eval");
+    },
+  );
+}
+
+#[test]
+fn npm_packages_command() {
+  let mut env_vars = util::env_vars_for_npm_tests();
+  let temp_dir = TempDir::new();
+  env_vars.push(("DENO_DIR".to_string(), temp_dir.path().to_string()));
+
+  let (out, err) = util::run_and_collect_output_with_args(
+    true,
+    vec!["repl", "--quiet", "--allow-read", "--allow-env"],
+    Some(vec![
+      ".npm",
+      r#"import chalk from "npm:chalk@5";"#,
+      r#"const chalk2 = await import("npm:chalk@5");"#,
+      ".npm",
+      ".npm clear",
+      ".npm",
+    ]),
+    Some(env_vars),
+    true,
+  );
+
+  assert!(err.is_empty());
+  let listed = out.matches("npm:chalk@5 -> 5.0.1").count();
+  assert_eq!(listed, 1, "{out}");
+  assert_eq!(
+    out.matches("No npm packages were added in this session").count(),
+    2,
+    "{out}"
+  );
+  assert_contains!(out, "Cleared the list of npm packages");
+}
+
+#[test]
+fn json_test_reporter() {
+  util::with_pty(&["repl"], |mut console| {
+    console.write_line(".reporter json");
+    console.expect("Reporting tests as JSON lines");
+    console.write_line(r#"Deno.test("json pass", () => {});"#);
+    console.expect(r#"{"type":"wait","name":"json pass""#);
+    console.expect(r#"{"type":"result","name":"json pass""#);
+    console.expect(r#""result":"ok""#);
+    console.expect(r#"{"type":"summary","passed":1,"failed":0,"ignored":0"#);
+
+    console.write_line(".reporter pretty");
+    console.expect("Reporting tests with the pretty reporter");
+    console.write_line(r#"Deno.test("pretty pass", () => {});"#);
+    console.expect("pretty pass ... ok");
+  });
+}
+
+#[test]
+fn awaiting_notice() {
+  util::with_pty(&["repl"], |mut console| {
+    console.write_line(
+      "await new Promise((resolve) => setTimeout(() => resolve(40 + 2), 1000))",
+    );
+    console.expect("awaiting...");
+    console.expect("42");
+
+    // fast lines don't print the notice
+    console.write_line("'quick'");
+    console.expect("\"quick\"");
+    assert_eq!(console.all_output().matches("awaiting...").count(), 1);
+  });
+}
+
+#[test]
+fn split_statements() {
+  util::with_pty(&["repl"], |mut console| {
+    console.write_line(".statements on");
+    console.expect("Reporting the result of each statement");
+    console.write_line("const a = 1; a + 1; 'last'");
+    console.expect_all(&["undefined", "2", "\"last\""]);
+
+    // declarations from earlier lines are still visible
+    console.write_line("const b = a + 2; b * 2;");
+    console.expect("6");
+
+    console.write_line("1 + 1; throw new Error('second'); 'never'");
+    console.expect("Uncaught Error: second");
+    assert_not_contains!(console.all_output(), "never\"");
+
+    console.write_line(".statements off");
+    console.expect("Reporting the result of the last statement");
+  });
+}
@@ -32,6 +32,41 @@ Deno.test({
   },
 });
 
+Deno.test({
+  name: "openKv shared :memory:",
+  permissions: {},
+  async fn() {
+    const path = ":memory:?cache=shared&name=kv_test";
+    const db1 = await Deno.openKv(path);
+    const db2 = await Deno.openKv(path);
+    try {
+      await db1.set(["a"], 1);
+      assertEquals((await db2.get(["a"])).value, 1);
+    } finally {
+      db1.close();
+      db2.close();
+    }
+
+    const db3 = await Deno.openKv(path);
+    try {
+      assertEquals((await db3.get(["a"])).value, null);
+    } finally {
+      db3.close();
+    }
+
+    await assertRejects(
+      async () => await Deno.openKv(":memory:?name=kv_test"),
+      TypeError,
+      "In-memory databases with options require `cache=shared` and a non-empty `name`",
+    );
+    await assertRejects(
+      async () => await Deno.openKv(":memory:?cache=shared&name=a&mode=ro"),
+      TypeError,
+      "Unknown in-memory database option: mode",
+    );
+  },
+});
+
 Deno.test({
   name: "openKv invalid filenames",
   permissions: {},
@@ -49,6 +84,63 @@ Deno.test({
   },
 });
 
+Deno.test({
+  name: "openKv with json value encoding",
+  permissions: {},
+  async fn() {
+    await assertRejects(
+      // @ts-expect-error invalid encoding
+      async () => await Deno.openKv(":memory:", { valueEncoding: "cbor" }),
+      TypeError,
+      "valueEncoding must be 'v8' or 'json'",
+    );
+
+    const path = ":memory:?cache=shared&name=kv_json_test";
+    const json = await Deno.openKv(path, { valueEncoding: "json" });
+    const v8 = await Deno.openKv(path);
+    try {
+      const date = new Date(0);
+      await json.set(["obj"], { b: [1, "two", null], a: true });
+      await json.set(["date"], date);
+      await json.set(["nan"], NaN);
+      await json.set(["bytes"], new Uint8Array([1, 2]));
+      await json.set(["u64"], new Deno.KvU64(1n));
+      await json.atomic().set(["atomic"], { n: 1 }).commit();
+
+      assertEquals((await json.get(["obj"])).value, {
+        a: true,
+        b: [1, "two", null],
+      });
+      assertEquals((await json.get(["date"])).value, date.toISOString());
+      assertEquals((await json.get(["nan"])).value, null);
+      assertEquals((await json.get(["bytes"])).value, new Uint8Array([1, 2]));
+      assertEquals((await json.get(["u64"])).value, new Deno.KvU64(1n));
+      // Values are read in the encoding they were written with.
+      assertEquals((await v8.get(["atomic"])).value, { n: 1 });
+
+      // Values compare by their canonical text.
+      const res = await json.atomic()
+        .check({ key: ["obj"], value: { a: true, b: [1, "two", null] } })
+        .set(["checked"], 1)
+        .commit();
+      assert(res.ok);
+
+      await assertRejects(
+        () => json.set(["bigint"], 1n),
+        TypeError,
+      );
+      await assertRejects(
+        () => json.set(["undefined"], undefined),
+        TypeError,
+        "Value can not be encoded as JSON",
+      );
+    } finally {
+      json.close();
+      v8.close();
+    }
+  },
+});
+
 function dbTest(name: string, fn: (db: Deno.Kv) => Promise<void> | void) {
   Deno.test({
     name,
@@ -272,6 +364,110 @@ dbTest("compare and mutate not exists", async (db) => {
   assert(!res.ok);
 });
 
+dbTest("failed check index", async (db) => {
+  const { versionstamp } = await db.set(["a"], "1");
+
+  let res = await db.atomic()
+    .check({ key: ["a"], versionstamp })
+    .check({ key: ["a"], versionstamp: null })
+    .set(["b"], "2")
+    .commit();
+  assert(!res.ok);
+  assertEquals(res.failedCheck, 1);
+
+  // `setIfAbsent` counts as a check after the explicit ones.
+  res = await db.atomic()
+    .check({ key: ["b"], versionstamp: null })
+    .set(["c"], "3")
+    .setIfAbsent(["a"], "2")
+    .commit();
+  assert(!res.ok);
+  assertEquals(res.failedCheck, 1);
+
+  // So does a move with a missing source, and the lowest failing check is
+  // reported no matter which mutations carry them.
+  res = await db.atomic()
+    .check({ key: ["a"], versionstamp })
+    .setIfAbsent(["d"], "4")
+    .move(["missing"], ["e"])
+    .commit();
+  assert(!res.ok);
+  assertEquals(res.failedCheck, 2);
+  res = await db.atomic()
+    .move(["missing"], ["e"])
+    .setIfAbsent(["a"], "2")
+    .commit();
+  assert(!res.ok);
+  assertEquals(res.failedCheck, 0);
+  res = await db.atomic()
+    .setIfAbsent(["a"], "2")
+    .move(["missing"], ["e"])
+    .commit();
+  assert(!res.ok);
+  assertEquals(res.failedCheck, 0);
+
+  // Nothing from the failed operations was applied.
+  assertEquals(
+    (await db.getMany([["b"], ["c"], ["d"], ["e"]])).map((x) => x.value),
+    [null, null, null, null],
+  );
+
+  res = await db.atomic()
+    .check({ key: ["a"], versionstamp })
+    .commit();
+  assert(res.ok);
+  assertEquals("failedCheck" in res, false);
+});
+
+dbTest("value checks", async (db) => {
+  await db.set(["a"], { n: 1 });
+  await db.set(["u64"], new Deno.KvU64(1n));
+
+  let res = await db.atomic()
+    .check({ key: ["a"], value: { n: 1 } })
+    .check({ key: ["u64"], value: new Deno.KvU64(1n) })
+    .set(["b"], "ok")
+    .commit();
+  assert(res.ok);
+  assertEquals((await db.get(["b"])).value, "ok");
+
+  res = await db.atomic()
+    .check({ key: ["a"], value: { n: 1 } })
+    .check({ key: ["a"], value: { n: 2 } })
+    .commit();
+  assert(!res.ok);
+  assertEquals(res.failedCheck, 1);
+
+  // A KvU64 is not equal to a bigint with the same value.
+  res = await db.atomic().check({ key: ["u64"], value: 1n }).commit();
+  assert(!res.ok);
+
+  // Bytes only equal the same bytes.
+  await db.set(["bytes"], new Uint8Array([1, 2]));
+  res = await db.atomic()
+    .check({ key: ["bytes"], value: new Uint8Array([1, 2]) })
+    .commit();
+  assert(res.ok);
+  res = await db.atomic()
+    .check({ key: ["bytes"], value: new Uint8Array([1, 3]) })
+    .commit();
+  assert(!res.ok);
+
+  // A key that doesn't exist never equals a value.
+  res = await db.atomic().check({ key: ["missing"], value: null }).commit();
+  assert(!res.ok);
+  res = await db.atomic().check({ key: ["missing"], value: undefined })
+    .commit();
+  assert(!res.ok);
+
+  // A check is either a versionstamp check or a value check.
+  const { versionstamp } = await db.get(["a"]);
+  await assertRejects(async () => {
+    await db.atomic().check({ key: ["a"], versionstamp, value: { n: 1 } })
+      .commit();
+  });
+});
+
 dbTest("atomic mutation helper (sum)", async (db) => {
   await db.set(["t"], new Deno.KvU64(42n));
   assertEquals((await db.get(["t"])).value, new Deno.KvU64(42n));
@@ -384,6 +580,51 @@ dbTest("atomic mutation type=set overwrite", async (db) => {
   assertEquals(result.value, "2");
 });
 
+dbTest("atomic mutation type=setIfAbsent", async (db) => {
+  const res = await db.atomic()
+    .setIfAbsent(["a"], "1")
+    .mutate({ key: ["b"], value: "2", type: "setIfAbsent" })
+    .set(["c"], "3")
+    .commit();
+  assert(res.ok);
+  assertEquals(
+    (await db.getMany([["a"], ["b"], ["c"]])).map((x) => x.value),
+    ["1", "2", "3"],
+  );
+});
+
+dbTest("atomic mutation type=setIfAbsent exists", async (db) => {
+  await db.set(["a"], "1");
+  const res = await db.atomic()
+    .setIfAbsent(["a"], "2")
+    .set(["b"], "3")
+    .commit();
+  assert(!res.ok);
+  assertEquals((await db.get(["a"])).value, "1");
+  assertEquals((await db.get(["b"])).value, null);
+});
+
+dbTest("atomic mutation type=setIfAbsent contended", async (db) => {
+  const results = await Promise.all(
+    [1, 2, 3, 4, 5].map((i) =>
+      db.atomic().setIfAbsent(["a"], i).sum(["count"], 1n).commit()
+    ),
+  );
+  const winners = results.filter((res) => res.ok);
+  assertEquals(winners.length, 1);
+  assertEquals(
+    (await db.get(["count"])).value,
+    new Deno.KvU64(1n),
+  );
+  // Once the key exists, a later write with a passing check still fails.
+  const { versionstamp } = await db.get(["b"]);
+  const res = await db.atomic()
+    .check({ key: ["b"], versionstamp })
+    .setIfAbsent(["a"], 6)
+    .commit();
+  assert(!res.ok);
+});
+
 dbTest("atomic mutation type=delete", async (db) => {
   await db.set(["a"], "1");
   const res = await db.atomic()
@@ -403,6 +644,212 @@ dbTest("atomic mutation type=delete no exists", async (db) => {
   assertEquals(result.value, null);
 });
 
+dbTest("atomic commit returnOld", async (db) => {
+  await db.set(["a"], "1");
+  await db.set(["b"], "2");
+  const res = await db.atomic()
+    .set(["a"], "3")
+    .delete(["b"])
+    .set(["c"], "4")
+    .sum(["d"], 1n)
+    .commit({ returnOld: true });
+  assert(res.ok);
+  assertEquals(res.oldValues!.length, 4);
+  assertEquals(res.oldValues![0]!.value, "1");
+  assertEquals(res.oldValues![1]!.value, "2");
+  assertEquals(res.oldValues![2], null);
+  assertEquals(res.oldValues![3], null);
+
+  const res2 = await db.atomic().set(["a"], "5").commit();
+  assert(res2.ok);
+  assertEquals(res2.oldValues, undefined);
+});
+
+dbTest("atomic mutation deletePrefix", async (db) => {
+  await db.set(["users"], "root");
+  await db.set(["users", "a"], "1");
+  await db.set(["users", "b", "c"], "2");
+  await db.set(["usersx"], "3");
+  await db.set(["other"], "4");
+  const res = await db.atomic()
+    .deletePrefix(["users"])
+    .set(["users", "d"], "5")
+    .commit();
+  assert(res.ok);
+  assertEquals(res.mutationCounts!.keysDeleted, 2);
+  assertEquals(
+    (await collect(db.list({ prefix: [] }))).map((x) => x.key),
+    [["other"], ["users"], ["users", "d"], ["usersx"]],
+  );
+
+  const res2 = await db.atomic()
+    .mutate({ key: ["other"], type: "deletePrefix" })
+    .commit();
+  assert(res2.ok);
+  assertEquals(res2.mutationCounts!.mutationsApplied, 0);
+
+  await assertRejects(
+    async () => await db.atomic().deletePrefix([]).commit(),
+    TypeError,
+    "prefix of 'deletePrefix' mutation must have at least one key part",
+  );
+  assertEquals((await collect(db.list({ prefix: [] }))).length, 4);
+});
+
+dbTest("atomic mutation move", async (db) => {
+  await db.set(["a"], "1", { expireIn: 60_000 });
+  const source = await db.get(["a"]);
+  assert(source.versionstamp !== null);
+  const expireAt = source.expireAt;
+  const res = await db.atomic().move(["a"], ["b"]).commit();
+  assert(res.ok);
+  assertEquals(res.mutationCounts, {
+    mutationsApplied: 1,
+    keysCreated: 1,
+    keysDeleted: 1,
+  });
+  assertEquals(await db.get(["a"]), {
+    key: ["a"],
+    value: null,
+    versionstamp: null,
+  });
+  assertEquals(await db.get(["b"]), {
+    key: ["b"],
+    value: "1",
+    versionstamp: res.versionstamp,
+    expireAt,
+  });
+
+  // Moves see the mutations before them in the same operation.
+  const res2 = await db.atomic()
+    .set(["c"], "2")
+    .mutate({ key: ["c"], to: ["d"], type: "move" })
+    .commit();
+  assert(res2.ok);
+  assertEquals((await db.get(["d"])).value, "2");
+});
+
+dbTest("atomic mutation move missing source", async (db) => {
+  const res = await db.atomic()
+    .set(["x"], "1")
+    .move(["a"], ["b"])
+    .commit();
+  assert(!res.ok);
+  assertEquals((await db.get(["x"])).value, null);
+  assertEquals((await db.get(["b"])).value, null);
+
+  await assertRejects(
+    async () => await db.atomic().move(["x"], ["x"]).commit(),
+    TypeError,
+    "cannot move a key onto itself",
+  );
+});
+
+dbTest("atomic mutation move destination exists", async (db) => {
+  await db.set(["a"], "1");
+  await db.set(["b"], "2");
+  const res = await db.atomic().move(["a"], ["b"]).commit();
+  assert(!res.ok);
+  assertEquals((await db.get(["a"])).value, "1");
+  assertEquals((await db.get(["b"])).value, "2");
+
+  const res2 = await db.atomic()
+    .move(["a"], ["b"], { overwrite: true })
+    .commit();
+  assert(res2.ok);
+  assertEquals(res2.mutationCounts!.keysCreated, 0);
+  assertEquals((await db.get(["a"])).value, null);
+  assertEquals((await db.get(["b"])).value, "1");
+});
+
+dbTest("atomic commit mutationCounts", async (db) => {
+  await db.set(["a"], "1");
+  await db.set(["b"], "2");
+  const res = await db.atomic()
+    .set(["a"], "3")
+    .set(["c"], "4")
+    .delete(["b"])
+    .delete(["missing"])
+    .sum(["d"], 1n)
+    .sum(["d"], 1n)
+    .commit();
+  assert(res.ok);
+  assertEquals(res.mutationCounts, {
+    mutationsApplied: 5,
+    keysCreated: 2,
+    keysDeleted: 1,
+  });
+});
+
+dbTest("atomic commit dryRun", async (db) => {
+  const { versionstamp } = await db.set(["a"], "1");
+  const before = await collect(db.list({ prefix: [] }));
+
+  const res = await db.atomic()
+    .check({ key: ["a"], versionstamp })
+    .set(["a"], "2")
+    .delete(["a"])
+    .set(["b"], "3")
+    .sum(["c"], 1n)
+    .enqueue("message")
+    .commit({ dryRun: true, returnOld: true });
+  assert(res.ok);
+  assertEquals(res.dryRun, true);
+  assert(!("versionstamp" in res));
+  assertEquals(res.oldValues![0]!.value, "1");
+  assertEquals(await collect(db.list({ prefix: [] })), before);
+
+  // A dry run that fails its checks reports so, like a real commit.
+  const res2 = await db.atomic()
+    .check({ key: ["a"], versionstamp: null })
+    .set(["a"], "2")
+    .commit({ dryRun: true });
+  assert(!res2.ok);
+
+  // A real commit afterwards gets a fresh versionstamp.
+  const res3 = await db.atomic().set(["b"], "3").commit();
+  assert(res3.ok);
+  assert(res3.versionstamp > versionstamp);
+  assert(!("dryRun" in res3));
+});
+
+dbTest("replacePrefix", async (db) => {
+  await db.set(["index", "a"], "1");
+  await db.set(["index", "b"], "2");
+  await db.set(["other"], "3");
+  const res = await db.replacePrefix(["index"], [
+    [["index", "b"], "4"],
+    [["index", "c"], "5"],
+  ]);
+  assert(res.ok);
+  const entries = await collect(db.list({ prefix: ["index"] }));
+  assertEquals(entries.map((e) => [e.key, e.value]), [
+    [["index", "b"], "4"],
+    [["index", "c"], "5"],
+  ]);
+  assertEquals((await db.get(["other"])).value, "3");
+});
+
+dbTest("replacePrefix rejects keys outside the prefix", async (db) => {
+  await db.set(["index", "a"], "1");
+  await assertRejects(
+    async () => await db.replacePrefix(["index"], [[["other"], "2"]]),
+    TypeError,
+    "key is not within the prefix",
+  );
+  assertEquals((await db.get(["index", "a"])).value, "1");
+});
+
+dbTest("replacePrefix rejects an empty prefix", async (db) => {
+  await db.set(["a"], "1");
+  await assertRejects(
+    async () => await db.replacePrefix([], [[["b"], "2"]]),
+    TypeError,
+    "prefix of replacePrefix must have at least one key part",
+  );
+  assertEquals((await db.get(["a"])).value, "1");
+});
+
 dbTest("atomic mutation type=sum", async (db) => {
   await db.set(["a"], new Deno.KvU64(10n));
   const res = await db.atomic()
@@ -579,6 +1026,113 @@ dbTest("atomic mutation type=max wrong type in mutation", async (db) => {
   );
 });
 
+dbTest("export and import", async (db) => {
+  await db.set(["a", 1n, 1.5, true], "1");
+  await db.set(["b", new Uint8Array([0, 1])], new Uint8Array([2, 3]));
+  await db.set(["c", NaN], new Deno.KvU64(42n));
+  const filename = await Deno.makeTempFile({ prefix: "kv_export" });
+  try {
+    assertEquals(await db.export(filename), 3);
+    const lines = (await Deno.readTextFile(filename)).trim().split("\n");
+    assertEquals(lines.length, 3);
+
+    const db2 = await Deno.openKv(":memory:");
+    try {
+      assertEquals(await db2.import(filename), 3);
+      assertEquals((await db2.get(["a", 1n, 1.5, true])).value, "1");
+      assertEquals(
+        (await db2.get(["b", new Uint8Array([0, 1])])).value,
+        new Uint8Array([2, 3]),
+      );
+      assertEquals((await db2.get(["c", NaN])).value, new Deno.KvU64(42n));
+
+      await assertRejects(
+        async () => await db2.import(filename),
+        TypeError,
+        "Import would overwrite an existing key",
+      );
+      assertEquals(await db2.import(filename, { overwrite: true }), 3);
+    } finally {
+      db2.close();
+    }
+  } finally {
+    await Deno.remove(filename);
+  }
+});
+
+dbTest("import keeps expirations and checks sizes", async (db) => {
+  await db.set(["e"], "1", { expireIn: 60_000 });
+  const filename = await Deno.makeTempFile({ prefix: "kv_export" });
+  try {
+    assertEquals(await db.export(filename), 1);
+    const exported = JSON.parse(await Deno.readTextFile(filename));
+    assert(exported.expire_at > Date.now());
+
+    const db2 = await Deno.openKv(":memory:");
+    try {
+      assertEquals(await db2.import(filename), 1);
+      assertEquals((await db2.get(["e"])).value, "1");
+      assertEquals(await db2.export(filename), 1);
+      const reexported = JSON.parse(await Deno.readTextFile(filename));
+      assertEquals(reexported.expire_at, exported.expire_at);
+
+      // Entries that expired since the export are skipped.
+      const line = (entry: Record<string, unknown>) =>
+        JSON.stringify({ ...exported, ...entry }) + "\n";
+      await Deno.writeTextFile(
+        filename,
+        line({ key: [{ type: "string", value: "f" }], expire_at: 1 }),
+      );
+      assertEquals(await db2.import(filename), 0);
+      assertEquals((await db2.get(["f"])).value, null);
+
+      await Deno.writeTextFile(
+        filename,
+        line({
+          key: [{ type: "string", value: "g" }],
+          value: btoa("x".repeat(65537)),
+          encoding: "bytes",
+          expire_at: undefined,
+        }),
+      );
+      await assertRejects(
+        async () => await db2.import(filename),
+        TypeError,
+        "value too large (max 65536 bytes)",
+      );
+      await Deno.writeTextFile(
+        filename,
+        line({
+          key: [{ type: "string", value: "g".repeat(2049) }],
+          expire_at: undefined,
+        }),
+      );
+      await assertRejects(
+        async () => await db2.import(filename),
+        TypeError,
+        "key too large for write (max 2048 bytes)",
+      );
+      await Deno.writeTextFile(
+        filename,
+        line({
+          key: new Array(65).fill({ type: "string", value: "g" }),
+          expire_at: undefined,
+        }),
+      );
+      await assertRejects(
+        async () => await db2.import(filename),
+        TypeError,
+        "too many key parts (max 64)",
+      );
+      assertEquals((await db2.get(["g"])).value, null);
+    } finally {
+      db2.close();
+    }
+  } finally {
+    await Deno.remove(filename);
+  }
+});
+
 Deno.test("KvU64 comparison", () => {
   const a = new Deno.KvU64(1n);
   const b = new Deno.KvU64(1n);
@@ -670,6 +1224,28 @@ dbTest("list prefix", async (db) => {
   ]);
 });
 
+dbTest("count", async (db) => {
+  await setupData(db);
+  const selectors: Deno.KvListSelector[] = [
+    { prefix: ["a"] },
+    { prefix: ["a"], end: ["a", "c"] },
+    { prefix: ["a"], start: ["a", "c"] },
+    { start: ["a"], end: ["b", "a"] },
+    { prefix: ["c"] },
+  ];
+  for (const selector of selectors) {
+    assertEquals(
+      await db.count(selector),
+      (await collect(db.list(selector))).length,
+    );
+  }
+  assertEquals(await db.count({ prefix: ["a"] }), 5);
+  assertEquals(
+    await db.count({ prefix: ["a"] }, { consistency: "eventual" }),
+    5,
+  );
+});
+
 dbTest("list prefix empty", async (db) => {
   await setupData(db);
   const entries = await collect(db.list({ prefix: ["c"] }));
@@ -840,6 +1416,32 @@ dbTest("list prefix with small batch size and limit reverse", async (db) => {
   ]);
 });
 
+dbTest("list prefix with max bytes", async (db) => {
+  const value = "x".repeat(1024);
+  const ao = db.atomic();
+  for (let i = 0; i < 10; i++) {
+    ao.set(["a", i], value);
+  }
+  await ao.commit();
+
+  const entries = await collect(
+    db.list({ prefix: ["a"] }, { batchSize: 100, maxBytes: 2048 }),
+  );
+  assertEquals(entries.length, 10);
+  entries.forEach((e, i) => assertEquals(e.key, ["a", i]));
+
+  const reversed = await collect(
+    db.list({ prefix: ["a"] }, { maxBytes: 1, limit: 3, reverse: true }),
+  );
+  assertEquals(reversed.map((e) => e.key), [["a", 9], ["a", 8], ["a", 7]]);
+
+  await assertRejects(
+    async () => await collect(db.list({ prefix: ["a"] }, { maxBytes: 0 })),
+    Error,
+    "maxBytes must be positive",
+  );
+});
+
 dbTest("list prefix with manual cursor", async (db) => {
   await setupData(db);
 
@@ -1015,6 +1617,168 @@ dbTest("list range with manual cursor reverse", async (db) => {
   ]);
 });
 
+async function assertPaginationMatchesScan(
+  db: Deno.Kv,
+  selector: Deno.KvListSelector,
+  reverse: boolean,
+) {
+  const expected = (await collect(db.list(selector, { reverse })))
+    .map((entry) => entry.key);
+  assert(expected.length > 0);
+  for (let limit = 1; limit <= 3; limit++) {
+    const keys: Deno.KvKey[] = [];
+    let cursor: string | undefined = undefined;
+    while (true) {
+      const iterator: Deno.KvListIterator<unknown> = db.list(selector, {
+        limit,
+        reverse,
+        cursor,
+      });
+      const page = await collect(iterator);
+      keys.push(...page.map((entry) => entry.key));
+      if (page.length < limit) break;
+      cursor = iterator.cursor;
+    }
+    assertEquals(keys, expected);
+  }
+}
+
+dbTest("list range without common prefix with cursor", async (db) => {
+  // Keys of different types don't share any encoded prefix.
+  await db.atomic()
+    .set([new Uint8Array([1])], 0)
+    .set(["a"], 1)
+    .set(["b", 1], 2)
+    .set([1n], 3)
+    .set([1.5], 4)
+    .set([false], 5)
+    .set([true], 6)
+    .commit();
+  const selector = { start: [new Uint8Array()], end: [true] };
+  await assertPaginationMatchesScan(db, selector, false);
+  await assertPaginationMatchesScan(db, selector, true);
+});
+
+dbTest("list range starting at the common prefix with cursor", async (db) => {
+  await setupData(db);
+  // The start key is a prefix of the end key, so the start key itself is the
+  // common prefix and its cursor is empty.
+  const selector = { start: ["a"], end: ["a", "z"] };
+  await assertPaginationMatchesScan(db, selector, false);
+  await assertPaginationMatchesScan(db, selector, true);
+});
+
+dbTest("list with cursor out of bounds", async (db) => {
+  await setupData(db);
+  await db.set(["c"], 5);
+
+  // The cursor of a key outside the selector, from a selector without a
+  // common prefix, so that it encodes the whole key.
+  const iterator = db.list({ start: [new Uint8Array()], end: [true] });
+  for await (const entry of iterator) {
+    if (entry.key[0] === "c") break;
+  }
+  const outsideCursor = iterator.cursor;
+
+  for (const reverse of [false, true]) {
+    // A crafted cursor pointing past the end of the prefix.
+    await assertRejects(
+      async () =>
+        await collect(db.list({ prefix: ["a"] }, { cursor: "_w==", reverse })),
+      TypeError,
+      "cursor out of bounds",
+    );
+    await assertRejects(
+      async () =>
+        await collect(
+          db.list({ start: [new Uint8Array()], end: ["b"] }, {
+            cursor: outsideCursor,
+            reverse,
+          }),
+        ),
+      TypeError,
+      "cursor out of bounds",
+    );
+  }
+});
+
+dbTest("signed zero and NaN key parts", async (db) => {
+  await db.set([-0], "negative zero");
+  const zero = await db.get([0]);
+  assertEquals(zero.value, "negative zero");
+  assert(Object.is(zero.key[0], 0));
+
+  await db.set([NaN], "nan");
+  await db.set([Infinity], "infinity");
+  assertEquals((await db.get([NaN])).value, "nan");
+  // A NaN with a different bit pattern refers to the same key.
+  const otherNaN = new Float64Array(new Uint32Array([1, 0xfff80000]).buffer)[0];
+  assert(Number.isNaN(otherNaN));
+  assertEquals((await db.get([otherNaN])).value, "nan");
+
+  const entries = await collect(db.list({ prefix: [] }));
+  assertEquals(entries.map((entry) => entry.value), [
+    "negative zero",
+    "infinity",
+    "nan",
+  ]);
+  assert(Object.is(entries[0].key[0], 0));
+
+  await db.delete([-0]);
+  assertEquals((await db.get([0])).value, null);
+});
+
+dbTest("list stream", async (db) => {
+  for (let batch = 0; batch < 5; batch++) {
+    const atomic = db.atomic();
+    for (let i = batch * 500; i < (batch + 1) * 500; i++) {
+      atomic.set(["a", i], i);
+    }
+    await atomic.commit();
+  }
+
+  // More entries than a single snapshot read can return.
+  const values = [];
+  for await (const entry of db.listStream({ prefix: ["a"] })) {
+    values.push(entry.value);
+  }
+  assertEquals(values.length, 2500);
+  assertEquals(values[0], 0);
+  assertEquals(values[2499], 2499);
+
+  const reversed = [];
+  for await (
+    const entry of db.listStream({ prefix: ["a"] }, {
+      reverse: true,
+      limit: 250,
+      batchSize: 1000,
+    })
+  ) {
+    reversed.push(entry.value);
+  }
+  assertEquals(reversed.length, 250);
+  assertEquals(reversed[0], 2499);
+  assertEquals(reversed[249], 2250);
+
+  // Breaking out of the iteration closes the stream.
+  for await (const entry of db.listStream({ prefix: ["a"] })) {
+    assertEquals(entry.value, 0);
+    break;
+  }
+
+  await assertRejects(
+    async () => {
+      for await (
+        const _ of db.listStream({ prefix: ["a"] }, { batchSize: 1001 })
+      ) {
+        // pass
+      }
+    },
+    TypeError,
+    "batch size must be between 1 and 1000",
+  );
+});
+
 dbTest("list invalid selector", async (db) => {
   await setupData(db);
 
@@ -1131,6 +1895,81 @@ dbTest("key size limit", async (db) => {
   );
 });
 
+dbTest("key parts limit", async (db) => {
+  const lastValidKey = new Array(64).fill("a");
+  const firstInvalidKey = new Array(65).fill("a");
+
+  await db.set(lastValidKey, 1);
+  assertEquals((await db.get(lastValidKey)).value, 1);
+
+  await assertRejects(
+    async () => await db.set(firstInvalidKey, 1),
+    TypeError,
+    "too many key parts (max 64)",
+  );
+  await assertRejects(
+    async () => await db.atomic().sum(firstInvalidKey, 1n).commit(),
+    TypeError,
+    "too many key parts (max 64)",
+  );
+
+  // Keys with more parts may have been written before the limit existed, so
+  // they can still be read and deleted.
+  assertEquals((await db.get(firstInvalidKey)).value, null);
+  for await (const _ of db.list({ prefix: firstInvalidKey })) {
+    throw new Error("unreachable");
+  }
+  await db.delete(firstInvalidKey);
+});
+
+dbTest("list with bytes prefix", async (db) => {
+  const keys = [
+    [new Uint8Array([1])],
+    [new Uint8Array([1]), "child"],
+    [new Uint8Array([1, 0])],
+    [new Uint8Array([1, 2])],
+    [new Uint8Array([1, 255, 255])],
+    [new Uint8Array([2])],
+    [new Uint8Array([0, 1])],
+  ];
+  for (const key of keys) {
+    await db.set(key, 1);
+  }
+
+  const listKeys = async (selector: Deno.KvListSelector, batchSize = 100) =>
+    (await collect(db.list(selector, { batchSize }))).map((entry) => entry.key);
+
+  assertEquals(await listKeys({ prefix: [new Uint8Array([1])] }), [
+    [new Uint8Array([1]), "child"],
+  ]);
+  const matching = [
+    [new Uint8Array([1])],
+    [new Uint8Array([1]), "child"],
+    [new Uint8Array([1, 0])],
+    [new Uint8Array([1, 2])],
+    [new Uint8Array([1, 255, 255])],
+  ];
+  const bytesPrefix = { prefix: [new Uint8Array([1])], bytesPrefix: true };
+  assertEquals(await listKeys(bytesPrefix), matching);
+  assertEquals(await listKeys(bytesPrefix, 1), matching);
+  assertEquals(
+    await listKeys({ prefix: [new Uint8Array([1, 255])], bytesPrefix: true }),
+    [[new Uint8Array([1, 255, 255])]],
+  );
+  assertEquals(await db.count(bytesPrefix), 5);
+
+  assertThrows(
+    () => db.list({ start: ["a"], end: ["b"], bytesPrefix: true } as never),
+    TypeError,
+    "Selector can only specify 'bytesPrefix' when specifying 'prefix'.",
+  );
+  await assertRejects(
+    async () => await collect(db.list({ prefix: ["a"], bytesPrefix: true })),
+    TypeError,
+    "bytesPrefix requires a prefix whose last part is a Uint8Array",
+  );
+});
+
 dbTest("value size limit", async (db) => {
   const lastValidValue = new Uint8Array(65536);
   const firstInvalidValue = new Uint8Array(65537);
@@ -1177,6 +2016,13 @@ dbTest("operation size limit", async (db) => {
     "too many entries (max 1000)",
   );
 
+  await assertRejects(
+    async () =>
+      await collect(db.list({ prefix: ["a"] }, { batchSize: 2 ** 32 - 1 })),
+    TypeError,
+    "too many entries (max 1000)",
+  );
+
   // when batchSize is not specified, limit is used but is clamped to 500
   assertEquals(
     (await collect(db.list({ prefix: ["a"] }, { limit: 1001 }))).length,
@@ -1298,6 +2144,43 @@ dbTest("total key size limit", async (db) => {
   );
 });
 
+dbTest("enqueue payload size limit", async (db) => {
+  // Queue payloads may be larger than values.
+  const largeValue = new Uint8Array(100_000);
+  await assertRejects(
+    () => db.set(["a"], largeValue),
+    TypeError,
+    "value too large (max 65536 bytes)",
+  );
+  const res = await db.enqueue(largeValue);
+  assert(res.ok);
+
+  await assertRejects(
+    () => db.enqueue(new Uint8Array(262145)),
+    TypeError,
+    "enqueue payload too large (max 262144 bytes)",
+  );
+});
+
+dbTest("keysIfUndelivered limits", async (db) => {
+  const tenKeys: Deno.KvKey[] = new Array(10).fill(0).map((_, i) => ["u", i]);
+  const res = await db.enqueue("msg", { keysIfUndelivered: tenKeys });
+  assert(res.ok);
+
+  await assertRejects(
+    () => db.enqueue("msg", { keysIfUndelivered: [...tenKeys, ["u", 10]] }),
+    TypeError,
+    "too many keysIfUndelivered (max 10)",
+  );
+
+  const longString = new Array(2048).fill("a").join("");
+  await assertRejects(
+    () => db.enqueue("msg", { keysIfUndelivered: [[longString]] }),
+    TypeError,
+    "key too large for write (max 2048 bytes)",
+  );
+});
+
 dbTest("keys must be arrays", async (db) => {
   await assertRejects(
     // @ts-expect-error invalid type
@@ -1556,6 +2439,44 @@ dbTest("queue large delay", async (db) => {
   }, TypeError);
 });
 
+queueTest("queue at absolute time", async (db) => {
+  let dequeueTime: number | undefined;
+  const promise = deferred();
+  const listener = db.listenQueue((_msg) => {
+    dequeueTime = Date.now();
+    promise.resolve();
+  });
+  try {
+    const at = new Date(Date.now() + 1000);
+    // The absolute time wins over the delay.
+    const res = await db.atomic()
+      .enqueue("test", { at, delay: 60000 })
+      .commit();
+    assert(res.ok);
+    await promise;
+    assert(dequeueTime !== undefined);
+    assert(dequeueTime >= at.getTime());
+  } finally {
+    db.close();
+    await listener;
+  }
+});
+
+dbTest("queue at limits", async (db) => {
+  const maxDelay = 30 * 24 * 60 * 60 * 1000;
+  await db.enqueue("test", { at: new Date(0) });
+  await assertRejects(
+    () => db.enqueue("test", { at: new Date(Date.now() + maxDelay + 60000) }),
+    TypeError,
+    "enqueue time cannot be more than 2592000000 ms in the future",
+  );
+  await assertRejects(
+    () => db.enqueue("test", { at: new Date(NaN) }),
+    TypeError,
+    "at must be a valid Date",
+  );
+});
+
 queueTest("listenQueue with async callback", async (db) => {
   const promise = deferred();
   let dequeuedMessage: unknown = null;
@@ -1593,6 +2514,88 @@ queueTest("queue retries", async (db) => {
   assertEquals(4, count);
 });
 
+queueTest("listenQueue reports delivery info", async (db) => {
+  const promise = deferred();
+  const attempts: (number | null)[] = [];
+  const enqueuedAts: (Date | null)[] = [];
+  const listener = db.listenQueue((_msg, info) => {
+    attempts.push(info.attempt);
+    enqueuedAts.push(info.enqueuedAt);
+    if (attempts.length === 1) {
+      throw new TypeError("dequeue error");
+    }
+    promise.resolve();
+  });
+  try {
+    const before = Date.now();
+    await db.enqueue("test");
+    const after = Date.now();
+    await promise;
+    assertEquals(attempts, [1, 2]);
+    // Retries keep the original enqueue time.
+    assertEquals(enqueuedAts[0], enqueuedAts[1]);
+    const enqueuedAt = enqueuedAts[0]!.getTime();
+    assert(enqueuedAt >= before && enqueuedAt <= after);
+  } finally {
+    db.close();
+    await listener;
+  }
+});
+
+queueTest("listenQueue handlers can extend their deadline", async (db) => {
+  const promise = deferred();
+  const attempts: (number | null)[] = [];
+  const listener = db.listenQueue(async (_msg, info) => {
+    attempts.push(info.attempt);
+    await assertRejects(
+      () => info.extendDeadline(-1),
+      TypeError,
+      "timeout must be a positive integer",
+    );
+    await info.extendDeadline();
+    await info.extendDeadline(60000);
+    promise.resolve();
+  });
+  try {
+    await db.enqueue("test");
+    await promise;
+    assertEquals(attempts, [1]);
+  } finally {
+    db.close();
+    await listener;
+  }
+});
+
+queueTest("atomic check queue running", async (db) => {
+  const promise = deferred<string>();
+  const listener = db.listenQueue(async (_msg, info) => {
+    assert(info.id !== null);
+    const res = await db.atomic()
+      .checkQueueRunning(info.id)
+      .set(["done"], true)
+      .commit();
+    assert(res.ok);
+    promise.resolve(info.id);
+  });
+  try {
+    await db.enqueue("test");
+    const messageId = await promise;
+    assertEquals((await db.get(["done"])).value, true);
+
+    // The message is finished once the handler returns.
+    await sleep(100);
+    const res = await db.atomic()
+      .checkQueueRunning(messageId)
+      .set(["done"], false)
+      .commit();
+    assert(!res.ok);
+    assertEquals((await db.get(["done"])).value, true);
+  } finally {
+    db.close();
+    await listener;
+  }
+});
+
 queueTest("multiple listenQueues", async (db) => {
   const numListens = 10;
   let count = 0;
@@ -1703,6 +2706,136 @@ queueTest("enqueue with atomic nonce", async (db) => {
   }
 });
 
+dbTest("queue dead letters", async (db) => {
+  assertEquals(await db.listDeadLetters(), []);
+  assertEquals(await db.retryDeadLetter("nonexistent"), false);
+  await assertRejects(async () => {
+    await db.listDeadLetters({ limit: 0 });
+  }, TypeError);
+  await assertRejects(async () => {
+    await db.listDeadLetters({ limit: 1001 });
+  }, TypeError);
+});
+
+dbTest("queue stats", async (db) => {
+  assertEquals(await db.queueStats(), {
+    ready: 0,
+    running: 0,
+    earliestReady: null,
+  });
+  const before = Date.now();
+  await db.enqueue("a", { delay: 60000 });
+  await db.enqueue("b", { delay: 120000 });
+  const stats = await db.queueStats();
+  assertEquals(stats.ready, 2);
+  assertEquals(stats.running, 0);
+  assert(stats.earliestReady !== null);
+  assert(stats.earliestReady.getTime() >= before + 60000);
+  assert(stats.earliestReady.getTime() < before + 120000);
+});
+
+dbTest("database stats", async (db) => {
+  const empty = await db.stats();
+  assertEquals(empty.keyCount, 0);
+  assertEquals(empty.totalValueBytes, 0);
+  await db.set(["a"], new Uint8Array(10));
+  await db.set(["b"], new Uint8Array(20));
+  await db.set(["c"], new Deno.KvU64(1n));
+  const stats = await db.stats();
+  assertEquals(stats.keyCount, 3);
+  assertEquals(stats.totalValueBytes, 38);
+  assert(stats.dbFileBytes > 0);
+});
+
+dbTest("list union", async (db) => {
+  await db.set(["a", 1], "a1");
+  await db.set(["a", 2], "a2");
+  await db.set(["a", 2, "x"], "a2x");
+  await db.set(["b", 1], "b1");
+  await db.set(["c", 1], "c1");
+
+  // ["a", 2] is also under ["a"], and ["b"] is listed twice.
+  const { entries, hasMore } = await db.listUnion([
+    ["b"],
+    ["a", 2],
+    ["a"],
+    ["b"],
+  ]);
+  assertEquals(entries.map((entry) => entry.key), [
+    ["a", 1],
+    ["a", 2],
+    ["a", 2, "x"],
+    ["b", 1],
+  ]);
+  assertEquals(hasMore, false);
+
+  const limited = await db.listUnion([["c"], ["a"]], { limit: 2 });
+  assertEquals(limited.entries.map((entry) => entry.value), ["a1", "a2"]);
+  assertEquals(limited.hasMore, true);
+});
+
+dbTest("ping", async (db) => {
+  const latency = await db.ping();
+  assert(latency >= 0);
+});
+
+dbTest("check integrity", async (db) => {
+  await db.set(["a"], 1);
+  await db.enqueue("msg");
+  assertEquals(await db.checkIntegrity(), []);
+});
+
+dbTest("query unknown index", async (db) => {
+  await assertRejects(async () => {
+    await db.queryIndex("missing", { prefix: [] });
+  }, TypeError);
+  await assertRejects(async () => {
+    await db.queryIndex("missing", { prefix: [] }, { limit: 1001 });
+  }, TypeError);
+});
+
+dbTest("maintenance in memory", async (db) => {
+  // In-memory databases don't use a write-ahead log.
+  assertEquals(await db.maintenance(), {
+    busy: false,
+    logFrames: -1,
+    checkpointedFrames: -1,
+  });
+  await assertRejects(async () => {
+    // @ts-expect-error invalid mode
+    await db.maintenance("invalid");
+  }, TypeError);
+});
+
+Deno.test({
+  name: "maintenance truncates the write-ahead log",
+  async fn() {
+    const filename = await Deno.makeTempFile({ prefix: "maintenance_db" });
+    try {
+      const db = await Deno.openKv(filename);
+      try {
+        for (let i = 0; i < 100; i++) {
+          await db.set(["key", i], "x".repeat(1000));
+        }
+        const passive = await db.maintenance("passive");
+        assertEquals(passive.busy, false);
+        assert(passive.logFrames > 0);
+        assertEquals(passive.checkpointedFrames, passive.logFrames);
+
+        await db.maintenance("vacuum");
+        assertEquals((await Deno.stat(filename + "-wal")).size, 0);
+        assertEquals((await db.get(["key", 99])).value, "x".repeat(1000));
+      } finally {
+        db.close();
+      }
+    } finally {
+      await Deno.remove(filename);
+      await Deno.remove(filename + "-wal").catch(() => {});
+      await Deno.remove(filename + "-shm").catch(() => {});
+    }
+  },
+});
+
 Deno.test({
   name: "queue persistence with inflight messages",
   sanitizeOps: false,
@@ -1863,6 +2996,44 @@ Deno.test({
   },
 });
 
+Deno.test({
+  name: "queue close gracefully waits for handlers",
+  async fn() {
+    const db: Deno.Kv = await Deno.openKv(":memory:");
+    const started = deferred();
+    let finished = false;
+    const listener = db.listenQueue(async (_msg) => {
+      started.resolve();
+      await sleep(100);
+      finished = true;
+    });
+    await db.enqueue("test");
+    await started;
+    assert(await db.closeGracefully({ timeout: 5000 }));
+    assert(finished);
+    await listener;
+  },
+});
+
+Deno.test({
+  name: "queue close gracefully times out",
+  async fn() {
+    const db: Deno.Kv = await Deno.openKv(":memory:");
+    const started = deferred();
+    const release = deferred();
+    const listener = db.listenQueue(async (_msg) => {
+      started.resolve();
+      await release;
+    });
+    await db.enqueue("test");
+    await started;
+    assertEquals(await db.closeGracefully({ timeout: 10 }), false);
+    release.resolve();
+    await listener;
+    assertThrows(() => db.close(), Deno.errors.BadResource);
+  },
+});
+
 dbTest("atomic operation is exposed", (db) => {
   assert(Deno.AtomicOperation);
   const ao = db.atomic();
@@ -1890,6 +3061,34 @@ Deno.test({
   },
 });
 
+Deno.test({
+  name: "closing a shared database stops only its own listener",
+  async fn() {
+    const filename = await Deno.makeTempFile({ prefix: "shared_listen_db" });
+    try {
+      const db1 = await Deno.openKv(filename);
+      const db2 = await Deno.openKv(filename);
+      const listener1 = db1.listenQueue(() => {
+        throw new Error("closed database received a message");
+      });
+      const received = deferred<unknown>();
+      const listener2 = db2.listenQueue((msg) => received.resolve(msg));
+
+      // The first listener stops although the database stays open for the
+      // second handle, which gets every message from now on.
+      db1.close();
+      await listener1;
+      await db2.enqueue("msg");
+      assertEquals(await received, "msg");
+
+      db2.close();
+      await listener2;
+    } finally {
+      await Deno.remove(filename);
+    }
+  },
+});
+
 Deno.test({
   name: "racy write",
   async fn() {
@@ -1982,6 +3181,51 @@ Deno.test({
   },
 });
 
+dbTest("expired entries are not read before they are swept", async (db) => {
+  await db.set(["a"], 1, { expireIn: 100 });
+  await db.set(["b"], 2, { expireIn: 100 });
+  await db.set(["c"], 3);
+  assertEquals((await db.get(["a"])).value, 1);
+
+  await sleep(200);
+
+  assertEquals((await db.get(["a"])).value, null);
+  assertEquals(
+    (await db.getMany([["a"], ["b"], ["c"]])).map((x) => x.value),
+    [null, null, 3],
+  );
+  assertEquals(
+    (await collect(db.list({ prefix: [] }))).map((x) => x.key),
+    [["c"]],
+  );
+  assertEquals(await db.count({ prefix: [] }), 1);
+
+  // A logically expired key satisfies a check for a missing key.
+  const res = await db.atomic().check({ key: ["a"], versionstamp: null })
+    .set(["a"], 4).commit();
+  assert(res.ok);
+  assertEquals((await db.get(["a"])).value, 4);
+});
+
+dbTest("entries report their expiration time", async (db) => {
+  const before = Date.now();
+  await db.set(["a"], 1, { expireIn: 60_000 });
+  await db.set(["b"], 2);
+  const after = Date.now();
+
+  const a = await db.get(["a"]);
+  assert(a.versionstamp !== null && a.expireAt !== undefined);
+  assert(a.expireAt >= before + 60_000 && a.expireAt <= after + 60_000);
+  assertEquals("expireAt" in await db.get(["b"]), false);
+
+  const entries = await collect(db.list({ prefix: [] }));
+  assertEquals(entries.map((x) => x.expireAt), [a.expireAt, undefined]);
+
+  // Overwriting without `expireIn` clears the expiration.
+  await db.set(["a"], 3);
+  assertEquals("expireAt" in await db.get(["a"]), false);
+});
+
 Deno.test({
   name: "kv expiration with atomic",
   async fn() {
@@ -2060,6 +3304,38 @@ Deno.test({
   },
 });
 
+Deno.test({
+  name: "remote backend enqueue",
+  async fn() {
+    const db = await Deno.openKv("http://localhost:4545/kv_remote_authorize");
+    try {
+      const res = await db.enqueue("msg", {
+        delay: 1000,
+        keysIfUndelivered: [["undelivered"]],
+      });
+      assert(res.ok);
+      const res2 = await db.atomic()
+        .set(["some-key"], 1)
+        .enqueue("msg2")
+        .commit();
+      assert(res2.ok);
+    } finally {
+      db.close();
+    }
+  },
+});
+
+Deno.test({
+  name: "remote backend listenQueue terminates on close",
+  async fn() {
+    const db = await Deno.openKv("http://localhost:4545/kv_remote_authorize");
+    const listener = db.listenQueue((_msg) => {});
+    await sleep(500);
+    db.close();
+    await listener;
+  },
+});
+
 Deno.test({
   name: "remote backend invalid format",
   async fn() {
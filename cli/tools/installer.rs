@@ -7,6 +7,7 @@ use crate::args::InstallFlags;
 use crate::args::TypeCheckMode;
 use crate::factory::CliFactory;
 use crate::http_util::HttpClient;
+use crate::npm::CliNpmResolver;
 use crate::util::fs::canonicalize_path_maybe_not_exists;
 
 use deno_config::ConfigFlag;
@@ -15,7 +16,10 @@ use deno_core::error::generic_error;
 use deno_core::error::AnyError;
 use deno_core::resolve_url_or_path;
 use deno_core::url::Url;
+use deno_core::ModuleSpecifier;
+use deno_graph::ModuleGraph;
 use deno_semver::npm::NpmPackageReqReference;
+use deno_semver::package::PackageReqReference;
 use log::Level;
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -55,7 +59,7 @@ fn validate_name(exec_name: &str) -> Result<(), AnyError> {
 /// Generate batch script to satisfy that.
 fn generate_executable_file(shim_data: &ShimData) -> Result<(), AnyError> {
   let args: Vec<String> =
-    shim_data.args.iter().map(|c| format!("\"{c}\"")).collect();
+    shim_data.args.iter().map(|c| quote_shim_arg(c)).collect();
   let template = format!(
     "% generated by deno install %\n@deno {} %*\n",
     args
@@ -105,6 +109,153 @@ exec deno {} "$@"
   Ok(())
 }
 
+/// Reads back the arguments that a shim written by `generate_executable_file`
+/// passes to `deno`, not including the forwarded command line arguments.
+fn read_shim_args(file_path: &Path) -> Result<Vec<String>, AnyError> {
+  let content = fs::read_to_string(file_path)
+    .with_context(|| format!("error reading {}", file_path.display()))?;
+  parse_shim_args(&content).ok_or_else(|| {
+    generic_error(format!(
+      "{} was not generated by deno install",
+      file_path.display()
+    ))
+  })
+}
+
+/// Quotes an argument the way Windows programs split their command line, where
+/// a quote within an argument is escaped with a backslash, and backslashes
+/// only need escaping in front of a quote.
+#[cfg(windows)]
+fn quote_shim_arg(arg: &str) -> String {
+  let mut quoted = String::from('"');
+  let mut backslashes = 0;
+  for c in arg.chars() {
+    if c == '"' {
+      quoted.extend(std::iter::repeat('\\').take(backslashes + 1));
+    }
+    backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+    quoted.push(c);
+  }
+  quoted.extend(std::iter::repeat('\\').take(backslashes));
+  quoted.push('"');
+  quoted
+}
+
+#[cfg(windows)]
+fn parse_shim_args(content: &str) -> Option<Vec<String>> {
+  let line = content
+    .lines()
+    .find_map(|line| line.strip_prefix("@deno "))?;
+  let line = line.trim_end().strip_suffix("%*")?.replace("%%", "%");
+  let mut rest = line.trim();
+  let mut args = vec![];
+  while !rest.is_empty() {
+    let mut chars = rest.strip_prefix('"')?.char_indices();
+    let mut arg = String::new();
+    let mut backslashes = 0;
+    let end = loop {
+      let (i, c) = chars.next()?;
+      if c == '\\' {
+        backslashes += 1;
+        continue;
+      }
+      let escaped = c == '"' && backslashes % 2 == 1;
+      let literal = if c == '"' {
+        backslashes / 2
+      } else {
+        backslashes
+      };
+      arg.extend(std::iter::repeat('\\').take(literal));
+      backslashes = 0;
+      if c == '"' && !escaped {
+        break i;
+      }
+      arg.push(c);
+    };
+    args.push(arg);
+    rest = rest[end + 2..].trim_start();
+  }
+  Some(args)
+}
+
+#[cfg(not(windows))]
+fn parse_shim_args(content: &str) -> Option<Vec<String>> {
+  let line = content
+    .lines()
+    .find_map(|line| line.strip_prefix("exec deno "))?;
+  let line = line.trim_end().strip_suffix(r#""$@""#)?;
+  let mut args = vec![];
+  let mut current: Option<String> = None;
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      ' ' => args.extend(current.take()),
+      '\'' => {
+        let arg = current.get_or_insert_with(String::new);
+        loop {
+          match chars.next()? {
+            '\'' => break,
+            c => arg.push(c),
+          }
+        }
+      }
+      '\\' => current.get_or_insert_with(String::new).push(chars.next()?),
+      c => current.get_or_insert_with(String::new).push(c),
+    }
+  }
+  args.extend(current);
+  Some(args)
+}
+
+/// Flags written by `resolve_shim_data` that take their value as a separate
+/// argument.
+const SHIM_FLAGS_WITH_VALUE: &[&str] = &[
+  "--location",
+  "--cert",
+  "--log-level",
+  "--seed",
+  "--import-map",
+  "--config",
+  "--lock",
+];
+
+/// Splits the arguments of a shim into the installed module URL and the
+/// arguments that are passed to it.
+fn parse_installed_module(
+  shim_args: &[String],
+) -> Option<(String, Vec<String>)> {
+  let mut args = shim_args.iter();
+  if args.next()? != "run" {
+    return None;
+  }
+  while let Some(arg) = args.next() {
+    if SHIM_FLAGS_WITH_VALUE.contains(&arg.as_str()) {
+      args.next();
+    } else if !arg.starts_with('-') {
+      return Some((arg.clone(), args.cloned().collect()));
+    }
+  }
+  None
+}
+
+fn get_shim_file_path(installation_dir: &Path, name: &str) -> PathBuf {
+  let file_path = installation_dir.join(name);
+  if cfg!(windows) {
+    file_path.with_extension("cmd")
+  } else {
+    file_path
+  }
+}
+
+fn get_installation_dir(root: Option<&PathBuf>) -> Result<PathBuf, AnyError> {
+  let root = if let Some(root) = root {
+    canonicalize_path_maybe_not_exists(root)?
+  } else {
+    get_installer_root()?
+  };
+  Ok(root.join("bin"))
+}
+
 fn get_installer_root() -> Result<PathBuf, io::Error> {
   if let Ok(env_dir) = env::var("DENO_INSTALL_ROOT") {
     if !env_dir.is_empty() {
@@ -177,21 +328,120 @@ pub async fn infer_name_from_url(url: &Url) -> Option<String> {
 }
 
 pub fn uninstall(name: String, root: Option<PathBuf>) -> Result<(), AnyError> {
-  let root = if let Some(root) = root {
-    canonicalize_path_maybe_not_exists(&root)?
-  } else {
-    get_installer_root()?
-  };
-  let installation_dir = root.join("bin");
+  let installation_dir = get_installation_dir(root.as_ref())?;
+  ensure_installation_dir_is_dir(&installation_dir)?;
 
-  // ensure directory exists
-  if let Ok(metadata) = fs::metadata(&installation_dir) {
+  if !remove_installation(&installation_dir, &name)? {
+    return Err(generic_error(format!("No installation found for {name}")));
+  }
+
+  log::info!("✅ Successfully uninstalled {}", name);
+  Ok(())
+}
+
+pub fn uninstall_all(root: Option<PathBuf>) -> Result<(), AnyError> {
+  let installation_dir = get_installation_dir(root.as_ref())?;
+  ensure_installation_dir_is_dir(&installation_dir)?;
+
+  let scripts = get_installed_scripts(&installation_dir)?;
+  if scripts.is_empty() {
+    log::info!(
+      "No installed scripts found in {}",
+      installation_dir.display()
+    );
+    return Ok(());
+  }
+  for script in &scripts {
+    remove_installation(&installation_dir, &script.name)?;
+  }
+
+  log::info!("✅ Successfully uninstalled {} scripts", scripts.len());
+  Ok(())
+}
+
+pub fn list_installed(root: Option<PathBuf>) -> Result<(), AnyError> {
+  let installation_dir = get_installation_dir(root.as_ref())?;
+  ensure_installation_dir_is_dir(&installation_dir)?;
+
+  let scripts = get_installed_scripts(&installation_dir)?;
+  if scripts.is_empty() {
+    log::info!(
+      "No installed scripts found in {}",
+      installation_dir.display()
+    );
+    return Ok(());
+  }
+  let name_width = scripts.iter().map(|s| s.name.len()).max().unwrap_or(0);
+  for script in scripts {
+    println!("{:name_width$}  {}", script.name, script.module_url);
+  }
+  Ok(())
+}
+
+fn ensure_installation_dir_is_dir(
+  installation_dir: &Path,
+) -> Result<(), AnyError> {
+  if let Ok(metadata) = fs::metadata(installation_dir) {
     if !metadata.is_dir() {
       return Err(generic_error("Installation path is not a directory"));
     }
   }
+  Ok(())
+}
 
-  let file_path = installation_dir.join(&name);
+struct InstalledScript {
+  name: String,
+  module_url: String,
+}
+
+/// Finds the shims generated by `deno install` in the installation directory,
+/// sorted by name. Other files, like the deno executable, are skipped.
+fn get_installed_scripts(
+  installation_dir: &Path,
+) -> Result<Vec<InstalledScript>, AnyError> {
+  let entries = match fs::read_dir(installation_dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  let mut scripts = vec![];
+  for entry in entries {
+    let file_path = entry?.path();
+    let name = if cfg!(windows) {
+      file_path.file_stem()
+    } else {
+      file_path.file_name()
+    };
+    let Some(name) = name.and_then(|name| name.to_str()) else {
+      continue;
+    };
+    if validate_name(name).is_err()
+      || get_shim_file_path(installation_dir, name) != file_path
+    {
+      continue;
+    }
+    // the deno executable and other binaries aren't valid UTF-8
+    let Ok(shim_args) = read_shim_args(&file_path) else {
+      continue;
+    };
+    if let Some((module_url, _)) = parse_installed_module(&shim_args) {
+      scripts.push(InstalledScript {
+        name: name.to_string(),
+        module_url,
+      });
+    }
+  }
+  scripts.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(scripts)
+}
+
+/// Removes the shim for `name` along with its extra files, returning whether
+/// an installation was found.
+fn remove_installation(
+  installation_dir: &Path,
+  name: &str,
+) -> Result<bool, AnyError> {
+  let file_path = installation_dir.join(name);
 
   let mut removed = false;
 
@@ -211,40 +461,126 @@ pub fn uninstall(name: String, root: Option<PathBuf>) -> Result<(), AnyError> {
   }
 
   if !removed {
-    return Err(generic_error(format!("No installation found for {name}")));
+    return Ok(false);
   }
 
-  // There might be some extra files to delete
+  for file_path in remove_extra_files(&file_path)? {
+    log::info!("deleted {}", file_path.to_string_lossy());
+  }
+
+  Ok(true)
+}
+
+/// Removes the config and lock files that were written for the shim at
+/// `file_path`, and returns their paths.
+fn remove_extra_files(file_path: &Path) -> Result<Vec<PathBuf>, AnyError> {
+  let mut removed = vec![];
   // Note: tsconfig.json is legacy. We renamed it to deno.json.
   // Remove cleaning it up after January 2024
   for ext in ["tsconfig.json", "deno.json", "lock.json"] {
-    let file_path = file_path.with_extension(ext);
-    if file_path.exists() {
-      fs::remove_file(&file_path)?;
-      log::info!("deleted {}", file_path.to_string_lossy());
+    for file_path in [
+      file_path.with_extension(ext),
+      get_hidden_file_with_ext(file_path, ext),
+    ] {
+      if file_path.exists() {
+        fs::remove_file(&file_path)?;
+        removed.push(file_path);
+      }
     }
   }
-
-  log::info!("✅ Successfully uninstalled {}", name);
-  Ok(())
+  Ok(removed)
 }
 
 pub async fn install_command(
   flags: Flags,
   install_flags: InstallFlags,
 ) -> Result<(), AnyError> {
+  let mut install_flags = resolve_update_flags(install_flags)?;
+
   // ensure the module is cached
-  CliFactory::from_flags(flags.clone())
-    .await?
-    .module_load_preparer()
-    .await?
-    .load_and_type_check_files(&[install_flags.module_url.clone()])
-    .await?;
+  if let Some(module_url) = &install_flags.module_url {
+    let factory = CliFactory::from_flags(flags.clone()).await?;
+    factory
+      .module_load_preparer()
+      .await?
+      .load_and_type_check_files(&[module_url.clone()])
+      .await?;
+
+    // pin npm and jsr specifiers to the version that was just cached
+    let cwd = std::env::current_dir().context("Unable to get CWD")?;
+    let specifier = resolve_url_or_path(module_url, &cwd)?;
+    if let Some(pinned) = pin_package_specifier(
+      &specifier,
+      &factory.graph_container().graph(),
+      factory.npm_resolver().await?.as_ref(),
+    ) {
+      install_flags.module_url = Some(pinned);
+    }
+  }
 
   // create the install shim
   create_install_shim(flags, install_flags).await
 }
 
+/// Resolves an npm or jsr specifier to the exact version that was loaded, so
+/// that later registry changes don't alter what the shim runs.
+fn pin_package_specifier(
+  specifier: &ModuleSpecifier,
+  graph: &ModuleGraph,
+  npm_resolver: &dyn CliNpmResolver,
+) -> Option<String> {
+  let (scheme, nv, sub_path) =
+    if let Ok(npm_ref) = NpmPackageReqReference::from_specifier(specifier) {
+      let npm_ref = npm_ref.into_inner();
+      let snapshot = npm_resolver.as_managed()?.snapshot();
+      let nv = snapshot.package_reqs().get(&npm_ref.req)?.clone();
+      ("npm", nv, npm_ref.sub_path)
+    } else if let Some(jsr_ref) = specifier
+      .as_str()
+      .strip_prefix("jsr:")
+      .and_then(|specifier| PackageReqReference::from_str(specifier).ok())
+    {
+      let nv = graph.packages.mappings().get(&jsr_ref.req)?.clone();
+      ("jsr", nv, jsr_ref.sub_path)
+    } else {
+      return None;
+    };
+  Some(match sub_path {
+    Some(sub_path) => format!("{scheme}:{nv}/{sub_path}"),
+    None => format!("{scheme}:{nv}"),
+  })
+}
+
+/// When updating an installation without giving a new module URL, takes the
+/// module URL and its arguments from the existing shim.
+fn resolve_update_flags(
+  mut install_flags: InstallFlags,
+) -> Result<InstallFlags, AnyError> {
+  if !install_flags.update || install_flags.module_url.is_some() {
+    return Ok(install_flags);
+  }
+  let Some(name) = &install_flags.name else {
+    return Err(generic_error(
+      "An executable name must be provided with --name to update an installation without a module URL.",
+    ));
+  };
+  let installation_dir = get_installation_dir(install_flags.root.as_ref())?;
+  let file_path = get_shim_file_path(&installation_dir, name);
+  if !file_path.exists() {
+    return Err(generic_error(format!("No installation found for {name}")));
+  }
+  let shim_args = read_shim_args(&file_path)?;
+  let Some((module_url, args)) = parse_installed_module(&shim_args) else {
+    return Err(generic_error(format!(
+      "Unable to find the installed module in {}",
+      file_path.display()
+    )));
+  };
+  install_flags.module_url = Some(module_url);
+  install_flags.args = args;
+  Ok(install_flags)
+}
+
 async fn create_install_shim(
   flags: Flags,
   install_flags: InstallFlags,
@@ -260,18 +596,28 @@ async fn create_install_shim(
     fs::create_dir_all(&shim_data.installation_dir)?;
   };
 
-  if shim_data.file_path.exists() && !install_flags.force {
+  let is_update = shim_data.file_path.exists();
+  if is_update && !install_flags.force && !install_flags.update {
     return Err(generic_error(
-      "Existing installation found. Aborting (Use -f to overwrite).",
+      "Existing installation found. Aborting (Use -f to overwrite or --update to change its flags).",
     ));
   };
 
+  // The config and lock files of the previous installation are stale, even
+  // if the new flags don't replace them.
+  if is_update {
+    remove_extra_files(&shim_data.file_path)?;
+  }
   generate_executable_file(&shim_data)?;
   for (path, contents) in shim_data.extra_files {
     fs::write(path, contents)?;
   }
 
-  log::info!("✅ Successfully installed {}", shim_data.name);
+  if is_update && install_flags.update {
+    log::info!("✅ Successfully updated {}", shim_data.name);
+  } else {
+    log::info!("✅ Successfully installed {}", shim_data.name);
+  }
   log::info!("{}", shim_data.file_path.display());
   if cfg!(windows) {
     let display_path = shim_data.file_path.with_extension("");
@@ -303,16 +649,15 @@ async fn resolve_shim_data(
   flags: &Flags,
   install_flags: &InstallFlags,
 ) -> Result<ShimData, AnyError> {
-  let root = if let Some(root) = &install_flags.root {
-    canonicalize_path_maybe_not_exists(root)?
-  } else {
-    get_installer_root()?
+  let installation_dir = get_installation_dir(install_flags.root.as_ref())?;
+
+  let Some(module_url) = &install_flags.module_url else {
+    return Err(generic_error("A module URL was not provided. Aborting."));
   };
-  let installation_dir = root.join("bin");
 
   // Check if module_url is remote
   let cwd = std::env::current_dir().context("Unable to get CWD")?;
-  let module_url = resolve_url_or_path(&install_flags.module_url, &cwd)?;
+  let module_url = resolve_url_or_path(module_url, &cwd)?;
 
   let name = if install_flags.name.is_some() {
     install_flags.name.clone()
@@ -328,11 +673,7 @@ async fn resolve_shim_data(
   };
 
   validate_name(name.as_str())?;
-  let mut file_path = installation_dir.join(&name);
-
-  if cfg!(windows) {
-    file_path = file_path.with_extension("cmd");
-  }
+  let file_path = get_shim_file_path(&installation_dir, &name);
 
   let mut extra_files: Vec<(PathBuf, String)> = vec![];
 
@@ -632,11 +973,13 @@ mod tests {
         ..Flags::default()
       },
       InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -666,11 +1009,13 @@ mod tests {
     let shim_data = resolve_shim_data(
       &Flags::default(),
       &InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec![],
         name: None,
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -688,11 +1033,13 @@ mod tests {
     let shim_data = resolve_shim_data(
       &Flags::default(),
       &InstallFlags {
-        module_url: "http://localhost:4545/subdir/main.ts".to_string(),
+        module_url: Some("http://localhost:4545/subdir/main.ts".to_string()),
         args: vec![],
         name: None,
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -711,12 +1058,16 @@ mod tests {
     let shim_data = resolve_shim_data(
       &Flags::default(),
       &InstallFlags {
-        module_url: "http://localhost:4550/?redirect_to=/subdir/redirects/a.ts"
-          .to_string(),
+        module_url: Some(
+          "http://localhost:4550/?redirect_to=/subdir/redirects/a.ts"
+            .to_string(),
+        ),
         args: vec![],
         name: None,
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -738,11 +1089,13 @@ mod tests {
     let shim_data = resolve_shim_data(
       &Flags::default(),
       &InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -766,11 +1119,13 @@ mod tests {
         ..Flags::default()
       },
       &InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec!["--foobar".to_string()],
         name: Some("echo_test".to_string()),
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -799,11 +1154,13 @@ mod tests {
         ..Flags::default()
       },
       &InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -828,11 +1185,13 @@ mod tests {
         ..Flags::default()
       },
       &InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -858,11 +1217,13 @@ mod tests {
         ..Flags::default()
       },
       &InstallFlags {
-        module_url: "npm:cowsay".to_string(),
+        module_url: Some("npm:cowsay".to_string()),
         args: vec![],
         name: None,
         root: Some(temp_dir.clone()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -892,11 +1253,13 @@ mod tests {
         ..Flags::default()
       },
       &InstallFlags {
-        module_url: "npm:cowsay".to_string(),
+        module_url: Some("npm:cowsay".to_string()),
         args: vec![],
         name: None,
         root: Some(env::temp_dir()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -927,11 +1290,13 @@ mod tests {
     create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: local_module_str.to_string(),
+        module_url: Some(local_module_str.to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -956,11 +1321,13 @@ mod tests {
     create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -976,11 +1343,13 @@ mod tests {
     let no_force_result = create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: "http://localhost:4545/cat.ts".to_string(), // using a different URL
+        module_url: Some("http://localhost:4545/cat.ts".to_string()), // using a different URL
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await;
@@ -997,11 +1366,13 @@ mod tests {
     let force_result = create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: "http://localhost:4545/cat.ts".to_string(), // using a different URL
+        module_url: Some("http://localhost:4545/cat.ts".to_string()), // using a different URL
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: true,
+        update: false,
+        list: false,
       },
     )
     .await;
@@ -1011,6 +1382,130 @@ mod tests {
     assert!(file_content_2.contains("cat.ts"));
   }
 
+  #[tokio::test]
+  async fn install_update() {
+    let temp_dir = TempDir::new();
+    let bin_dir = temp_dir.path().join("bin");
+    std::fs::create_dir(&bin_dir).unwrap();
+
+    create_install_shim(
+      Flags::default(),
+      InstallFlags {
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
+        args: vec!["--foo".to_string(), "it's".to_string()],
+        name: Some("echo_test".to_string()),
+        root: Some(temp_dir.path().to_path_buf()),
+        force: false,
+        update: false,
+        list: false,
+      },
+    )
+    .await
+    .unwrap();
+
+    // Update the flags, keeping the module and its arguments.
+    let install_flags = resolve_update_flags(InstallFlags {
+      module_url: None,
+      args: vec![],
+      name: Some("echo_test".to_string()),
+      root: Some(temp_dir.path().to_path_buf()),
+      force: false,
+      update: true,
+      list: false,
+    })
+    .unwrap();
+    assert_eq!(
+      install_flags.module_url.as_deref(),
+      Some("http://localhost:4545/echo_server.ts")
+    );
+    assert_eq!(install_flags.args, vec!["--foo", "it's"]);
+    create_install_shim(
+      Flags {
+        allow_read: Some(vec![]),
+        ..Flags::default()
+      },
+      install_flags,
+    )
+    .await
+    .unwrap();
+
+    let file_path = get_shim_file_path(&bin_dir, "echo_test");
+    let shim_args = read_shim_args(&file_path).unwrap();
+    assert_eq!(
+      shim_args,
+      vec![
+        "run",
+        "--allow-read",
+        "--no-config",
+        "http://localhost:4545/echo_server.ts",
+        "--foo",
+        "it's"
+      ]
+    );
+
+    // Updating with a new module replaces the module and its arguments.
+    create_install_shim(
+      Flags::default(),
+      InstallFlags {
+        module_url: Some("http://localhost:4545/cat.ts".to_string()),
+        args: vec![],
+        name: Some("echo_test".to_string()),
+        root: Some(temp_dir.path().to_path_buf()),
+        force: false,
+        update: true,
+        list: false,
+      },
+    )
+    .await
+    .unwrap();
+    let shim_args = read_shim_args(&file_path).unwrap();
+    assert_eq!(
+      shim_args,
+      vec!["run", "--no-config", "http://localhost:4545/cat.ts"]
+    );
+
+    // Updating requires an existing installation.
+    let result = resolve_update_flags(InstallFlags {
+      module_url: None,
+      args: vec![],
+      name: Some("missing".to_string()),
+      root: Some(temp_dir.path().to_path_buf()),
+      force: false,
+      update: true,
+      list: false,
+    });
+    assert!(result
+      .unwrap_err()
+      .to_string()
+      .contains("No installation found for missing"));
+  }
+
+  #[test]
+  fn parse_installed_module_skips_flag_values() {
+    let shim_args = [
+      "run",
+      "--allow-net",
+      "--seed",
+      "1",
+      "--config",
+      "/root/.deno/bin/.serve.deno.json",
+      "--lock",
+      "/root/.deno/bin/.serve.lock.json",
+      "https://deno.land/std/http/file_server.ts",
+      "--port",
+      "8080",
+    ]
+    .map(String::from);
+    assert_eq!(
+      parse_installed_module(&shim_args),
+      Some((
+        "https://deno.land/std/http/file_server.ts".to_string(),
+        vec!["--port".to_string(), "8080".to_string()]
+      ))
+    );
+    assert_eq!(parse_installed_module(&["run".to_string()]), None);
+  }
+
   #[tokio::test]
   async fn install_with_config() {
     let temp_dir = TempDir::new();
@@ -1027,11 +1522,13 @@ mod tests {
         ..Flags::default()
       },
       InstallFlags {
-        module_url: "http://localhost:4545/cat.ts".to_string(),
+        module_url: Some("http://localhost:4545/cat.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: true,
+        update: false,
+        list: false,
       },
     )
     .await;
@@ -1041,8 +1538,66 @@ mod tests {
 
     let file_path = bin_dir.join(config_file_name);
     assert!(file_path.exists());
-    let content = fs::read_to_string(file_path).unwrap();
+    let content = fs::read_to_string(&file_path).unwrap();
     assert!(content == "{}");
+
+    // An update that drops the config doesn't leave the copy behind.
+    let result = create_install_shim(
+      Flags::default(),
+      InstallFlags {
+        module_url: Some("http://localhost:4545/cat.ts".to_string()),
+        args: vec![],
+        name: Some("echo_test".to_string()),
+        root: Some(temp_dir.path().to_path_buf()),
+        force: false,
+        update: true,
+        list: false,
+      },
+    )
+    .await;
+    assert!(result.is_ok());
+    assert!(!file_path.exists());
+  }
+
+  #[tokio::test]
+  async fn shim_args_round_trip() {
+    let temp_dir = TempDir::new();
+    let bin_dir = temp_dir.path().join("bin");
+    std::fs::create_dir(&bin_dir).unwrap();
+
+    let args = [
+      "it's",
+      r#"say "hi""#,
+      r#"C:\dir\"#,
+      r#"\"quoted\""#,
+      "100%",
+      "two  spaces",
+    ]
+    .map(String::from);
+    create_install_shim(
+      Flags::default(),
+      InstallFlags {
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
+        args: args.to_vec(),
+        name: Some("echo_test".to_string()),
+        root: Some(temp_dir.path().to_path_buf()),
+        force: false,
+        update: false,
+        list: false,
+      },
+    )
+    .await
+    .unwrap();
+
+    let file_path = get_shim_file_path(&bin_dir, "echo_test");
+    let shim_args = read_shim_args(&file_path).unwrap();
+    assert_eq!(
+      parse_installed_module(&shim_args),
+      Some((
+        "http://localhost:4545/echo_server.ts".to_string(),
+        args.to_vec()
+      ))
+    );
   }
 
   // TODO: enable on Windows after fixing batch escaping
@@ -1056,11 +1611,13 @@ mod tests {
     create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: "http://localhost:4545/echo_server.ts".to_string(),
+        module_url: Some("http://localhost:4545/echo_server.ts".to_string()),
         args: vec!["\"".to_string()],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -1096,11 +1653,13 @@ mod tests {
     create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: local_module_str.to_string(),
+        module_url: Some(local_module_str.to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: false,
+        update: false,
+        list: false,
       },
     )
     .await
@@ -1140,11 +1699,13 @@ mod tests {
         ..Flags::default()
       },
       InstallFlags {
-        module_url: "http://localhost:4545/cat.ts".to_string(),
+        module_url: Some("http://localhost:4545/cat.ts".to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: true,
+        update: false,
+        list: false,
       },
     )
     .await;
@@ -1182,11 +1743,13 @@ mod tests {
     let result = create_install_shim(
       Flags::default(),
       InstallFlags {
-        module_url: file_module_string.to_string(),
+        module_url: Some(file_module_string.to_string()),
         args: vec![],
         name: Some("echo_test".to_string()),
         root: Some(temp_dir.path().to_path_buf()),
         force: true,
+        update: false,
+        list: false,
       },
     )
     .await;
@@ -1250,4 +1813,51 @@ mod tests {
       assert!(!file_path.exists());
     }
   }
+
+  #[tokio::test]
+  async fn uninstall_all_installed_scripts() {
+    let temp_dir = TempDir::new();
+    let bin_dir = temp_dir.path().join("bin");
+    std::fs::create_dir(&bin_dir).unwrap();
+
+    for (name, module_url) in [
+      ("echo_test", "http://localhost:4545/echo_server.ts"),
+      ("cat_test", "http://localhost:4545/cat.ts"),
+    ] {
+      create_install_shim(
+        Flags::default(),
+        InstallFlags {
+          module_url: Some(module_url.to_string()),
+          args: vec![],
+          name: Some(name.to_string()),
+          root: Some(temp_dir.path().to_path_buf()),
+          force: false,
+          update: false,
+          list: false,
+        },
+      )
+      .await
+      .unwrap();
+    }
+    // files that weren't generated by deno install are left alone
+    std::fs::write(bin_dir.join("other"), "#!/bin/sh\necho other\n").unwrap();
+
+    let scripts = get_installed_scripts(bin_dir.as_path()).unwrap();
+    let scripts = scripts
+      .iter()
+      .map(|s| (s.name.as_str(), s.module_url.as_str()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      scripts,
+      vec![
+        ("cat_test", "http://localhost:4545/cat.ts"),
+        ("echo_test", "http://localhost:4545/echo_server.ts"),
+      ]
+    );
+
+    uninstall_all(Some(temp_dir.path().to_path_buf())).unwrap();
+
+    assert!(get_installed_scripts(bin_dir.as_path()).unwrap().is_empty());
+    assert!(bin_dir.join("other").exists());
+  }
 }
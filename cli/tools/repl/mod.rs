@@ -12,6 +12,7 @@ use deno_core::unsync::spawn_blocking;
 use deno_runtime::permissions::Permissions;
 use deno_runtime::permissions::PermissionsContainer;
 use rustyline::error::ReadlineError;
+use std::time::Duration;
 use tokio::sync::mpsc::unbounded_channel;
 
 pub(crate) mod cdp;
@@ -25,6 +26,7 @@ use channel::RustylineSyncMessageHandler;
 use channel::RustylineSyncResponse;
 use editor::EditorHelper;
 use editor::ReplEditor;
+use session::format_exception_thrown;
 pub use session::EvaluationOutput;
 pub use session::ReplSession;
 pub use session::REPL_INTERNALS_NAME;
@@ -69,16 +71,8 @@ async fn read_line_and_poll(
         poll_worker = true;
       }
       message = notifications.next() => {
-        if let Some(message) = message {
-          let method = message.get("method").unwrap().as_str().unwrap();
-          if method == "Runtime.exceptionThrown" {
-            let params = message.get("params").unwrap().as_object().unwrap();
-            let exception_details = params.get("exceptionDetails").unwrap().as_object().unwrap();
-            let text = exception_details.get("text").unwrap().as_str().unwrap();
-            let exception = exception_details.get("exception").unwrap().as_object().unwrap();
-            let description = exception.get("description").and_then(|d| d.as_str()).unwrap_or("undefined");
-            println!("{text} {description}");
-          }
+        if let Some(text) = message.as_ref().and_then(format_exception_thrown) {
+          println!("{text}");
         }
       }
       _ = repl_session.run_event_loop(), if poll_worker => {
@@ -143,6 +137,8 @@ pub async fn run(flags: Flags, repl_flags: ReplFlags) -> Result<i32, AnyError> {
     test_event_receiver,
   )
   .await?;
+  repl_session
+    .set_eval_timeout(repl_flags.eval_timeout.map(Duration::from_millis));
   let mut rustyline_channel = rustyline_channel();
 
   let helper = EditorHelper {
@@ -1,8 +1,11 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
 use std::cell::RefCell;
+use std::io::IsTerminal;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
 
 use crate::args::CliOptions;
 use crate::colors;
@@ -10,6 +13,7 @@ use crate::lsp::ReplLanguageServer;
 use crate::npm::CliNpmResolver;
 use crate::resolver::CliGraphResolver;
 use crate::tools::test::report_tests;
+use crate::tools::test::reporters::JsonTestReporter;
 use crate::tools::test::reporters::PrettyTestReporter;
 use crate::tools::test::reporters::TestReporter;
 use crate::tools::test::run_tests_for_worker;
@@ -23,9 +27,14 @@ use deno_ast::swc::visit::Visit;
 use deno_ast::swc::visit::VisitWith;
 use deno_ast::DiagnosticsError;
 use deno_ast::ImportsNotUsedAsValues;
+use deno_ast::MediaType;
 use deno_ast::ModuleSpecifier;
+use deno_ast::SourceRange;
+use deno_ast::SourceRangedForSpanned;
+use deno_core::anyhow::anyhow;
 use deno_core::error::AnyError;
 use deno_core::futures::channel::mpsc::UnboundedReceiver;
+use deno_core::futures::future::poll_fn;
 use deno_core::futures::FutureExt;
 use deno_core::futures::StreamExt;
 use deno_core::serde_json;
@@ -36,6 +45,8 @@ use deno_graph::source::ResolutionMode;
 use deno_graph::source::Resolver;
 use deno_runtime::worker::MainWorker;
 use deno_semver::npm::NpmPackageReqReference;
+use deno_semver::package::PackageReq;
+use deno_semver::Version;
 use once_cell::sync::Lazy;
 
 use super::cdp;
@@ -100,6 +111,14 @@ globalThis.clear = console.clear.bind(console);
   )
 }
 
+/// How long an evaluation may be pending before the REPL reports that it is
+/// still waiting for it.
+const AWAITING_NOTICE_DELAY: Duration = Duration::from_millis(500);
+
+fn pretty_test_reporter() -> Box<dyn TestReporter> {
+  Box::new(PrettyTestReporter::new(false, true, false, true))
+}
+
 pub enum EvaluationOutput {
   Value(String),
   Error(String),
@@ -114,6 +133,26 @@ impl std::fmt::Display for EvaluationOutput {
   }
 }
 
+/// Formats a `Runtime.exceptionThrown` inspector notification, which is how
+/// errors thrown outside of an evaluation (such as unhandled promise
+/// rejections) are reported. Returns `None` for other notifications.
+pub fn format_exception_thrown(notification: &Value) -> Option<String> {
+  if notification.get("method")?.as_str()? != "Runtime.exceptionThrown" {
+    return None;
+  }
+  let exception_details = notification
+    .get("params")?
+    .get("exceptionDetails")?
+    .as_object()?;
+  let text = exception_details.get("text")?.as_str()?;
+  let description = exception_details
+    .get("exception")
+    .and_then(|e| e.get("description"))
+    .and_then(|d| d.as_str())
+    .unwrap_or("undefined");
+  Some(format!("{text} {description}"))
+}
+
 pub fn result_to_evaluation_output(
   r: Result<EvaluationOutput, AnyError>,
 ) -> EvaluationOutput {
@@ -125,6 +164,40 @@ pub fn result_to_evaluation_output(
   }
 }
 
+/// The language that lines entered into the REPL are parsed as.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReplLanguage {
+  #[default]
+  TypeScript,
+  JavaScript,
+}
+
+impl ReplLanguage {
+  fn parse(name: &str) -> Option<Self> {
+    match name {
+      "ts" | "typescript" => Some(Self::TypeScript),
+      "js" | "javascript" => Some(Self::JavaScript),
+      _ => None,
+    }
+  }
+
+  fn media_type(self) -> MediaType {
+    match self {
+      Self::TypeScript => MediaType::TypeScript,
+      Self::JavaScript => MediaType::JavaScript,
+    }
+  }
+}
+
+impl std::fmt::Display for ReplLanguage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::TypeScript => f.write_str("TypeScript"),
+      Self::JavaScript => f.write_str("JavaScript"),
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct TsEvaluateResponse {
   pub ts_code: String,
@@ -145,6 +218,16 @@ pub struct ReplSession {
   test_event_sender: TestEventSender,
   /// This is only optional because it's temporarily taken when evaluating.
   test_event_receiver: Option<tokio::sync::mpsc::UnboundedReceiver<TestEvent>>,
+  language: ReplLanguage,
+  eval_timeout: Option<Duration>,
+  /// Source of the lines that evaluated without throwing, in order.
+  history: Vec<String>,
+  /// npm packages that were added because of imports in this session, with
+  /// the version each one resolved to.
+  npm_packages: Vec<(PackageReq, Option<Version>)>,
+  /// Whether each top level statement of a line is evaluated and reported
+  /// separately.
+  split_statements: bool,
 }
 
 impl ReplSession {
@@ -206,12 +289,15 @@ impl ReplSession {
       language_server,
       referrer,
       notifications: Rc::new(RefCell::new(notification_rx)),
-      test_reporter_factory: Box::new(|| {
-        Box::new(PrettyTestReporter::new(false, true, false, true))
-      }),
+      test_reporter_factory: Box::new(pretty_test_reporter),
       main_module,
       test_event_sender,
       test_event_receiver: Some(test_event_receiver),
+      language: ReplLanguage::default(),
+      eval_timeout: None,
+      history: Vec::new(),
+      npm_packages: Vec::new(),
+      split_statements: false,
     };
 
     // inject prelude
@@ -227,6 +313,136 @@ impl ReplSession {
     self.test_reporter_factory = f;
   }
 
+  /// Sets the maximum time a single line may take to evaluate before it is
+  /// aborted. `None` disables the limit.
+  pub fn set_eval_timeout(&mut self, timeout: Option<Duration>) {
+    self.eval_timeout = timeout;
+  }
+
+  /// Returns the source of every line that was evaluated without throwing
+  /// in this session, in evaluation order. Replaying them in a new session
+  /// (for example with `--eval-file`) restores its declarations.
+  pub fn history(&self) -> &[String] {
+    &self.history
+  }
+
+  /// Handles REPL meta-commands, which are lines starting with a `.` directly
+  /// followed by a command name. Returns `None` if the line is not a command
+  /// and should be evaluated as code instead.
+  fn handle_command(&mut self, line: &str) -> Option<EvaluationOutput> {
+    let command = line.trim().strip_prefix('.')?;
+    if !command.starts_with(|c: char| c.is_ascii_alphabetic()) {
+      // something like `.5` is a number literal
+      return None;
+    }
+    let mut parts = command.split_whitespace();
+    let name = parts.next().unwrap();
+    let args = parts.collect::<Vec<_>>();
+
+    Some(match (name, args.as_slice()) {
+      ("language", []) => EvaluationOutput::Value(format!(
+        "Evaluating input as {}",
+        self.language
+      )),
+      ("language", [language]) => match ReplLanguage::parse(language) {
+        Some(language) => {
+          self.language = language;
+          EvaluationOutput::Value(format!("Evaluating input as {language}"))
+        }
+        None => EvaluationOutput::Error(format!(
+          "{} unknown language \"{language}\" (expected \"ts\" or \"js\")",
+          colors::red("error:"),
+        )),
+      },
+      ("save", [_, ..]) => {
+        let path = command["save".len()..].trim();
+        let mut contents = self.history().join("\n");
+        contents.push('\n');
+        match std::fs::write(path, contents) {
+          Ok(()) => EvaluationOutput::Value(format!(
+            "Saved {} evaluated lines to {path}",
+            self.history().len()
+          )),
+          Err(err) => EvaluationOutput::Error(format!(
+            "{} failed to save history to {path}: {err}",
+            colors::red("error:"),
+          )),
+        }
+      }
+      ("npm", []) => EvaluationOutput::Value(if self.npm_packages.is_empty() {
+        "No npm packages were added in this session".to_string()
+      } else {
+        self
+          .npm_packages
+          .iter()
+          .map(|(req, version)| match version {
+            Some(version) => format!("npm:{req} -> {version}"),
+            None => format!("npm:{req}"),
+          })
+          .collect::<Vec<_>>()
+          .join("\n")
+      }),
+      ("npm", ["clear"]) => {
+        self.npm_packages.clear();
+        EvaluationOutput::Value("Cleared the list of npm packages".to_string())
+      }
+      ("reporter", ["pretty"]) => {
+        self.set_test_reporter_factory(Box::new(pretty_test_reporter));
+        EvaluationOutput::Value(
+          "Reporting tests with the pretty reporter".into(),
+        )
+      }
+      ("reporter", ["json"]) => {
+        self.set_json_test_reporter(|| Box::new(std::io::stdout()));
+        EvaluationOutput::Value("Reporting tests as JSON lines".into())
+      }
+      ("statements", [setting @ ("on" | "off")]) => {
+        self.split_statements = *setting == "on";
+        EvaluationOutput::Value(if self.split_statements {
+          "Reporting the result of each statement".to_string()
+        } else {
+          "Reporting the result of the last statement".to_string()
+        })
+      }
+      ("timeout", []) => EvaluationOutput::Value(match self.eval_timeout {
+        Some(timeout) => {
+          format!("Evaluation timeout is {} ms", timeout.as_millis())
+        }
+        None => "Evaluation timeout is off".to_string(),
+      }),
+      ("timeout", ["off"]) => {
+        self.eval_timeout = None;
+        EvaluationOutput::Value("Evaluation timeout is off".to_string())
+      }
+      ("timeout", [millis]) => match millis.parse::<u64>() {
+        Ok(millis) if millis > 0 => {
+          self.eval_timeout = Some(Duration::from_millis(millis));
+          EvaluationOutput::Value(format!("Evaluation timeout is {millis} ms"))
+        }
+        _ => EvaluationOutput::Error(format!(
+          "{} invalid timeout \"{millis}\" (expected milliseconds or \"off\")",
+          colors::red("error:"),
+        )),
+      },
+      _ => EvaluationOutput::Error(format!(
+        "{} unknown REPL command \".{command}\"",
+        colors::red("error:"),
+      )),
+    })
+  }
+
+  /// Reports tests defined in the REPL as newline delimited JSON written to
+  /// the writers returned by `make_writer`, which is called once per
+  /// evaluated line that registered tests.
+  pub fn set_json_test_reporter(
+    &mut self,
+    make_writer: impl Fn() -> Box<dyn std::io::Write> + 'static,
+  ) {
+    self.set_test_reporter_factory(Box::new(move || {
+      Box::new(JsonTestReporter::new(make_writer()))
+    }));
+  }
+
   pub async fn closing(&mut self) -> Result<bool, AnyError> {
     let closed = self
       .evaluate_expression("(this.closed)")
@@ -274,7 +490,22 @@ impl ReplSession {
       session: &mut ReplSession,
       line: &str,
     ) -> Result<EvaluationOutput, AnyError> {
-      match session.evaluate_line_with_object_wrapping(line).await {
+      let evaluation = session.evaluate_line_with_object_wrapping(line);
+      tokio::pin!(evaluation);
+      // Top level await makes the evaluation wait for the promise to settle,
+      // so let the user know that a slow line is still pending.
+      let evaluate_result = tokio::select! {
+        biased;
+        result = &mut evaluation => result,
+        _ = tokio::time::sleep(AWAITING_NOTICE_DELAY) => {
+          if std::io::stderr().is_terminal() {
+            eprintln!("{}", colors::gray("awaiting..."));
+          }
+          evaluation.await
+        }
+      };
+
+      match evaluate_result {
         Ok(evaluate_response) => {
           let cdp::EvaluateResponse {
             result,
@@ -305,6 +536,7 @@ impl ReplSession {
               .commit_text(&evaluate_response.ts_code)
               .await;
 
+            session.history.push(line.to_string());
             session.set_last_eval_result(&result).await?;
             let value = session.get_eval_value(&result).await?;
             EvaluationOutput::Value(value)
@@ -332,8 +564,115 @@ impl ReplSession {
       }
     }
 
-    let result = inner(self, line).await;
-    result_to_evaluation_output(result)
+    if let Some(output) = self.handle_command(line) {
+      return output;
+    }
+
+    // Exceptions that were reported before the line is evaluated come from
+    // earlier lines, such as their timers, so they are printed ahead of the
+    // output without failing this line.
+    let earlier = self.take_uncaught_exceptions().await;
+
+    let statements = if self.split_statements {
+      self.split_top_level_statements(line)
+    } else {
+      None
+    };
+    let output = match statements {
+      Some(statements) if statements.len() > 1 => {
+        // Statements are evaluated one after another in the same context, so
+        // declarations from earlier ones are visible to later ones. Stop at
+        // the first one that throws, like a script would.
+        let mut outputs = Vec::with_capacity(statements.len());
+        let mut failed = false;
+        for statement in statements {
+          let output =
+            result_to_evaluation_output(inner(self, &statement).await);
+          failed = matches!(output, EvaluationOutput::Error(_));
+          outputs.push(output.to_string());
+          if failed {
+            break;
+          }
+        }
+        let text = outputs.join("\n");
+        if failed {
+          EvaluationOutput::Error(text)
+        } else {
+          EvaluationOutput::Value(text)
+        }
+      }
+      _ => result_to_evaluation_output(inner(self, line).await),
+    };
+
+    // Rejections of promises created by the line are only detected once the
+    // event loop checks for them, after the evaluation itself has returned.
+    let uncaught = self.take_uncaught_exceptions().await;
+    let output = if uncaught.is_empty() {
+      output
+    } else {
+      EvaluationOutput::Error(format!("{output}\n{}", uncaught.join("\n")))
+    };
+    if earlier.is_empty() {
+      return output;
+    }
+    let earlier = earlier.join("\n");
+    match output {
+      EvaluationOutput::Value(value) => {
+        EvaluationOutput::Value(format!("{earlier}\n{value}"))
+      }
+      EvaluationOutput::Error(error) => {
+        EvaluationOutput::Error(format!("{earlier}\n{error}"))
+      }
+    }
+  }
+
+  /// Splits a line into the source of its top level statements. Returns `None`
+  /// if the line doesn't parse, in which case it should be evaluated as a
+  /// whole so that the diagnostic is reported.
+  fn split_top_level_statements(&self, line: &str) -> Option<Vec<String>> {
+    let parsed_module = deno_ast::parse_module(deno_ast::ParseParams {
+      specifier: "repl.ts".to_string(),
+      text_info: deno_ast::SourceTextInfo::from_string(line.to_string()),
+      media_type: self.language.media_type(),
+      capture_tokens: false,
+      maybe_syntax: None,
+      scope_analysis: false,
+    })
+    .ok()?;
+
+    let mut collector = StatementCollector::new();
+    parsed_module.program().visit_with(&mut collector);
+    let text_info = parsed_module.text_info();
+    Some(
+      collector
+        .ranges
+        .iter()
+        .map(|range| text_info.range_text(range).to_string())
+        .collect(),
+    )
+  }
+
+  /// Gives the event loop a chance to report unhandled promise rejections and
+  /// drains the exceptions it reported since the last call, followed by the
+  /// error the event loop failed with, if any.
+  async fn take_uncaught_exceptions(&mut self) -> Vec<String> {
+    let event_loop_result = poll_fn(|cx| {
+      Poll::Ready(match self.worker.poll_event_loop(cx, false) {
+        Poll::Ready(result) => result,
+        Poll::Pending => Ok(()),
+      })
+    })
+    .await;
+
+    let mut notifications = self.notifications.borrow_mut();
+    let mut uncaught = vec![];
+    while let Ok(Some(notification)) = notifications.try_next() {
+      uncaught.extend(format_exception_thrown(&notification));
+    }
+    if let Err(err) = event_loop_result {
+      uncaught.push(result_to_evaluation_output(Err(err)).to_string());
+    }
+    uncaught
   }
 
   pub async fn evaluate_line_with_object_wrapping(
@@ -518,15 +857,36 @@ impl ReplSession {
     let parsed_module = deno_ast::parse_module(deno_ast::ParseParams {
       specifier: "repl.ts".to_string(),
       text_info: deno_ast::SourceTextInfo::from_string(expression.to_string()),
-      media_type: deno_ast::MediaType::TypeScript,
+      media_type: self.language.media_type(),
       capture_tokens: false,
       maybe_syntax: None,
       scope_analysis: false,
     })?;
 
-    self
-      .check_for_npm_or_node_imports(&parsed_module.program())
-      .await?;
+    let program = parsed_module.program();
+    self.check_for_npm_or_node_imports(&program).await?;
+
+    // In JavaScript mode there are no types to strip, so the source is
+    // evaluated as written unless it has import or export declarations,
+    // which still need to be rewritten to work in the REPL.
+    let has_module_decls = match &*program {
+      swc_ast::Program::Module(module) => module
+        .body
+        .iter()
+        .any(|item| matches!(item, swc_ast::ModuleItem::ModuleDecl(_))),
+      swc_ast::Program::Script(_) => false,
+    };
+    if self.language == ReplLanguage::JavaScript && !has_module_decls {
+      let value = self
+        .evaluate_expression_with_timeout(&format!(
+          "'use strict'; void 0;\n{expression}"
+        ))
+        .await?;
+      return Ok(TsEvaluateResponse {
+        ts_code: expression.to_string(),
+        value,
+      });
+    }
 
     let transpiled_src = parsed_module
       .transpile(&deno_ast::EmitOptions {
@@ -548,7 +908,9 @@ impl ReplSession {
       .text;
 
     let value = self
-      .evaluate_expression(&format!("'use strict'; void 0;\n{transpiled_src}"))
+      .evaluate_expression_with_timeout(&format!(
+        "'use strict'; void 0;\n{transpiled_src}"
+      ))
       .await?;
 
     Ok(TsEvaluateResponse {
@@ -590,6 +952,18 @@ impl ReplSession {
     if !npm_imports.is_empty() || has_node_specifier {
       npm_resolver.add_package_reqs(&npm_imports).await?;
 
+      let snapshot = npm_resolver.snapshot();
+      for req in npm_imports {
+        if self.npm_packages.iter().any(|(added, _)| *added == req) {
+          continue;
+        }
+        let version = snapshot
+          .package_reqs()
+          .get(&req)
+          .map(|nv| nv.version.clone());
+        self.npm_packages.push((req, version));
+      }
+
       // prevent messages in the repl about @types/node not being cached
       if has_node_specifier {
         npm_resolver.inject_synthetic_types_node_package().await?;
@@ -598,6 +972,52 @@ impl ReplSession {
     Ok(())
   }
 
+  /// Evaluates user input, aborting it once the session's evaluation timeout
+  /// elapses. Synchronous code such as `while (true) {}` never yields back to
+  /// the event loop, so a watchdog thread terminates execution in the isolate,
+  /// while asynchronous waits are bounded by racing the evaluation against a
+  /// timer. Either way the termination is cancelled afterwards so that the
+  /// session stays usable for the next line.
+  async fn evaluate_expression_with_timeout(
+    &mut self,
+    expression: &str,
+  ) -> Result<cdp::EvaluateResponse, AnyError> {
+    let Some(timeout) = self.eval_timeout else {
+      return self.evaluate_expression(expression).await;
+    };
+
+    let isolate_handle =
+      self.worker.js_runtime.v8_isolate().thread_safe_handle();
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let watchdog =
+      std::thread::spawn(move || match done_rx.recv_timeout(timeout) {
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+          isolate_handle.terminate_execution()
+        }
+        _ => false,
+      });
+
+    let result =
+      tokio::time::timeout(timeout, self.evaluate_expression(expression)).await;
+    let _ = done_tx.send(());
+    let terminated = watchdog.join().unwrap();
+
+    match result {
+      Ok(result) if !terminated => result,
+      _ => {
+        self
+          .worker
+          .js_runtime
+          .v8_isolate()
+          .cancel_terminate_execution();
+        Err(anyhow!(
+          "evaluation timed out after {} ms",
+          timeout.as_millis()
+        ))
+      }
+    }
+  }
+
   async fn evaluate_expression(
     &mut self,
     expression: &str,
@@ -628,6 +1048,26 @@ impl ReplSession {
   }
 }
 
+/// Walk an AST and get the source ranges of its top level statements,
+/// without descending into them.
+struct StatementCollector {
+  pub ranges: Vec<SourceRange>,
+}
+
+impl StatementCollector {
+  pub fn new() -> Self {
+    Self { ranges: vec![] }
+  }
+}
+
+impl Visit for StatementCollector {
+  noop_visit_type!();
+
+  fn visit_module_item(&mut self, module_item: &swc_ast::ModuleItem) {
+    self.ranges.push(module_item.range());
+  }
+}
+
 /// Walk an AST and get all import specifiers for analysis if any of them is
 /// an npm specifier.
 struct ImportCollector {
@@ -106,10 +106,11 @@ async fn run_with_watch(
 ) -> Result<i32, AnyError> {
   util::file_watcher::watch_func(
     flags,
-    util::file_watcher::PrintConfig {
-      job_name: "Process".to_string(),
-      clear_screen: !watch_flags.no_clear_screen,
-    },
+    util::file_watcher::PrintConfig::new(
+      "Process",
+      !watch_flags.no_clear_screen,
+    )
+    .with_restart_on_enter(watch_flags.restart_on_enter),
     move |flags, watcher_communicator, _changed_paths| {
       Ok(async move {
         let factory = CliFactoryBuilder::new()
@@ -0,0 +1,230 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+use deno_core::serde_json;
+use serde::Serialize;
+
+use super::*;
+
+/// A test reporter that writes one JSON object per line for each test event,
+/// for tools that need to parse the results.
+pub struct JsonTestReporter {
+  writer: Box<dyn Write>,
+  /// The first error writing an event. Nothing is written after it, and it
+  /// is returned when the report is flushed.
+  write_error: Option<std::io::Error>,
+  /// Set once the reader of the output went away, after which the events are
+  /// silently dropped.
+  closed: bool,
+  passed: usize,
+  failed: usize,
+  ignored: usize,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum JsonTestEvent<'a> {
+  Plan {
+    origin: &'a str,
+    total: usize,
+    #[serde(rename = "filteredOut")]
+    filtered_out: usize,
+  },
+  Wait {
+    name: &'a str,
+    origin: &'a str,
+  },
+  Result {
+    name: &'a str,
+    origin: &'a str,
+    result: &'static str,
+    duration: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+  },
+  StepResult {
+    name: &'a str,
+    #[serde(rename = "rootName")]
+    root_name: &'a str,
+    origin: &'a str,
+    result: &'static str,
+    duration: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+  },
+  UncaughtError {
+    origin: &'a str,
+    error: String,
+  },
+  Summary {
+    passed: usize,
+    failed: usize,
+    ignored: usize,
+    duration: u128,
+  },
+  Sigint {
+    pending: Vec<&'a str>,
+  },
+}
+
+impl JsonTestReporter {
+  pub fn new(writer: Box<dyn Write>) -> JsonTestReporter {
+    JsonTestReporter {
+      writer,
+      write_error: None,
+      closed: false,
+      passed: 0,
+      failed: 0,
+      ignored: 0,
+    }
+  }
+
+  fn write_event(&mut self, event: &JsonTestEvent) {
+    if self.closed || self.write_error.is_some() {
+      return;
+    }
+    let line =
+      serde_json::to_string(event).expect("failed to serialize test event");
+    match writeln!(self.writer, "{line}") {
+      Ok(()) => {}
+      Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {
+        self.closed = true;
+      }
+      Err(err) => self.write_error = Some(err),
+    }
+  }
+}
+
+impl TestReporter for JsonTestReporter {
+  fn report_register(&mut self, _description: &TestDescription) {}
+
+  fn report_plan(&mut self, plan: &TestPlan) {
+    self.write_event(&JsonTestEvent::Plan {
+      origin: &plan.origin,
+      total: plan.total,
+      filtered_out: plan.filtered_out,
+    });
+  }
+
+  fn report_wait(&mut self, description: &TestDescription) {
+    self.write_event(&JsonTestEvent::Wait {
+      name: &description.name,
+      origin: &description.origin,
+    });
+  }
+
+  fn report_output(&mut self, _output: &[u8]) {}
+
+  fn report_result(
+    &mut self,
+    description: &TestDescription,
+    result: &TestResult,
+    elapsed: u64,
+  ) {
+    let (result, error) = match result {
+      TestResult::Ok => {
+        self.passed += 1;
+        ("ok", None)
+      }
+      TestResult::Ignored => {
+        self.ignored += 1;
+        ("ignored", None)
+      }
+      TestResult::Failed(failure) => {
+        self.failed += 1;
+        ("failed", Some(failure.to_string()))
+      }
+      TestResult::Cancelled => {
+        self.failed += 1;
+        ("cancelled", None)
+      }
+    };
+    self.write_event(&JsonTestEvent::Result {
+      name: &description.name,
+      origin: &description.origin,
+      result,
+      duration: elapsed,
+      error,
+    });
+  }
+
+  fn report_uncaught_error(&mut self, origin: &str, error: Box<JsError>) {
+    self.failed += 1;
+    self.write_event(&JsonTestEvent::UncaughtError {
+      origin,
+      error: format_js_error(&error),
+    });
+  }
+
+  fn report_step_register(&mut self, _description: &TestStepDescription) {}
+
+  fn report_step_wait(&mut self, _description: &TestStepDescription) {}
+
+  fn report_step_result(
+    &mut self,
+    desc: &TestStepDescription,
+    result: &TestStepResult,
+    elapsed: u64,
+    _tests: &IndexMap<usize, TestDescription>,
+    _test_steps: &IndexMap<usize, TestStepDescription>,
+  ) {
+    let (result, error) = match result {
+      TestStepResult::Ok => ("ok", None),
+      TestStepResult::Ignored => ("ignored", None),
+      TestStepResult::Failed(failure) => ("failed", Some(failure.to_string())),
+    };
+    self.write_event(&JsonTestEvent::StepResult {
+      name: &desc.name,
+      root_name: &desc.root_name,
+      origin: &desc.origin,
+      result,
+      duration: elapsed,
+      error,
+    });
+  }
+
+  fn report_summary(
+    &mut self,
+    elapsed: &Duration,
+    _tests: &IndexMap<usize, TestDescription>,
+    _test_steps: &IndexMap<usize, TestStepDescription>,
+  ) {
+    self.write_event(&JsonTestEvent::Summary {
+      passed: self.passed,
+      failed: self.failed,
+      ignored: self.ignored,
+      duration: elapsed.as_millis(),
+    });
+  }
+
+  fn report_sigint(
+    &mut self,
+    tests_pending: &HashSet<usize>,
+    tests: &IndexMap<usize, TestDescription>,
+    _test_steps: &IndexMap<usize, TestStepDescription>,
+  ) {
+    let pending = tests_pending
+      .iter()
+      .filter_map(|id| tests.get(id))
+      .map(|description| description.name.as_str())
+      .collect();
+    self.write_event(&JsonTestEvent::Sigint { pending });
+  }
+
+  fn flush_report(
+    &mut self,
+    _elapsed: &Duration,
+    _tests: &IndexMap<usize, TestDescription>,
+    _test_steps: &IndexMap<usize, TestStepDescription>,
+  ) -> anyhow::Result<()> {
+    if let Some(err) = self.write_error.take() {
+      return Err(err.into());
+    }
+    if self.closed {
+      return Ok(());
+    }
+    match self.writer.flush() {
+      Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+      res => Ok(res?),
+    }
+  }
+}
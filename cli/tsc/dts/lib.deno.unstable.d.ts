@@ -1312,10 +1312,49 @@ declare namespace Deno {
    * `localStorage` persistence). More information about the origin storage key
    * can be found in the Deno Manual.
    *
+   * The path `:memory:` opens a private in-memory database. A path like
+   * `:memory:?cache=shared&name=foo` opens a named in-memory database that is
+   * shared by every connection opened with the same name in this process. It
+   * is discarded once all of those connections are closed.
+   *
    * @tags allow-read, allow-write
    * @category KV
    */
-  export function openKv(path?: string): Promise<Deno.Kv>;
+  export function openKv(
+    path?: string,
+    options?: KvOpenOptions,
+  ): Promise<Deno.Kv>;
+
+  /** **UNSTABLE**: New API, yet to be vetted.
+   *
+   * Options for {@linkcode Deno.openKv}.
+   *
+   * @category KV
+   */
+  export interface KvOpenOptions {
+    /**
+     * How values written through the connection are encoded, other than
+     * `Uint8Array` and {@linkcode Deno.KvU64} values, which always have an
+     * encoding of their own. Defaults to `"v8"`.
+     *
+     * - `"v8"` stores values with the structured serialization of V8. Any
+     *   structured-cloneable value can be stored, but only Deno can read it.
+     * - `"json"` stores values as canonical JSON text, with object keys
+     *   sorted, so that other tools can read a local database file directly.
+     *   Writing a value that `JSON.stringify` can't encode, like a `bigint`,
+     *   a cyclic object or `undefined`, throws. Other values are read back
+     *   the way `JSON.parse(JSON.stringify(value))` returns them: strings,
+     *   finite numbers, booleans, `null`, and plain arrays and objects
+     *   survive; `Date`s become strings, `NaN` and infinities become `null`,
+     *   `Map`s, `Set`s and class instances become plain objects of their
+     *   enumerable properties, and `undefined` object properties are
+     *   dropped.
+     *
+     * Values are always read back in the encoding they were written with,
+     * whatever the option. Remote databases don't support `"json"`.
+     */
+    valueEncoding?: "v8" | "json";
+  }
 
   /** **UNSTABLE**: New API, yet to be vetted.
    *
@@ -1328,9 +1367,9 @@ declare namespace Deno {
    * relative significance of the types can be found in documentation for the
    * {@linkcode Deno.KvKeyPart} type.
    *
-   * Keys have a maximum size of 2048 bytes serialized. If the size of the key
-   * exceeds this limit, an error will be thrown on the operation that this key
-   * was passed to.
+   * Keys have a maximum size of 2048 bytes serialized, and at most 64 parts.
+   * If a key exceeds these limits, an error will be thrown on the operation
+   * that this key was passed to.
    *
    * @category KV
    */
@@ -1355,9 +1394,10 @@ declare namespace Deno {
    * - `Uint8Array` is ordered by the byte ordering of the array
    * - `string` is ordered by the byte ordering of the UTF-8 encoding of the
    *   string
-   * - `number` is ordered following this pattern: `-NaN`
-   *   < `-Infinity` < `-100.0` < `-1.0` < -`0.5` < `-0.0` < `0.0` < `0.5`
-   *   < `1.0` < `100.0` < `Infinity` < `NaN`
+   * - `number` is ordered following this pattern: `-Infinity` < `-100.0`
+   *   < `-1.0` < -`0.5` < `0.0` < `0.5` < `1.0` < `100.0` < `Infinity`
+   *   < `NaN`. `-0.0` is stored as `0.0`, so both refer to the same key, and
+   *   all `NaN` values refer to the same key.
    * - `bigint` is ordered by mathematical ordering, with the largest negative
    *   number being the least first value, and the largest positive number
    *   being the last value
@@ -1393,12 +1433,19 @@ declare namespace Deno {
    * starting at a given key). A range selector selects all keys that are
    * lexicographically between the given start and end keys.
    *
+   * A prefix selector only matches whole key parts: `{ prefix: ["a"] }`
+   * matches `["a", "b"]` but not `["ab"]`. If the last part of the prefix is
+   * a `Uint8Array`, setting `bytesPrefix` makes the selector also match keys
+   * whose part at that position starts with those bytes. For example,
+   * `{ prefix: [new Uint8Array([1])], bytesPrefix: true }` matches
+   * `[new Uint8Array([1, 2])]` as well as `[new Uint8Array([1]), "x"]`.
+   *
    * @category KV
    */
   export type KvListSelector =
-    | { prefix: KvKey }
-    | { prefix: KvKey; start: KvKey }
-    | { prefix: KvKey; end: KvKey }
+    | { prefix: KvKey; bytesPrefix?: boolean }
+    | { prefix: KvKey; start: KvKey; bytesPrefix?: boolean }
+    | { prefix: KvKey; end: KvKey; bytesPrefix?: boolean }
     | { start: KvKey; end: KvKey };
 
   /** **UNSTABLE**: New API, yet to be vetted.
@@ -1415,8 +1462,17 @@ declare namespace Deno {
    *   specified duration has passed, the key may still be visible for some
    *   additional time. If the `expireIn` option is not specified, the key will
    *   not expire.
+   * - `setIfAbsent` - Like `set`, but only if the key does not exist when the
+   *   operation is committed. If the key exists, the entire atomic operation
+   *   fails as if a check had failed.
    * - `delete` - Deletes the key from the database. The mutation is a no-op if
    *   the key does not exist.
+   * - `deletePrefix` - Deletes all keys that start with the key of the
+   *   mutation, but not the key itself. The key must have at least one part.
+   * - `move` - Moves the value of the key to the key `to`, keeping its
+   *   expiration, and deletes the key. If the key does not exist, or if `to`
+   *   exists and `overwrite` is not set, the entire atomic operation fails as
+   *   if a check had failed.
    * - `sum` - Adds the given value to the existing value of the key. Both the
    *   value specified in the mutation, and any existing value must be of type
    *   `Deno.KvU64`. If the key does not exist, the value is set to the given
@@ -1437,7 +1493,10 @@ declare namespace Deno {
     & { key: KvKey }
     & (
       | { type: "set"; value: unknown; expireIn?: number }
+      | { type: "setIfAbsent"; value: unknown; expireIn?: number }
       | { type: "delete" }
+      | { type: "deletePrefix" }
+      | { type: "move"; to: KvKey; overwrite?: boolean }
       | { type: "sum"; value: KvU64 }
       | { type: "max"; value: KvU64 }
       | { type: "min"; value: KvU64 }
@@ -1472,9 +1531,19 @@ declare namespace Deno {
    * key-value pair. It can be used to perform atomic operations on the KV store
    * by passing it to the `check` method of a {@linkcode Deno.AtomicOperation}.
    *
+   * If the key-value pair was set with an `expireIn` option, `expireAt` is the
+   * time at which it expires, in milliseconds since the Unix epoch. The entry
+   * may still be read for a short while after this time, until it is removed
+   * from the database. The field is absent for entries that do not expire.
+   *
    * @category KV
    */
-  export type KvEntry<T> = { key: KvKey; value: T; versionstamp: string };
+  export type KvEntry<T> = {
+    key: KvKey;
+    value: T;
+    versionstamp: string;
+    expireAt?: number;
+  };
 
   /**
    * **UNSTABLE**: New API, yet to be vetted.
@@ -1547,6 +1616,18 @@ declare namespace Deno {
      * clamped.
      */
     batchSize?: number;
+    /**
+     * The approximate maximum number of value bytes to read in a single batch.
+     * Once the values read for a batch add up to at least this many bytes, the
+     * batch is ended early and the remaining entries are pulled in further
+     * batches. Every batch contains at least one entry, so a single value
+     * larger than `maxBytes` is still returned.
+     *
+     * This only bounds the size of each batch, not the total number of entries
+     * returned by the list operation. By default batches are only limited by
+     * `batchSize`.
+     */
+    maxBytes?: number;
   }
 
   /** @category KV */
@@ -1554,11 +1635,257 @@ declare namespace Deno {
     ok: true;
     /** The versionstamp of the value committed to KV. */
     versionstamp: string;
+    /**
+     * The entries that were overwritten or deleted by the `set` and `delete`
+     * mutations of the operation, one for each mutation in the order they
+     * were added. Only present if the operation was committed with the
+     * `returnOld` option. The element is `null` for other mutation types, or
+     * if the key did not exist before the commit.
+     */
+    oldValues?: (KvEntry<unknown> | null)[];
+    /**
+     * What the mutations of the operation did to the KV store. Only present
+     * for databases that keep track of it, which currently are local ones.
+     */
+    mutationCounts?: KvMutationCounts;
+  }
+
+  /** **UNSTABLE**: New API, yet to be vetted.
+   *
+   * The result of a {@linkcode Deno.AtomicOperation} committed with the
+   * `dryRun` option whose checks passed. Nothing was written, so unlike a
+   * {@linkcode Deno.KvCommitResult} it has no versionstamp.
+   *
+   * @category KV
+   */
+  export interface KvDryRunResult {
+    ok: true;
+    dryRun: true;
+    /** See {@linkcode Deno.KvCommitResult.oldValues}. */
+    oldValues?: (KvEntry<unknown> | null)[];
+    /** See {@linkcode Deno.KvCommitResult.mutationCounts}. */
+    mutationCounts?: KvMutationCounts;
+  }
+
+  /** **UNSTABLE**: New API, yet to be vetted.
+   *
+   * Counts of the effects of the mutations of a committed
+   * {@linkcode Deno.AtomicOperation}.
+   *
+   * @category KV
+   */
+  export interface KvMutationCounts {
+    /**
+     * The number of mutations that changed the KV store. Deletes of keys that
+     * did not exist and prefix deletes that matched no keys are not counted.
+     */
+    mutationsApplied: number;
+    /**
+     * The number of keys that did not exist and were created by `set`, `sum`,
+     * `min` or `max` mutations.
+     */
+    keysCreated: number;
+    /**
+     * The number of keys removed by `delete` and `deletePrefix` mutations.
+     */
+    keysDeleted: number;
+  }
+
+  /**
+   * A queue message that was not delivered successfully before its backoff
+   * schedule ran out. Dead letters can be inspected with
+   * {@linkcode Deno.Kv.listDeadLetters} and re-enqueued with
+   * {@linkcode Deno.Kv.retryDeadLetter}.
+   *
+   * @category KV
+   */
+  export interface KvDeadLetter {
+    /** The identifier of the message. */
+    id: string;
+    /** The message that was enqueued. */
+    value: unknown;
+    /** The time at which the message was originally enqueued. */
+    enqueuedAt: Date;
+    /** The time at which the last delivery attempt failed. */
+    failedAt: Date;
+    /** The number of failed delivery attempts. */
+    failureCount: number;
+  }
+
+  /**
+   * The kind of maintenance performed by {@linkcode Deno.Kv.maintenance}.
+   *
+   * - `passive` checkpoints as much of the write-ahead log as possible
+   *   without waiting for other connections.
+   * - `truncate` checkpoints the whole write-ahead log and truncates it.
+   * - `vacuum` additionally rebuilds the database file to release unused
+   *   space.
+   *
+   * @category KV
+   */
+  export type KvMaintenanceMode = "passive" | "truncate" | "vacuum";
+
+  /**
+   * The outcome of a write-ahead log checkpoint performed by
+   * {@linkcode Deno.Kv.maintenance}.
+   *
+   * @category KV
+   */
+  export interface KvCheckpointResult {
+    /**
+     * Whether the checkpoint could not complete because of other connections
+     * reading or writing the database.
+     */
+    busy: boolean;
+    /**
+     * The number of frames in the write-ahead log, or -1 if the database does
+     * not use one.
+     */
+    logFrames: number;
+    /**
+     * The number of frames moved back into the database file, or -1 if the
+     * database does not use a write-ahead log.
+     */
+    checkpointedFrames: number;
+  }
+
+  /**
+   * The kind of problem found by {@linkcode Deno.Kv.checkIntegrity}.
+   *
+   * - `corruption` means the database file itself is damaged.
+   * - `versionAhead` means entries have a versionstamp newer than the
+   *   database, so future writes may reuse their versionstamps.
+   * - `staleRunningMessage` means queue messages have been running for more
+   *   than an hour, and were most likely abandoned.
+   * - `invalidExpiration` means entries have an invalid expiration time.
+   *
+   * @category KV
+   */
+  export type KvIntegrityProblemKind =
+    | "corruption"
+    | "versionAhead"
+    | "staleRunningMessage"
+    | "invalidExpiration";
+
+  /**
+   * A problem found by {@linkcode Deno.Kv.checkIntegrity}.
+   *
+   * @category KV
+   */
+  export interface KvIntegrityProblem {
+    kind: KvIntegrityProblemKind;
+    /** A human readable description of the problem. */
+    message: string;
+  }
+
+  /**
+   * A snapshot of the depth of the queue of a database, as returned by
+   * {@linkcode Deno.Kv.queueStats}.
+   *
+   * @category KV
+   */
+  export interface KvQueueStats {
+    /**
+     * The number of waiting messages, including delayed messages that are not
+     * ready yet.
+     */
+    ready: number;
+    /** The number of messages that are currently being handled. */
+    running: number;
+    /**
+     * The time at which the earliest waiting message becomes ready, or `null`
+     * if no messages are waiting.
+     */
+    earliestReady: Date | null;
+  }
+
+  /**
+   * The entries under several prefixes, as returned by
+   * {@linkcode Deno.Kv.listUnion}.
+   *
+   * @category KV
+   */
+  export interface KvListUnionResult<T> {
+    /** The entries, ordered by key, with each key present only once. */
+    entries: KvEntry<T>[];
+    /**
+     * Whether the entries were cut short by the `limit` option, in which case
+     * there are more entries under the prefixes.
+     */
+    hasMore: boolean;
+  }
+
+  /**
+   * A snapshot of the size of a database, as returned by
+   * {@linkcode Deno.Kv.stats}.
+   *
+   * @category KV
+   */
+  export interface KvStats {
+    /**
+     * The number of keys, including expired keys that have not been removed
+     * yet.
+     */
+    keyCount: number;
+    /** The total size of the encoded values of all keys, in bytes. */
+    totalValueBytes: number;
+    /**
+     * The size of the database file, in bytes. This does not include the
+     * write-ahead log, and space that is free for reuse is counted until the
+     * database is compacted.
+     */
+    dbFileBytes: number;
+  }
+
+  /**
+   * Information about the delivery of a queue message, passed to the handler
+   * of {@linkcode Deno.Kv.listenQueue}.
+   *
+   * @category KV
+   */
+  export interface KvQueueMessageInfo {
+    /**
+     * The id of this delivery of the message, for
+     * {@linkcode Deno.AtomicOperation.checkQueueRunning}, or `null` if the
+     * database does not support that. Every delivery of a message gets a new
+     * id.
+     */
+    id: string | null;
+    /**
+     * The delivery attempt, starting at 1, or `null` if the database does
+     * not keep track of it.
+     */
+    attempt: number | null;
+    /**
+     * The time at which the message was enqueued, or `null` if the database
+     * does not keep track of it.
+     */
+    enqueuedAt: Date | null;
+    /**
+     * Postpones the time at which the message is considered stuck and
+     * delivered again to `timeout` milliseconds from now, or to the
+     * visibility timeout of the database from now if not given. Timeouts are
+     * capped at the visibility timeout, and the deadline is never moved
+     * earlier. Long-running handlers call this periodically to keep their
+     * message from being redelivered while they are still working on it.
+     *
+     * Rejects if the message was already redelivered, or if the database
+     * does not support it.
+     */
+    extendDeadline(timeout?: number): Promise<void>;
   }
 
   /** @category KV */
   export interface KvCommitError {
     ok: false;
+    /**
+     * The index of the check that failed, in the order the checks were added
+     * to the operation. `setIfAbsent` and `move` mutations check that their
+     * keys do or don't exist, and count as checks added after all others, in
+     * the order of the mutations. Not present if the database doesn't report
+     * which check failed.
+     */
+    failedCheck?: number;
   }
 
   /** **UNSTABLE**: New API, yet to be vetted.
@@ -1568,12 +1895,18 @@ declare namespace Deno {
    * not match the given versionstamp. A check with a `null` versionstamp checks
    * that the key-value pair does not currently exist in the KV store.
    *
+   * Instead of a versionstamp, a check can specify the `value` the key is
+   * expected to have, which avoids reading the key first to learn its
+   * versionstamp. Values are compared by their serialized representation, so
+   * the check fails if the key does not exist, or if its value was stored as a
+   * different type, e.g. a `Deno.KvU64` instead of a `bigint`. Value checks
+   * are not supported by remote databases.
+   *
    * @category KV
    */
-  export interface AtomicCheck {
-    key: KvKey;
-    versionstamp: string | null;
-  }
+  export type AtomicCheck =
+    | { key: KvKey; versionstamp: string | null }
+    | { key: KvKey; value: unknown };
 
   /** **UNSTABLE**: New API, yet to be vetted.
    *
@@ -1614,12 +1947,31 @@ declare namespace Deno {
    */
   export class AtomicOperation {
     /**
-     * Add to the operation a check that ensures that the versionstamp of the
-     * key-value pair in the KV store matches the given versionstamp. If the
-     * check fails, the entire operation will fail and no mutations will be
+     * Add to the operation a check that ensures that the versionstamp (or the
+     * value) of the key-value pair in the KV store matches the given one. If
+     * the check fails, the entire operation will fail and no mutations will be
      * performed during the commit.
      */
     check(...checks: AtomicCheck[]): this;
+    /**
+     * Add to the operation a check that ensures that the delivery of a queue
+     * message with the given id, from {@linkcode Deno.KvQueueMessageInfo.id},
+     * is still running. A handler uses this to commit the results of its work
+     * only if the message was not redelivered in the meantime, for example
+     * because its deadline passed.
+     *
+     * ```ts
+     * db.listenQueue(async (msg, info) => {
+     *   await db.atomic()
+     *     .checkQueueRunning(info.id!)
+     *     .set(["done", msg.job], true)
+     *     .commit();
+     * });
+     * ```
+     *
+     * This operation is only supported for local databases.
+     */
+    checkQueueRunning(id: string): this;
     /**
      * Add to the operation a mutation that performs the specified mutation on
      * the specified key if all checks pass during the commit. The types and
@@ -1657,18 +2009,63 @@ declare namespace Deno {
      * option is not specified, the key will not expire.
      */
     set(key: KvKey, value: unknown, options?: { expireIn?: number }): this;
+    /**
+     * Add to the operation a mutation that sets the value of the specified key
+     * to the specified value, but only if the key does not exist in the KV
+     * store when the operation is committed. If the key exists, the entire
+     * operation fails as if a check had failed, and no mutations are
+     * performed.
+     *
+     * This is a shortcut for a `set` mutation combined with a check that the
+     * key has a `null` versionstamp. The `expireIn` option behaves as it does
+     * for {@linkcode Deno.AtomicOperation.set}.
+     */
+    setIfAbsent(
+      key: KvKey,
+      value: unknown,
+      options?: { expireIn?: number },
+    ): this;
     /**
      * Add to the operation a mutation that deletes the specified key if all
      * checks pass during the commit.
      */
     delete(key: KvKey): this;
+    /**
+     * Add to the operation a mutation that deletes all keys that start with
+     * the specified prefix if all checks pass during the commit. A key equal
+     * to the prefix itself is not deleted. The prefix must have at least one
+     * key part, so that the whole KV store can not be cleared by accident.
+     *
+     * The number of keys that can be deleted by a single operation is bounded
+     * by the backend. If the prefix contains too many keys, an exception is
+     * thrown and no mutations are performed. Prefix deletes are only
+     * supported for local databases.
+     */
+    deletePrefix(prefix: KvKey): this;
+    /**
+     * Add to the operation a mutation that moves the value of the `from` key
+     * to the `to` key and deletes the `from` key if all checks pass during
+     * the commit, so that there is no point in time at which both or neither
+     * of the keys exist. The expiration of the value is kept, and the moved
+     * entry gets the versionstamp of the commit.
+     *
+     * If `from` does not exist, or if `to` exists and the `overwrite` option
+     * is not set, the entire operation fails as if a check had failed. These
+     * conditions are evaluated after the mutations added to the operation
+     * before this one. Moves are only supported for local databases.
+     */
+    move(from: KvKey, to: KvKey, options?: { overwrite?: boolean }): this;
     /**
      * Add to the operation a mutation that enqueues a value into the queue
      * if all checks pass during the commit.
      */
     enqueue(
       value: unknown,
-      options?: { delay?: number; keysIfUndelivered?: Deno.KvKey[] },
+      options?: {
+        delay?: number;
+        at?: Date;
+        keysIfUndelivered?: Deno.KvKey[];
+      },
     ): this;
     /**
      * Commit the operation to the KV store. Returns a value indicating whether
@@ -1684,8 +2081,26 @@ declare namespace Deno {
      * with updated checks and mutations and attempt to commit it again. See the
      * note on optimistic locking in the documentation for
      * {@linkcode Deno.AtomicOperation}.
+     *
+     * If the `returnOld` option is set, the result contains the entries that
+     * were overwritten or deleted by the operation in its `oldValues`
+     * property.
+     *
+     * If the `dryRun` option is set, the operation is evaluated but not
+     * committed: the KV store is left unchanged and no messages are enqueued.
+     * The result still reports whether the checks passed, but is a
+     * {@linkcode Deno.KvDryRunResult} without a versionstamp if they did. Dry
+     * runs are only supported for local databases.
      */
-    commit(): Promise<KvCommitResult | KvCommitError>;
+    commit(
+      options?: { returnOld?: boolean; dryRun?: false },
+    ): Promise<KvCommitResult | KvCommitError>;
+    commit(
+      options: { returnOld?: boolean; dryRun: true },
+    ): Promise<KvDryRunResult | KvCommitError>;
+    commit(
+      options?: { returnOld?: boolean; dryRun?: boolean },
+    ): Promise<KvCommitResult | KvDryRunResult | KvCommitError>;
   }
 
   /** **UNSTABLE**: New API, yet to be vetted.
@@ -1810,6 +2225,29 @@ declare namespace Deno {
      */
     delete(key: KvKey): Promise<void>;
 
+    /**
+     * Atomically delete all keys under the given prefix and set the given
+     * entries. Readers never observe a state where only some of the keys under
+     * the prefix have been replaced. All keys in `entries` must be within the
+     * prefix.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * await db.replacePrefix(["index"], [
+     *   [["index", "a"], 1],
+     *   [["index", "b"], 2],
+     * ]);
+     * ```
+     *
+     * The number of keys that can be deleted by a single call is bounded by
+     * the backend. If the prefix contains too many keys, an exception is
+     * thrown and the database is left unmodified.
+     */
+    replacePrefix(
+      prefix: KvKey,
+      entries: [KvKey, unknown][],
+    ): Promise<KvCommitResult>;
+
     /**
      * Retrieve a list of keys in the database. The returned list is an
      * {@linkcode Deno.KvListIterator} which can be used to iterate over the
@@ -1854,6 +2292,60 @@ declare namespace Deno {
       options?: KvListOptions,
     ): KvListIterator<T>;
 
+    /**
+     * Stream the entries of a range of keys, like {@linkcode Deno.Kv.list},
+     * but with batches of at most `batchSize` entries pulled from the
+     * database only as the iteration proceeds. Only the current batch is held
+     * in memory, so this is suitable for scanning large ranges. Breaking out
+     * of the iteration releases the stream.
+     *
+     * Unlike with {@linkcode Deno.Kv.list}, the batch size defaults to 100
+     * regardless of `limit`, and may be up to 1000.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * for await (const entry of db.listStream({ prefix: ["logs"] })) {
+     *   console.log(entry.key, entry.value);
+     * }
+     * ```
+     */
+    listStream<T = unknown>(
+      selector: KvListSelector,
+      options?: KvListOptions,
+    ): AsyncIterableIterator<KvEntry<T>>;
+
+    /**
+     * Count the keys matched by a selector, like the number of entries
+     * {@linkcode Deno.Kv.list} would yield, but without reading their values
+     * or paginating.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * const users = await db.count({ prefix: ["users"] });
+     * ```
+     */
+    count(
+      selector: KvListSelector,
+      options?: { consistency?: KvConsistencyLevel },
+    ): Promise<number>;
+
+    /**
+     * Retrieve the entries under any of the given prefixes (at most 10) as a
+     * single list, ordered by key. Keys under more than one of the prefixes,
+     * because the prefixes overlap, are only returned once. The `limit`
+     * option (100 by default, at most 1000) bounds the number of entries
+     * returned.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * const { entries } = await db.listUnion([["users"], ["admins"]]);
+     * ```
+     */
+    listUnion<T = unknown>(
+      prefixes: KvKey[],
+      options?: { limit?: number; consistency?: KvConsistencyLevel },
+    ): Promise<KvListUnionResult<T>>;
+
     /**
      * Add a value into the database queue to be delivered to the queue
      * listener via {@linkcode Deno.Kv.listenQueue}.
@@ -1872,6 +2364,17 @@ declare namespace Deno {
      * await db.enqueue("bar", { delay: 60000 });
      * ```
      *
+     * The `at` option can be used instead to deliver the value at an absolute
+     * time, which does not drift if the clock of the process changes between
+     * enqueueing and delivery. It takes precedence over `delay` if both are
+     * set, and times in the past mean immediate delivery. Like the delay, it
+     * can be at most 30 days in the future.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * await db.enqueue("bar", { at: new Date("2030-01-01T03:00:00Z") });
+     * ```
+     *
      * The `keysIfUndelivered` option can be used to specify the keys to
      * be set if the value is not successfully delivered to the queue
      * listener after several attempts. The values are set to the value of
@@ -1884,7 +2387,11 @@ declare namespace Deno {
      */
     enqueue(
       value: unknown,
-      options?: { delay?: number; keysIfUndelivered?: Deno.KvKey[] },
+      options?: {
+        delay?: number;
+        at?: Date;
+        keysIfUndelivered?: Deno.KvKey[];
+      },
     ): Promise<KvCommitResult>;
 
     /**
@@ -1900,9 +2407,16 @@ declare namespace Deno {
      *   await db.set(["foo"], msg);
      * });
      * ```
+     *
+     * The handler also receives the delivery attempt of the message and the
+     * time at which it was enqueued, which can be used to behave differently
+     * on retries or to track how long messages wait.
      */
     listenQueue(
-      handler: (value: unknown) => Promise<void> | void,
+      handler: (
+        value: unknown,
+        info: KvQueueMessageInfo,
+      ) => Promise<void> | void,
     ): Promise<void>;
 
     /**
@@ -1914,6 +2428,159 @@ declare namespace Deno {
      */
     atomic(): AtomicOperation;
 
+    /**
+     * Write a snapshot of all entries in the database to the file at `path`.
+     * The snapshot is newline-delimited JSON, where each line contains the key
+     * parts, the base64 encoded value, the value encoding, the versionstamp
+     * and, if the entry expires, the expiration time in milliseconds since the
+     * Unix epoch of a single entry. Returns the number of exported entries.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * await db.export("./backup.jsonl");
+     * ```
+     *
+     * This operation is only supported for local databases.
+     */
+    export(path: string): Promise<number>;
+
+    /**
+     * Insert all entries from a snapshot previously written by
+     * {@linkcode Deno.Kv.export}. The import fails without modifying the
+     * database if any of the keys already exist, unless the `overwrite`
+     * option is set. Imported entries are assigned a new versionstamp and
+     * keep their expiration time; entries that have expired since the
+     * snapshot was written are skipped. Keys and values are subject to the
+     * same size limits as in {@linkcode Deno.AtomicOperation}. Returns the
+     * number of imported entries.
+     *
+     * ```ts
+     * const db = await Deno.openKv();
+     * await db.import("./backup.jsonl", { overwrite: true });
+     * ```
+     *
+     * This operation is only supported for local databases.
+     */
+    import(path: string, options?: { overwrite?: boolean }): Promise<number>;
+
+    /**
+     * List the queue messages that could not be delivered before their
+     * backoff schedule ran out, oldest failures first. At most `limit`
+     * messages are returned, which defaults to 100 and can be at most 1000.
+     *
+     * This operation is only supported for local databases.
+     */
+    listDeadLetters(options?: { limit?: number }): Promise<KvDeadLetter[]>;
+
+    /**
+     * Enqueue the dead letter with the given `id` again for immediate
+     * delivery, with the default backoff schedule. Returns `false` if there
+     * is no dead letter with this `id`.
+     *
+     * This operation is only supported for local databases.
+     */
+    retryDeadLetter(id: string): Promise<boolean>;
+
+    /**
+     * Get the number of waiting and running messages in the queue of the
+     * database. This is cheap enough to be polled regularly, for example to
+     * monitor how backed up the queue is.
+     *
+     * This operation is only supported for local databases.
+     */
+    queueStats(): Promise<KvQueueStats>;
+
+    /**
+     * Get the number of keys and the size of the database, for example for
+     * capacity planning. This counts every key, so it takes longer the larger
+     * the database is.
+     *
+     * This operation is only supported for local databases.
+     */
+    stats(): Promise<KvStats>;
+
+    /**
+     * Check that the database can be reached, and resolve with the round trip
+     * time in milliseconds. For a remote database, this fetches its metadata
+     * and checks that the endpoint accepts the access token, so that a server
+     * can fail at startup rather than on its first query. A failed request is
+     * retried once at most, so an unreachable database is reported within a
+     * few seconds.
+     *
+     * ```ts
+     * const db = await Deno.openKv("https://example.com/db");
+     * await db.ping();
+     * ```
+     */
+    ping(): Promise<number>;
+
+    /**
+     * Checkpoint the write-ahead log of the database to reclaim disk space,
+     * without having to close the database. Defaults to the `passive` mode.
+     *
+     * ```ts
+     * const db = await Deno.openKv("./db.sqlite3");
+     * await db.maintenance("vacuum");
+     * ```
+     *
+     * This operation is only supported for local databases.
+     */
+    maintenance(mode?: KvMaintenanceMode): Promise<KvCheckpointResult>;
+
+    /**
+     * Check the database file for corruption, and its contents for
+     * inconsistencies, for example after a crash. Resolves to the list of
+     * problems found, which is empty for a healthy database.
+     *
+     * This operation is only supported for local databases.
+     */
+    checkIntegrity(): Promise<KvIntegrityProblem[]>;
+
+    /**
+     * Retrieve the entries of the secondary index `name` whose index keys
+     * match the selector, ordered by index key. Indexes are registered by
+     * the embedder, each over the keys under a prefix, and are kept up to
+     * date by every write to those keys. The `limit` option (100 by default,
+     * at most 1000) bounds the number of entries returned.
+     *
+     * ```ts
+     * // With an index "byScore" over ["users"] keyed by the score.
+     * const top = await db.queryIndex("byScore", { prefix: [] }, {
+     *   limit: 10,
+     *   reverse: true,
+     * });
+     * ```
+     *
+     * This operation is only supported for local databases.
+     */
+    queryIndex<T = unknown>(
+      name: string,
+      selector: KvListSelector,
+      options?: { limit?: number; reverse?: boolean },
+    ): Promise<KvEntry<T>[]>;
+
+    /**
+     * Close the database connection after the queue messages that are being
+     * handled by {@linkcode Deno.Kv.listenQueue} have finished. No new
+     * messages are delivered once this is called, and no further operations
+     * can be performed on the database.
+     *
+     * The `timeout` option (10 seconds by default) bounds how long to wait,
+     * in milliseconds. Resolves to `true` if every message finished in time,
+     * or `false` if the database was closed with messages still in flight.
+     * Those messages are delivered again when the database is next opened.
+     *
+     * ```ts
+     * const kv = await Deno.openKv();
+     * kv.listenQueue(handleMessage);
+     * Deno.addSignalListener("SIGTERM", async () => {
+     *   await kv.closeGracefully({ timeout: 5000 });
+     *   Deno.exit();
+     * });
+     * ```
+     */
+    closeGracefully(options?: { timeout?: number }): Promise<boolean>;
+
     /**
      * Close the database connection. This will prevent any further operations
      * from being performed on the database, and interrupt any in-flight
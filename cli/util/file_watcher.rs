@@ -3,21 +3,30 @@
 use crate::args::Flags;
 use crate::colors;
 use crate::util::fs::canonicalize_path;
+use crate::util::glob::is_glob_pattern;
+use crate::util::glob::GlobPattern;
 
 use deno_core::error::AnyError;
 use deno_core::error::JsError;
+use deno_core::futures::future::LocalBoxFuture;
 use deno_core::futures::Future;
 use deno_core::futures::FutureExt;
 use deno_runtime::fmt_errors::format_js_error;
 use log::info;
+use log::warn;
 use notify::event::Event as NotifyEvent;
 use notify::event::EventKind;
+use notify::event::ModifyKind;
+use notify::event::RenameMode;
+use notify::Config as NotifyConfig;
 use notify::Error as NotifyError;
+use notify::PollWatcher;
 use notify::RecommendedWatcher;
 use notify::RecursiveMode;
 use notify::Watcher;
 use std::collections::HashSet;
 use std::io::IsTerminal;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
@@ -28,17 +37,36 @@ use tokio::time::sleep;
 
 const CLEAR_SCREEN: &str = "\x1B[2J\x1B[1;1H";
 const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(200);
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+/// Polling scans every watched path, so shorter intervals would keep a core
+/// busy on large projects.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for a cancelled operation to be torn down before running
+/// it again regardless.
+const OPERATION_TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Paths reported by a single file system event.
+#[derive(Debug, Default)]
+struct ChangedPaths {
+  changed: Vec<PathBuf>,
+  /// Sources of renames. A source that is still pending as a change, like
+  /// the temporary file of an editor that saves atomically by writing it and
+  /// renaming it over the target, is dropped from the pending changes, so
+  /// that the save is reported as a single change to the target. Other
+  /// sources were moved away, which is a change of their own.
+  renamed_from: Vec<PathBuf>,
+}
 
 struct DebouncedReceiver {
   // The `recv()` call could be used in a tokio `select!` macro,
   // and so we store this state on the struct to ensure we don't
   // lose items if a `recv()` never completes
   received_items: HashSet<PathBuf>,
-  receiver: UnboundedReceiver<Vec<PathBuf>>,
+  receiver: UnboundedReceiver<ChangedPaths>,
 }
 
 impl DebouncedReceiver {
-  fn new_with_sender() -> (Arc<mpsc::UnboundedSender<Vec<PathBuf>>>, Self) {
+  fn new_with_sender() -> (Arc<mpsc::UnboundedSender<ChangedPaths>>, Self) {
     let (sender, receiver) = mpsc::unbounded_channel();
     (
       Arc::new(sender),
@@ -51,15 +79,14 @@ impl DebouncedReceiver {
 
   async fn recv(&mut self) -> Option<Vec<PathBuf>> {
     if self.received_items.is_empty() {
-      self
-        .received_items
-        .extend(self.receiver.recv().await?.into_iter());
+      let paths = self.receiver.recv().await?;
+      self.add(paths);
     }
 
     loop {
       select! {
-        items = self.receiver.recv() => {
-          self.received_items.extend(items?);
+        paths = self.receiver.recv() => {
+          self.add(paths?);
         }
         _ = sleep(DEBOUNCE_INTERVAL) => {
           return Some(self.received_items.drain().collect());
@@ -67,6 +94,15 @@ impl DebouncedReceiver {
       }
     }
   }
+
+  fn add(&mut self, paths: ChangedPaths) {
+    for path in paths.renamed_from {
+      if !self.received_items.remove(&path) {
+        self.received_items.insert(path);
+      }
+    }
+    self.received_items.extend(paths.changed);
+  }
 }
 
 async fn error_handler<F>(watch_future: F) -> bool
@@ -90,11 +126,94 @@ where
   }
 }
 
+/// Changes to paths in these directories are ignored by default, as they tend
+/// to be written to by package managers and version control rather than by
+/// the user editing their code.
+const DEFAULT_IGNORED: [&str; 2] = ["**/node_modules/**", "**/.git/**"];
+
+/// A pattern for paths whose changes should not trigger a restart. It is
+/// matched against canonicalized absolute paths.
+pub enum IgnorePattern {
+  /// Ignores the path itself and everything inside of it.
+  Prefix(PathBuf),
+  Glob(GlobPattern),
+}
+
+impl IgnorePattern {
+  /// Creates a glob pattern if `pattern` contains wildcards and a prefix
+  /// pattern otherwise.
+  pub fn new(pattern: &str) -> Result<Self, AnyError> {
+    if is_glob_pattern(pattern) {
+      return Ok(Self::Glob(GlobPattern::new(pattern)?));
+    }
+    // the watcher reports canonicalized paths, so the prefix needs to be too
+    let path = PathBuf::from(pattern);
+    Ok(Self::Prefix(canonicalize_path(&path).unwrap_or(path)))
+  }
+
+  pub fn matches(&self, path: &Path) -> bool {
+    match self {
+      Self::Prefix(prefix) => path.starts_with(prefix),
+      Self::Glob(pattern) => pattern.matches_path(path),
+    }
+  }
+}
+
 pub struct PrintConfig {
   /// printing watcher status to terminal.
   pub job_name: String,
   /// determine whether to clear the terminal screen; applicable to TTY environments only.
   pub clear_screen: bool,
+  /// changes to paths matching any of these patterns don't trigger a restart.
+  pub ignore: Vec<IgnorePattern>,
+  /// whether pressing Enter in the terminal triggers a restart; off by default.
+  pub restart_on_enter: bool,
+  /// called with the changed paths before every restart; awaited before the
+  /// operation runs again.
+  pub on_restart: Option<OnRestartHook>,
+}
+
+pub type OnRestartHook = Box<
+  dyn FnMut(
+    Option<Vec<PathBuf>>,
+  ) -> LocalBoxFuture<'static, Result<(), AnyError>>,
+>;
+
+impl PrintConfig {
+  pub fn new(job_name: &str, clear_screen: bool) -> Self {
+    Self {
+      job_name: job_name.to_string(),
+      clear_screen,
+      ignore: DEFAULT_IGNORED
+        .iter()
+        .map(|pattern| IgnorePattern::new(pattern).unwrap())
+        .collect(),
+      restart_on_enter: false,
+      on_restart: None,
+    }
+  }
+
+  /// Runs `on_restart` before every restart, for cleanup such as stopping
+  /// processes the previous run started. Errors are printed, but don't stop
+  /// the watcher.
+  #[allow(dead_code)]
+  pub fn with_on_restart(
+    mut self,
+    on_restart: impl FnMut(
+        Option<Vec<PathBuf>>,
+      ) -> LocalBoxFuture<'static, Result<(), AnyError>>
+      + 'static,
+  ) -> Self {
+    self.on_restart = Some(Box::new(on_restart));
+    self
+  }
+
+  /// Lets users restart the job by pressing Enter. The watcher consumes the
+  /// lines read from stdin while it runs, so they don't reach the job.
+  pub fn with_restart_on_enter(mut self, restart_on_enter: bool) -> Self {
+    self.restart_on_enter = restart_on_enter;
+    self
+  }
 }
 
 fn create_print_after_restart_fn(clear_screen: bool) -> impl Fn() {
@@ -113,7 +232,8 @@ fn create_print_after_restart_fn(clear_screen: bool) -> impl Fn() {
 #[derive(Debug)]
 pub struct WatcherCommunicator {
   /// Send a list of paths that should be watched for changes.
-  paths_to_watch_tx: tokio::sync::mpsc::UnboundedSender<Vec<PathBuf>>,
+  paths_to_watch_tx:
+    tokio::sync::mpsc::UnboundedSender<Vec<(PathBuf, RecursiveMode)>>,
 
   /// Listen for a list of paths that were changed.
   changed_paths_rx: tokio::sync::broadcast::Receiver<Option<Vec<PathBuf>>>,
@@ -133,9 +253,46 @@ impl Clone for WatcherCommunicator {
 }
 
 impl WatcherCommunicator {
+  /// Watches directories recursively and files on their own, so that changes
+  /// to a file's siblings don't trigger a restart.
   pub fn watch_paths(&self, paths: Vec<PathBuf>) -> Result<(), AnyError> {
+    self.watch_paths_with_mode(
+      paths
+        .into_iter()
+        .map(|path| {
+          let mode = if path.is_dir() {
+            RecursiveMode::Recursive
+          } else {
+            RecursiveMode::NonRecursive
+          };
+          (path, mode)
+        })
+        .collect(),
+    )
+  }
+
+  pub fn watch_paths_with_mode(
+    &self,
+    paths: Vec<(PathBuf, RecursiveMode)>,
+  ) -> Result<(), AnyError> {
     self.paths_to_watch_tx.send(paths).map_err(AnyError::from)
   }
+
+  /// Waits for the next set of changed paths. Only receives anything in
+  /// `WatcherRestartMode::Manual`, where it's up to the operation to decide
+  /// what to do about the changes.
+  #[allow(dead_code)]
+  pub async fn changed_paths(
+    &mut self,
+  ) -> Result<Option<Vec<PathBuf>>, AnyError> {
+    self.changed_paths_rx.recv().await.map_err(AnyError::from)
+  }
+
+  /// Restarts the operation.
+  #[allow(dead_code)]
+  pub fn force_restart(&self) -> Result<(), AnyError> {
+    self.restart_tx.send(()).map_err(AnyError::from)
+  }
 }
 
 /// Creates a file watcher.
@@ -172,9 +329,11 @@ pub enum WatcherRestartMode {
   /// When a file path changes the process is restarted.
   Automatic,
 
-  /// When a file path changes the caller will trigger a restart, using
-  /// `WatcherCommunicator.restart_tx`.
-  // TODO(bartlomieju): this mode will be used in a follow up PR
+  /// When a file path changes while the operation is running, the changed
+  /// paths are broadcast to it through `WatcherCommunicator::changed_paths`
+  /// and it decides whether to restart, using
+  /// `WatcherCommunicator::force_restart`.
+  // TODO(bartlomieju): no subcommand uses this mode yet
   #[allow(dead_code)]
   Manual,
 }
@@ -208,7 +367,12 @@ where
   let PrintConfig {
     job_name,
     clear_screen,
+    ignore,
+    restart_on_enter,
+    mut on_restart,
   } = print_config;
+  let ignore = Arc::new(ignore);
+  let poll_interval = poll_interval_from_env();
 
   let print_after_restart = create_print_after_restart_fn(clear_screen);
   let watcher_communicator = WatcherCommunicator {
@@ -217,69 +381,93 @@ where
     restart_tx: restart_tx.clone(),
   };
   info!("{} {} started.", colors::intense_blue("Watcher"), job_name,);
+  if restart_on_enter {
+    spawn_restart_on_enter(restart_tx.clone());
+  }
 
   let mut changed_paths = None;
-  loop {
-    // We may need to give the runtime a tick to settle, as cancellations may need to propagate
-    // to tasks. We choose yielding 10 times to the runtime as a decent heuristic. If watch tests
-    // start to fail, this may need to be increased.
-    for _ in 0..10 {
+  let mut previous_operation_done = None;
+  'restart: loop {
+    // Wait until the previous operation future has been dropped, which is
+    // signalled by the sender it owns closing the channel, and then yield once
+    // so that tasks woken up by its cancellation get to run before the
+    // operation starts again.
+    if let Some(done) = previous_operation_done.take() {
+      let _ = tokio::time::timeout(OPERATION_TEARDOWN_TIMEOUT, done).await;
       tokio::task::yield_now().await;
     }
 
-    let mut watcher = new_watcher(watcher_sender.clone())?;
+    let mut watcher =
+      new_watcher(watcher_sender.clone(), ignore.clone(), poll_interval)?;
     consume_paths_to_watch(&mut watcher, &mut paths_to_watch_rx);
 
-    let receiver_future = async {
-      loop {
-        let maybe_paths = paths_to_watch_rx.recv().await;
-        add_paths_to_watcher(&mut watcher, &maybe_paths.unwrap());
-      }
-    };
     let operation_future = error_handler(operation(
       flags.clone(),
       watcher_communicator.clone(),
       changed_paths.take(),
     )?);
+    let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+    previous_operation_done = Some(done_rx);
+    let operation_future = async move {
+      // dropped when the operation finishes or is cancelled
+      let _done_tx = done_tx;
+      operation_future.await
+    };
+    tokio::pin!(operation_future);
 
     // don't reload dependencies after the first run
     flags.reload = false;
 
-    select! {
-      _ = receiver_future => {},
-      _ = restart_rx.recv() => {
-        print_after_restart();
-        continue;
-      },
-      received_changed_paths = watcher_receiver.recv() => {
-        changed_paths = received_changed_paths.clone();
-
-        match restart_mode {
-          WatcherRestartMode::Automatic => {
-            print_after_restart();
-            continue;
-          },
-          WatcherRestartMode::Manual => {
-            // TODO(bartlomieju): should we fail on sending changed paths?
-            let _ = changed_paths_tx.send(received_changed_paths);
-          }
+    // In manual mode the operation keeps running while changes are broadcast
+    // to it, so this loops until it finishes or a restart is requested.
+    loop {
+      let receiver_future = async {
+        loop {
+          let maybe_paths = paths_to_watch_rx.recv().await;
+          add_paths_to_watcher(&mut watcher, &maybe_paths.unwrap());
         }
-      },
-      success = operation_future => {
-        consume_paths_to_watch(&mut watcher, &mut paths_to_watch_rx);
-        // TODO(bartlomieju): print exit code here?
-        info!(
-          "{} {} {}. Restarting on file change...",
-          colors::intense_blue("Watcher"),
-          job_name,
-          if success {
-            "finished"
-          } else {
-            "failed"
+      };
+
+      select! {
+        _ = receiver_future => {},
+        _ = restart_rx.recv() => {
+          run_on_restart(&mut on_restart, changed_paths.clone()).await;
+          print_after_restart();
+          continue 'restart;
+        },
+        received_changed_paths = watcher_receiver.recv() => {
+          changed_paths = received_changed_paths.clone();
+
+          match restart_mode {
+            WatcherRestartMode::Automatic => {
+              run_on_restart(&mut on_restart, changed_paths.clone()).await;
+              print_after_restart();
+              continue 'restart;
+            },
+            WatcherRestartMode::Manual => {
+              // The send only fails if there are no receivers, in which case
+              // nobody is interested in the changes.
+              let _ = changed_paths_tx.send(received_changed_paths);
+            }
           }
-        );
-      },
-    };
+        },
+        success = &mut operation_future => {
+          consume_paths_to_watch(&mut watcher, &mut paths_to_watch_rx);
+          // TODO(bartlomieju): print exit code here?
+          info!(
+            "{} {} {}. Restarting on file change...",
+            colors::intense_blue("Watcher"),
+            job_name,
+            if success {
+              "finished"
+            } else {
+              "failed"
+            }
+          );
+          break;
+        },
+      };
+    }
 
     let receiver_future = async {
       loop {
@@ -293,7 +481,13 @@ where
     // watched paths has changed.
     select! {
       _ = receiver_future => {},
+      _ = restart_rx.recv() => {
+        run_on_restart(&mut on_restart, changed_paths.clone()).await;
+        print_after_restart();
+        continue;
+      },
       received_changed_paths = watcher_receiver.recv() => {
+        run_on_restart(&mut on_restart, received_changed_paths.clone()).await;
         print_after_restart();
         changed_paths = received_changed_paths;
         continue;
@@ -302,44 +496,173 @@ where
   }
 }
 
+async fn run_on_restart(
+  on_restart: &mut Option<OnRestartHook>,
+  changed_paths: Option<Vec<PathBuf>>,
+) {
+  if let Some(on_restart) = on_restart {
+    error_handler(on_restart(changed_paths)).await;
+  }
+}
+
+/// Requests a restart through `restart_tx` for every empty line or `r` read
+/// from stdin, so that users can restart manually by pressing Enter. Does
+/// nothing when stdin is not a terminal.
+///
+/// A blocking read of stdin can't be interrupted, so the reading thread only
+/// notices that the watcher owning `restart_tx` stopped once the next line is
+/// read, and then exits without passing that line on.
+fn spawn_restart_on_enter(restart_tx: mpsc::UnboundedSender<()>) {
+  if !std::io::stdin().is_terminal() {
+    return;
+  }
+  std::thread::spawn(move || {
+    let mut line = String::new();
+    loop {
+      line.clear();
+      match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => break,
+        Ok(_) => {}
+      }
+      if !matches!(line.trim(), "" | "r") {
+        continue;
+      }
+      if restart_tx.send(()).is_err() {
+        break;
+      }
+    }
+  });
+}
+
+/// Native file system events are not delivered reliably on network file
+/// systems, Docker bind mounts and some WSL setups. Setting `DENO_WATCH_POLL`
+/// to an interval in milliseconds makes the watcher poll for changes instead,
+/// which works everywhere but costs a scan of every watched path per interval
+/// and only notices changes once the interval elapses. Intervals below
+/// `MIN_POLL_INTERVAL` are raised to it, and values that aren't a number fall
+/// back to `DEFAULT_POLL_INTERVAL` with a warning.
+fn poll_interval_from_env() -> Option<Duration> {
+  parse_poll_interval(&std::env::var("DENO_WATCH_POLL").ok()?)
+}
+
+fn parse_poll_interval(value: &str) -> Option<Duration> {
+  if value.is_empty() {
+    return None;
+  }
+  Some(match value.parse::<u64>() {
+    Ok(millis) => Duration::from_millis(millis).max(MIN_POLL_INTERVAL),
+    Err(_) => {
+      warn!(
+        "{} DENO_WATCH_POLL is not a number of milliseconds ({value:?}), \
+         polling every {}ms instead.",
+        colors::yellow("Warning"),
+        DEFAULT_POLL_INTERVAL.as_millis(),
+      );
+      DEFAULT_POLL_INTERVAL
+    }
+  })
+}
+
+/// Canonicalizes a path that may no longer exist, such as the source of a
+/// rename, by canonicalizing its parent directory instead.
+fn canonicalize_removed_path(path: &Path) -> Option<PathBuf> {
+  let parent = canonicalize_path(path.parent()?).ok()?;
+  Some(parent.join(path.file_name()?))
+}
+
+/// Creates a watcher that uses native file system events, or one that polls
+/// with the given interval if `poll_interval` is set. Both report events
+/// through the same handler.
 fn new_watcher(
-  sender: Arc<mpsc::UnboundedSender<Vec<PathBuf>>>,
-) -> Result<RecommendedWatcher, AnyError> {
-  Ok(Watcher::new(
-    move |res: Result<NotifyEvent, NotifyError>| {
-      let Ok(event) = res else {
-        return;
-      };
+  sender: Arc<mpsc::UnboundedSender<ChangedPaths>>,
+  ignore: Arc<Vec<IgnorePattern>>,
+  poll_interval: Option<Duration>,
+) -> Result<Box<dyn Watcher>, AnyError> {
+  let event_handler = move |res: Result<NotifyEvent, NotifyError>| {
+    let Ok(event) = res else {
+      return;
+    };
+
+    if !matches!(
+      event.kind,
+      EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    ) {
+      return;
+    }
+
+    let mut all_ignored = !event.paths.is_empty();
+    let is_ignored =
+      |path: &Path| ignore.iter().any(|pattern| pattern.matches(path));
+
+    // Depending on the platform, a rename is reported as separate events for
+    // its source and destination, as one event with both paths, or both.
+    let (renamed_from, changed) = match event.kind {
+      EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+        if event.paths.len() == 2 =>
+      {
+        (&event.paths[..1], &event.paths[1..])
+      }
+      EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+        (&event.paths[..], &[][..])
+      }
+      _ => (&[][..], &event.paths[..]),
+    };
+    let renamed_from = renamed_from
+      .iter()
+      .map(|path| {
+        canonicalize_removed_path(path).unwrap_or_else(|| path.clone())
+      })
+      .inspect(|path| all_ignored &= is_ignored(path))
+      .collect();
 
-      if !matches!(
-        event.kind,
-        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
-      ) {
-        return;
+    let mut paths = ChangedPaths {
+      changed: Vec::with_capacity(changed.len()),
+      renamed_from,
+    };
+    for path in changed {
+      match canonicalize_path(path) {
+        Ok(path) => {
+          if !is_ignored(&path) {
+            all_ignored = false;
+            paths.changed.push(path);
+          }
+        }
+        // removed paths can't be canonicalized, but still count as changes
+        Err(_) => all_ignored &= is_ignored(path),
       }
+    }
+    if all_ignored {
+      return;
+    }
+    sender.send(paths).unwrap();
+  };
 
-      let paths = event
-        .paths
-        .iter()
-        .filter_map(|path| canonicalize_path(path).ok())
-        .collect();
-      sender.send(paths).unwrap();
-    },
-    Default::default(),
-  )?)
+  Ok(match poll_interval {
+    Some(interval) => Box::new(PollWatcher::new(
+      event_handler,
+      NotifyConfig::default().with_poll_interval(interval),
+    )?),
+    None => Box::new(RecommendedWatcher::new(
+      event_handler,
+      NotifyConfig::default(),
+    )?),
+  })
 }
 
-fn add_paths_to_watcher(watcher: &mut RecommendedWatcher, paths: &[PathBuf]) {
+fn add_paths_to_watcher(
+  watcher: &mut dyn Watcher,
+  paths: &[(PathBuf, RecursiveMode)],
+) {
   // Ignore any error e.g. `PathNotFound`
-  for path in paths {
-    let _ = watcher.watch(path, RecursiveMode::Recursive);
+  for (path, mode) in paths {
+    let _ = watcher.watch(path, *mode);
   }
   log::debug!("Watching paths: {:?}", paths);
 }
 
 fn consume_paths_to_watch(
-  watcher: &mut RecommendedWatcher,
-  receiver: &mut UnboundedReceiver<Vec<PathBuf>>,
+  watcher: &mut dyn Watcher,
+  receiver: &mut UnboundedReceiver<Vec<(PathBuf, RecursiveMode)>>,
 ) {
   loop {
     match receiver.try_recv() {
@@ -356,3 +679,473 @@ fn consume_paths_to_watch(
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::AtomicBool;
+  use std::sync::atomic::Ordering;
+  use test_util::TempDir;
+
+  async fn next_change(
+    receiver: &mut DebouncedReceiver,
+  ) -> Option<Vec<PathBuf>> {
+    tokio::time::timeout(Duration::from_secs(2), receiver.recv())
+      .await
+      .ok()
+      .flatten()
+  }
+
+  #[tokio::test]
+  async fn restart_tx_restarts_operation() {
+    let (invocations_tx, mut invocations_rx) = mpsc::unbounded_channel();
+    let mut invocation = 0;
+    let watcher = watch_recv(
+      Flags::default(),
+      PrintConfig::new("Test", false),
+      WatcherRestartMode::Automatic,
+      move |_flags, communicator: WatcherCommunicator, changed_paths| {
+        invocation += 1;
+        invocations_tx
+          .send((invocation, communicator.clone(), changed_paths))
+          .unwrap();
+        let keep_running = invocation == 1;
+        Ok(async move {
+          if keep_running {
+            std::future::pending::<()>().await;
+          }
+          Ok(())
+        })
+      },
+    );
+
+    let test = async {
+      // restart while the operation is still running
+      let (invocation, communicator, _) = invocations_rx.recv().await.unwrap();
+      assert_eq!(invocation, 1);
+      communicator.restart_tx.send(()).unwrap();
+
+      // and while the watcher is idle after the operation finished
+      let (invocation, communicator, changed_paths) =
+        invocations_rx.recv().await.unwrap();
+      assert_eq!(invocation, 2);
+      assert_eq!(changed_paths, None);
+      communicator.restart_tx.send(()).unwrap();
+
+      let (invocation, _, _) = invocations_rx.recv().await.unwrap();
+      assert_eq!(invocation, 3);
+    };
+
+    select! {
+      result = watcher => panic!("watcher stopped: {result:?}"),
+      result = tokio::time::timeout(Duration::from_secs(10), test) => {
+        result.expect("timed out waiting for restarts");
+      },
+    }
+  }
+
+  #[tokio::test]
+  async fn manual_mode_broadcasts_changed_paths() {
+    let temp_dir = TempDir::new();
+    temp_dir.write("main.ts", "");
+    let root = temp_dir.path().canonicalize().to_path_buf();
+
+    let (reports_tx, mut reports_rx) = mpsc::unbounded_channel();
+    let mut invocation = 0;
+    let watched = root.clone();
+    let watcher = watch_recv(
+      Flags::default(),
+      PrintConfig::new("Test", false),
+      WatcherRestartMode::Manual,
+      move |_flags, mut communicator: WatcherCommunicator, changed_paths| {
+        invocation += 1;
+        let invocation = invocation;
+        let reports_tx = reports_tx.clone();
+        let watched = watched.clone();
+        Ok(async move {
+          if invocation > 1 {
+            reports_tx.send((invocation, changed_paths)).unwrap();
+            return Ok(());
+          }
+          communicator.watch_paths(vec![watched])?;
+          reports_tx.send((invocation, None)).unwrap();
+          // report the changes instead of being restarted by them, then
+          // decide to restart
+          let changed = communicator.changed_paths().await?;
+          reports_tx.send((invocation, changed)).unwrap();
+          communicator.force_restart()?;
+          std::future::pending::<()>().await;
+          Ok(())
+        })
+      },
+    );
+
+    let test = async {
+      assert_eq!(reports_rx.recv().await.unwrap(), (1, None));
+      // give the watcher a moment to start watching the path
+      tokio::time::sleep(Duration::from_millis(500)).await;
+      temp_dir.write("main.ts", "console.log(1);");
+
+      let expected = Some(vec![root.join("main.ts")]);
+      assert_eq!(reports_rx.recv().await.unwrap(), (1, expected.clone()));
+      // the restarted operation also gets the changed paths
+      assert_eq!(reports_rx.recv().await.unwrap(), (2, expected));
+    };
+
+    select! {
+      result = watcher => panic!("watcher stopped: {result:?}"),
+      result = tokio::time::timeout(Duration::from_secs(10), test) => {
+        result.expect("timed out waiting for changed paths");
+      },
+    }
+  }
+
+  #[tokio::test]
+  async fn on_restart_runs_once_per_restart() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let (invocations_tx, mut invocations_rx) = mpsc::unbounded_channel();
+    let mut hook_calls = 0;
+    let hook_log = log.clone();
+    let print_config =
+      PrintConfig::new("Test", false).with_on_restart(move |_changed_paths| {
+        hook_calls += 1;
+        hook_log.borrow_mut().push(format!("hook {hook_calls}"));
+        let fail = hook_calls == 1;
+        async move {
+          if fail {
+            deno_core::anyhow::bail!("hook failed");
+          }
+          Ok(())
+        }
+        .boxed_local()
+      });
+
+    let mut invocation = 0;
+    let operation_log = log.clone();
+    let watcher = watch_recv(
+      Flags::default(),
+      print_config,
+      WatcherRestartMode::Automatic,
+      move |_flags, communicator: WatcherCommunicator, _changed_paths| {
+        invocation += 1;
+        operation_log
+          .borrow_mut()
+          .push(format!("operation {invocation}"));
+        invocations_tx.send(communicator.clone()).unwrap();
+        Ok(async { Ok(()) })
+      },
+    );
+
+    let test = async {
+      for _ in 0..2 {
+        let communicator = invocations_rx.recv().await.unwrap();
+        communicator.restart_tx.send(()).unwrap();
+      }
+      // a failing hook doesn't stop the watcher from restarting
+      invocations_rx.recv().await.unwrap();
+    };
+
+    select! {
+      result = watcher => panic!("watcher stopped: {result:?}"),
+      result = tokio::time::timeout(Duration::from_secs(10), test) => {
+        result.expect("timed out waiting for restarts");
+      },
+    }
+    assert_eq!(
+      *log.borrow(),
+      vec![
+        "operation 1",
+        "hook 1",
+        "operation 2",
+        "hook 2",
+        "operation 3"
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn rapid_restarts() {
+    const RESTARTS: usize = 20;
+    let temp_dir = TempDir::new();
+    temp_dir.write("main.ts", "");
+    let root = temp_dir.path().canonicalize().to_path_buf();
+
+    let (invocations_tx, mut invocations_rx) = mpsc::unbounded_channel();
+    let watched = root.clone();
+    let watcher = watch_recv(
+      Flags::default(),
+      PrintConfig::new("Test", false),
+      WatcherRestartMode::Automatic,
+      move |_flags, communicator: WatcherCommunicator, changed_paths| {
+        communicator.watch_paths(vec![watched.clone()])?;
+        invocations_tx.send((communicator, changed_paths)).unwrap();
+        Ok(std::future::pending())
+      },
+    );
+
+    let test = async {
+      let (communicator, _) = invocations_rx.recv().await.unwrap();
+      for _ in 0..RESTARTS {
+        communicator.restart_tx.send(()).unwrap();
+      }
+      // every restart runs the operation again
+      for _ in 0..RESTARTS {
+        invocations_rx.recv().await.unwrap();
+      }
+
+      // and the watcher still picks up changes afterwards
+      tokio::time::sleep(Duration::from_millis(500)).await;
+      temp_dir.write("main.ts", "console.log(1);");
+      let (_, changed_paths) = invocations_rx.recv().await.unwrap();
+      assert_eq!(changed_paths, Some(vec![root.join("main.ts")]));
+    };
+
+    select! {
+      result = watcher => panic!("watcher stopped: {result:?}"),
+      result = tokio::time::timeout(Duration::from_secs(20), test) => {
+        result.expect("timed out waiting for restarts");
+      },
+    }
+  }
+
+  #[tokio::test]
+  async fn previous_operation_is_torn_down_before_restart() {
+    const RESTARTS: usize = 5;
+
+    struct Running(Arc<AtomicBool>);
+    impl Drop for Running {
+      fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+      }
+    }
+    // aborting a task only drops it once the runtime gets to run it again
+    struct AbortOnDrop(tokio::task::JoinHandle<()>);
+    impl Drop for AbortOnDrop {
+      fn drop(&mut self) {
+        self.0.abort();
+      }
+    }
+
+    let running = Arc::new(AtomicBool::new(false));
+    let (invocations_tx, mut invocations_rx) = mpsc::unbounded_channel();
+    let watcher = watch_recv(
+      Flags::default(),
+      PrintConfig::new("Test", false),
+      WatcherRestartMode::Automatic,
+      move |_flags, communicator: WatcherCommunicator, _changed_paths| {
+        let was_running = running.swap(true, Ordering::SeqCst);
+        invocations_tx.send((communicator, was_running)).unwrap();
+        let guard = Running(running.clone());
+        let task = AbortOnDrop(tokio::spawn(async move {
+          let _guard = guard;
+          std::future::pending::<()>().await;
+        }));
+        Ok(async move {
+          let _task = task;
+          std::future::pending().await
+        })
+      },
+    );
+
+    let test = async {
+      for _ in 0..RESTARTS {
+        let (communicator, was_running) = invocations_rx.recv().await.unwrap();
+        assert!(!was_running, "previous operation was still running");
+        communicator.restart_tx.send(()).unwrap();
+      }
+      let (_, was_running) = invocations_rx.recv().await.unwrap();
+      assert!(!was_running, "previous operation was still running");
+    };
+
+    select! {
+      result = watcher => panic!("watcher stopped: {result:?}"),
+      result = tokio::time::timeout(Duration::from_secs(10), test) => {
+        result.expect("timed out waiting for restarts");
+      },
+    }
+  }
+
+  #[test]
+  fn ignore_pattern_matches() {
+    let glob = IgnorePattern::new("**/node_modules/**").unwrap();
+    assert!(glob.matches(Path::new("/project/node_modules/pkg/index.js")));
+    assert!(!glob.matches(Path::new("/project/src/index.js")));
+
+    let prefix = IgnorePattern::new("/project/build").unwrap();
+    assert!(prefix.matches(Path::new("/project/build")));
+    assert!(prefix.matches(Path::new("/project/build/out.js")));
+    assert!(!prefix.matches(Path::new("/project/build2/out.js")));
+  }
+
+  #[tokio::test]
+  async fn ignored_paths_do_not_trigger_restart() {
+    let temp_dir = TempDir::new();
+    temp_dir.create_dir_all("build");
+    temp_dir.write("build/out.js", "");
+    temp_dir.write("main.ts", "");
+    let root = temp_dir.path().canonicalize().to_path_buf();
+
+    let (sender, mut receiver) = DebouncedReceiver::new_with_sender();
+    let ignore =
+      vec![IgnorePattern::new(&root.join("build").to_string_lossy()).unwrap()];
+    let mut watcher = new_watcher(sender, Arc::new(ignore), None).unwrap();
+    add_paths_to_watcher(
+      &mut watcher,
+      &[(root.clone(), RecursiveMode::Recursive)],
+    );
+
+    temp_dir.write("build/out.js", "console.log(1);");
+    assert_eq!(next_change(&mut receiver).await, None);
+
+    temp_dir.write("main.ts", "console.log(2);");
+    assert_eq!(
+      next_change(&mut receiver).await,
+      Some(vec![root.join("main.ts")])
+    );
+  }
+
+  #[tokio::test]
+  async fn atomic_save_is_a_single_change() {
+    let temp_dir = TempDir::new();
+    temp_dir.write("main.ts", "");
+    let root = temp_dir.path().canonicalize().to_path_buf();
+
+    let (sender, mut receiver) = DebouncedReceiver::new_with_sender();
+    let mut watcher = new_watcher(sender, Arc::new(vec![]), None).unwrap();
+    add_paths_to_watcher(
+      &mut watcher,
+      &[(root.clone(), RecursiveMode::Recursive)],
+    );
+
+    // what editors do to save without ever leaving a partially written file
+    temp_dir.write("main.ts.tmp", "console.log(1);");
+    temp_dir.rename("main.ts.tmp", "main.ts");
+
+    assert_eq!(
+      next_change(&mut receiver).await,
+      Some(vec![root.join("main.ts")])
+    );
+    assert_eq!(next_change(&mut receiver).await, None);
+  }
+
+  #[tokio::test]
+  async fn debounced_receiver_drops_rename_sources() {
+    let (sender, mut receiver) = DebouncedReceiver::new_with_sender();
+    sender
+      .send(ChangedPaths {
+        changed: vec![PathBuf::from("/a/main.ts.tmp")],
+        renamed_from: vec![],
+      })
+      .unwrap();
+    sender
+      .send(ChangedPaths {
+        changed: vec![PathBuf::from("/a/main.ts")],
+        renamed_from: vec![PathBuf::from("/a/main.ts.tmp")],
+      })
+      .unwrap();
+    assert_eq!(
+      receiver.recv().await,
+      Some(vec![PathBuf::from("/a/main.ts")])
+    );
+
+    // Renaming a file that wasn't just written changes both paths.
+    sender
+      .send(ChangedPaths {
+        changed: vec![PathBuf::from("/a/new.ts")],
+        renamed_from: vec![PathBuf::from("/a/old.ts")],
+      })
+      .unwrap();
+    let mut changed = receiver.recv().await.unwrap();
+    changed.sort();
+    assert_eq!(
+      changed,
+      vec![PathBuf::from("/a/new.ts"), PathBuf::from("/a/old.ts")]
+    );
+  }
+
+  #[test]
+  fn watch_paths_default_modes() {
+    let temp_dir = TempDir::new();
+    temp_dir.create_dir_all("src");
+    temp_dir.write("deno.json", "{}");
+    let (paths_to_watch_tx, mut paths_to_watch_rx) = mpsc::unbounded_channel();
+    let (restart_tx, _restart_rx) = mpsc::unbounded_channel();
+    let (_changed_paths_tx, changed_paths_rx) =
+      tokio::sync::broadcast::channel(1);
+    let communicator = WatcherCommunicator {
+      paths_to_watch_tx,
+      changed_paths_rx,
+      restart_tx,
+    };
+
+    let src = temp_dir.path().join("src").to_path_buf();
+    let config = temp_dir.path().join("deno.json").to_path_buf();
+    communicator
+      .watch_paths(vec![src.clone(), config.clone()])
+      .unwrap();
+    assert_eq!(
+      paths_to_watch_rx.try_recv().unwrap(),
+      vec![
+        (src, RecursiveMode::Recursive),
+        (config, RecursiveMode::NonRecursive)
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn non_recursive_file_watch_ignores_siblings() {
+    let temp_dir = TempDir::new();
+    temp_dir.write("deno.json", "{}");
+    temp_dir.write("sibling.ts", "");
+    let root = temp_dir.path().canonicalize().to_path_buf();
+
+    let (sender, mut receiver) = DebouncedReceiver::new_with_sender();
+    let mut watcher = new_watcher(sender, Arc::new(vec![]), None).unwrap();
+    add_paths_to_watcher(
+      &mut watcher,
+      &[(root.join("deno.json"), RecursiveMode::NonRecursive)],
+    );
+
+    temp_dir.write("sibling.ts", "console.log(1);");
+    assert_eq!(next_change(&mut receiver).await, None);
+
+    temp_dir.write("deno.json", r#"{ "tasks": {} }"#);
+    assert_eq!(
+      next_change(&mut receiver).await,
+      Some(vec![root.join("deno.json")])
+    );
+  }
+
+  #[test]
+  fn poll_interval_is_parsed() {
+    assert_eq!(parse_poll_interval(""), None);
+    assert_eq!(parse_poll_interval("500"), Some(Duration::from_millis(500)));
+    assert_eq!(parse_poll_interval("0"), Some(MIN_POLL_INTERVAL));
+    assert_eq!(parse_poll_interval("1"), Some(MIN_POLL_INTERVAL));
+    assert_eq!(parse_poll_interval("1s"), Some(DEFAULT_POLL_INTERVAL));
+  }
+
+  #[tokio::test]
+  async fn poll_watcher_reports_changes() {
+    let temp_dir = TempDir::new();
+    temp_dir.write("main.ts", "");
+    let root = temp_dir.path().canonicalize().to_path_buf();
+
+    let (sender, mut receiver) = DebouncedReceiver::new_with_sender();
+    let mut watcher =
+      new_watcher(sender, Arc::new(vec![]), Some(Duration::from_millis(100)))
+        .unwrap();
+    add_paths_to_watcher(
+      &mut watcher,
+      &[(root.clone(), RecursiveMode::Recursive)],
+    );
+
+    temp_dir.write("other.ts", "");
+    // the directory itself may be reported as well, as its mtime changed
+    let changed = next_change(&mut receiver).await.unwrap();
+    assert!(changed.contains(&root.join("other.ts")), "{changed:?}");
+  }
+}
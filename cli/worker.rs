@@ -90,6 +90,7 @@ pub struct CliMainWorkerOptions {
   pub location: Option<Url>,
   pub maybe_binary_npm_command_name: Option<String>,
   pub origin_data_folder_path: Option<PathBuf>,
+  pub kv_metadata_folder_path: Option<PathBuf>,
   pub seed: Option<u64>,
   pub unsafely_ignore_certificate_errors: Option<Vec<String>>,
   pub unstable: bool,
@@ -507,6 +508,7 @@ impl CliMainWorkerFactory {
       get_error_class_fn: Some(&errors::get_error_class_name),
       cache_storage_dir,
       origin_storage_dir,
+      kv_metadata_cache_dir: shared.options.kv_metadata_folder_path.clone(),
       blob_store: shared.blob_store.clone(),
       broadcast_channel: shared.broadcast_channel.clone(),
       shared_array_buffer_store: Some(shared.shared_array_buffer_store.clone()),
@@ -686,6 +688,7 @@ fn create_web_worker_callback(
       ),
       stdio: stdio.clone(),
       cache_storage_dir,
+      kv_metadata_cache_dir: shared.options.kv_metadata_folder_path.clone(),
       feature_checker: shared.feature_checker.clone(),
     };
 
@@ -4,6 +4,8 @@
 const {
   AsyncGeneratorPrototype,
   BigIntPrototypeToString,
+  JSONParse,
+  JSONStringify,
   ObjectFreeze,
   ObjectGetPrototypeOf,
   ObjectPrototypeIsPrototypeOf,
@@ -15,15 +17,37 @@ const {
 const core = Deno.core;
 const ops = core.ops;
 
+type RawSelector = [
+  Deno.KvKey | null,
+  Deno.KvKey | null,
+  Deno.KvKey | null,
+  boolean,
+];
+
+function selectorToRaw(selector: Deno.KvListSelector): RawSelector {
+  return [
+    "prefix" in selector ? selector.prefix : null,
+    "start" in selector ? selector.start : null,
+    "end" in selector ? selector.end : null,
+    "bytesPrefix" in selector && selector.bytesPrefix === true,
+  ];
+}
+
 const encodeCursor: (
-  selector: [Deno.KvKey | null, Deno.KvKey | null, Deno.KvKey | null],
+  selector: RawSelector,
   boundaryKey: Deno.KvKey,
 ) => string = (selector, boundaryKey) =>
   ops.op_kv_encode_cursor(selector, boundaryKey);
 
-async function openKv(path: string) {
+type ValueEncoding = "v8" | "json";
+
+async function openKv(path: string, options?: { valueEncoding?: string }) {
+  const valueEncoding = options?.valueEncoding ?? "v8";
+  if (valueEncoding !== "v8" && valueEncoding !== "json") {
+    throw new TypeError("valueEncoding must be 'v8' or 'json'");
+  }
   const rid = await core.opAsync("op_kv_database_open", path);
-  return new Kv(rid, kvSymbol);
+  return new Kv(rid, kvSymbol, valueEncoding);
 }
 
 const maxQueueDelay = 30 * 24 * 60 * 60 * 1000;
@@ -40,10 +64,62 @@ function validateQueueDelay(delay: number) {
   }
 }
 
+function queueTimeFromDate(at: Date): number {
+  const time = at instanceof Date ? at.getTime() : NaN;
+  if (isNaN(time)) {
+    throw new TypeError("at must be a valid Date");
+  }
+  // Times in the past are delivered right away.
+  return Math.max(time, 0);
+}
+
+interface EnqueueOptions {
+  delay?: number;
+  at?: Date;
+  keysIfUndelivered?: Deno.KvKey[];
+  group?: string;
+}
+
+// [payload, delay, keysIfUndelivered, backoffSchedule, at, group]
+type RawEnqueue = [
+  Uint8Array,
+  number,
+  Deno.KvKey[],
+  number[] | null,
+  number | null,
+  string | null,
+];
+
+function enqueueToRaw(message: unknown, opts?: EnqueueOptions): RawEnqueue {
+  if (opts?.delay !== undefined) {
+    validateQueueDelay(opts?.delay);
+  }
+  return [
+    core.serialize(message, { forStorage: true }),
+    opts?.delay ?? 0,
+    opts?.keysIfUndelivered ?? [],
+    null,
+    opts?.at !== undefined ? queueTimeFromDate(opts.at) : null,
+    opts?.group ?? null,
+  ];
+}
+
 interface RawKvEntry {
   key: Deno.KvKey;
   value: RawValue;
   versionstamp: string;
+  expireAt?: number;
+}
+
+interface RawReadRangeOutput {
+  entries: RawKvEntry[];
+  hasMore: boolean;
+}
+
+interface ListBatch {
+  entries: Deno.KvEntry<unknown>[];
+  /** The batch was cut short by its limits, not by the end of the range. */
+  hasMore: boolean;
 }
 
 type RawValue = {
@@ -55,28 +131,82 @@ type RawValue = {
 } | {
   kind: "u64";
   value: bigint;
+} | {
+  kind: "json";
+  value: string;
 };
 
+interface RawCommitResult {
+  versionstamp: string;
+  oldValues?: (RawKvEntry | null)[];
+  mutationCounts?: Deno.KvMutationCounts;
+}
+
+interface RawCheckFailure {
+  failedCheck: number | null;
+}
+
+interface RawDryRunResult {
+  dryRun: true;
+  oldValues?: (RawKvEntry | null)[];
+  mutationCounts?: Deno.KvMutationCounts;
+}
+
+interface RawDeadLetter {
+  id: string;
+  payload: Uint8Array;
+  enqueuedAt: number;
+  failedAt: number;
+  failureCount: number;
+}
+
+interface RawQueueStats {
+  ready: number;
+  running: number;
+  earliestReady: number | null;
+}
+
+interface RawStats {
+  keyCount: number;
+  totalValueBytes: number;
+  dbFileBytes: number;
+}
+
+// [payload, handleId, attempt, enqueuedAt, id]
+type RawQueueMessage = [
+  Uint8Array,
+  number,
+  number | null,
+  number | null,
+  string | null,
+];
+
 const kvSymbol = Symbol("KvRid");
 
 class Kv {
   #rid: number;
+  #valueEncoding: ValueEncoding;
 
-  constructor(rid: number = undefined, symbol: symbol = undefined) {
+  constructor(
+    rid: number = undefined,
+    symbol: symbol = undefined,
+    valueEncoding: ValueEncoding = "v8",
+  ) {
     if (kvSymbol !== symbol) {
       throw new TypeError(
         "Deno.Kv can not be constructed, use Deno.openKv instead.",
       );
     }
     this.#rid = rid;
+    this.#valueEncoding = valueEncoding;
   }
 
   atomic() {
-    return new AtomicOperation(this.#rid);
+    return new AtomicOperation(this.#rid, this.#valueEncoding);
   }
 
   async get(key: Deno.KvKey, opts?: { consistency?: Deno.KvConsistencyLevel }) {
-    const [entries]: [RawKvEntry[]] = await core.opAsync(
+    const [{ entries }]: [RawReadRangeOutput] = await core.opAsync(
       "op_kv_snapshot_read",
       this.#rid,
       [[
@@ -86,6 +216,7 @@ class Kv {
         1,
         false,
         null,
+        null,
       ]],
       opts?.consistency ?? "strong",
     );
@@ -103,7 +234,7 @@ class Kv {
     keys: Deno.KvKey[],
     opts?: { consistency?: Deno.KvConsistencyLevel },
   ): Promise<Deno.KvEntry<unknown>[]> {
-    const ranges: RawKvEntry[][] = await core.opAsync(
+    const ranges: RawReadRangeOutput[] = await core.opAsync(
       "op_kv_snapshot_read",
       this.#rid,
       keys.map((key) => [
@@ -113,10 +244,11 @@ class Kv {
         1,
         false,
         null,
+        null,
       ]),
       opts?.consistency ?? "strong",
     );
-    return ranges.map((entries, i) => {
+    return ranges.map(({ entries }, i) => {
       if (!entries.length) {
         return {
           key: keys[i],
@@ -129,28 +261,30 @@ class Kv {
   }
 
   async set(key: Deno.KvKey, value: unknown, options?: { expireIn?: number }) {
-    value = serializeValue(value);
+    value = serializeValue(value, this.#valueEncoding);
 
     const checks: Deno.AtomicCheck[] = [];
     const mutations = [
-      [key, "set", value, options?.expireIn],
+      [key, "set", value, options?.expireIn, null],
     ];
 
-    const versionstamp = await core.opAsync(
+    const result = await core.opAsync(
       "op_kv_atomic_write",
       this.#rid,
       checks,
       mutations,
       [],
+      false,
+      false,
     );
-    if (versionstamp === null) throw new TypeError("Failed to set value");
-    return { ok: true, versionstamp };
+    if ("failedCheck" in result) throw new TypeError("Failed to set value");
+    return { ok: true, versionstamp: result.versionstamp };
   }
 
   async delete(key: Deno.KvKey) {
     const checks: Deno.AtomicCheck[] = [];
     const mutations = [
-      [key, "delete", null, undefined],
+      [key, "delete", null, undefined, null],
     ];
 
     const result = await core.opAsync(
@@ -159,8 +293,26 @@ class Kv {
       checks,
       mutations,
       [],
+      false,
+      false,
+    );
+    if ("failedCheck" in result) throw new TypeError("Failed to set value");
+  }
+
+  async replacePrefix(
+    prefix: Deno.KvKey,
+    entries: [Deno.KvKey, unknown][],
+  ) {
+    const versionstamp = await core.opAsync(
+      "op_kv_replace_prefix",
+      this.#rid,
+      prefix,
+      entries.map(([key, value]) => [
+        key,
+        serializeValue(value, this.#valueEncoding),
+      ]),
     );
-    if (!result) throw new TypeError("Failed to set value");
+    return { ok: true, versionstamp };
   }
 
   list(
@@ -171,11 +323,15 @@ class Kv {
       cursor?: string;
       reverse?: boolean;
       consistency?: Deno.KvConsistencyLevel;
+      maxBytes?: number;
     } = {},
   ): KvListIterator {
     if (options.limit !== undefined && options.limit <= 0) {
       throw new Error("limit must be positive");
     }
+    if (options.maxBytes !== undefined && options.maxBytes <= 0) {
+      throw new Error("maxBytes must be positive");
+    }
 
     let batchSize = options.batchSize ?? (options.limit ?? 100);
     if (batchSize <= 0) throw new Error("batchSize must be positive");
@@ -187,71 +343,120 @@ class Kv {
       cursor: options.cursor,
       reverse: options.reverse ?? false,
       consistency: options.consistency ?? "strong",
-      batchSize,
-      pullBatch: this.#pullBatch(batchSize),
+      pullBatch: this.#pullBatch(batchSize, options.maxBytes),
     });
   }
 
-  #pullBatch(batchSize: number): (
+  async count(
+    selector: Deno.KvListSelector,
+    options?: { consistency?: Deno.KvConsistencyLevel },
+  ): Promise<number> {
+    return await core.opAsync(
+      "op_kv_count",
+      this.#rid,
+      selectorToRaw(selector),
+      options?.consistency ?? "strong",
+    );
+  }
+
+  async listUnion(
+    prefixes: Deno.KvKey[],
+    options?: { limit?: number; consistency?: Deno.KvConsistencyLevel },
+  ): Promise<Deno.KvListUnionResult<unknown>> {
+    const { entries, hasMore }: RawReadRangeOutput = await core.opAsync(
+      "op_kv_list_union",
+      this.#rid,
+      prefixes,
+      options?.limit ?? 100,
+      options?.consistency ?? "strong",
+    );
+    return { entries: entries.map(deserializeValue), hasMore };
+  }
+
+  async *listStream(
+    selector: Deno.KvListSelector,
+    options: {
+      limit?: number;
+      batchSize?: number;
+      cursor?: string;
+      reverse?: boolean;
+      consistency?: Deno.KvConsistencyLevel;
+    } = {},
+  ): AsyncIterableIterator<Deno.KvEntry<unknown>> {
+    if (options.limit !== undefined && options.limit <= 0) {
+      throw new Error("limit must be positive");
+    }
+
+    const streamRid = ops.op_kv_list_stream(
+      this.#rid,
+      selectorToRaw(selector),
+      options.reverse ?? false,
+      options.cursor ?? null,
+      options.consistency ?? "strong",
+      options.batchSize ?? 100,
+      options.limit ?? null,
+    );
+    try {
+      while (true) {
+        const entries: RawKvEntry[] = await core.opAsync(
+          "op_kv_list_stream_next",
+          streamRid,
+        );
+        if (entries.length === 0) return;
+        for (const entry of entries) {
+          yield deserializeValue(entry);
+        }
+      }
+    } finally {
+      core.tryClose(streamRid);
+    }
+  }
+
+  #pullBatch(batchSize: number, maxBytes: number | undefined): (
     selector: Deno.KvListSelector,
     cursor: string | undefined,
     reverse: boolean,
     consistency: Deno.KvConsistencyLevel,
-  ) => Promise<Deno.KvEntry<unknown>[]> {
+  ) => Promise<ListBatch> {
     return async (selector, cursor, reverse, consistency) => {
-      const [entries]: [RawKvEntry[]] = await core.opAsync(
+      const [{ entries, hasMore }]: [RawReadRangeOutput] = await core.opAsync(
         "op_kv_snapshot_read",
         this.#rid,
         [[
-          "prefix" in selector ? selector.prefix : null,
-          "start" in selector ? selector.start : null,
-          "end" in selector ? selector.end : null,
+          ...selectorToRaw(selector),
           batchSize,
           reverse,
           cursor,
+          maxBytes ?? null,
         ]],
         consistency,
       );
 
-      return entries.map(deserializeValue);
+      return { entries: entries.map(deserializeValue), hasMore };
     };
   }
 
-  async enqueue(
-    message: unknown,
-    opts?: { delay?: number; keysIfUndelivered?: Deno.KvKey[] },
-  ) {
-    if (opts?.delay !== undefined) {
-      validateQueueDelay(opts?.delay);
-    }
-
-    const enqueues = [
-      [
-        core.serialize(message, { forStorage: true }),
-        opts?.delay ?? 0,
-        opts?.keysIfUndelivered ?? [],
-        null,
-      ],
-    ];
+  async enqueue(message: unknown, opts?: EnqueueOptions) {
+    const enqueues = [enqueueToRaw(message, opts)];
 
     const versionstamp = await core.opAsync(
-      "op_kv_atomic_write",
+      "op_kv_enqueue",
       this.#rid,
-      [],
-      [],
       enqueues,
     );
-    if (versionstamp === null) throw new TypeError("Failed to enqueue value");
     return { ok: true, versionstamp };
   }
 
   async listenQueue(
-    handler: (message: unknown) => Promise<void> | void,
+    handler: (
+      message: unknown,
+      info: Deno.KvQueueMessageInfo,
+    ) => Promise<void> | void,
   ): Promise<void> {
     const finishMessageOps = new Map<number, Promise<void>>();
     while (true) {
       // Wait for the next message.
-      const next: { 0: Uint8Array; 1: number } = await core.opAsync(
+      const next: RawQueueMessage | null = await core.opAsync(
         "op_kv_dequeue_next_message",
         this.#rid,
       );
@@ -260,21 +465,56 @@ class Kv {
       }
 
       // Deserialize the payload.
-      const { 0: payload, 1: handleId } = next;
+      const {
+        0: payload,
+        1: handleId,
+        2: attempt,
+        3: enqueuedAt,
+        4: id,
+      } = next;
       const deserializedPayload = core.deserialize(payload, {
         forStorage: true,
       });
 
+      // Extensions that are still in flight when the handler returns must
+      // settle before the message is finished.
+      const extensions = new Set<Promise<void>>();
+      const extendDeadline = async (timeout?: number) => {
+        if (
+          timeout !== undefined &&
+          !(Number.isInteger(timeout) && timeout > 0)
+        ) {
+          throw new TypeError("timeout must be a positive integer");
+        }
+        const promise: Promise<void> = core.opAsync(
+          "op_kv_extend_dequeued_message_deadline",
+          handleId,
+          timeout ?? null,
+        );
+        extensions.add(promise);
+        try {
+          await promise;
+        } finally {
+          extensions.delete(promise);
+        }
+      };
+
       // Dispatch the payload.
       (async () => {
         let success = false;
         try {
-          const result = handler(deserializedPayload);
+          const result = handler(deserializedPayload, {
+            id,
+            attempt,
+            enqueuedAt: enqueuedAt === null ? null : new Date(enqueuedAt),
+            extendDeadline,
+          });
           const _res = result instanceof Promise ? (await result) : result;
           success = true;
         } catch (error) {
           console.error("Exception in queue handler", error);
         } finally {
+          await Promise.allSettled(extensions);
           const promise: Promise<void> = core.opAsync(
             "op_kv_finish_dequeued_message",
             handleId,
@@ -296,6 +536,108 @@ class Kv {
     finishMessageOps.clear();
   }
 
+  async export(path: string): Promise<number> {
+    return await core.opAsync("op_kv_export", this.#rid, path);
+  }
+
+  async import(
+    path: string,
+    options?: { overwrite?: boolean },
+  ): Promise<number> {
+    return await core.opAsync(
+      "op_kv_import",
+      this.#rid,
+      path,
+      options?.overwrite ?? false,
+    );
+  }
+
+  async listDeadLetters(
+    options?: { limit?: number },
+  ): Promise<Deno.KvDeadLetter[]> {
+    const messages: RawDeadLetter[] = await core.opAsync(
+      "op_kv_list_dead_letter",
+      this.#rid,
+      options?.limit ?? 100,
+    );
+    return messages.map((message) => ({
+      id: message.id,
+      value: core.deserialize(message.payload, { forStorage: true }),
+      enqueuedAt: new Date(message.enqueuedAt),
+      failedAt: new Date(message.failedAt),
+      failureCount: message.failureCount,
+    }));
+  }
+
+  async retryDeadLetter(id: string): Promise<boolean> {
+    return await core.opAsync("op_kv_retry_dead_letter", this.#rid, id);
+  }
+
+  async maintenance(
+    mode: Deno.KvMaintenanceMode = "passive",
+  ): Promise<Deno.KvCheckpointResult> {
+    return await core.opAsync("op_kv_maintenance", this.#rid, mode);
+  }
+
+  async checkIntegrity(): Promise<Deno.KvIntegrityProblem[]> {
+    return await core.opAsync("op_kv_check_integrity", this.#rid);
+  }
+
+  async queryIndex(
+    name: string,
+    selector: Deno.KvListSelector,
+    options?: { limit?: number; reverse?: boolean },
+  ): Promise<Deno.KvEntry<unknown>[]> {
+    const entries: RawKvEntry[] = await core.opAsync(
+      "op_kv_read_index",
+      this.#rid,
+      name,
+      selectorToRaw(selector),
+      options?.limit ?? 100,
+      options?.reverse ?? false,
+    );
+    return entries.map(deserializeValue);
+  }
+
+  async queueStats(): Promise<Deno.KvQueueStats> {
+    const stats: RawQueueStats = await core.opAsync(
+      "op_kv_queue_stats",
+      this.#rid,
+    );
+    return {
+      ready: stats.ready,
+      running: stats.running,
+      earliestReady: stats.earliestReady === null
+        ? null
+        : new Date(stats.earliestReady),
+    };
+  }
+
+  async stats(): Promise<Deno.KvStats> {
+    const stats: RawStats = await core.opAsync("op_kv_stats", this.#rid);
+    return {
+      keyCount: stats.keyCount,
+      totalValueBytes: stats.totalValueBytes,
+      dbFileBytes: stats.dbFileBytes,
+    };
+  }
+
+  async ping(): Promise<number> {
+    return await core.opAsync("op_kv_ping", this.#rid);
+  }
+
+  async closeGracefully(options?: { timeout?: number }): Promise<boolean> {
+    const timeout = options?.timeout ?? 10000;
+    if (!(timeout >= 0 && timeout <= 0xffffffff)) {
+      throw new TypeError("timeout must be a non-negative number");
+    }
+    return await core.opAsync(
+      "op_kv_database_close_graceful",
+      this.#rid,
+      timeout,
+    );
+  }
+
   close() {
     core.close(this.#rid);
   }
@@ -304,35 +646,62 @@ class Kv {
 class AtomicOperation {
   #rid: number;
 
-  #checks: [Deno.KvKey, string | null][] = [];
-  #mutations: [Deno.KvKey, string, RawValue | null, number | undefined][] = [];
-  #enqueues: [Uint8Array, number, Deno.KvKey[], number[] | null][] = [];
-
-  constructor(rid: number) {
+  #checks: [Deno.KvKey, string | null, RawValue | null, string | null][] =
+    [];
+  #mutations: [
+    Deno.KvKey,
+    string,
+    RawValue | null,
+    number | undefined,
+    [Deno.KvKey, boolean] | null,
+  ][] = [];
+  #enqueues: RawEnqueue[] = [];
+  #valueEncoding: ValueEncoding;
+
+  constructor(rid: number, valueEncoding: ValueEncoding) {
     this.#rid = rid;
+    this.#valueEncoding = valueEncoding;
   }
 
   check(...checks: Deno.AtomicCheck[]): this {
     for (const check of checks) {
-      this.#checks.push([check.key, check.versionstamp]);
+      if ("value" in check) {
+        this.#checks.push([
+          check.key,
+          // rejected together with a value
+          (check as { versionstamp?: string | null }).versionstamp ?? null,
+          serializeValue(check.value, this.#valueEncoding),
+          null,
+        ]);
+      } else {
+        this.#checks.push([check.key, check.versionstamp, null, null]);
+      }
     }
     return this;
   }
 
+  checkQueueRunning(id: string): this {
+    this.#checks.push([[], null, null, id]);
+    return this;
+  }
+
   mutate(...mutations: Deno.KvMutation[]): this {
     for (const mutation of mutations) {
       const key = mutation.key;
       let type: string;
       let value: RawValue | null;
       let expireIn: number | undefined = undefined;
+      let moveTo: [Deno.KvKey, boolean] | null = null;
       switch (mutation.type) {
         case "delete":
-          type = "delete";
+        case "deletePrefix":
+          type = mutation.type;
           if (mutation.value) {
-            throw new TypeError("invalid mutation 'delete' with value");
+            throw new TypeError(`invalid mutation '${type}' with value`);
           }
           break;
         case "set":
+        case "setIfAbsent":
           if (typeof mutation.expireIn === "number") {
             expireIn = mutation.expireIn;
           }
@@ -344,28 +713,53 @@ class AtomicOperation {
           if (!("value" in mutation)) {
             throw new TypeError(`invalid mutation '${type}' without value`);
           }
-          value = serializeValue(mutation.value);
+          value = serializeValue(mutation.value, this.#valueEncoding);
+          break;
+        case "move":
+          type = "move";
+          if (!("to" in mutation)) {
+            throw new TypeError("invalid mutation 'move' without target");
+          }
+          moveTo = [mutation.to, mutation.overwrite ?? false];
           break;
         default:
           throw new TypeError("Invalid mutation type");
       }
-      this.#mutations.push([key, type, value, expireIn]);
+      this.#mutations.push([key, type, value, expireIn, moveTo]);
     }
     return this;
   }
 
   sum(key: Deno.KvKey, n: bigint): this {
-    this.#mutations.push([key, "sum", serializeValue(new KvU64(n)), undefined]);
+    this.#mutations.push([
+      key,
+      "sum",
+      serializeValue(new KvU64(n)),
+      undefined,
+      null,
+    ]);
     return this;
   }
 
   min(key: Deno.KvKey, n: bigint): this {
-    this.#mutations.push([key, "min", serializeValue(new KvU64(n)), undefined]);
+    this.#mutations.push([
+      key,
+      "min",
+      serializeValue(new KvU64(n)),
+      undefined,
+      null,
+    ]);
     return this;
   }
 
   max(key: Deno.KvKey, n: bigint): this {
-    this.#mutations.push([key, "max", serializeValue(new KvU64(n)), undefined]);
+    this.#mutations.push([
+      key,
+      "max",
+      serializeValue(new KvU64(n)),
+      undefined,
+      null,
+    ]);
     return this;
   }
 
@@ -377,43 +771,92 @@ class AtomicOperation {
     this.#mutations.push([
       key,
       "set",
-      serializeValue(value),
+      serializeValue(value, this.#valueEncoding),
+      options?.expireIn,
+      null,
+    ]);
+    return this;
+  }
+
+  setIfAbsent(
+    key: Deno.KvKey,
+    value: unknown,
+    options?: { expireIn?: number },
+  ): this {
+    this.#mutations.push([
+      key,
+      "setIfAbsent",
+      serializeValue(value, this.#valueEncoding),
       options?.expireIn,
+      null,
     ]);
     return this;
   }
 
   delete(key: Deno.KvKey): this {
-    this.#mutations.push([key, "delete", null, undefined]);
+    this.#mutations.push([key, "delete", null, undefined, null]);
+    return this;
+  }
+
+  deletePrefix(prefix: Deno.KvKey): this {
+    this.#mutations.push([prefix, "deletePrefix", null, undefined, null]);
     return this;
   }
 
-  enqueue(
-    message: unknown,
-    opts?: { delay?: number; keysIfUndelivered?: Deno.KvKey[] },
+  move(
+    from: Deno.KvKey,
+    to: Deno.KvKey,
+    options?: { overwrite?: boolean },
   ): this {
-    if (opts?.delay !== undefined) {
-      validateQueueDelay(opts?.delay);
-    }
-    this.#enqueues.push([
-      core.serialize(message, { forStorage: true }),
-      opts?.delay ?? 0,
-      opts?.keysIfUndelivered ?? [],
+    this.#mutations.push([
+      from,
+      "move",
       null,
+      undefined,
+      [to, options?.overwrite ?? false],
     ]);
     return this;
   }
 
-  async commit(): Promise<Deno.KvCommitResult | Deno.KvCommitError> {
-    const versionstamp = await core.opAsync(
-      "op_kv_atomic_write",
-      this.#rid,
-      this.#checks,
-      this.#mutations,
-      this.#enqueues,
-    );
-    if (versionstamp === null) return { ok: false };
-    return { ok: true, versionstamp };
+  enqueue(message: unknown, opts?: EnqueueOptions): this {
+    this.#enqueues.push(enqueueToRaw(message, opts));
+    return this;
+  }
+
+  async commit(
+    options?: { returnOld?: boolean; dryRun?: boolean },
+  ): Promise<Deno.KvCommitResult | Deno.KvDryRunResult | Deno.KvCommitError> {
+    const returnOld = options?.returnOld ?? false;
+    const result: RawCommitResult | RawDryRunResult | RawCheckFailure =
+      await core.opAsync(
+        "op_kv_atomic_write",
+        this.#rid,
+        this.#checks,
+        this.#mutations,
+        this.#enqueues,
+        returnOld,
+        options?.dryRun ?? false,
+      );
+    if ("failedCheck" in result) {
+      const commitError: Deno.KvCommitError = { ok: false };
+      if (result.failedCheck !== null) {
+        commitError.failedCheck = result.failedCheck;
+      }
+      return commitError;
+    }
+    const commitResult: Deno.KvCommitResult | Deno.KvDryRunResult =
+      "dryRun" in result
+        ? { ok: true, dryRun: true }
+        : { ok: true, versionstamp: result.versionstamp };
+    if (returnOld) {
+      commitResult.oldValues = result.oldValues!.map((entry) =>
+        entry === null ? null : deserializeValue(entry)
+      );
+    }
+    if (result.mutationCounts !== undefined) {
+      commitResult.mutationCounts = result.mutationCounts;
+    }
+    return commitResult;
   }
 
   then() {
@@ -482,12 +925,20 @@ function deserializeValue(entry: RawKvEntry): Deno.KvEntry<unknown> {
         ...entry,
         value: new KvU64(value),
       };
+    case "json":
+      return {
+        ...entry,
+        value: JSONParse(value),
+      };
     default:
       throw new TypeError("Invalid value type");
   }
 }
 
-function serializeValue(value: unknown): RawValue {
+function serializeValue(
+  value: unknown,
+  encoding: ValueEncoding = "v8",
+): RawValue {
   if (ObjectPrototypeIsPrototypeOf(Uint8ArrayPrototype, value)) {
     return {
       kind: "bytes",
@@ -498,6 +949,15 @@ function serializeValue(value: unknown): RawValue {
       kind: "u64",
       value: value.valueOf(),
     };
+  } else if (encoding === "json") {
+    const text = JSONStringify(value);
+    if (text === undefined) {
+      throw new TypeError("Value can not be encoded as JSON");
+    }
+    return {
+      kind: "json",
+      value: text,
+    };
   } else {
     return {
       kind: "v8",
@@ -528,27 +988,25 @@ class KvListIterator extends AsyncIterator
     cursor: string | undefined,
     reverse: boolean,
     consistency: Deno.KvConsistencyLevel,
-  ) => Promise<Deno.KvEntry<unknown>[]>;
+  ) => Promise<ListBatch>;
   #limit: number | undefined;
   #count = 0;
   #reverse: boolean;
-  #batchSize: number;
   #consistency: Deno.KvConsistencyLevel;
 
   constructor(
-    { limit, selector, cursor, reverse, consistency, batchSize, pullBatch }: {
+    { limit, selector, cursor, reverse, consistency, pullBatch }: {
       limit?: number;
       selector: Deno.KvListSelector;
       cursor?: string;
       reverse: boolean;
-      batchSize: number;
       consistency: Deno.KvConsistencyLevel;
       pullBatch: (
         selector: Deno.KvListSelector,
         cursor: string | undefined,
         reverse: boolean,
         consistency: Deno.KvConsistencyLevel,
-      ) => Promise<Deno.KvEntry<unknown>[]>;
+      ) => Promise<ListBatch>;
     },
   ) {
     super();
@@ -564,6 +1022,8 @@ class KvListIterator extends AsyncIterator
     if ("end" in selector && selector.end !== undefined) {
       end = ObjectFreeze([...selector.end]);
     }
+    const bytesPrefix = "bytesPrefix" in selector &&
+      selector.bytesPrefix === true;
     if (prefix) {
       if (start && end) {
         throw new TypeError(
@@ -571,12 +1031,16 @@ class KvListIterator extends AsyncIterator
         );
       }
       if (start) {
-        this.#selector = { prefix, start };
+        this.#selector = { prefix, start, bytesPrefix };
       } else if (end) {
-        this.#selector = { prefix, end };
+        this.#selector = { prefix, end, bytesPrefix };
       } else {
-        this.#selector = { prefix };
+        this.#selector = { prefix, bytesPrefix };
       }
+    } else if (bytesPrefix) {
+      throw new TypeError(
+        "Selector can only specify 'bytesPrefix' when specifying 'prefix'.",
+      );
     } else {
       if (start && end) {
         this.#selector = { start, end };
@@ -591,8 +1055,9 @@ class KvListIterator extends AsyncIterator
     this.#limit = limit;
     this.#reverse = reverse;
     this.#consistency = consistency;
-    this.#batchSize = batchSize;
-    this.#cursorGen = cursor ? () => cursor : null;
+    // An empty cursor is valid: it is the cursor of a range's start key when
+    // that key is also the common prefix of the range.
+    this.#cursorGen = cursor !== undefined ? () => cursor : null;
   }
 
   get cursor(): string {
@@ -614,7 +1079,7 @@ class KvListIterator extends AsyncIterator
 
     // Attempt to fill the buffer
     if (!this.#entries?.length && !this.#lastBatch) {
-      const batch = await this.#pullBatch(
+      const { entries: batch, hasMore } = await this.#pullBatch(
         this.#selector,
         this.#cursorGen ? this.#cursorGen() : undefined,
         this.#reverse,
@@ -626,7 +1091,7 @@ class KvListIterator extends AsyncIterator
       this.#entries = batch;
 
       // Last batch, do not attempt to pull more
-      if (batch.length < this.#batchSize) {
+      if (!hasMore) {
         this.#lastBatch = true;
       }
     }
@@ -638,14 +1103,8 @@ class KvListIterator extends AsyncIterator
       return { done: true, value: undefined };
     }
 
-    this.#cursorGen = () => {
-      const selector = this.#selector;
-      return encodeCursor([
-        "prefix" in selector ? selector.prefix : null,
-        "start" in selector ? selector.start : null,
-        "end" in selector ? selector.end : null,
-      ], entry.key);
-    };
+    this.#cursorGen = () =>
+      encodeCursor(selectorToRaw(this.#selector), entry.key);
     this.#count++;
     return {
       done: false,
@@ -0,0 +1,89 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use bencher::benchmark_group;
+use bencher::benchmark_main;
+use bencher::Bencher;
+use deno_core::error::AnyError;
+use deno_core::OpState;
+use deno_kv::sqlite::SqliteDbHandler;
+use deno_kv::sqlite::SqliteDbHandlerPermissions;
+use deno_kv::AtomicWrite;
+use deno_kv::Database;
+use deno_kv::DatabaseHandler;
+use deno_kv::KvMutation;
+use deno_kv::MutationKind;
+use deno_kv::Value;
+
+struct AllowAll;
+
+impl SqliteDbHandlerPermissions for AllowAll {
+  fn check_read(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+    Ok(())
+  }
+
+  fn check_write(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+    Ok(())
+  }
+}
+
+/// Inserts 10000 keys with 256 byte values, in batches of 500, into a new
+/// database file opened by `handler`.
+fn bulk_insert(b: &mut Bencher, handler: fn() -> SqliteDbHandler<AllowAll>) {
+  let runtime = tokio::runtime::Builder::new_current_thread()
+    .enable_all()
+    .build()
+    .unwrap();
+  let dir = tempfile::tempdir().unwrap();
+  let state = Rc::new(RefCell::new(OpState::new(1, None)));
+  state.borrow_mut().put(AllowAll);
+  let mut run = 0;
+  b.iter(|| {
+    run += 1;
+    let path = dir.path().join(format!("{run}.sqlite3"));
+    runtime.block_on(async {
+      let db = handler()
+        .open(state.clone(), Some(path.to_string_lossy().into_owned()))
+        .await
+        .unwrap();
+      for batch in 0..20u32 {
+        let write = AtomicWrite {
+          checks: vec![],
+          mutations: (0..500u32)
+            .map(|i| KvMutation {
+              key: (batch * 500 + i).to_be_bytes().to_vec(),
+              kind: MutationKind::Set(Value::Bytes(vec![0; 256])),
+              expire_at: None,
+            })
+            .collect(),
+          enqueues: vec![],
+          return_old: false,
+          dry_run: false,
+        };
+        db.atomic_write(state.clone(), write).await.unwrap();
+      }
+      db.close();
+    });
+  });
+}
+
+fn bulk_insert_default(b: &mut Bencher) {
+  bulk_insert(b, || SqliteDbHandler::new(None));
+}
+
+fn bulk_insert_tuned(b: &mut Bencher) {
+  bulk_insert(b, || {
+    SqliteDbHandler::new(None)
+      .with_page_size(16384)
+      .unwrap()
+      .with_cache_size(64 * 1024)
+      .unwrap()
+      .with_mmap_size(64 * 1024 * 1024)
+      .unwrap()
+  });
+}
+
+benchmark_group!(benches, bulk_insert_default, bulk_insert_tuned);
+benchmark_main!(benches);
@@ -1,6 +1,8 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 // Ported from https://github.com/foundationdb-rs/foundationdb-rs/blob/main/foundationdb/src/tuple/pack.rs
 
+use std::cmp::Ordering;
+
 use crate::Key;
 use crate::KeyPart;
 
@@ -64,6 +66,18 @@ pub fn encode_key(key: &Key) -> std::io::Result<Vec<u8>> {
   Ok(output)
 }
 
+/// Compares two keys in the order in which they are stored in the database.
+///
+/// This is always the same as comparing the outputs of [encode_key] for both
+/// keys byte by byte. Key parts of different types are ordered by type:
+/// bytes < strings < integers < floats < false < true. Within a type, parts
+/// are ordered as documented on [KeyPart]. Shorter keys sort before longer
+/// keys that start with them. The encoding is persisted in databases, so this
+/// order is stable across versions.
+pub fn compare_keys(a: &Key, b: &Key) -> Ordering {
+  a.cmp(b)
+}
+
 pub fn decode_key(mut bytes: &[u8]) -> std::io::Result<Key> {
   let mut key = Key(vec![]);
   while !bytes.is_empty() {
@@ -301,11 +315,15 @@ fn parse_slice(input: &[u8]) -> std::io::Result<(&[u8], Vec<u8>)> {
 #[cfg(test)]
 mod tests {
   use num_bigint::BigInt;
+  use rand::rngs::StdRng;
+  use rand::Rng;
+  use rand::SeedableRng;
   use std::cmp::Ordering;
 
   use crate::Key;
   use crate::KeyPart;
 
+  use super::compare_keys;
   use super::decode_key;
   use super::encode_key;
 
@@ -321,9 +339,86 @@ mod tests {
     let b_bytes = encode_key(&b).unwrap();
 
     assert_eq!(a.cmp(&b), expected);
+    assert_eq!(compare_keys(&a, &b), expected);
     assert_eq!(a_bytes.cmp(&b_bytes), expected);
   }
 
+  /// Key parts that exercise the edges of every type.
+  fn sample_key_parts() -> Vec<KeyPart> {
+    vec![
+      KeyPart::Bytes(vec![]),
+      KeyPart::Bytes(vec![0]),
+      KeyPart::Bytes(vec![0, 0xff]),
+      KeyPart::Bytes(vec![1]),
+      KeyPart::Bytes(vec![0xff]),
+      KeyPart::String("".into()),
+      KeyPart::String("\0".into()),
+      KeyPart::String("a".into()),
+      KeyPart::String("a\0b".into()),
+      KeyPart::String("ab".into()),
+      KeyPart::String("\u{10ffff}".into()),
+      KeyPart::Int(BigInt::from(i64::MIN) * 1000),
+      KeyPart::Int(BigInt::from(-256)),
+      KeyPart::Int(BigInt::from(-1)),
+      KeyPart::Int(BigInt::from(0)),
+      KeyPart::Int(BigInt::from(1)),
+      KeyPart::Int(BigInt::from(255)),
+      KeyPart::Int(BigInt::from(256)),
+      KeyPart::Int(BigInt::from(u64::MAX) * 1000),
+      KeyPart::Float(-f64::NAN),
+      KeyPart::Float(f64::NEG_INFINITY),
+      KeyPart::Float(-1.5),
+      KeyPart::Float(-f64::MIN_POSITIVE),
+      KeyPart::Float(-0.0),
+      KeyPart::Float(0.0),
+      KeyPart::Float(f64::MIN_POSITIVE),
+      KeyPart::Float(1.5),
+      KeyPart::Float(f64::INFINITY),
+      KeyPart::Float(f64::NAN),
+      KeyPart::False,
+      KeyPart::True,
+    ]
+  }
+
+  fn random_key(rng: &mut StdRng, parts: &[KeyPart]) -> Key {
+    let len = rng.gen_range(0..4);
+    Key(
+      (0..len)
+        .map(|_| parts[rng.gen_range(0..parts.len())].clone())
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn sample_parts_are_sorted() {
+    let parts = sample_key_parts();
+    for (i, a) in parts.iter().enumerate() {
+      for (j, b) in parts.iter().enumerate() {
+        check_order(Key(vec![a.clone()]), Key(vec![b.clone()]), i.cmp(&j));
+      }
+    }
+  }
+
+  #[test]
+  fn compare_keys_matches_encoding() {
+    let parts = sample_key_parts();
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..10000 {
+      let a = random_key(&mut rng, &parts);
+      let b = random_key(&mut rng, &parts);
+      roundtrip(a.clone());
+      let a_bytes = encode_key(&a).unwrap();
+      let b_bytes = encode_key(&b).unwrap();
+      assert_eq!(
+        compare_keys(&a, &b),
+        a_bytes.cmp(&b_bytes),
+        "{:?} {:?}",
+        a,
+        b
+      );
+    }
+  }
+
   fn check_bijection(key: Key, serialized: &[u8]) {
     let bytes = encode_key(&key).unwrap();
     assert_eq!(&bytes[..], serialized);
@@ -1,24 +1,49 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
 use std::rc::Rc;
+use std::time::Duration;
 
+use crate::remote::RemoteDbConfig;
+use crate::remote::RemoteDbHandler;
 use crate::remote::RemoteDbHandlerPermissions;
 use crate::sqlite::SqliteDbHandler;
 use crate::sqlite::SqliteDbHandlerPermissions;
 use crate::AtomicWrite;
-use crate::CommitResult;
+use crate::CheckpointResult;
+use crate::CommitOutcome;
+use crate::Consistency;
 use crate::Database;
 use crate::DatabaseHandler;
+use crate::DatabaseStats;
+use crate::DeadLetterMessage;
+use crate::Enqueue;
+use crate::IntegrityProblem;
+use crate::KvClock;
+use crate::KvEntry;
+use crate::KvMutation;
+use crate::MaintenanceMode;
+use crate::MutationKind;
 use crate::QueueMessageHandle;
+use crate::QueueStats;
 use crate::ReadRange;
 use crate::ReadRangeOutput;
 use crate::SnapshotReadOptions;
+use crate::Value;
 use async_trait::async_trait;
 use deno_core::error::type_error;
 use deno_core::error::AnyError;
 use deno_core::OpState;
 
+/// A [DatabaseHandler] that picks a backend by the path passed to
+/// `Deno.openKv`, so that local and remote databases can be opened from the
+/// same process.
+///
+/// Each backend is registered with a list of path prefixes, matched in order
+/// and ignoring ASCII case so that URL schemes like `HTTPS://` are
+/// recognized. An empty prefix matches any path, including no path at all.
 pub struct MultiBackendDbHandler {
   backends: Vec<(&'static [&'static str], Box<dyn DynamicDbHandler>)>,
 }
@@ -30,15 +55,22 @@ impl MultiBackendDbHandler {
     Self { backends }
   }
 
+  /// Opens `http://` and `https://` URLs with a [RemoteDbHandler], and
+  /// everything else with a [SqliteDbHandler].
   pub fn remote_or_sqlite<
     P: SqliteDbHandlerPermissions + RemoteDbHandlerPermissions + 'static,
   >(
     default_storage_dir: Option<std::path::PathBuf>,
+    metadata_cache_dir: Option<std::path::PathBuf>,
   ) -> Self {
+    let remote_config = RemoteDbConfig {
+      metadata_cache_dir,
+      ..Default::default()
+    };
     Self::new(vec![
       (
         &["https://", "http://"],
-        Box::new(crate::remote::RemoteDbHandler::<P>::new()),
+        Box::new(RemoteDbHandler::<P>::with_config(remote_config)),
       ),
       (
         &[""],
@@ -46,27 +78,25 @@ impl MultiBackendDbHandler {
       ),
     ])
   }
-}
-
-#[async_trait(?Send)]
-impl DatabaseHandler for MultiBackendDbHandler {
-  type DB = Box<dyn DynamicDb>;
 
-  async fn open(
+  /// Returns the index and the handler of the backend for `path`.
+  fn backend(
     &self,
-    state: Rc<RefCell<OpState>>,
-    path: Option<String>,
-  ) -> Result<Self::DB, AnyError> {
-    for (prefixes, handler) in &self.backends {
+    path: Option<&str>,
+  ) -> Result<(usize, &dyn DynamicDbHandler), AnyError> {
+    for (i, (prefixes, handler)) in self.backends.iter().enumerate() {
       for &prefix in *prefixes {
         if prefix.is_empty() {
-          return handler.dyn_open(state.clone(), path.clone()).await;
+          return Ok((i, handler.as_ref()));
         }
-        let Some(path) = &path else {
+        let Some(path) = path else {
           continue;
         };
-        if path.starts_with(prefix) {
-          return handler.dyn_open(state.clone(), Some(path.clone())).await;
+        let matches = path
+          .get(..prefix.len())
+          .is_some_and(|scheme| scheme.eq_ignore_ascii_case(prefix));
+        if matches {
+          return Ok((i, handler.as_ref()));
         }
       }
     }
@@ -77,6 +107,32 @@ impl DatabaseHandler for MultiBackendDbHandler {
   }
 }
 
+#[async_trait(?Send)]
+impl DatabaseHandler for MultiBackendDbHandler {
+  type DB = Box<dyn DynamicDb>;
+
+  async fn open(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: Option<String>,
+  ) -> Result<Self::DB, AnyError> {
+    let (_, handler) = self.backend(path.as_deref())?;
+    handler.dyn_open(state, path).await
+  }
+
+  /// Keys are scoped to the backend, as backends don't know about each
+  /// other's keys.
+  fn shared_key(
+    &self,
+    state: &mut OpState,
+    path: Option<String>,
+  ) -> Result<Option<String>, AnyError> {
+    let (i, handler) = self.backend(path.as_deref())?;
+    let key = handler.dyn_shared_key(state, path)?;
+    Ok(key.map(|key| format!("{i}:{key}")))
+  }
+}
+
 #[async_trait(?Send)]
 pub trait DynamicDbHandler {
   async fn dyn_open(
@@ -84,6 +140,12 @@ pub trait DynamicDbHandler {
     state: Rc<RefCell<OpState>>,
     path: Option<String>,
   ) -> Result<Box<dyn DynamicDb>, AnyError>;
+
+  fn dyn_shared_key(
+    &self,
+    state: &mut OpState,
+    path: Option<String>,
+  ) -> Result<Option<String>, AnyError>;
 }
 
 #[async_trait(?Send)]
@@ -97,6 +159,14 @@ impl DatabaseHandler for Box<dyn DynamicDbHandler> {
   ) -> Result<Self::DB, AnyError> {
     (**self).dyn_open(state, path).await
   }
+
+  fn shared_key(
+    &self,
+    state: &mut OpState,
+    path: Option<String>,
+  ) -> Result<Option<String>, AnyError> {
+    (**self).dyn_shared_key(state, path)
+  }
 }
 
 #[async_trait(?Send)]
@@ -112,6 +182,14 @@ where
   ) -> Result<Box<dyn DynamicDb>, AnyError> {
     Ok(Box::new(self.open(state, path).await?))
   }
+
+  fn dyn_shared_key(
+    &self,
+    state: &mut OpState,
+    path: Option<String>,
+  ) -> Result<Option<String>, AnyError> {
+    self.shared_key(state, path)
+  }
 }
 
 #[async_trait(?Send)]
@@ -123,17 +201,107 @@ pub trait DynamicDb {
     options: SnapshotReadOptions,
   ) -> Result<Vec<ReadRangeOutput>, AnyError>;
 
+  async fn dyn_snapshot_read_stream(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    range: &mut ReadRange,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<KvEntry>, AnyError>;
+
+  async fn dyn_count_range(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    options: SnapshotReadOptions,
+  ) -> Result<u64, AnyError>;
+
+  async fn dyn_read_union(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    limit: NonZeroU32,
+    options: SnapshotReadOptions,
+  ) -> Result<ReadRangeOutput, AnyError>;
+
   async fn dyn_atomic_write(
     &self,
     state: Rc<RefCell<OpState>>,
     write: AtomicWrite,
-  ) -> Result<Option<CommitResult>, AnyError>;
+  ) -> Result<CommitOutcome, AnyError>;
+
+  async fn dyn_enqueue(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    enqueues: Vec<Enqueue>,
+  ) -> Result<[u8; 10], AnyError>;
 
   async fn dyn_dequeue_next_message(
     &self,
     state: Rc<RefCell<OpState>>,
   ) -> Result<Option<Box<dyn QueueMessageHandle>>, AnyError>;
 
+  async fn dyn_export(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+  ) -> Result<u64, AnyError>;
+
+  async fn dyn_import(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+    overwrite: bool,
+  ) -> Result<u64, AnyError>;
+
+  async fn dyn_list_dead_letters(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    limit: u32,
+  ) -> Result<Vec<DeadLetterMessage>, AnyError>;
+
+  async fn dyn_retry_dead_letter(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    id: String,
+  ) -> Result<bool, AnyError>;
+
+  async fn dyn_queue_stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<QueueStats, AnyError>;
+
+  async fn dyn_stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<DatabaseStats, AnyError>;
+
+  async fn dyn_ping(&self, state: Rc<RefCell<OpState>>)
+    -> Result<(), AnyError>;
+
+  async fn dyn_checkpoint(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    mode: MaintenanceMode,
+  ) -> Result<CheckpointResult, AnyError>;
+
+  async fn dyn_check_integrity(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<Vec<IntegrityProblem>, AnyError>;
+
+  async fn dyn_read_index(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    range: ReadRange,
+  ) -> Result<ReadRangeOutput, AnyError>;
+
+  async fn dyn_close_graceful(
+    &self,
+    timeout: Duration,
+  ) -> Result<bool, AnyError>;
+
   fn dyn_close(&self);
 }
 
@@ -150,14 +318,53 @@ impl Database for Box<dyn DynamicDb> {
     (**self).dyn_snapshot_read(state, requests, options).await
   }
 
+  async fn snapshot_read_stream(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    range: &mut ReadRange,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<KvEntry>, AnyError> {
+    (**self)
+      .dyn_snapshot_read_stream(state, range, options)
+      .await
+  }
+
+  async fn count_range(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    options: SnapshotReadOptions,
+  ) -> Result<u64, AnyError> {
+    (**self).dyn_count_range(state, start, end, options).await
+  }
+
+  async fn read_union(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    limit: NonZeroU32,
+    options: SnapshotReadOptions,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    (**self).dyn_read_union(state, ranges, limit, options).await
+  }
+
   async fn atomic_write(
     &self,
     state: Rc<RefCell<OpState>>,
     write: AtomicWrite,
-  ) -> Result<Option<CommitResult>, AnyError> {
+  ) -> Result<CommitOutcome, AnyError> {
     (**self).dyn_atomic_write(state, write).await
   }
 
+  async fn enqueue(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    enqueues: Vec<Enqueue>,
+  ) -> Result<[u8; 10], AnyError> {
+    (**self).dyn_enqueue(state, enqueues).await
+  }
+
   async fn dequeue_next_message(
     &self,
     state: Rc<RefCell<OpState>>,
@@ -165,6 +372,85 @@ impl Database for Box<dyn DynamicDb> {
     (**self).dyn_dequeue_next_message(state).await
   }
 
+  async fn export(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+  ) -> Result<u64, AnyError> {
+    (**self).dyn_export(state, path).await
+  }
+
+  async fn import(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+    overwrite: bool,
+  ) -> Result<u64, AnyError> {
+    (**self).dyn_import(state, path, overwrite).await
+  }
+
+  async fn list_dead_letters(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    limit: u32,
+  ) -> Result<Vec<DeadLetterMessage>, AnyError> {
+    (**self).dyn_list_dead_letters(state, limit).await
+  }
+
+  async fn retry_dead_letter(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    id: String,
+  ) -> Result<bool, AnyError> {
+    (**self).dyn_retry_dead_letter(state, id).await
+  }
+
+  async fn queue_stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<QueueStats, AnyError> {
+    (**self).dyn_queue_stats(state).await
+  }
+
+  async fn stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<DatabaseStats, AnyError> {
+    (**self).dyn_stats(state).await
+  }
+
+  async fn ping(&self, state: Rc<RefCell<OpState>>) -> Result<(), AnyError> {
+    (**self).dyn_ping(state).await
+  }
+
+  async fn checkpoint(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    mode: MaintenanceMode,
+  ) -> Result<CheckpointResult, AnyError> {
+    (**self).dyn_checkpoint(state, mode).await
+  }
+
+  async fn check_integrity(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<Vec<IntegrityProblem>, AnyError> {
+    (**self).dyn_check_integrity(state).await
+  }
+
+  async fn read_index(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    range: ReadRange,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    (**self).dyn_read_index(state, name, range).await
+  }
+
+  async fn close_graceful(&self, timeout: Duration) -> Result<bool, AnyError> {
+    (**self).dyn_close_graceful(timeout).await
+  }
+
   fn close(&self) {
     (**self).dyn_close()
   }
@@ -185,14 +471,51 @@ where
     Ok(self.snapshot_read(state, requests, options).await?)
   }
 
+  async fn dyn_snapshot_read_stream(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    range: &mut ReadRange,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<KvEntry>, AnyError> {
+    Ok(self.snapshot_read_stream(state, range, options).await?)
+  }
+
+  async fn dyn_count_range(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    options: SnapshotReadOptions,
+  ) -> Result<u64, AnyError> {
+    Ok(self.count_range(state, start, end, options).await?)
+  }
+
+  async fn dyn_read_union(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    limit: NonZeroU32,
+    options: SnapshotReadOptions,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    Ok(self.read_union(state, ranges, limit, options).await?)
+  }
+
   async fn dyn_atomic_write(
     &self,
     state: Rc<RefCell<OpState>>,
     write: AtomicWrite,
-  ) -> Result<Option<CommitResult>, AnyError> {
+  ) -> Result<CommitOutcome, AnyError> {
     Ok(self.atomic_write(state, write).await?)
   }
 
+  async fn dyn_enqueue(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    enqueues: Vec<Enqueue>,
+  ) -> Result<[u8; 10], AnyError> {
+    Ok(self.enqueue(state, enqueues).await?)
+  }
+
   async fn dyn_dequeue_next_message(
     &self,
     state: Rc<RefCell<OpState>>,
@@ -205,6 +528,91 @@ where
     )
   }
 
+  async fn dyn_export(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+  ) -> Result<u64, AnyError> {
+    Ok(self.export(state, path).await?)
+  }
+
+  async fn dyn_import(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+    overwrite: bool,
+  ) -> Result<u64, AnyError> {
+    Ok(self.import(state, path, overwrite).await?)
+  }
+
+  async fn dyn_list_dead_letters(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    limit: u32,
+  ) -> Result<Vec<DeadLetterMessage>, AnyError> {
+    Ok(self.list_dead_letters(state, limit).await?)
+  }
+
+  async fn dyn_retry_dead_letter(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    id: String,
+  ) -> Result<bool, AnyError> {
+    Ok(self.retry_dead_letter(state, id).await?)
+  }
+
+  async fn dyn_queue_stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<QueueStats, AnyError> {
+    Ok(self.queue_stats(state).await?)
+  }
+
+  async fn dyn_stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<DatabaseStats, AnyError> {
+    Ok(self.stats(state).await?)
+  }
+
+  async fn dyn_ping(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<(), AnyError> {
+    Ok(self.ping(state).await?)
+  }
+
+  async fn dyn_checkpoint(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    mode: MaintenanceMode,
+  ) -> Result<CheckpointResult, AnyError> {
+    Ok(self.checkpoint(state, mode).await?)
+  }
+
+  async fn dyn_check_integrity(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<Vec<IntegrityProblem>, AnyError> {
+    Ok(self.check_integrity(state).await?)
+  }
+
+  async fn dyn_read_index(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    range: ReadRange,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    Ok(self.read_index(state, name, range).await?)
+  }
+
+  async fn dyn_close_graceful(
+    &self,
+    timeout: Duration,
+  ) -> Result<bool, AnyError> {
+    Ok(self.close_graceful(timeout).await?)
+  }
+
   fn dyn_close(&self) {
     self.close()
   }
@@ -218,4 +626,743 @@ impl QueueMessageHandle for Box<dyn QueueMessageHandle> {
   async fn finish(&self, success: bool) -> Result<(), AnyError> {
     (**self).finish(success).await
   }
+  fn attempt(&self) -> Option<u64> {
+    (**self).attempt()
+  }
+  fn enqueued_at_ms(&self) -> Option<u64> {
+    (**self).enqueued_at_ms()
+  }
+  fn id(&self) -> Option<String> {
+    (**self).id()
+  }
+  async fn extend_deadline(
+    &self,
+    timeout: Option<Duration>,
+  ) -> Result<(), AnyError> {
+    (**self).extend_deadline(timeout).await
+  }
+}
+
+/// A [Database] that serves recent single key reads from a local cache
+/// database, typically a SQLite database in front of a remote one.
+///
+/// Eventually consistent reads of a single key are answered by the cache
+/// when it has the key, and otherwise read from the remote database and
+/// cached for `ttl`, together with their remote versionstamp. Strongly
+/// consistent reads and range reads always go to the remote database. A read
+/// may therefore see an entry that is up to `ttl` old, unless it was written
+/// through this database, as writes are forwarded to the remote database and
+/// then evict the keys they touched from the cache. For `ttl` after such a
+/// write, remote entries with an older versionstamp than the write are not
+/// cached, so a lagging eventually consistent read can't bring the old value
+/// back into the cache.
+///
+/// Everything else, including the queue, is handled by the remote database.
+pub struct CachingDb<R: Database, L: Database> {
+  remote: R,
+  local: L,
+  ttl: Duration,
+  /// The versionstamps of recent writes through this database, by key, and
+  /// until when they are remembered.
+  written: RefCell<HashMap<Vec<u8>, ([u8; 10], u64)>>,
+}
+
+impl<R: Database, L: Database> CachingDb<R, L> {
+  pub fn new(remote: R, local: L, ttl: Duration) -> Self {
+    Self {
+      remote,
+      local,
+      ttl,
+      written: RefCell::new(HashMap::new()),
+    }
+  }
+
+  /// Returns the key that `request` reads if it reads exactly one key.
+  fn single_key(request: &ReadRange) -> Option<&[u8]> {
+    let (last, prefix) = request.end.split_last()?;
+    (*last == 0 && prefix == request.start.as_slice())
+      .then_some(request.start.as_slice())
+  }
+
+  async fn read_cached(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    key: &[u8],
+  ) -> Result<Option<KvEntry>, AnyError> {
+    let output = self
+      .local
+      .snapshot_read(
+        state,
+        vec![single_key_range(key)],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await?;
+    let entry = output.into_iter().next().and_then(|mut o| o.entries.pop());
+    Ok(entry.and_then(|entry| match entry.value {
+      Value::Bytes(bytes) => decode_cached_entry(entry.key, &bytes),
+      _ => None,
+    }))
+  }
+
+  async fn write_cached(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    entries: &[&KvEntry],
+  ) -> Result<(), AnyError> {
+    let now = KvClock::from_state(&state.borrow()).now_ms();
+    let cache_until = now + self.ttl.as_millis() as u64;
+    let written = self.written.borrow();
+    let mutations = entries
+      .iter()
+      .filter(|entry| {
+        written
+          .get(&entry.key)
+          .map_or(true, |(versionstamp, until)| {
+            *until <= now || entry.versionstamp >= *versionstamp
+          })
+      })
+      .map(|entry| KvMutation {
+        key: entry.key.clone(),
+        kind: MutationKind::Set(Value::Bytes(encode_cached_entry(entry))),
+        expire_at: Some(
+          entry
+            .expire_at_ms
+            .map_or(cache_until, |expire_at| expire_at.min(cache_until)),
+        ),
+      })
+      .collect();
+    drop(written);
+    self.write_local(state, mutations).await
+  }
+
+  /// Remembers the versionstamp of a committed write to `keys` for `ttl`.
+  fn remember_write(
+    &self,
+    state: &Rc<RefCell<OpState>>,
+    keys: &[&[u8]],
+    versionstamp: [u8; 10],
+  ) {
+    let now = KvClock::from_state(&state.borrow()).now_ms();
+    let until = now + self.ttl.as_millis() as u64;
+    let mut written = self.written.borrow_mut();
+    written.retain(|_, (_, written_until)| *written_until > now);
+    for key in keys {
+      written.insert(key.to_vec(), (versionstamp, until));
+    }
+  }
+
+  async fn write_local(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    mutations: Vec<KvMutation>,
+  ) -> Result<(), AnyError> {
+    if mutations.is_empty() {
+      return Ok(());
+    }
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations,
+      enqueues: vec![],
+      return_old: false,
+      dry_run: false,
+    };
+    self.local.atomic_write(state, write).await?;
+    Ok(())
+  }
+}
+
+fn single_key_range(key: &[u8]) -> ReadRange {
+  ReadRange {
+    start: key.to_vec(),
+    end: key.iter().copied().chain(Some(0)).collect(),
+    limit: NonZeroU32::new(1).unwrap(),
+    reverse: false,
+    max_bytes: None,
+  }
+}
+
+const CACHED_V8: u8 = 0;
+const CACHED_BYTES: u8 = 1;
+const CACHED_U64: u8 = 2;
+const CACHED_JSON: u8 = 3;
+
+/// Encodes a remote entry as the value of a cache entry: the kind of the
+/// value, the versionstamp, the expiration time (zero for none) and then
+/// the value itself.
+fn encode_cached_entry(entry: &KvEntry) -> Vec<u8> {
+  let (kind, value) = match &entry.value {
+    Value::V8(value) => (CACHED_V8, value.clone()),
+    Value::Bytes(value) => (CACHED_BYTES, value.clone()),
+    Value::U64(value) => (CACHED_U64, value.to_le_bytes().to_vec()),
+    Value::Json(value) => (CACHED_JSON, value.clone().into_bytes()),
+  };
+  let mut buf = Vec::with_capacity(19 + value.len());
+  buf.push(kind);
+  buf.extend_from_slice(&entry.versionstamp);
+  buf.extend_from_slice(&entry.expire_at_ms.unwrap_or(0).to_be_bytes());
+  buf.extend_from_slice(&value);
+  buf
+}
+
+fn decode_cached_entry(key: Vec<u8>, buf: &[u8]) -> Option<KvEntry> {
+  let (&kind, rest) = buf.split_first()?;
+  let versionstamp = rest.get(..10)?.try_into().ok()?;
+  let expire_at_ms = u64::from_be_bytes(rest.get(10..18)?.try_into().ok()?);
+  let value = &rest[18..];
+  let value = match kind {
+    CACHED_V8 => Value::V8(value.to_vec()),
+    CACHED_BYTES => Value::Bytes(value.to_vec()),
+    CACHED_U64 => Value::U64(u64::from_le_bytes(value.try_into().ok()?)),
+    CACHED_JSON => Value::Json(String::from_utf8(value.to_vec()).ok()?),
+    _ => return None,
+  };
+  Some(KvEntry {
+    key,
+    value,
+    versionstamp,
+    expire_at_ms: (expire_at_ms != 0).then_some(expire_at_ms),
+    commit_ms: None,
+  })
+}
+
+#[async_trait(?Send)]
+impl<R: Database, L: Database> Database for CachingDb<R, L> {
+  type QMH = R::QMH;
+
+  async fn snapshot_read(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    requests: Vec<ReadRange>,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<ReadRangeOutput>, AnyError> {
+    if options.consistency == Consistency::Strong {
+      return self.remote.snapshot_read(state, requests, options).await;
+    }
+
+    let mut outputs = Vec::with_capacity(requests.len());
+    let mut misses = vec![];
+    for request in requests {
+      let cached = match Self::single_key(&request) {
+        Some(key) => self.read_cached(state.clone(), key).await?,
+        None => None,
+      };
+      match cached {
+        Some(entry) => outputs.push(Some(ReadRangeOutput {
+          entries: vec![entry],
+          has_more: false,
+        })),
+        None => {
+          outputs.push(None);
+          misses.push(request);
+        }
+      }
+    }
+    if misses.is_empty() {
+      return Ok(outputs.into_iter().flatten().collect());
+    }
+
+    let cacheable = misses
+      .iter()
+      .map(|request| Self::single_key(request).is_some())
+      .collect::<Vec<_>>();
+    let fetched = self
+      .remote
+      .snapshot_read(state.clone(), misses, options)
+      .await?;
+    let to_cache = fetched
+      .iter()
+      .zip(cacheable)
+      .filter(|(_, cacheable)| *cacheable)
+      .flat_map(|(output, _)| &output.entries)
+      .collect::<Vec<_>>();
+    self.write_cached(state, &to_cache).await?;
+
+    let mut fetched = fetched.into_iter();
+    Ok(
+      outputs
+        .into_iter()
+        .map(|output| output.or_else(|| fetched.next()))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| type_error("Remote read returned too few ranges"))?,
+    )
+  }
+
+  async fn count_range(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    options: SnapshotReadOptions,
+  ) -> Result<u64, AnyError> {
+    self.remote.count_range(state, start, end, options).await
+  }
+
+  async fn read_union(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    limit: NonZeroU32,
+    options: SnapshotReadOptions,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    self.remote.read_union(state, ranges, limit, options).await
+  }
+
+  async fn atomic_write(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    write: AtomicWrite,
+  ) -> Result<CommitOutcome, AnyError> {
+    let mut evictions = vec![];
+    for mutation in &write.mutations {
+      let kind = match &mutation.kind {
+        MutationKind::DeletePrefix => MutationKind::DeletePrefix,
+        MutationKind::Move { to, .. } => {
+          evictions.push(KvMutation {
+            key: to.clone(),
+            kind: MutationKind::Delete,
+            expire_at: None,
+          });
+          MutationKind::Delete
+        }
+        _ => MutationKind::Delete,
+      };
+      evictions.push(KvMutation {
+        key: mutation.key.clone(),
+        kind,
+        expire_at: None,
+      });
+    }
+    let dry_run = write.dry_run;
+    // Evict even if the write failed, as its outcome may be unknown.
+    let result = self.remote.atomic_write(state.clone(), write).await;
+    if dry_run {
+      return result;
+    }
+    if let Ok(CommitOutcome::Committed(commit)) = &result {
+      let keys = evictions
+        .iter()
+        .filter(|eviction| matches!(eviction.kind, MutationKind::Delete))
+        .map(|eviction| eviction.key.as_slice())
+        .collect::<Vec<_>>();
+      self.remember_write(&state, &keys, commit.versionstamp);
+    }
+    // The remote write is done either way, so failing to evict is not an
+    // error of the write. The cached entries still expire after `ttl`.
+    if let Err(e) = self.write_local(state, evictions).await {
+      log::warn!("kv: Failed to evict written keys from the cache: {}", e);
+    }
+    result
+  }
+
+  async fn enqueue(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    enqueues: Vec<Enqueue>,
+  ) -> Result<[u8; 10], AnyError> {
+    self.remote.enqueue(state, enqueues).await
+  }
+
+  async fn dequeue_next_message(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<Option<Self::QMH>, AnyError> {
+    self.remote.dequeue_next_message(state).await
+  }
+
+  async fn list_dead_letters(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    limit: u32,
+  ) -> Result<Vec<DeadLetterMessage>, AnyError> {
+    self.remote.list_dead_letters(state, limit).await
+  }
+
+  async fn retry_dead_letter(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    id: String,
+  ) -> Result<bool, AnyError> {
+    self.remote.retry_dead_letter(state, id).await
+  }
+
+  async fn queue_stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<QueueStats, AnyError> {
+    self.remote.queue_stats(state).await
+  }
+
+  async fn stats(
+    &self,
+    state: Rc<RefCell<OpState>>,
+  ) -> Result<DatabaseStats, AnyError> {
+    self.remote.stats(state).await
+  }
+
+  async fn ping(&self, state: Rc<RefCell<OpState>>) -> Result<(), AnyError> {
+    self.remote.ping(state).await
+  }
+
+  async fn read_index(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    range: ReadRange,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    self.remote.read_index(state, name, range).await
+  }
+
+  async fn close_graceful(&self, timeout: Duration) -> Result<bool, AnyError> {
+    let finished = self.remote.close_graceful(timeout).await?;
+    self.local.close();
+    Ok(finished)
+  }
+
+  fn close(&self) {
+    self.remote.close();
+    self.local.close();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+  use std::cell::RefCell;
+  use std::path::Path;
+  use std::rc::Rc;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use async_trait::async_trait;
+  use deno_core::error::type_error;
+  use deno_core::error::AnyError;
+  use deno_core::url::Url;
+  use deno_core::OpState;
+
+  use super::CachingDb;
+  use super::MultiBackendDbHandler;
+  use crate::codec::encode_key;
+  use crate::remote::RemoteDbHandlerPermissions;
+  use crate::sqlite::SqliteDb;
+  use crate::sqlite::SqliteDbHandler;
+  use crate::sqlite::SqliteDbHandlerPermissions;
+  use crate::AtomicWrite;
+  use crate::CommitOutcome;
+  use crate::Consistency;
+  use crate::Database;
+  use crate::DatabaseHandler;
+  use crate::Enqueue;
+  use crate::FixedClock;
+  use crate::Key;
+  use crate::KeyPart;
+  use crate::KvClock;
+  use crate::KvEntry;
+  use crate::KvMutation;
+  use crate::MutationKind;
+  use crate::QueueMessageHandle;
+  use crate::ReadRange;
+  use crate::ReadRangeOutput;
+  use crate::SnapshotReadOptions;
+  use crate::Value;
+
+  /// Denies everything with an error naming the backend that asked, so that
+  /// routing can be observed without touching the disk or the network.
+  struct DenyAll;
+
+  impl SqliteDbHandlerPermissions for DenyAll {
+    fn check_read(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+      Err(type_error("sqlite"))
+    }
+
+    fn check_write(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+      Err(type_error("sqlite"))
+    }
+  }
+
+  impl RemoteDbHandlerPermissions for DenyAll {
+    fn check_env(&mut self, _var: &str) -> Result<(), AnyError> {
+      Err(type_error("remote"))
+    }
+
+    fn check_net_url(
+      &mut self,
+      _url: &Url,
+      _api_name: &str,
+    ) -> Result<(), AnyError> {
+      Err(type_error("remote"))
+    }
+  }
+
+  #[tokio::test]
+  async fn remote_or_sqlite_routing() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    state.borrow_mut().put(DenyAll);
+    let handler =
+      MultiBackendDbHandler::remote_or_sqlite::<DenyAll>(None, None);
+
+    let backend = |path: &str| {
+      let state = state.clone();
+      let handler = &handler;
+      let path = path.to_string();
+      async move {
+        match handler.open(state, Some(path)).await {
+          Ok(_) => panic!("permissions should have been checked"),
+          Err(err) => err.to_string(),
+        }
+      }
+    };
+    assert_eq!(backend("https://kv.example.com").await, "remote");
+    assert_eq!(backend("http://localhost:4545/kv").await, "remote");
+    assert_eq!(backend("HTTPS://kv.example.com").await, "remote");
+    assert_eq!(backend("./my.db").await, "sqlite");
+    assert_eq!(backend("/tmp/https:/my.db").await, "sqlite");
+    assert_eq!(backend("ftp://example.com/my.db").await, "sqlite");
+
+    // Without a path, the default local database is opened.
+    let db = handler.open(state.clone(), None).await.unwrap();
+    crate::Database::close(&db);
+  }
+
+  #[tokio::test]
+  async fn multi_backend_queue_message_id() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    let handler =
+      MultiBackendDbHandler::remote_or_sqlite::<DenyAll>(None, None);
+    let db = handler.open(state.clone(), None).await.unwrap();
+    let enqueue = Enqueue {
+      payload: b"msg".to_vec(),
+      delay_ms: 0,
+      enqueue_at_ms: None,
+      group: None,
+      keys_if_undelivered: vec![],
+      backoff_schedule: None,
+    };
+    db.enqueue(state.clone(), vec![enqueue]).await.unwrap();
+
+    let handle = db.dequeue_next_message(state.clone()).await.unwrap();
+    let handle = handle.expect("message should be delivered");
+    assert!(handle.id().is_some());
+    handle.finish(true).await.unwrap();
+    db.close();
+  }
+
+  /// Forwards to an in-memory SQLite database, counting the reads.
+  struct CountingDb {
+    db: SqliteDb,
+    reads: Rc<Cell<usize>>,
+  }
+
+  #[async_trait(?Send)]
+  impl Database for CountingDb {
+    type QMH = <SqliteDb as Database>::QMH;
+
+    async fn snapshot_read(
+      &self,
+      state: Rc<RefCell<OpState>>,
+      requests: Vec<ReadRange>,
+      options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, AnyError> {
+      self.reads.set(self.reads.get() + 1);
+      self.db.snapshot_read(state, requests, options).await
+    }
+
+    async fn atomic_write(
+      &self,
+      state: Rc<RefCell<OpState>>,
+      write: AtomicWrite,
+    ) -> Result<CommitOutcome, AnyError> {
+      self.db.atomic_write(state, write).await
+    }
+
+    async fn dequeue_next_message(
+      &self,
+      state: Rc<RefCell<OpState>>,
+    ) -> Result<Option<Self::QMH>, AnyError> {
+      self.db.dequeue_next_message(state).await
+    }
+
+    fn close(&self) {
+      self.db.close()
+    }
+  }
+
+  async fn open_caching_db(
+    state: &Rc<RefCell<OpState>>,
+    ttl: Duration,
+  ) -> (CachingDb<CountingDb, SqliteDb>, Rc<Cell<usize>>) {
+    let handler = SqliteDbHandler::<DenyAll>::new(None);
+    let reads = Rc::new(Cell::new(0));
+    let remote = CountingDb {
+      db: handler.open(state.clone(), None).await.unwrap(),
+      reads: reads.clone(),
+    };
+    let local = handler.open(state.clone(), None).await.unwrap();
+    (CachingDb::new(remote, local, ttl), reads)
+  }
+
+  async fn set(
+    db: &impl Database,
+    state: &Rc<RefCell<OpState>>,
+    key: &[u8],
+    value: &[u8],
+  ) -> [u8; 10] {
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations: vec![KvMutation {
+        key: key.to_vec(),
+        kind: MutationKind::Set(Value::Bytes(value.to_vec())),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+      return_old: false,
+      dry_run: false,
+    };
+    db.atomic_write(state.clone(), write)
+      .await
+      .unwrap()
+      .into_committed()
+      .unwrap()
+      .versionstamp
+  }
+
+  async fn get(
+    db: &impl Database,
+    state: &Rc<RefCell<OpState>>,
+    key: &[u8],
+    consistency: Consistency,
+  ) -> Option<(Vec<u8>, [u8; 10])> {
+    let output = db
+      .snapshot_read(
+        state.clone(),
+        vec![super::single_key_range(key)],
+        SnapshotReadOptions { consistency },
+      )
+      .await
+      .unwrap();
+    let entry = output.into_iter().next().unwrap().entries.pop()?;
+    match entry.value {
+      Value::Bytes(value) => Some((value, entry.versionstamp)),
+      _ => panic!("unexpected value"),
+    }
+  }
+
+  #[tokio::test]
+  async fn caching_db_warm_cache_skips_remote() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    let (db, reads) = open_caching_db(&state, Duration::from_secs(60)).await;
+    let key = encode_key(&Key(vec![KeyPart::String("a".to_string())])).unwrap();
+
+    let versionstamp = set(&db, &state, &key, b"1").await;
+    let expected = Some((b"1".to_vec(), versionstamp));
+
+    assert_eq!(
+      get(&db, &state, &key, Consistency::Eventual).await,
+      expected
+    );
+    assert_eq!(reads.get(), 1);
+    // The second read is served by the cache, with the remote versionstamp.
+    assert_eq!(
+      get(&db, &state, &key, Consistency::Eventual).await,
+      expected
+    );
+    assert_eq!(reads.get(), 1);
+    // Strong reads always go to the remote database.
+    assert_eq!(get(&db, &state, &key, Consistency::Strong).await, expected);
+    assert_eq!(reads.get(), 2);
+
+    // Missing keys are not cached.
+    let missing =
+      encode_key(&Key(vec![KeyPart::String("b".to_string())])).unwrap();
+    assert_eq!(
+      get(&db, &state, &missing, Consistency::Eventual).await,
+      None
+    );
+    assert_eq!(
+      get(&db, &state, &missing, Consistency::Eventual).await,
+      None
+    );
+    assert_eq!(reads.get(), 4);
+  }
+
+  #[tokio::test]
+  async fn caching_db_write_invalidates_cached_entry() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    let (db, reads) = open_caching_db(&state, Duration::from_secs(60)).await;
+    let key = encode_key(&Key(vec![KeyPart::String("a".to_string())])).unwrap();
+
+    set(&db, &state, &key, b"1").await;
+    get(&db, &state, &key, Consistency::Eventual).await;
+    assert_eq!(reads.get(), 1);
+
+    let versionstamp = set(&db, &state, &key, b"2").await;
+    assert_eq!(
+      get(&db, &state, &key, Consistency::Eventual).await,
+      Some((b"2".to_vec(), versionstamp))
+    );
+    assert_eq!(reads.get(), 2);
+  }
+
+  #[tokio::test]
+  async fn caching_db_entries_expire_after_ttl() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    let clock = Arc::new(FixedClock::new(1_000));
+    state.borrow_mut().put(KvClock(clock.clone()));
+    let (db, reads) = open_caching_db(&state, Duration::from_secs(60)).await;
+    let key = encode_key(&Key(vec![KeyPart::String("a".to_string())])).unwrap();
+
+    set(&db, &state, &key, b"1").await;
+    get(&db, &state, &key, Consistency::Eventual).await;
+    get(&db, &state, &key, Consistency::Eventual).await;
+    assert_eq!(reads.get(), 1);
+
+    clock.advance(60_000);
+    get(&db, &state, &key, Consistency::Eventual).await;
+    assert_eq!(reads.get(), 2);
+  }
+
+  #[tokio::test]
+  async fn caching_db_skips_entries_older_than_a_write() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    let (db, reads) = open_caching_db(&state, Duration::from_secs(60)).await;
+    let key = encode_key(&Key(vec![KeyPart::String("a".to_string())])).unwrap();
+
+    let old_versionstamp = set(&db, &state, &key, b"1").await;
+    let versionstamp = set(&db, &state, &key, b"2").await;
+
+    // A lagging remote read returns the entry as it was before the write.
+    let stale = KvEntry {
+      key: key.clone(),
+      value: Value::Bytes(b"1".to_vec()),
+      versionstamp: old_versionstamp,
+      expire_at_ms: None,
+      commit_ms: None,
+    };
+    db.write_cached(state.clone(), &[&stale]).await.unwrap();
+    assert_eq!(
+      get(&db, &state, &key, Consistency::Eventual).await,
+      Some((b"2".to_vec(), versionstamp))
+    );
+    assert_eq!(reads.get(), 1);
+    assert_eq!(
+      get(&db, &state, &key, Consistency::Eventual).await,
+      Some((b"2".to_vec(), versionstamp))
+    );
+    assert_eq!(reads.get(), 1);
+  }
+
+  #[tokio::test]
+  async fn caching_db_write_survives_failed_eviction() {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    let (db, _) = open_caching_db(&state, Duration::from_secs(60)).await;
+    let key = encode_key(&Key(vec![KeyPart::String("a".to_string())])).unwrap();
+
+    db.local.close();
+    let versionstamp = set(&db, &state, &key, b"1").await;
+    assert_eq!(
+      get(&db.remote, &state, &key, Consistency::Strong).await,
+      Some((b"1".to_vec(), versionstamp))
+    );
+  }
 }
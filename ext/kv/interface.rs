@@ -4,13 +4,20 @@ use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::num::NonZeroU32;
 use std::rc::Rc;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
+use deno_core::error::type_error;
 use deno_core::error::AnyError;
 use deno_core::OpState;
 use num_bigint::BigInt;
 
 use crate::codec::canonicalize_f64;
+use crate::codec::encode_key;
 
 #[async_trait(?Send)]
 pub trait DatabaseHandler {
@@ -21,6 +28,20 @@ pub trait DatabaseHandler {
     state: Rc<RefCell<OpState>>,
     path: Option<String>,
   ) -> Result<Self::DB, AnyError>;
+
+  /// Returns a key that identifies the database `open` would open for
+  /// `path`, if that database may be shared by everything that opens it.
+  /// Opening a path whose key belongs to a database that is still open then
+  /// reuses that database instead of opening it again. Implementations must
+  /// perform the same permission checks as `open`. By default databases are
+  /// not shared.
+  fn shared_key(
+    &self,
+    _state: &mut OpState,
+    _path: Option<String>,
+  ) -> Result<Option<String>, AnyError> {
+    Ok(None)
+  }
 }
 
 #[async_trait(?Send)]
@@ -34,17 +55,256 @@ pub trait Database {
     options: SnapshotReadOptions,
   ) -> Result<Vec<ReadRangeOutput>, AnyError>;
 
+  /// Reads the next batch of a range that is streamed in batches instead of
+  /// being read at once, where `range.limit` is the batch size. The range is
+  /// advanced past the returned entries, and an empty batch means that it is
+  /// exhausted.
+  async fn snapshot_read_stream(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    range: &mut ReadRange,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<KvEntry>, AnyError> {
+    let request = ReadRange {
+      start: range.start.clone(),
+      end: range.end.clone(),
+      limit: range.limit,
+      reverse: range.reverse,
+      max_bytes: None,
+    };
+    let entries = self
+      .snapshot_read(state, vec![request], options)
+      .await?
+      .pop()
+      .map(|output| output.entries)
+      .unwrap_or_default();
+    range.advance(&entries);
+    Ok(entries)
+  }
+
+  /// Counts the live keys in the range from `start` (inclusive) to `end`
+  /// (exclusive). The default implementation reads the range in batches,
+  /// databases that can count without reading values should override it.
+  async fn count_range(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    options: SnapshotReadOptions,
+  ) -> Result<u64, AnyError> {
+    let mut range = ReadRange {
+      start,
+      end,
+      limit: NonZeroU32::new(1000).unwrap(),
+      reverse: false,
+      max_bytes: None,
+    };
+    let mut count = 0;
+    loop {
+      let entries = self
+        .snapshot_read_stream(
+          state.clone(),
+          &mut range,
+          SnapshotReadOptions {
+            consistency: options.consistency,
+          },
+        )
+        .await?;
+      if entries.is_empty() {
+        return Ok(count);
+      }
+      count += entries.len() as u64;
+    }
+  }
+
+  /// Reads the live entries in any of the `(start, end)` ranges, in key
+  /// order and each only once, up to `limit` entries. The default
+  /// implementation reads every range up to `limit` in one snapshot read,
+  /// databases that can stop once `limit` is reached should override it.
+  async fn read_union(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    limit: NonZeroU32,
+    options: SnapshotReadOptions,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    let requests = coalesce_ranges(ranges)
+      .into_iter()
+      .map(|(start, end)| ReadRange {
+        start,
+        end,
+        limit,
+        reverse: false,
+        max_bytes: None,
+      })
+      .collect();
+    let outputs = self.snapshot_read(state, requests, options).await?;
+    let limit = limit.get() as usize;
+    let mut entries = Vec::new();
+    for mut output in outputs {
+      let room = limit - entries.len();
+      if output.entries.len() > room || output.has_more {
+        output.entries.truncate(room);
+        entries.extend(output.entries);
+        return Ok(ReadRangeOutput {
+          entries,
+          has_more: true,
+        });
+      }
+      entries.extend(output.entries);
+    }
+    Ok(ReadRangeOutput {
+      entries,
+      has_more: false,
+    })
+  }
+
   async fn atomic_write(
     &self,
     state: Rc<RefCell<OpState>>,
     write: AtomicWrite,
-  ) -> Result<Option<CommitResult>, AnyError>;
+  ) -> Result<CommitOutcome, AnyError>;
+
+  /// Adds messages to the queue without writing to the keyspace. Returns the
+  /// versionstamp of the commit.
+  async fn enqueue(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    enqueues: Vec<Enqueue>,
+  ) -> Result<Versionstamp, AnyError> {
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations: vec![],
+      enqueues,
+      return_old: false,
+      dry_run: false,
+    };
+    match self.atomic_write(state, write).await? {
+      CommitOutcome::Committed(result) => Ok(result.versionstamp),
+      CommitOutcome::CheckFailed { .. } | CommitOutcome::DryRun(_) => {
+        Err(type_error("Failed to enqueue value"))
+      }
+    }
+  }
 
   async fn dequeue_next_message(
     &self,
     state: Rc<RefCell<OpState>>,
   ) -> Result<Option<Self::QMH>, AnyError>;
 
+  /// Writes all entries in the database to the file at `path` as
+  /// newline-delimited JSON. Returns the number of exported entries.
+  async fn export(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    _path: String,
+  ) -> Result<u64, AnyError> {
+    Err(type_error("Export is not supported by this database"))
+  }
+
+  /// Inserts all entries from a newline-delimited JSON file previously
+  /// written by [Database::export]. Fails if any of the keys already exist,
+  /// unless `overwrite` is set. Returns the number of imported entries.
+  async fn import(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    _path: String,
+    _overwrite: bool,
+  ) -> Result<u64, AnyError> {
+    Err(type_error("Import is not supported by this database"))
+  }
+
+  /// Lists up to `limit` queue messages that exhausted their backoff
+  /// schedule, oldest failures first.
+  async fn list_dead_letters(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    _limit: u32,
+  ) -> Result<Vec<DeadLetterMessage>, AnyError> {
+    Err(type_error(
+      "Dead letters are not supported by this database",
+    ))
+  }
+
+  /// Moves the dead letter with the given id back into the queue. Returns
+  /// `false` if there is no such dead letter.
+  async fn retry_dead_letter(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    _id: String,
+  ) -> Result<bool, AnyError> {
+    Err(type_error(
+      "Dead letters are not supported by this database",
+    ))
+  }
+
+  /// Returns the current depth of the queue.
+  async fn queue_stats(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+  ) -> Result<QueueStats, AnyError> {
+    Err(type_error("Queue stats are not supported by this database"))
+  }
+
+  /// Returns the number of keys and the size of the database.
+  async fn stats(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+  ) -> Result<DatabaseStats, AnyError> {
+    Err(type_error(
+      "Database stats are not supported by this database",
+    ))
+  }
+
+  /// Checks that the database can be reached, for example that the endpoint
+  /// and access token of a remote database are valid.
+  async fn ping(&self, _state: Rc<RefCell<OpState>>) -> Result<(), AnyError> {
+    Err(type_error("Ping is not supported by this database"))
+  }
+
+  /// Checkpoints the write-ahead log of the database, and optionally
+  /// compacts the database, to reclaim disk space.
+  async fn checkpoint(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    _mode: MaintenanceMode,
+  ) -> Result<CheckpointResult, AnyError> {
+    Err(type_error("Maintenance is not supported by this database"))
+  }
+
+  /// Validates the database file and the invariants of its contents. Returns
+  /// the problems that were found, which is empty for a healthy database.
+  async fn check_integrity(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+  ) -> Result<Vec<IntegrityProblem>, AnyError> {
+    Err(type_error(
+      "Integrity checks are not supported by this database",
+    ))
+  }
+
+  /// Reads the live entries whose index keys in the secondary index `name`
+  /// fall within the range, ordered by index key and then by key. The
+  /// `start` and `end` of the range are encoded index keys.
+  async fn read_index(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    _name: String,
+    _range: ReadRange,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    Err(type_error(
+      "Secondary indexes are not supported by this database",
+    ))
+  }
+
+  /// Closes the database once the queue messages that are being handled have
+  /// finished, or once `timeout` has passed. No new messages are dequeued in
+  /// the meantime. Returns whether every message finished in time.
+  async fn close_graceful(&self, _timeout: Duration) -> Result<bool, AnyError> {
+    self.close();
+    Ok(true)
+  }
+
   fn close(&self);
 }
 
@@ -52,6 +312,309 @@ pub trait Database {
 pub trait QueueMessageHandle {
   async fn take_payload(&mut self) -> Result<Vec<u8>, AnyError>;
   async fn finish(&self, success: bool) -> Result<(), AnyError>;
+
+  /// The delivery attempt of the message, starting at 1, if the database
+  /// keeps track of it.
+  fn attempt(&self) -> Option<u64> {
+    None
+  }
+
+  /// The time at which the message was enqueued, in milliseconds since the
+  /// epoch, if the database keeps track of it.
+  fn enqueued_at_ms(&self) -> Option<u64> {
+    None
+  }
+
+  /// The id of this delivery of the message, if the database supports
+  /// checking in an atomic write that it is still running, see
+  /// [CheckKind::QueueRunning]. A redelivery of the message gets a new id.
+  fn id(&self) -> Option<String> {
+    None
+  }
+
+  /// Pushes back the time at which the message is considered stuck and
+  /// redelivered to `timeout` from now, or to the visibility timeout of the
+  /// database from now if `None`. Longer timeouts are capped at the
+  /// visibility timeout, and the deadline is never moved earlier. Fails if
+  /// the message is no longer running because it was already redelivered.
+  async fn extend_deadline(
+    &self,
+    _timeout: Option<Duration>,
+  ) -> Result<(), AnyError> {
+    Err(type_error(
+      "Extending the deadline of queue messages is not supported by this database",
+    ))
+  }
+}
+
+/// A source of the current time, in milliseconds since the Unix epoch. Used
+/// for entry expiration and for queue message timestamps, so that embedders
+/// can control time in tests and simulations.
+pub trait Clock: Send + Sync {
+  fn now_ms(&self) -> u64;
+}
+
+/// The wall clock of the system. This is the default clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now_ms(&self) -> u64 {
+    SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap()
+      .as_millis() as u64
+  }
+}
+
+/// A clock that only moves when told to.
+pub struct FixedClock(AtomicU64);
+
+impl FixedClock {
+  pub fn new(now_ms: u64) -> Self {
+    Self(AtomicU64::new(now_ms))
+  }
+
+  pub fn set(&self, now_ms: u64) {
+    self.0.store(now_ms, std::sync::atomic::Ordering::SeqCst);
+  }
+
+  pub fn advance(&self, ms: u64) {
+    self.0.fetch_add(ms, std::sync::atomic::Ordering::SeqCst);
+  }
+}
+
+impl Clock for FixedClock {
+  fn now_ms(&self) -> u64 {
+    self.0.load(std::sync::atomic::Ordering::SeqCst)
+  }
+}
+
+/// The clock used by the KV ops and databases of a runtime. Put one in the
+/// `OpState` to replace the [SystemClock]. Databases read it when they are
+/// opened.
+#[derive(Clone)]
+pub struct KvClock(pub Arc<dyn Clock>);
+
+/// Shared by every runtime without a [KvClock], so that databases of
+/// different runtimes can tell that they use the same clock.
+static SYSTEM_CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+
+impl KvClock {
+  pub fn from_state(state: &OpState) -> Arc<dyn Clock> {
+    match state.try_borrow::<KvClock>() {
+      Some(clock) => clock.0.clone(),
+      None => SYSTEM_CLOCK.get_or_init(|| Arc::new(SystemClock)).clone(),
+    }
+  }
+}
+
+/// A hook for observing KV operations. Every method has a no-op default, so
+/// implementations only override what they care about.
+pub trait KvMetrics: Send + Sync {
+  /// A snapshot read or list batch completed, successfully or not.
+  fn record_read(&self, _duration: Duration) {}
+  /// An atomic write was committed.
+  fn record_write(&self, _duration: Duration) {}
+  /// An atomic write was rejected because one of its checks failed.
+  fn record_commit_conflict(&self, _duration: Duration) {}
+  /// A queue message was handed to a listener. The duration is how long the
+  /// message waited after it became ready.
+  fn record_queue_dispatch(&self, _duration: Duration) {}
+}
+
+/// Metrics that are discarded. This is the default.
+pub struct NoopKvMetrics;
+
+impl KvMetrics for NoopKvMetrics {}
+
+/// Shared by every runtime without a [KvMetricsHook], so that each operation
+/// doesn't allocate its own.
+static NOOP_KV_METRICS: OnceLock<Arc<dyn KvMetrics>> = OnceLock::new();
+
+/// Metrics that are counted in memory, for tests.
+#[derive(Default)]
+pub struct InMemoryKvMetrics {
+  pub reads: AtomicU64,
+  pub writes: AtomicU64,
+  pub commit_conflicts: AtomicU64,
+  pub queue_dispatches: AtomicU64,
+  /// The sum of all recorded durations, in microseconds.
+  pub total_micros: AtomicU64,
+}
+
+impl InMemoryKvMetrics {
+  fn record(&self, counter: &AtomicU64, duration: Duration) {
+    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    self.total_micros.fetch_add(
+      duration.as_micros() as u64,
+      std::sync::atomic::Ordering::SeqCst,
+    );
+  }
+}
+
+impl KvMetrics for InMemoryKvMetrics {
+  fn record_read(&self, duration: Duration) {
+    self.record(&self.reads, duration);
+  }
+
+  fn record_write(&self, duration: Duration) {
+    self.record(&self.writes, duration);
+  }
+
+  fn record_commit_conflict(&self, duration: Duration) {
+    self.record(&self.commit_conflicts, duration);
+  }
+
+  fn record_queue_dispatch(&self, duration: Duration) {
+    self.record(&self.queue_dispatches, duration);
+  }
+}
+
+/// The metrics hook of a runtime. Put one in the `OpState` to replace the
+/// [NoopKvMetrics]. Databases read it when they are opened.
+#[derive(Clone)]
+pub struct KvMetricsHook(pub Arc<dyn KvMetrics>);
+
+impl KvMetricsHook {
+  pub fn from_state(state: &OpState) -> Arc<dyn KvMetrics> {
+    match state.try_borrow::<KvMetricsHook>() {
+      Some(hook) => hook.0.clone(),
+      None => NOOP_KV_METRICS
+        .get_or_init(|| Arc::new(NoopKvMetrics))
+        .clone(),
+    }
+  }
+}
+
+/// A queue message that was not delivered successfully before its backoff
+/// schedule ran out.
+pub struct DeadLetterMessage {
+  pub id: String,
+  pub payload: Vec<u8>,
+  /// The time at which the message was originally enqueued, in milliseconds
+  /// since the epoch.
+  pub enqueued_at_ms: u64,
+  /// The time of the last failed delivery, in milliseconds since the epoch.
+  pub failed_at_ms: u64,
+  /// The number of failed deliveries.
+  pub failure_count: u64,
+}
+
+/// A snapshot of the depth of a queue.
+pub struct QueueStats {
+  /// The number of messages waiting to be delivered, including delayed ones.
+  pub ready: u64,
+  /// The number of messages currently being handled.
+  pub running: u64,
+  /// The time at which the earliest waiting message becomes ready, in
+  /// milliseconds since the epoch.
+  pub earliest_ready_ms: Option<u64>,
+}
+
+/// A snapshot of the size of a database.
+pub struct DatabaseStats {
+  /// The number of keys, including expired keys that haven't been swept yet.
+  pub key_count: u64,
+  /// The total size of the values of all keys, in bytes.
+  pub total_value_bytes: u64,
+  /// The size of the database file, in bytes, excluding its write-ahead log.
+  pub db_file_bytes: u64,
+}
+
+/// The kind of maintenance to perform on a database.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum MaintenanceMode {
+  /// Checkpoint as much of the write-ahead log as possible without waiting
+  /// for readers or writers.
+  Passive,
+  /// Checkpoint the whole write-ahead log and truncate it to zero bytes.
+  Truncate,
+  /// Rebuild the database file to release unused pages, then checkpoint and
+  /// truncate the write-ahead log.
+  Vacuum,
+}
+
+/// The outcome of a write-ahead log checkpoint.
+pub struct CheckpointResult {
+  /// Whether the checkpoint could not complete because of concurrent readers
+  /// or writers.
+  pub busy: bool,
+  /// The number of frames in the write-ahead log, or -1 if the database does
+  /// not use one.
+  pub log_frames: i64,
+  /// The number of frames that were moved back into the database file, or -1
+  /// if the database does not use a write-ahead log.
+  pub checkpointed_frames: i64,
+}
+
+/// The kind of problem found by [Database::check_integrity].
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum IntegrityProblemKind {
+  /// The storage engine reported that the database file is corrupt.
+  Corruption,
+  /// Entries have a version newer than the version of the database.
+  VersionAhead,
+  /// Queue messages have been running for suspiciously long.
+  StaleRunningMessage,
+  /// Entries have an invalid expiration time.
+  InvalidExpiration,
+}
+
+/// A problem found by [Database::check_integrity].
+pub struct IntegrityProblem {
+  pub kind: IntegrityProblemKind,
+  pub message: String,
+}
+
+/// Computes the index key of an entry from its key and value, or `None` if
+/// the entry should not be indexed.
+pub type KvIndexExtractor =
+  Arc<dyn Fn(&Key, &Value) -> Option<Key> + Send + Sync>;
+
+/// A secondary index over the entries under a key prefix. The database keeps
+/// the index up to date in the same transaction as the writes to those
+/// entries, so a read of the index never sees a value that a read of the
+/// entry itself would not.
+#[derive(Clone)]
+pub struct KvIndex {
+  pub name: String,
+  pub prefix: Key,
+  pub extract: KvIndexExtractor,
+  /// Identifies the extractor. Databases only rebuild an index when its name,
+  /// prefix or version changes, so the version must be bumped whenever the
+  /// extractor starts computing different index keys.
+  pub version: u32,
+}
+
+impl KvIndex {
+  pub fn new(
+    name: impl Into<String>,
+    prefix: Key,
+    extract: impl Fn(&Key, &Value) -> Option<Key> + Send + Sync + 'static,
+  ) -> Self {
+    Self {
+      name: name.into(),
+      prefix,
+      extract: Arc::new(extract),
+      version: 0,
+    }
+  }
+
+  pub fn with_version(mut self, version: u32) -> Self {
+    self.version = version;
+    self
+  }
+
+  /// Indexes the entries under `prefix` by their value. Only `Deno.KvU64`
+  /// and `Uint8Array` values can be compared without deserializing them, so
+  /// entries with other values are not indexed.
+  pub fn by_value(name: impl Into<String>, prefix: Key) -> Self {
+    Self::new(name, prefix, |_, value| match value {
+      Value::U64(n) => Some(Key(vec![KeyPart::Int(BigInt::from(*n))])),
+      Value::Bytes(bytes) => Some(Key(vec![KeyPart::Bytes(bytes.clone())])),
+      Value::V8(_) | Value::Json(_) => None,
+    })
+  }
 }
 
 /// Options for a snapshot read.
@@ -153,17 +716,232 @@ impl PartialOrd for KeyPart {
 /// The range is inclusive of the start and exclusive of the end. The start may
 /// not be greater than the end.
 ///
-/// The range is limited to `limit` number of entries.
+/// The range is limited to `limit` number of entries. If `max_bytes` is set,
+/// the read also stops after the first entry that brings the total size of
+/// the values read to at least `max_bytes`. Either way, a read that stops
+/// before the end of the range marks the output with `has_more` so that it
+/// can be resumed.
 pub struct ReadRange {
   pub start: Vec<u8>,
   pub end: Vec<u8>,
   pub limit: NonZeroU32,
   pub reverse: bool,
+  pub max_bytes: Option<NonZeroU32>,
+}
+
+impl ReadRange {
+  /// Narrows the range to the keys after `entries`, which must be the result
+  /// of reading this range. A short result exhausts the range.
+  pub fn advance(&mut self, entries: &[KvEntry]) {
+    match entries.last() {
+      Some(last) if entries.len() == self.limit.get() as usize => {
+        if self.reverse {
+          self.end = last.key.clone();
+        } else {
+          self.start = last.key.iter().copied().chain(Some(0)).collect();
+        }
+      }
+      _ => self.start = self.end.clone(),
+    }
+  }
+}
+
+/// A builder for a [ReadRange] over typed keys, for calling
+/// [Database::snapshot_read] from Rust.
+///
+/// Select all keys under a prefix, ten at a time:
+///
+/// ```
+/// # use std::num::NonZeroU32;
+/// # use deno_kv::{Key, KeyPart, KeyRange};
+/// let users = Key(vec![KeyPart::String("users".to_string())]);
+/// let range = KeyRange::prefix(&users)
+///   .unwrap()
+///   .limit(NonZeroU32::new(10).unwrap())
+///   .into_read_range();
+/// assert_eq!(range.start, b"\x02users\x00\x00");
+/// assert_eq!(range.end, b"\x02users\x00\xff");
+/// assert_eq!(range.limit.get(), 10);
+/// ```
+///
+/// Select the keys from `start` (inclusive) to `end` (exclusive), in reverse
+/// order:
+///
+/// ```
+/// # use deno_kv::{Key, KeyPart, KeyRange};
+/// let start = Key(vec![KeyPart::String("a".to_string())]);
+/// let end = Key(vec![KeyPart::String("c".to_string())]);
+/// let range = KeyRange::range(&start, &end)
+///   .unwrap()
+///   .reverse()
+///   .into_read_range();
+/// assert_eq!(range.start, b"\x02a\x00");
+/// assert_eq!(range.end, b"\x02c\x00");
+/// assert!(range.reverse);
+/// ```
+///
+/// Select a single key:
+///
+/// ```
+/// # use deno_kv::{Key, KeyPart, KeyRange};
+/// let key = Key(vec![KeyPart::String("a".to_string()), KeyPart::True]);
+/// let range = KeyRange::key(&key).unwrap().into_read_range();
+/// assert_eq!(range.start, b"\x02a\x00\x27");
+/// assert_eq!(range.end, b"\x02a\x00\x27\x00");
+/// ```
+///
+/// To read the next page of a range, pass the key of the last entry that was
+/// read to [KeyRange::after].
+#[derive(Clone, Debug)]
+pub struct KeyRange {
+  start: Vec<u8>,
+  end: Vec<u8>,
+  after: Option<Vec<u8>>,
+  limit: NonZeroU32,
+  reverse: bool,
+}
+
+impl KeyRange {
+  /// The limit of a range that doesn't set one explicitly.
+  pub const DEFAULT_LIMIT: u32 = 100;
+
+  /// Selects all keys that start with `prefix`, excluding `prefix` itself.
+  pub fn prefix(prefix: &Key) -> std::io::Result<Self> {
+    let prefix = encode_key(prefix)?;
+    Ok(Self::from_encoded(
+      prefix.iter().copied().chain(Some(0)).collect(),
+      prefix.into_iter().chain(Some(0xff)).collect(),
+    ))
+  }
+
+  /// Selects the keys from `start` (inclusive) to `end` (exclusive).
+  pub fn range(start: &Key, end: &Key) -> std::io::Result<Self> {
+    Ok(Self::from_encoded(encode_key(start)?, encode_key(end)?))
+  }
+
+  /// Selects only `key`.
+  pub fn key(key: &Key) -> std::io::Result<Self> {
+    let key = encode_key(key)?;
+    Ok(Self::from_encoded(
+      key.clone(),
+      key.into_iter().chain(Some(0)).collect(),
+    ))
+  }
+
+  fn from_encoded(start: Vec<u8>, end: Vec<u8>) -> Self {
+    Self {
+      start,
+      end,
+      after: None,
+      limit: NonZeroU32::new(Self::DEFAULT_LIMIT).unwrap(),
+      reverse: false,
+    }
+  }
+
+  /// Resumes the range after the encoded key of the last entry that was
+  /// read, in the direction of the range.
+  pub fn after(mut self, key: Vec<u8>) -> Self {
+    self.after = Some(key);
+    self
+  }
+
+  /// Sets the maximum number of entries to read.
+  pub fn limit(mut self, limit: NonZeroU32) -> Self {
+    self.limit = limit;
+    self
+  }
+
+  /// Reads the range in descending key order.
+  pub fn reverse(mut self) -> Self {
+    self.reverse = true;
+    self
+  }
+
+  pub fn into_read_range(self) -> ReadRange {
+    let mut start = self.start;
+    let mut end = self.end;
+    if let Some(after) = self.after {
+      if self.reverse {
+        end = end.min(after);
+      } else {
+        start = start.max(after.into_iter().chain(Some(0)).collect());
+      }
+      if start > end {
+        end = start.clone();
+      }
+    }
+    ReadRange {
+      start,
+      end,
+      limit: self.limit,
+      reverse: self.reverse,
+      max_bytes: None,
+    }
+  }
+}
+
+impl From<KeyRange> for ReadRange {
+  fn from(range: KeyRange) -> Self {
+    range.into_read_range()
+  }
 }
 
 /// A response to a `ReadRange` request.
 pub struct ReadRangeOutput {
   pub entries: Vec<KvEntry>,
+  /// Whether the read was cut short by `limit` or `max_bytes` rather than by
+  /// reaching the end of the range, in which case there are (likely) more
+  /// entries to read after the last returned one.
+  pub has_more: bool,
+}
+
+impl ReadRangeOutput {
+  /// Builds the output of a read that should have returned at most `limit`
+  /// entries, truncating them according to `limit` and `max_bytes`. Used by
+  /// backends that can't stop a read early or look past `limit`, so a full
+  /// result is assumed to have more entries.
+  pub fn from_limited(
+    mut entries: Vec<KvEntry>,
+    limit: NonZeroU32,
+    max_bytes: Option<NonZeroU32>,
+  ) -> Self {
+    let mut has_more = entries.len() >= limit.get() as usize;
+    entries.truncate(limit.get() as usize);
+    if let Some(max_bytes) = max_bytes {
+      let mut total_bytes = 0usize;
+      for (i, entry) in entries.iter().enumerate() {
+        total_bytes += entry.value.byte_size();
+        if total_bytes >= max_bytes.get() as usize {
+          has_more |= i + 1 < entries.len();
+          entries.truncate(i + 1);
+          break;
+        }
+      }
+    }
+    Self { entries, has_more }
+  }
+}
+
+/// Sorts `(start, end)` key ranges and merges the ones that overlap or touch,
+/// so that every key is in at most one of the returned ranges. Empty ranges
+/// are dropped.
+pub fn coalesce_ranges(
+  mut ranges: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+  ranges.retain(|(start, end)| start < end);
+  ranges.sort();
+  let mut coalesced: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(ranges.len());
+  for (start, end) in ranges {
+    match coalesced.last_mut() {
+      Some((_, last_end)) if start <= *last_end => {
+        if end > *last_end {
+          *last_end = end;
+        }
+      }
+      _ => coalesced.push((start, end)),
+    }
+  }
+  coalesced
 }
 
 /// A versionstamp is a 10 byte array that is used to represent the version of
@@ -175,6 +953,12 @@ pub struct KvEntry {
   pub key: Vec<u8>,
   pub value: Value,
   pub versionstamp: Versionstamp,
+  /// The time at which the entry expires, in milliseconds since the Unix
+  /// epoch, or `None` if the entry was written without an expiration.
+  pub expire_at_ms: Option<u64>,
+  /// The time of the commit that last wrote the entry, in milliseconds since
+  /// the Unix epoch, or `None` if the database doesn't record it.
+  pub commit_ms: Option<u64>,
 }
 
 /// A serialized value for a KV pair as stored in the database. All values
@@ -196,10 +980,26 @@ pub struct KvEntry {
 ///
 /// - **Bytes**: an arbitrary byte array.
 /// - **U64**: a 64-bit unsigned integer.
+///
+/// Values can also be stored as canonical JSON text, with object keys sorted
+/// and no insignificant whitespace, so that tools other than Deno can read
+/// them. Only values that JSON can represent survive the round trip.
 pub enum Value {
   V8(Vec<u8>),
   Bytes(Vec<u8>),
   U64(u64),
+  Json(String),
+}
+
+impl Value {
+  /// The size of the value in bytes, as counted against read limits.
+  pub fn byte_size(&self) -> usize {
+    match self {
+      Value::V8(x) | Value::Bytes(x) => x.len(),
+      Value::U64(_) => 8,
+      Value::Json(x) => x.len(),
+    }
+  }
 }
 
 /// A request to perform an atomic check-modify-write operation on the database.
@@ -220,18 +1020,57 @@ pub enum Value {
 /// The mutations are performed in the order that they are specified in the
 /// `mutations` field. The order of checks is not specified, and is also not
 /// important because this ordering is un-observable.
+///
+/// If `return_old` is set, the entries overwritten or deleted by `Set` and
+/// `Delete` mutations are returned in [CommitResult::old_values].
+///
+/// If `dry_run` is set, the checks and mutations are evaluated as usual but
+/// the write is rolled back instead of committed, so the database is left
+/// unchanged and no messages are enqueued. The outcome is
+/// [CommitOutcome::DryRun] if the checks passed.
 pub struct AtomicWrite {
   pub checks: Vec<KvCheck>,
   pub mutations: Vec<KvMutation>,
   pub enqueues: Vec<Enqueue>,
+  pub return_old: bool,
+  pub dry_run: bool,
 }
 
-/// A request to perform a check on a key in the database. The check is not
-/// performed on the value of the key, but rather on the versionstamp of the
-/// key.
+/// A request to perform a check on a key in the database. What is checked is
+/// specified by the `kind` field, see [CheckKind].
 pub struct KvCheck {
   pub key: Vec<u8>,
-  pub versionstamp: Option<Versionstamp>,
+  pub kind: CheckKind,
+}
+
+/// The kind of a check performed on a key.
+///
+/// ## Versionstamp
+///
+/// The versionstamp check passes if the key has the given versionstamp, or if
+/// the versionstamp is `None` and the key does not exist. Checks are usually
+/// on versionstamps, as the versionstamp of a key changes whenever it is
+/// written.
+///
+/// ## Value
+///
+/// The value check passes if the key currently has a value with the same
+/// representation and bytes as the given value, or if the value is `None` and
+/// the key does not exist. A key that does not exist never equals a value.
+/// This allows asserting the value of a key without reading it first to learn
+/// its versionstamp.
+///
+/// ## Queue running
+///
+/// The queue running check passes if the delivery of a queue message with the
+/// given id, from [QueueMessageHandle::id], is still running, so that a
+/// handler can commit the results of its work only while it still owns the
+/// message. It fails once the message has been redelivered. The key of the
+/// check is ignored.
+pub enum CheckKind {
+  Versionstamp(Option<Versionstamp>),
+  Value(Option<Value>),
+  QueueRunning(String),
 }
 
 /// A request to perform a mutation on a key in the database. The mutation is
@@ -261,6 +1100,12 @@ pub struct KvMutation {
 pub struct Enqueue {
   pub payload: Vec<u8>,
   pub delay_ms: u64,
+  /// The time at which the message should be delivered, in milliseconds
+  /// since the epoch. Takes precedence over `delay_ms` when set.
+  pub enqueue_at_ms: Option<u64>,
+  /// The group of the message, for databases that dispatch messages fairly
+  /// across groups.
+  pub group: Option<String>,
   pub keys_if_undelivered: Vec<Vec<u8>>,
   pub backoff_schedule: Option<Vec<u32>>,
 }
@@ -274,6 +1119,16 @@ pub struct Enqueue {
 ///
 /// This operand supports all [Value] types.
 ///
+/// ## SetIfAbsent
+///
+/// The set if absent mutation sets the value of the key like a set mutation,
+/// but only if the key does not exist in the database when the atomic write
+/// starts. If the key exists, the whole atomic write fails as if a check had
+/// failed. This is equivalent to a set mutation combined with a check that
+/// the key has no versionstamp.
+///
+/// This operand supports all [Value] types.
+///
 /// ## Delete
 ///
 /// The delete mutation deletes the value of the key.
@@ -306,28 +1161,116 @@ pub struct Enqueue {
 /// the database must match the type of the value specified in the mutation. If
 /// the key does not exist in the database, then the value specified in the
 /// mutation is used as the new value of the key.
+///
+/// ## DeletePrefix
+///
+/// The delete prefix mutation deletes all keys that start with the key of the
+/// mutation. The key of the mutation itself is not deleted. Backends may bound
+/// the number of keys that can be deleted by a single prefix delete.
+///
+/// ## Move
+///
+/// The move mutation moves the value of the key to the key `to`, keeping its
+/// expiration, and deletes the key. The moved entry gets the versionstamp of
+/// the write. If the key does not exist, or if `to` exists and `overwrite` is
+/// not set, the whole atomic write fails as if a check had failed. Unlike
+/// checks, these conditions are evaluated against the state left by the
+/// preceding mutations of the write.
 pub enum MutationKind {
   Set(Value),
+  SetIfAbsent(Value),
   Delete,
   Sum(Value),
   Min(Value),
   Max(Value),
+  DeletePrefix,
+  Move { to: Vec<u8>, overwrite: bool },
 }
 
 impl MutationKind {
   pub fn value(&self) -> Option<&Value> {
     match self {
       MutationKind::Set(value) => Some(value),
+      MutationKind::SetIfAbsent(value) => Some(value),
       MutationKind::Sum(value) => Some(value),
       MutationKind::Min(value) => Some(value),
       MutationKind::Max(value) => Some(value),
       MutationKind::Delete => None,
+      MutationKind::DeletePrefix => None,
+      MutationKind::Move { .. } => None,
     }
   }
 }
 
+/// The outcome of an atomic write operation.
+pub enum CommitOutcome {
+  /// All checks passed and the write was committed.
+  Committed(CommitResult),
+  /// A check failed, so nothing was written.
+  CheckFailed {
+    /// The index of the first check that failed, or `None` if the backend
+    /// doesn't report it. `SetIfAbsent` and `Move` mutations check that their
+    /// keys exist or don't exist, and count as checks following
+    /// [AtomicWrite::checks], in the order of the mutations.
+    failed_index: Option<usize>,
+  },
+  /// All checks passed, but the write was a dry run and was rolled back, see
+  /// [AtomicWrite::dry_run].
+  DryRun(DryRunResult),
+}
+
+impl CommitOutcome {
+  /// Returns the result of the commit, or `None` if a check failed or the
+  /// write was a dry run.
+  pub fn into_committed(self) -> Option<CommitResult> {
+    match self {
+      CommitOutcome::Committed(result) => Some(result),
+      CommitOutcome::CheckFailed { .. } | CommitOutcome::DryRun(_) => None,
+    }
+  }
+}
+
+/// The result of a dry run of an atomic write operation whose checks passed.
+/// Unlike [CommitResult], it has no versionstamp, as nothing was committed.
+pub struct DryRunResult {
+  /// See [CommitResult::old_values].
+  pub old_values: Vec<Option<KvEntry>>,
+  /// See [CommitResult::mutation_counts].
+  pub mutation_counts: Option<MutationCounts>,
+}
+
 /// The result of a successful commit of an atomic write operation.
 pub struct CommitResult {
   /// The new versionstamp of the data that was committed.
   pub versionstamp: Versionstamp,
+  /// The entries that existed before the write, one for each mutation in the
+  /// order they were specified. Only populated if [AtomicWrite::return_old]
+  /// is set; `None` for mutations other than `Set` and `Delete`, or if the key
+  /// did not exist.
+  pub old_values: Vec<Option<KvEntry>>,
+  /// What the mutations of the write did to the database, if the backend
+  /// keeps track of it.
+  pub mutation_counts: Option<MutationCounts>,
+}
+
+/// Counts of the effects of the mutations of a committed atomic write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MutationCounts {
+  /// The number of mutations that changed the database. Deletes of keys that
+  /// did not exist and prefix deletes that matched no keys are not counted.
+  pub mutations_applied: u64,
+  /// The number of keys that did not exist and were created by `Set`, `Sum`,
+  /// `Min` or `Max` mutations.
+  pub keys_created: u64,
+  /// The number of keys removed by `Delete` and `DeletePrefix` mutations.
+  pub keys_deleted: u64,
+}
+
+impl MutationCounts {
+  /// Adds the effects of one mutation to the counts.
+  pub fn record(&mut self, applied: u64, created: u64, deleted: u64) {
+    self.mutations_applied += applied;
+    self.keys_created += created;
+    self.keys_deleted += deleted;
+  }
 }
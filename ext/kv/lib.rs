@@ -8,13 +8,18 @@ pub mod remote;
 pub mod sqlite;
 
 use std::borrow::Cow;
+use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::num::NonZeroU32;
 use std::rc::Rc;
+use std::rc::Weak;
+use std::time::Duration;
+use std::time::Instant;
 
 use base64::prelude::BASE64_URL_SAFE;
 use base64::Engine;
-use chrono::Utc;
+use codec::canonicalize_f64;
 use codec::decode_key;
 use codec::encode_key;
 use deno_core::anyhow::Context;
@@ -24,9 +29,13 @@ use deno_core::error::AnyError;
 use deno_core::op2;
 use deno_core::serde_v8::AnyValue;
 use deno_core::serde_v8::BigInt;
+use deno_core::AsyncRefCell;
 use deno_core::ByteString;
+use deno_core::CancelFuture;
+use deno_core::CancelHandle;
 use deno_core::JsBuffer;
 use deno_core::OpState;
+use deno_core::RcRef;
 use deno_core::Resource;
 use deno_core::ResourceId;
 use deno_core::ToJsBuffer;
@@ -40,24 +49,70 @@ pub const UNSTABLE_FEATURE_NAME: &str = "kv";
 const MAX_WRITE_KEY_SIZE_BYTES: usize = 2048;
 // range selectors can contain 0x00 or 0xff suffixes
 const MAX_READ_KEY_SIZE_BYTES: usize = MAX_WRITE_KEY_SIZE_BYTES + 1;
+// decoding and comparing keys gets slow with many parts
+const MAX_KEY_PARTS: usize = 64;
 const MAX_VALUE_SIZE_BYTES: usize = 65536;
+// queue messages often carry more context than a single stored value
+const MAX_ENQUEUE_PAYLOAD_SIZE_BYTES: usize = 256 * 1024;
 const MAX_READ_RANGES: usize = 10;
 const MAX_READ_ENTRIES: usize = 1000;
 const MAX_CHECKS: usize = 10;
 const MAX_MUTATIONS: usize = 1000;
 const MAX_TOTAL_MUTATION_SIZE_BYTES: usize = 800 * 1024;
 const MAX_TOTAL_KEY_SIZE_BYTES: usize = 80 * 1024;
+const MAX_QUEUE_DELAY_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+const MAX_QUEUE_BACKOFF_INTERVALS: usize = 5;
+const MAX_QUEUE_UNDELIVERED_KEYS: usize = 10;
+const MAX_QUEUE_GROUP_SIZE_BYTES: usize = 256;
+const DEFAULT_MAX_OPEN_DATABASES: usize = 128;
+/// How many queue messages a database hands out for processing at once,
+/// shared by the backends.
+pub(crate) const DEFAULT_DISPATCH_CONCURRENCY_LIMIT: usize = 100;
+
+/// Relaxes the limits of atomic writes when put into the `OpState`, for
+/// trusted embedders that write large batches, e.g. during migrations. Only
+/// the caps on the total size of a write are enforced then, not those on
+/// individual keys, values and queue payloads. Remote databases enforce
+/// their own limits regardless.
+#[derive(Clone, Copy, Default)]
+pub struct KvWriteLimits {
+  pub skip_individual_limits: bool,
+}
+
+impl KvWriteLimits {
+  fn from_state(state: &OpState) -> Self {
+    state.try_borrow::<Self>().copied().unwrap_or_default()
+  }
+}
 
 deno_core::extension!(deno_kv,
   deps = [ deno_console ],
   parameters = [ DBH: DatabaseHandler ],
   ops = [
     op_kv_database_open<DBH>,
+    op_kv_database_close_graceful<DBH>,
     op_kv_snapshot_read<DBH>,
+    op_kv_list_stream<DBH>,
+    op_kv_list_stream_next<DBH>,
     op_kv_atomic_write<DBH>,
+    op_kv_replace_prefix<DBH>,
+    op_kv_enqueue<DBH>,
     op_kv_encode_cursor,
     op_kv_dequeue_next_message<DBH>,
     op_kv_finish_dequeued_message<DBH>,
+    op_kv_extend_dequeued_message_deadline<DBH>,
+    op_kv_export<DBH>,
+    op_kv_import<DBH>,
+    op_kv_list_dead_letter<DBH>,
+    op_kv_retry_dead_letter<DBH>,
+    op_kv_queue_stats<DBH>,
+    op_kv_stats<DBH>,
+    op_kv_ping<DBH>,
+    op_kv_maintenance<DBH>,
+    op_kv_check_integrity<DBH>,
+    op_kv_read_index<DBH>,
+    op_kv_count<DBH>,
+    op_kv_list_union<DBH>,
   ],
   esm = [ "01_db.ts" ],
   options = {
@@ -68,8 +123,87 @@ deno_core::extension!(deno_kv,
   }
 );
 
+/// Caps the number of databases an isolate can have open at once when put
+/// into the `OpState`, as each one holds file descriptors and background
+/// tasks. A database that is opened again while it is still open is shared
+/// and only counts once.
+#[derive(Clone, Copy)]
+pub struct KvOpenLimits {
+  pub max_open_databases: usize,
+}
+
+impl Default for KvOpenLimits {
+  fn default() -> Self {
+    Self {
+      max_open_databases: DEFAULT_MAX_OPEN_DATABASES,
+    }
+  }
+}
+
+impl KvOpenLimits {
+  fn from_state(state: &OpState) -> Self {
+    state.try_borrow::<Self>().copied().unwrap_or_default()
+  }
+}
+
+/// The databases of an isolate that are open, see
+/// [DatabaseHandler::shared_key].
+struct OpenDatabases<DB: Database + 'static> {
+  count: Rc<Cell<usize>>,
+  shared: HashMap<String, Weak<OpenDatabase<DB>>>,
+}
+
+impl<DB: Database + 'static> Default for OpenDatabases<DB> {
+  fn default() -> Self {
+    Self {
+      count: Rc::new(Cell::new(0)),
+      shared: HashMap::new(),
+    }
+  }
+}
+
+/// A database that is shared by the resources of every open of it.
+struct OpenDatabase<DB: Database + 'static> {
+  db: Rc<DB>,
+  resources: Cell<usize>,
+  /// The open database count of the isolate.
+  count: Rc<Cell<usize>>,
+}
+
+impl<DB: Database + 'static> OpenDatabase<DB> {
+  fn is_open(&self) -> bool {
+    self.resources.get() > 0
+  }
+
+  /// Releases the database for one of its resources, and returns whether it
+  /// was the last one, in which case the database has to be closed.
+  fn release(&self) -> bool {
+    let resources = self.resources.get() - 1;
+    self.resources.set(resources);
+    if resources == 0 {
+      self.count.set(self.count.get() - 1);
+    }
+    resources == 0
+  }
+}
+
 struct DatabaseResource<DB: Database + 'static> {
   db: Rc<DB>,
+  open: Rc<OpenDatabase<DB>>,
+  /// Cancels the pending operations of this resource, such as waiting for a
+  /// queue message, when it is closed, even if the database stays open for
+  /// other resources.
+  cancel_handle: Rc<CancelHandle>,
+}
+
+impl<DB: Database + 'static> DatabaseResource<DB> {
+  fn new(open: Rc<OpenDatabase<DB>>) -> Self {
+    Self {
+      db: open.db.clone(),
+      open,
+      cancel_handle: CancelHandle::new_rc(),
+    }
+  }
 }
 
 impl<DB: Database + 'static> Resource for DatabaseResource<DB> {
@@ -78,7 +212,10 @@ impl<DB: Database + 'static> Resource for DatabaseResource<DB> {
   }
 
   fn close(self: Rc<Self>) {
-    self.db.close();
+    self.cancel_handle.cancel();
+    if self.open.release() {
+      self.db.close();
+    }
   }
 }
 
@@ -100,22 +237,119 @@ where
       .check_or_exit_with_legacy_fallback(UNSTABLE_FEATURE_NAME, "Deno.openKv");
     state.borrow::<Rc<DBH>>().clone()
   };
-  let db = handler.open(state.clone(), path).await?;
-  let rid = state
+  open_database(state, &*handler, path).await
+}
+
+/// Opens a database and adds a resource for it, sharing the database with
+/// earlier opens of it that are still open.
+async fn open_database<DBH>(
+  state: Rc<RefCell<OpState>>,
+  handler: &DBH,
+  path: Option<String>,
+) -> Result<ResourceId, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let (shared_key, count) = {
+    let mut state = state.borrow_mut();
+    let shared_key = handler.shared_key(&mut state, path.clone())?;
+    if state.try_borrow::<OpenDatabases<DBH::DB>>().is_none() {
+      state.put(OpenDatabases::<DBH::DB>::default());
+    }
+    let open_databases = state.borrow::<OpenDatabases<DBH::DB>>();
+    let shared = shared_key
+      .as_ref()
+      .and_then(|key| open_databases.shared.get(key))
+      .and_then(Weak::upgrade)
+      .filter(|open| open.is_open());
+    let count = open_databases.count.clone();
+    if let Some(open) = shared {
+      open.resources.set(open.resources.get() + 1);
+      let rid = state.resource_table.add(DatabaseResource::new(open));
+      return Ok(rid);
+    }
+    let max = KvOpenLimits::from_state(&state).max_open_databases;
+    if count.get() >= max {
+      return Err(type_error(format!("too many open databases (max {max})")));
+    }
+    // Counted before opening, so that concurrent opens can't exceed the cap.
+    count.set(count.get() + 1);
+    (shared_key, count)
+  };
+
+  let db = match handler.open(state.clone(), path).await {
+    Ok(db) => db,
+    Err(err) => {
+      count.set(count.get() - 1);
+      return Err(err);
+    }
+  };
+
+  // Another open of the same database may have finished in the meantime, in
+  // which case both stay open, as this one can't be undone without closing
+  // it.
+  let open = Rc::new(OpenDatabase {
+    db: Rc::new(db),
+    resources: Cell::new(1),
+    count,
+  });
+  let mut state = state.borrow_mut();
+  if let Some(key) = shared_key {
+    let shared = &mut state.borrow_mut::<OpenDatabases<DBH::DB>>().shared;
+    shared.retain(|_, open| open.upgrade().is_some_and(|open| open.is_open()));
+    shared.insert(key, Rc::downgrade(&open));
+  }
+  let rid = state.resource_table.add(DatabaseResource::new(open));
+  Ok(rid)
+}
+
+/// Closes a database once its in-flight queue messages are finished, or
+/// after `timeout_ms`. The resource is removed right away so that no new
+/// operations can start.
+#[op2(async)]
+async fn op_kv_database_close_graceful<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  timeout_ms: u32,
+) -> Result<bool, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let resource = state
     .borrow_mut()
     .resource_table
-    .add(DatabaseResource { db: Rc::new(db) });
-  Ok(rid)
+    .take::<DatabaseResource<DBH::DB>>(rid)?;
+  resource.cancel_handle.cancel();
+  // The database stays open for the other resources that share it.
+  if !resource.open.release() {
+    return Ok(true);
+  }
+  let timeout = Duration::from_millis(timeout_ms as u64);
+  resource.db.close_graceful(timeout).await
 }
 
 type KvKey = Vec<AnyValue>;
 
+/// JavaScript can't observe the sign of `NaN`, and `-0` and `0` compare
+/// equal, so number key parts are normalized to a single `NaN` and to `0` to
+/// make them equal as keys too. `canonicalize_f64` alone keeps the sign of
+/// `NaN`.
+fn canonicalize_key_number(n: f64) -> f64 {
+  if n.is_nan() {
+    canonicalize_f64(n.abs())
+  } else if n == 0.0 {
+    0.0
+  } else {
+    n
+  }
+}
+
 impl From<AnyValue> for KeyPart {
   fn from(value: AnyValue) -> Self {
     match value {
       AnyValue::Bool(false) => KeyPart::False,
       AnyValue::Bool(true) => KeyPart::True,
-      AnyValue::Number(n) => KeyPart::Float(n),
+      AnyValue::Number(n) => KeyPart::Float(canonicalize_key_number(n)),
       AnyValue::BigInt(n) => KeyPart::Int(n),
       AnyValue::String(s) => KeyPart::String(s),
       AnyValue::V8Buffer(buf) => KeyPart::Bytes(buf.to_vec()),
@@ -143,6 +377,7 @@ enum FromV8Value {
   V8(JsBuffer),
   Bytes(JsBuffer),
   U64(BigInt),
+  Json(String),
 }
 
 #[derive(Debug, Serialize)]
@@ -151,6 +386,7 @@ enum ToV8Value {
   V8(ToJsBuffer),
   Bytes(ToJsBuffer),
   U64(BigInt),
+  Json(String),
 }
 
 impl TryFrom<FromV8Value> for Value {
@@ -162,16 +398,44 @@ impl TryFrom<FromV8Value> for Value {
       FromV8Value::U64(n) => {
         Value::U64(num_bigint::BigInt::from(n).try_into()?)
       }
+      FromV8Value::Json(text) => Value::Json(canonicalize_json(&text)?),
     })
   }
 }
 
+/// Parses `text` as JSON and prints it back without insignificant whitespace
+/// and with the keys of objects sorted, so that equal values are stored as
+/// equal text.
+fn canonicalize_json(text: &str) -> Result<String, AnyError> {
+  fn sort_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+      serde_json::Value::Object(map) => {
+        let mut entries = map
+          .into_iter()
+          .map(|(key, value)| (key, sort_keys(value)))
+          .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        serde_json::Value::Object(entries.into_iter().collect())
+      }
+      serde_json::Value::Array(items) => {
+        serde_json::Value::Array(items.into_iter().map(sort_keys).collect())
+      }
+      value => value,
+    }
+  }
+
+  let value = serde_json::from_str(text)
+    .map_err(|_| type_error("Value is not valid JSON"))?;
+  Ok(serde_json::to_string(&sort_keys(value))?)
+}
+
 impl From<Value> for ToV8Value {
   fn from(value: Value) -> Self {
     match value {
       Value::V8(buf) => ToV8Value::V8(buf.into()),
       Value::Bytes(buf) => ToV8Value::Bytes(buf.into()),
       Value::U64(n) => ToV8Value::U64(num_bigint::BigInt::from(n).into()),
+      Value::Json(text) => ToV8Value::Json(text),
     }
   }
 }
@@ -181,6 +445,8 @@ struct ToV8KvEntry {
   key: KvKey,
   value: ToV8Value,
   versionstamp: ByteString,
+  #[serde(rename = "expireAt", skip_serializing_if = "Option::is_none")]
+  expire_at: Option<u64>,
 }
 
 impl TryFrom<KvEntry> for ToV8KvEntry {
@@ -194,6 +460,7 @@ impl TryFrom<KvEntry> for ToV8KvEntry {
         .collect(),
       value: entry.value.into(),
       versionstamp: hex::encode(entry.versionstamp).into(),
+      expire_at: entry.expire_at_ms,
     })
   }
 }
@@ -214,16 +481,25 @@ impl From<V8Consistency> for Consistency {
   }
 }
 
-// (prefix, start, end, limit, reverse, cursor)
+// (prefix, start, end, bytes prefix, limit, reverse, cursor, max_bytes)
 type SnapshotReadRange = (
   Option<KvKey>,
   Option<KvKey>,
   Option<KvKey>,
+  bool,
   u32,
   bool,
   Option<ByteString>,
+  Option<u32>,
 );
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8ReadRangeOutput {
+  entries: Vec<ToV8KvEntry>,
+  has_more: bool,
+}
+
 #[op2(async)]
 #[serde]
 async fn op_kv_snapshot_read<DBH>(
@@ -231,7 +507,7 @@ async fn op_kv_snapshot_read<DBH>(
   #[smi] rid: ResourceId,
   #[serde] ranges: Vec<SnapshotReadRange>,
   #[serde] consistency: V8Consistency,
-) -> Result<Vec<Vec<ToV8KvEntry>>, AnyError>
+) -> Result<Vec<V8ReadRangeOutput>, AnyError>
 where
   DBH: DatabaseHandler + 'static,
 {
@@ -249,51 +525,288 @@ where
     )));
   }
 
-  let mut total_entries = 0usize;
+  check_read_limits(ranges.iter().map(|range| range.4))?;
 
   let read_ranges = ranges
     .into_iter()
-    .map(|(prefix, start, end, limit, reverse, cursor)| {
-      let selector = RawSelector::from_tuple(prefix, start, end)?;
-
-      let (start, end) =
-        decode_selector_and_cursor(&selector, reverse, cursor.as_ref())?;
-      check_read_key_size(&start)?;
-      check_read_key_size(&end)?;
-
-      total_entries += limit as usize;
-      Ok(ReadRange {
+    .map(
+      |(
+        prefix,
         start,
         end,
-        limit: NonZeroU32::new(limit)
-          .with_context(|| "limit must be greater than 0")?,
+        bytes_prefix,
+        limit,
         reverse,
+        cursor,
+        max_bytes,
+      )| {
+        let selector =
+          RawSelector::from_tuple(prefix, start, end, bytes_prefix)?;
+
+        let (start, end) =
+          decode_selector_and_cursor(&selector, reverse, cursor.as_ref())?;
+        check_read_key_size(&start)?;
+        check_read_key_size(&end)?;
+
+        Ok(ReadRange {
+          start,
+          end,
+          limit: NonZeroU32::new(limit)
+            .with_context(|| "limit must be greater than 0")?,
+          reverse,
+          max_bytes: max_bytes
+            .map(|max_bytes| {
+              NonZeroU32::new(max_bytes)
+                .with_context(|| "maxBytes must be greater than 0")
+            })
+            .transpose()?,
+        })
+      },
+    )
+    .collect::<Result<Vec<_>, AnyError>>()?;
+
+  let opts = SnapshotReadOptions {
+    consistency: consistency.into(),
+  };
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start = Instant::now();
+  let output_ranges = db.snapshot_read(state.clone(), read_ranges, opts).await;
+  metrics.record_read(start.elapsed());
+  let output_ranges = output_ranges?;
+  let output_ranges = output_ranges
+    .into_iter()
+    .map(|x| {
+      Ok(V8ReadRangeOutput {
+        entries: x
+          .entries
+          .into_iter()
+          .map(TryInto::try_into)
+          .collect::<Result<Vec<_>, AnyError>>()?,
+        has_more: x.has_more,
       })
     })
     .collect::<Result<Vec<_>, AnyError>>()?;
+  Ok(output_ranges)
+}
 
-  if total_entries > MAX_READ_ENTRIES {
+/// Counts the keys matched by a selector without reading their values.
+#[op2(async)]
+#[number]
+async fn op_kv_count<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[serde] selector: EncodeCursorRangeSelector,
+  #[serde] consistency: V8Consistency,
+) -> Result<u64, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+
+  let selector =
+    RawSelector::from_tuple(selector.0, selector.1, selector.2, selector.3)?;
+  let start = selector.range_start_key();
+  let end = selector.range_end_key();
+  check_read_key_size(&start)?;
+  check_read_key_size(&end)?;
+
+  let opts = SnapshotReadOptions {
+    consistency: consistency.into(),
+  };
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start_time = Instant::now();
+  let count = db.count_range(state.clone(), start, end, opts).await;
+  metrics.record_read(start_time.elapsed());
+  count
+}
+
+/// Reads the entries under any of several prefixes as a single list, in key
+/// order and without duplicates where the prefixes overlap.
+#[op2(async)]
+#[serde]
+async fn op_kv_list_union<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[serde] prefixes: Vec<KvKey>,
+  limit: u32,
+  #[serde] consistency: V8Consistency,
+) -> Result<V8ReadRangeOutput, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+
+  if prefixes.len() > MAX_READ_RANGES {
     return Err(type_error(format!(
-      "too many entries (max {})",
-      MAX_READ_ENTRIES
+      "too many prefixes (max {})",
+      MAX_READ_RANGES
     )));
   }
+  check_read_limits([limit])?;
+
+  let ranges = prefixes
+    .into_iter()
+    .map(|prefix| {
+      let selector = RawSelector::from_tuple(Some(prefix), None, None, false)?;
+      let start = selector.range_start_key();
+      let end = selector.range_end_key();
+      check_read_key_size(&start)?;
+      check_read_key_size(&end)?;
+      Ok((start, end))
+    })
+    .collect::<Result<Vec<_>, AnyError>>()?;
 
   let opts = SnapshotReadOptions {
     consistency: consistency.into(),
   };
-  let output_ranges =
-    db.snapshot_read(state.clone(), read_ranges, opts).await?;
-  let output_ranges = output_ranges
+  let limit =
+    NonZeroU32::new(limit).with_context(|| "limit must be greater than 0")?;
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start = Instant::now();
+  let output = db.read_union(state.clone(), ranges, limit, opts).await;
+  metrics.record_read(start.elapsed());
+  let output = output?;
+  Ok(V8ReadRangeOutput {
+    entries: output
+      .entries
+      .into_iter()
+      .map(TryInto::try_into)
+      .collect::<Result<Vec<_>, AnyError>>()?,
+    has_more: output.has_more,
+  })
+}
+
+struct KvListStreamResource<DB: Database + 'static> {
+  db: Rc<DB>,
+  state: AsyncRefCell<KvListStreamState>,
+}
+
+struct KvListStreamState {
+  range: ReadRange,
+  batch_size: u32,
+  /// The number of entries left to return, if the stream is limited.
+  remaining: Option<u32>,
+  consistency: Consistency,
+}
+
+impl<DB: Database + 'static> Resource for KvListStreamResource<DB> {
+  fn name(&self) -> Cow<str> {
+    "kvListStream".into()
+  }
+}
+
+/// Starts reading a range in batches that are pulled from the database on
+/// demand with `op_kv_list_stream_next`. Unlike `op_kv_snapshot_read`, the
+/// total number of entries is not capped, only the size of each batch.
+#[op2]
+#[smi]
+#[allow(clippy::too_many_arguments)]
+fn op_kv_list_stream<DBH>(
+  state: &mut OpState,
+  #[smi] rid: ResourceId,
+  #[serde] selector: EncodeCursorRangeSelector,
+  reverse: bool,
+  #[serde] cursor: Option<ByteString>,
+  #[serde] consistency: V8Consistency,
+  #[smi] batch_size: u32,
+  #[serde] limit: Option<u32>,
+) -> Result<ResourceId, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = state
+    .resource_table
+    .get::<DatabaseResource<DBH::DB>>(rid)?
+    .db
+    .clone();
+
+  if batch_size == 0 || batch_size as usize > MAX_READ_ENTRIES {
+    return Err(type_error(format!(
+      "batch size must be between 1 and {}",
+      MAX_READ_ENTRIES
+    )));
+  }
+  if limit == Some(0) {
+    return Err(type_error("limit must be greater than 0"));
+  }
+
+  let selector =
+    RawSelector::from_tuple(selector.0, selector.1, selector.2, selector.3)?;
+  let (start, end) =
+    decode_selector_and_cursor(&selector, reverse, cursor.as_ref())?;
+  check_read_key_size(&start)?;
+  check_read_key_size(&end)?;
+
+  let rid = state.resource_table.add(KvListStreamResource {
+    db,
+    state: AsyncRefCell::new(KvListStreamState {
+      range: ReadRange {
+        start,
+        end,
+        limit: NonZeroU32::new(batch_size).unwrap(),
+        reverse,
+        max_bytes: None,
+      },
+      batch_size,
+      remaining: limit,
+      consistency: consistency.into(),
+    }),
+  });
+  Ok(rid)
+}
+
+/// Reads the next batch of a stream started with `op_kv_list_stream`. An
+/// empty batch means that the stream is exhausted.
+#[op2(async)]
+#[serde]
+async fn op_kv_list_stream_next<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<Vec<ToV8KvEntry>, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let resource = state
+    .borrow()
+    .resource_table
+    .get::<KvListStreamResource<DBH::DB>>(rid)?;
+  let mut stream = RcRef::map(&resource, |r| &r.state).borrow_mut().await;
+
+  let batch_size = match stream.remaining {
+    Some(0) => return Ok(vec![]),
+    Some(remaining) => remaining.min(stream.batch_size),
+    None => stream.batch_size,
+  };
+  stream.range.limit = NonZeroU32::new(batch_size).unwrap();
+  let opts = SnapshotReadOptions {
+    consistency: stream.consistency,
+  };
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start = Instant::now();
+  let entries = resource
+    .db
+    .snapshot_read_stream(state.clone(), &mut stream.range, opts)
+    .await;
+  metrics.record_read(start.elapsed());
+  let entries = entries?;
+  if let Some(remaining) = &mut stream.remaining {
+    *remaining -= entries.len() as u32;
+  }
+
+  entries
     .into_iter()
-    .map(|x| {
-      x.entries
-        .into_iter()
-        .map(TryInto::try_into)
-        .collect::<Result<Vec<_>, AnyError>>()
-    })
-    .collect::<Result<Vec<_>, AnyError>>()?;
-  Ok(output_ranges)
+    .map(TryInto::try_into)
+    .collect::<Result<Vec<_>, AnyError>>()
 }
 
 struct QueueMessageResource<QPH: QueueMessageHandle + 'static> {
@@ -306,16 +819,25 @@ impl<QMH: QueueMessageHandle + 'static> Resource for QueueMessageResource<QMH> {
   }
 }
 
+// (payload, handle rid, attempt, enqueued at, id)
+type V8DequeuedMessage = (
+  ToJsBuffer,
+  ResourceId,
+  Option<u64>,
+  Option<u64>,
+  Option<String>,
+);
+
 #[op2(async)]
 #[serde]
 async fn op_kv_dequeue_next_message<DBH>(
   state: Rc<RefCell<OpState>>,
   #[smi] rid: ResourceId,
-) -> Result<Option<(ToJsBuffer, ResourceId)>, AnyError>
+) -> Result<Option<V8DequeuedMessage>, AnyError>
 where
   DBH: DatabaseHandler + 'static,
 {
-  let db = {
+  let (db, cancel_handle) = {
     let state = state.borrow();
     let resource =
       match state.resource_table.get::<DatabaseResource<DBH::DB>>(rid) {
@@ -328,18 +850,30 @@ where
           }
         }
       };
-    resource.db.clone()
+    (resource.db.clone(), resource.cancel_handle.clone())
   };
 
-  let Some(mut handle) = db.dequeue_next_message(state.clone()).await? else {
+  // Closing this resource stops its listener, even if the database stays
+  // open for other resources that share it.
+  let Ok(next) = db
+    .dequeue_next_message(state.clone())
+    .or_cancel(cancel_handle)
+    .await
+  else {
+    return Ok(None);
+  };
+  let Some(mut handle) = next? else {
     return Ok(None);
   };
   let payload = handle.take_payload().await?.into();
+  let attempt = handle.attempt();
+  let enqueued_at_ms = handle.enqueued_at_ms();
+  let id = handle.id();
   let handle_rid = {
     let mut state = state.borrow_mut();
     state.resource_table.add(QueueMessageResource { handle })
   };
-  Ok(Some((payload, handle_rid)))
+  Ok(Some((payload, handle_rid, attempt, enqueued_at_ms, id)))
 }
 
 #[op2(async)]
@@ -364,38 +898,110 @@ where
   handle.finish(success).await
 }
 
-type V8KvCheck = (KvKey, Option<ByteString>);
+#[op2(async)]
+async fn op_kv_extend_dequeued_message_deadline<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] handle_rid: ResourceId,
+  #[serde] timeout_ms: Option<u64>,
+) -> Result<(), AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let resource = state
+    .borrow()
+    .resource_table
+    .get::<QueueMessageResource<<<DBH>::DB as Database>::QMH>>(handle_rid)
+    .map_err(|_| type_error("Queue message not found"))?;
+  resource
+    .handle
+    .extend_deadline(timeout_ms.map(Duration::from_millis))
+    .await
+}
+
+/// The third element is the expected value of a value check, which can't be
+/// combined with a versionstamp. The last element is the id of the queue message
+/// of a queue running check, in which case everything else is ignored.
+type V8KvCheck = (
+  KvKey,
+  Option<ByteString>,
+  Option<FromV8Value>,
+  Option<String>,
+);
 
 impl TryFrom<V8KvCheck> for KvCheck {
   type Error = AnyError;
   fn try_from(value: V8KvCheck) -> Result<Self, AnyError> {
-    let versionstamp = match value.1 {
-      Some(data) => {
+    if let Some(id) = value.3 {
+      return Ok(KvCheck {
+        key: vec![],
+        kind: CheckKind::QueueRunning(id),
+      });
+    }
+    let kind = match (value.1, value.2) {
+      (Some(_), Some(_)) => {
+        return Err(type_error(
+          "a check can't have both a versionstamp and a value",
+        ))
+      }
+      (None, Some(expected)) => CheckKind::Value(Some(expected.try_into()?)),
+      (Some(data), None) => {
         let mut out = [0u8; 10];
         hex::decode_to_slice(data, &mut out)
           .map_err(|_| type_error("invalid versionstamp"))?;
-        Some(out)
+        CheckKind::Versionstamp(Some(out))
       }
-      None => None,
+      (None, None) => CheckKind::Versionstamp(None),
     };
     Ok(KvCheck {
       key: encode_v8_key(value.0)?,
-      versionstamp,
+      kind,
     })
   }
 }
 
-type V8KvMutation = (KvKey, String, Option<FromV8Value>, Option<u64>);
+/// The last element is the destination key of a move mutation, and whether
+/// it may be overwritten.
+type V8KvMutation = (
+  KvKey,
+  String,
+  Option<FromV8Value>,
+  Option<u64>,
+  Option<(KvKey, bool)>,
+);
 
 impl TryFrom<(V8KvMutation, u64)> for KvMutation {
   type Error = AnyError;
   fn try_from(
     (value, current_timstamp): (V8KvMutation, u64),
   ) -> Result<Self, AnyError> {
+    if matches!(
+      value.1.as_str(),
+      "set" | "setIfAbsent" | "sum" | "min" | "max"
+    ) {
+      check_write_key_parts(&value.0)?;
+    }
     let key = encode_v8_key(value.0)?;
     let kind = match (value.1.as_str(), value.2) {
+      ("move", None) => {
+        let Some((to, overwrite)) = value.4 else {
+          return Err(type_error("invalid mutation 'move' without target"));
+        };
+        check_write_key_parts(&to)?;
+        let to = encode_v8_key(to)?;
+        if to == key {
+          return Err(type_error("cannot move a key onto itself"));
+        }
+        MutationKind::Move { to, overwrite }
+      }
       ("set", Some(value)) => MutationKind::Set(value.try_into()?),
+      ("setIfAbsent", Some(value)) => {
+        MutationKind::SetIfAbsent(value.try_into()?)
+      }
       ("delete", None) => MutationKind::Delete,
+      ("deletePrefix", None) => {
+        check_delete_prefix(&key, "'deletePrefix' mutation")?;
+        MutationKind::DeletePrefix
+      }
       ("sum", Some(value)) => MutationKind::Sum(value.try_into()?),
       ("min", Some(value)) => MutationKind::Min(value.try_into()?),
       ("max", Some(value)) => MutationKind::Max(value.try_into()?),
@@ -416,7 +1022,15 @@ impl TryFrom<(V8KvMutation, u64)> for KvMutation {
   }
 }
 
-type V8Enqueue = (JsBuffer, u64, Vec<KvKey>, Option<Vec<u32>>);
+// (payload, delay, keys if undelivered, backoff schedule, enqueue at, group)
+type V8Enqueue = (
+  JsBuffer,
+  u64,
+  Vec<KvKey>,
+  Option<Vec<u32>>,
+  Option<u64>,
+  Option<String>,
+);
 
 impl TryFrom<V8Enqueue> for Enqueue {
   type Error = AnyError;
@@ -427,22 +1041,54 @@ impl TryFrom<V8Enqueue> for Enqueue {
       keys_if_undelivered: value
         .2
         .into_iter()
-        .map(encode_v8_key)
-        .collect::<std::io::Result<_>>()?,
+        .map(|key| {
+          check_write_key_parts(&key)?;
+          encode_v8_key(key)
+        })
+        .collect::<Result<_, AnyError>>()?,
       backoff_schedule: value.3,
+      enqueue_at_ms: value.4,
+      group: value.5,
     })
   }
 }
 
-fn encode_v8_key(key: KvKey) -> Result<Vec<u8>, std::io::Error> {
-  encode_key(&Key(key.into_iter().map(From::from).collect()))
+/// An empty prefix would delete the whole database.
+fn check_delete_prefix(prefix: &[u8], what: &str) -> Result<(), AnyError> {
+  if prefix.is_empty() {
+    return Err(type_error(format!(
+      "prefix of {what} must have at least one key part"
+    )));
+  }
+  Ok(())
+}
+
+fn encode_v8_key(key: KvKey) -> Result<Vec<u8>, AnyError> {
+  Ok(encode_key(&Key(key.into_iter().map(From::from).collect()))?)
+}
+
+/// Only applies to keys that are written, so that keys with more parts that
+/// were written before the limit existed can still be read and deleted.
+fn check_write_key_parts<T>(key: &[T]) -> Result<(), AnyError> {
+  if key.len() > MAX_KEY_PARTS {
+    return Err(type_error(format!(
+      "too many key parts (max {})",
+      MAX_KEY_PARTS
+    )));
+  }
+  Ok(())
 }
 
 enum RawSelector {
+  /// Keys under `prefix`. If `bytes_prefix` is set, `prefix` is the encoding
+  /// of a key whose last part is a byte string without its terminator, and
+  /// the selector also matches keys whose last part merely starts with those
+  /// bytes.
   Prefixed {
     prefix: Vec<u8>,
     start: Option<Vec<u8>>,
     end: Option<Vec<u8>>,
+    bytes_prefix: bool,
   },
   Range {
     start: Vec<u8>,
@@ -455,8 +1101,29 @@ impl RawSelector {
     prefix: Option<KvKey>,
     start: Option<KvKey>,
     end: Option<KvKey>,
+    bytes_prefix: bool,
   ) -> Result<Self, AnyError> {
-    let prefix = prefix.map(encode_v8_key).transpose()?;
+    if bytes_prefix
+      && !matches!(
+        prefix.as_ref().and_then(|prefix| prefix.last()),
+        Some(AnyValue::V8Buffer(_))
+      )
+    {
+      return Err(type_error(
+        "bytesPrefix requires a prefix whose last part is a Uint8Array",
+      ));
+    }
+    let prefix = prefix
+      .map(|prefix| {
+        let mut prefix = encode_v8_key(prefix)?;
+        if bytes_prefix {
+          // Drop the terminator of the byte string, so that longer byte
+          // strings with the same leading bytes match too.
+          prefix.pop();
+        }
+        Ok::<_, AnyError>(prefix)
+      })
+      .transpose()?;
     let start = start.map(encode_v8_key).transpose()?;
     let end = end.map(encode_v8_key).transpose()?;
 
@@ -465,16 +1132,19 @@ impl RawSelector {
         prefix,
         start: None,
         end: None,
+        bytes_prefix,
       }),
       (Some(prefix), Some(start), None) => Ok(Self::Prefixed {
         prefix,
         start: Some(start),
         end: None,
+        bytes_prefix,
       }),
       (Some(prefix), None, Some(end)) => Ok(Self::Prefixed {
         prefix,
         start: None,
         end: Some(end),
+        bytes_prefix,
       }),
       (None, Some(start), Some(end)) => Ok(Self::Range { start, end }),
       (None, Some(start), None) => {
@@ -485,20 +1155,6 @@ impl RawSelector {
     }
   }
 
-  fn start(&self) -> Option<&[u8]> {
-    match self {
-      Self::Prefixed { start, .. } => start.as_deref(),
-      Self::Range { start, .. } => Some(start),
-    }
-  }
-
-  fn end(&self) -> Option<&[u8]> {
-    match self {
-      Self::Prefixed { end, .. } => end.as_deref(),
-      Self::Range { end, .. } => Some(end),
-    }
-  }
-
   fn common_prefix(&self) -> &[u8] {
     match self {
       Self::Prefixed { prefix, .. } => prefix,
@@ -512,6 +1168,11 @@ impl RawSelector {
         start: Some(start), ..
       } => start.clone(),
       Self::Range { start, .. } => start.clone(),
+      Self::Prefixed {
+        prefix,
+        bytes_prefix: true,
+        ..
+      } => prefix.clone(),
       Self::Prefixed { prefix, .. } => {
         prefix.iter().copied().chain(Some(0)).collect()
       }
@@ -522,6 +1183,11 @@ impl RawSelector {
     match self {
       Self::Prefixed { end: Some(end), .. } => end.clone(),
       Self::Range { end, .. } => end.clone(),
+      Self::Prefixed {
+        prefix,
+        bytes_prefix: true,
+        ..
+      } => bytes_prefix_end(prefix),
       Self::Prefixed { prefix, .. } => {
         prefix.iter().copied().chain(Some(0xff)).collect()
       }
@@ -529,6 +1195,21 @@ impl RawSelector {
   }
 }
 
+/// Returns the exclusive upper bound of the keys that start with `prefix`:
+/// the prefix up to its last byte that isn't 0xff, with that byte
+/// incremented. Escaping in the key encoding preserves byte order, so this
+/// works on the encoding of a byte string prefix. Encoded keys start with a
+/// type tag, so there is always a byte that isn't 0xff.
+fn bytes_prefix_end(prefix: &[u8]) -> Vec<u8> {
+  let i = prefix
+    .iter()
+    .rposition(|b| *b != 0xff)
+    .expect("encoded key prefix starts with a type tag");
+  let mut end = prefix[..=i].to_vec();
+  end[i] += 1;
+  end
+}
+
 fn common_prefix_for_bytes<'a>(a: &'a [u8], b: &'a [u8]) -> &'a [u8] {
   let mut i = 0;
   while i < a.len() && i < b.len() && a[i] == b[i] {
@@ -537,6 +1218,9 @@ fn common_prefix_for_bytes<'a>(a: &'a [u8], b: &'a [u8]) -> &'a [u8] {
   &a[..i]
 }
 
+/// Encodes the last key returned by a list operation as a cursor. Only the
+/// part of the key after the common prefix of the selector is stored, since
+/// the selector has to be passed again along with the cursor.
 fn encode_cursor(
   selector: &RawSelector,
   boundary_key: &[u8],
@@ -548,6 +1232,15 @@ fn encode_cursor(
   Ok(BASE64_URL_SAFE.encode(&boundary_key[common_prefix.len()..]))
 }
 
+/// Returns the range of keys to read for a selector, resuming after the
+/// boundary key in `cursor` if there is one.
+///
+/// The boundary key is always excluded, in both directions: a forward scan
+/// resumes at the smallest key greater than the boundary, and a reverse scan
+/// uses the boundary as its exclusive end. The range is clamped to the
+/// selector, so that concatenating all pages yields exactly the keys of an
+/// unpaginated scan, even when the selector has no common prefix and the
+/// cursor encodes the whole boundary key.
 fn decode_selector_and_cursor(
   selector: &RawSelector,
   reverse: bool,
@@ -561,61 +1254,113 @@ fn decode_selector_and_cursor(
   let cursor = BASE64_URL_SAFE
     .decode(cursor)
     .map_err(|_| type_error("invalid cursor"))?;
-
-  let first_key: Vec<u8>;
-  let last_key: Vec<u8>;
+  let boundary_key = common_prefix
+    .iter()
+    .copied()
+    .chain(cursor.iter().copied())
+    .collect::<Vec<u8>>();
+
+  let mut first_key = selector.range_start_key();
+  let mut last_key = selector.range_end_key();
+
+  // Defend against out-of-bounds reading. The boundary must be a key that
+  // the selector could have returned, except for an empty cursor, which
+  // refers to the common prefix itself.
+  if !boundary_key.starts_with(common_prefix)
+    || boundary_key >= last_key
+    || (boundary_key < first_key && !cursor.is_empty())
+  {
+    return Err(type_error("cursor out of bounds"));
+  }
 
   if reverse {
-    first_key = selector.range_start_key();
-    last_key = common_prefix
-      .iter()
-      .copied()
-      .chain(cursor.iter().copied())
-      .collect();
+    last_key = last_key.min(boundary_key);
   } else {
-    first_key = common_prefix
-      .iter()
-      .copied()
-      .chain(cursor.iter().copied())
-      .chain(Some(0))
-      .collect();
-    last_key = selector.range_end_key();
+    let after_boundary_key =
+      boundary_key.into_iter().chain(Some(0)).collect::<Vec<u8>>();
+    first_key = first_key.max(after_boundary_key);
   }
 
-  // Defend against out-of-bounds reading
-  if let Some(start) = selector.start() {
-    if &first_key[..] < start {
-      return Err(type_error("cursor out of bounds"));
-    }
-  }
-
-  if let Some(end) = selector.end() {
-    if &last_key[..] > end {
-      return Err(type_error("cursor out of bounds"));
-    }
+  // An empty cursor can precede the whole selector, which leaves nothing to
+  // read in reverse.
+  if first_key > last_key {
+    last_key = first_key.clone();
   }
 
   Ok((first_key, last_key))
 }
 
+#[derive(Serialize)]
+#[serde(untagged)]
+enum V8CommitOutcome {
+  Committed(V8CommitResult),
+  CheckFailed {
+    #[serde(rename = "failedCheck")]
+    failed_check: Option<usize>,
+  },
+  DryRun(V8DryRunResult),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8CommitResult {
+  versionstamp: ByteString,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  old_values: Option<Vec<Option<ToV8KvEntry>>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  mutation_counts: Option<V8MutationCounts>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8DryRunResult {
+  /// Always `true`, to tell the result apart from a commit.
+  dry_run: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  old_values: Option<Vec<Option<ToV8KvEntry>>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  mutation_counts: Option<V8MutationCounts>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8MutationCounts {
+  mutations_applied: u64,
+  keys_created: u64,
+  keys_deleted: u64,
+}
+
+impl From<MutationCounts> for V8MutationCounts {
+  fn from(counts: MutationCounts) -> Self {
+    Self {
+      mutations_applied: counts.mutations_applied,
+      keys_created: counts.keys_created,
+      keys_deleted: counts.keys_deleted,
+    }
+  }
+}
+
 #[op2(async)]
-#[string]
+#[serde]
 async fn op_kv_atomic_write<DBH>(
   state: Rc<RefCell<OpState>>,
   #[smi] rid: ResourceId,
   #[serde] checks: Vec<V8KvCheck>,
   #[serde] mutations: Vec<V8KvMutation>,
   #[serde] enqueues: Vec<V8Enqueue>,
-) -> Result<Option<String>, AnyError>
+  return_old: bool,
+  dry_run: bool,
+) -> Result<V8CommitOutcome, AnyError>
 where
   DBH: DatabaseHandler + 'static,
 {
-  let current_timestamp = Utc::now().timestamp_millis() as u64;
-  let db = {
+  let (current_timestamp, write_limits, db) = {
     let state = state.borrow();
+    let current_timestamp = KvClock::from_state(&state).now_ms();
+    let write_limits = KvWriteLimits::from_state(&state);
     let resource =
       state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
-    resource.db.clone()
+    (current_timestamp, write_limits, resource.db.clone())
   };
 
   if checks.len() > MAX_CHECKS {
@@ -629,87 +1374,793 @@ where
     )));
   }
 
+  // Sizes are checked as each part is converted, in a single pass.
+  let mut sizes = WriteSizes::new(write_limits);
   let checks = checks
     .into_iter()
-    .map(TryInto::try_into)
-    .collect::<Result<Vec<KvCheck>, AnyError>>()
-    .with_context(|| "invalid check")?;
+    .map(|check| {
+      let check = KvCheck::try_from(check).with_context(|| "invalid check")?;
+      sizes.add_check(&check)?;
+      Ok(check)
+    })
+    .collect::<Result<Vec<_>, AnyError>>()?;
   let mutations = mutations
     .into_iter()
-    .map(|mutation| TryFrom::try_from((mutation, current_timestamp)))
-    .collect::<Result<Vec<KvMutation>, AnyError>>()
-    .with_context(|| "invalid mutation")?;
+    .map(|mutation| {
+      let mutation = KvMutation::try_from((mutation, current_timestamp))
+        .with_context(|| "invalid mutation")?;
+      sizes.add_mutation(&mutation)?;
+      Ok(mutation)
+    })
+    .collect::<Result<Vec<_>, AnyError>>()?;
   let enqueues = enqueues
     .into_iter()
-    .map(TryInto::try_into)
-    .collect::<Result<Vec<Enqueue>, AnyError>>()
-    .with_context(|| "invalid enqueue")?;
+    .map(|enqueue| {
+      let enqueue =
+        Enqueue::try_from(enqueue).with_context(|| "invalid enqueue")?;
+      check_enqueue_limits(&enqueue, current_timestamp)?;
+      sizes.add_enqueue(&enqueue)?;
+      Ok(enqueue)
+    })
+    .collect::<Result<Vec<_>, AnyError>>()?;
+  sizes.finish()?;
 
-  let mut total_payload_size = 0usize;
-  let mut total_key_size = 0usize;
+  let atomic_write = AtomicWrite {
+    checks,
+    mutations,
+    enqueues,
+    return_old,
+    dry_run,
+  };
 
-  for key in checks
-    .iter()
-    .map(|c| &c.key)
-    .chain(mutations.iter().map(|m| &m.key))
-  {
-    if key.is_empty() {
-      return Err(type_error("key cannot be empty"));
-    }
+  let (versionstamp, old_values, mutation_counts) =
+    match atomic_write_with_metrics(state.clone(), &*db, atomic_write).await? {
+      CommitOutcome::Committed(result) => (
+        Some(result.versionstamp),
+        result.old_values,
+        result.mutation_counts,
+      ),
+      CommitOutcome::DryRun(result) => {
+        (None, result.old_values, result.mutation_counts)
+      }
+      CommitOutcome::CheckFailed { failed_index } => {
+        return Ok(V8CommitOutcome::CheckFailed {
+          failed_check: failed_index,
+        })
+      }
+    };
 
-    let checked_size = check_write_key_size(key)?;
-    total_payload_size += checked_size;
-    total_key_size += checked_size;
-  }
+  let old_values = if return_old {
+    Some(
+      old_values
+        .into_iter()
+        .map(|entry| entry.map(TryInto::try_into).transpose())
+        .collect::<Result<Vec<_>, AnyError>>()?,
+    )
+  } else {
+    None
+  };
+  let mutation_counts = mutation_counts.map(Into::into);
+
+  Ok(match versionstamp {
+    Some(versionstamp) => V8CommitOutcome::Committed(V8CommitResult {
+      versionstamp: hex::encode(versionstamp).into(),
+      old_values,
+      mutation_counts,
+    }),
+    None => V8CommitOutcome::DryRun(V8DryRunResult {
+      dry_run: true,
+      old_values,
+      mutation_counts,
+    }),
+  })
+}
 
-  for value in mutations.iter().flat_map(|m| m.kind.value()) {
-    total_payload_size += check_value_size(value)?;
+#[op2(async)]
+#[string]
+async fn op_kv_replace_prefix<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[serde] prefix: KvKey,
+  #[serde] entries: Vec<(KvKey, FromV8Value)>,
+) -> Result<String, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let (write_limits, db) = {
+    let state = state.borrow();
+    let write_limits = KvWriteLimits::from_state(&state);
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    (write_limits, resource.db.clone())
+  };
+
+  if entries.len() + 1 > MAX_MUTATIONS {
+    return Err(type_error(format!(
+      "too many mutations (max {})",
+      MAX_MUTATIONS
+    )));
   }
 
-  for enqueue in &enqueues {
-    total_payload_size += check_enqueue_payload_size(&enqueue.payload)?;
+  check_write_key_parts(&prefix)?;
+  let prefix = encode_v8_key(prefix)?;
+  check_delete_prefix(&prefix, "replacePrefix")?;
+
+  // Sizes are checked as each part is converted, in a single pass.
+  let mut sizes = WriteSizes::new(write_limits);
+  let mut mutations = Vec::with_capacity(entries.len() + 1);
+  let delete = KvMutation {
+    key: prefix.clone(),
+    kind: MutationKind::DeletePrefix,
+    expire_at: None,
+  };
+  sizes.add_mutation(&delete)?;
+  mutations.push(delete);
+  for (key, value) in entries {
+    check_write_key_parts(&key)?;
+    let key = encode_v8_key(key)?;
+    if key.len() <= prefix.len() || !key.starts_with(&prefix) {
+      return Err(type_error("key is not within the prefix"));
+    }
+    let mutation = KvMutation {
+      key,
+      kind: MutationKind::Set(value.try_into()?),
+      expire_at: None,
+    };
+    sizes.add_mutation(&mutation)?;
+    mutations.push(mutation);
   }
+  sizes.finish()?;
+
+  let atomic_write = AtomicWrite {
+    checks: vec![],
+    mutations,
+    enqueues: vec![],
+    return_old: false,
+    dry_run: false,
+  };
+
+  // Without checks, the write can only fail with an error.
+  let result = atomic_write_with_metrics(state.clone(), &*db, atomic_write)
+    .await?
+    .into_committed()
+    .ok_or_else(|| type_error("Failed to replace prefix"))?;
+
+  Ok(hex::encode(result.versionstamp))
+}
 
-  if total_payload_size > MAX_TOTAL_MUTATION_SIZE_BYTES {
+#[op2(async)]
+#[string]
+async fn op_kv_enqueue<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[serde] enqueues: Vec<V8Enqueue>,
+) -> Result<String, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let (current_timestamp, db) = {
+    let state = state.borrow();
+    let current_timestamp = KvClock::from_state(&state).now_ms();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    (current_timestamp, resource.db.clone())
+  };
+
+  if enqueues.len() > MAX_MUTATIONS {
     return Err(type_error(format!(
-      "total mutation size too large (max {} bytes)",
-      MAX_TOTAL_MUTATION_SIZE_BYTES
+      "too many mutations (max {})",
+      MAX_MUTATIONS
     )));
   }
 
-  if total_key_size > MAX_TOTAL_KEY_SIZE_BYTES {
+  let enqueues = enqueues
+    .into_iter()
+    .map(TryInto::try_into)
+    .collect::<Result<Vec<Enqueue>, AnyError>>()
+    .with_context(|| "invalid enqueue")?;
+  for enqueue in &enqueues {
+    check_enqueue_limits(enqueue, current_timestamp)?;
+  }
+  check_write_sizes(&[], &[], &enqueues)?;
+
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start = Instant::now();
+  let versionstamp = db.enqueue(state.clone(), enqueues).await?;
+  metrics.record_write(start.elapsed());
+
+  Ok(hex::encode(versionstamp))
+}
+
+/// Commits `write`, recording the outcome in the [KvMetrics] of the runtime.
+async fn atomic_write_with_metrics<DB: Database>(
+  state: Rc<RefCell<OpState>>,
+  db: &DB,
+  write: AtomicWrite,
+) -> Result<CommitOutcome, AnyError> {
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start = Instant::now();
+  let result = db.atomic_write(state, write).await?;
+  match result {
+    CommitOutcome::Committed(_) => metrics.record_write(start.elapsed()),
+    CommitOutcome::CheckFailed { .. } => {
+      metrics.record_commit_conflict(start.elapsed())
+    }
+    // Nothing was written.
+    CommitOutcome::DryRun(_) => {}
+  }
+  Ok(result)
+}
+
+#[op2(async)]
+#[number]
+async fn op_kv_export<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[string] path: String,
+) -> Result<u64, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  db.export(state, path).await
+}
+
+#[op2(async)]
+#[number]
+async fn op_kv_import<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[string] path: String,
+  overwrite: bool,
+) -> Result<u64, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  db.import(state, path, overwrite).await
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8DeadLetterMessage {
+  id: String,
+  payload: ToJsBuffer,
+  enqueued_at: u64,
+  failed_at: u64,
+  failure_count: u64,
+}
+
+#[op2(async)]
+#[serde]
+async fn op_kv_list_dead_letter<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  limit: u32,
+) -> Result<Vec<V8DeadLetterMessage>, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+
+  if limit == 0 || limit as usize > MAX_READ_ENTRIES {
     return Err(type_error(format!(
-      "total key size too large (max {} bytes)",
-      MAX_TOTAL_KEY_SIZE_BYTES
+      "limit must be between 1 and {}",
+      MAX_READ_ENTRIES
     )));
   }
 
-  let atomic_write = AtomicWrite {
-    checks,
-    mutations,
-    enqueues,
+  let messages = db.list_dead_letters(state, limit).await?;
+  Ok(
+    messages
+      .into_iter()
+      .map(|message| V8DeadLetterMessage {
+        id: message.id,
+        payload: message.payload.into(),
+        enqueued_at: message.enqueued_at_ms,
+        failed_at: message.failed_at_ms,
+        failure_count: message.failure_count,
+      })
+      .collect(),
+  )
+}
+
+#[op2(async)]
+async fn op_kv_retry_dead_letter<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[string] id: String,
+) -> Result<bool, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  db.retry_dead_letter(state, id).await
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8QueueStats {
+  ready: u64,
+  running: u64,
+  earliest_ready: Option<u64>,
+}
+
+#[op2(async)]
+#[serde]
+async fn op_kv_queue_stats<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<V8QueueStats, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  let stats = db.queue_stats(state).await?;
+  Ok(V8QueueStats {
+    ready: stats.ready,
+    running: stats.running,
+    earliest_ready: stats.earliest_ready_ms,
+  })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8DatabaseStats {
+  key_count: u64,
+  total_value_bytes: u64,
+  db_file_bytes: u64,
+}
+
+#[op2(async)]
+#[serde]
+async fn op_kv_stats<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<V8DatabaseStats, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  let stats = db.stats(state).await?;
+  Ok(V8DatabaseStats {
+    key_count: stats.key_count,
+    total_value_bytes: stats.total_value_bytes,
+    db_file_bytes: stats.db_file_bytes,
+  })
+}
+
+/// Checks that the database can be reached, returning how long that took in
+/// milliseconds.
+#[op2(async)]
+async fn op_kv_ping<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<f64, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
   };
+  let start = Instant::now();
+  db.ping(state).await?;
+  Ok(start.elapsed().as_secs_f64() * 1000.0)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum V8MaintenanceMode {
+  Passive,
+  Truncate,
+  Vacuum,
+}
+
+impl From<V8MaintenanceMode> for MaintenanceMode {
+  fn from(value: V8MaintenanceMode) -> Self {
+    match value {
+      V8MaintenanceMode::Passive => MaintenanceMode::Passive,
+      V8MaintenanceMode::Truncate => MaintenanceMode::Truncate,
+      V8MaintenanceMode::Vacuum => MaintenanceMode::Vacuum,
+    }
+  }
+}
 
-  let result = db.atomic_write(state.clone(), atomic_write).await?;
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct V8CheckpointResult {
+  busy: bool,
+  log_frames: i64,
+  checkpointed_frames: i64,
+}
+
+#[op2(async)]
+#[serde]
+async fn op_kv_maintenance<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[serde] mode: V8MaintenanceMode,
+) -> Result<V8CheckpointResult, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  let result = db.checkpoint(state, mode.into()).await?;
+  Ok(V8CheckpointResult {
+    busy: result.busy,
+    log_frames: result.log_frames,
+    checkpointed_frames: result.checkpointed_frames,
+  })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum V8IntegrityProblemKind {
+  Corruption,
+  VersionAhead,
+  StaleRunningMessage,
+  InvalidExpiration,
+}
+
+impl From<IntegrityProblemKind> for V8IntegrityProblemKind {
+  fn from(value: IntegrityProblemKind) -> Self {
+    match value {
+      IntegrityProblemKind::Corruption => V8IntegrityProblemKind::Corruption,
+      IntegrityProblemKind::VersionAhead => {
+        V8IntegrityProblemKind::VersionAhead
+      }
+      IntegrityProblemKind::StaleRunningMessage => {
+        V8IntegrityProblemKind::StaleRunningMessage
+      }
+      IntegrityProblemKind::InvalidExpiration => {
+        V8IntegrityProblemKind::InvalidExpiration
+      }
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct V8IntegrityProblem {
+  kind: V8IntegrityProblemKind,
+  message: String,
+}
 
-  Ok(result.map(|res| hex::encode(res.versionstamp)))
+#[op2(async)]
+#[serde]
+async fn op_kv_check_integrity<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<Vec<V8IntegrityProblem>, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+  let problems = db.check_integrity(state).await?;
+  Ok(
+    problems
+      .into_iter()
+      .map(|problem| V8IntegrityProblem {
+        kind: problem.kind.into(),
+        message: problem.message,
+      })
+      .collect(),
+  )
 }
 
-// (prefix, start, end)
-type EncodeCursorRangeSelector = (Option<KvKey>, Option<KvKey>, Option<KvKey>);
+// (prefix, start, end, bytes prefix)
+type EncodeCursorRangeSelector =
+  (Option<KvKey>, Option<KvKey>, Option<KvKey>, bool);
+
+/// Reads the entries of a secondary index registered by the embedder. Unlike
+/// `op_kv_snapshot_read` there is no cursor, callers page through an index
+/// by narrowing the selector.
+#[op2(async)]
+#[serde]
+async fn op_kv_read_index<DBH>(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+  #[string] name: String,
+  #[serde] selector: EncodeCursorRangeSelector,
+  limit: u32,
+  reverse: bool,
+) -> Result<Vec<ToV8KvEntry>, AnyError>
+where
+  DBH: DatabaseHandler + 'static,
+{
+  let db = {
+    let state = state.borrow();
+    let resource =
+      state.resource_table.get::<DatabaseResource<DBH::DB>>(rid)?;
+    resource.db.clone()
+  };
+
+  check_read_limits([limit])?;
+
+  let selector =
+    RawSelector::from_tuple(selector.0, selector.1, selector.2, selector.3)?;
+  let start = selector.range_start_key();
+  let end = selector.range_end_key();
+  check_read_key_size(&start)?;
+  check_read_key_size(&end)?;
+
+  let range = ReadRange {
+    start,
+    end,
+    limit: NonZeroU32::new(limit)
+      .with_context(|| "limit must be greater than 0")?,
+    reverse,
+    max_bytes: None,
+  };
+  let metrics = KvMetricsHook::from_state(&state.borrow());
+  let start = Instant::now();
+  let output = db.read_index(state.clone(), name, range).await;
+  metrics.record_read(start.elapsed());
+  output?
+    .entries
+    .into_iter()
+    .map(TryInto::try_into)
+    .collect::<Result<Vec<_>, AnyError>>()
+}
 
 #[op2]
 #[string]
 fn op_kv_encode_cursor(
-  #[serde] (prefix, start, end): EncodeCursorRangeSelector,
+  #[serde] (prefix, start, end, bytes_prefix): EncodeCursorRangeSelector,
   #[serde] boundary_key: KvKey,
 ) -> Result<String, AnyError> {
-  let selector = RawSelector::from_tuple(prefix, start, end)?;
+  let selector = RawSelector::from_tuple(prefix, start, end, bytes_prefix)?;
   let boundary_key = encode_v8_key(boundary_key)?;
   let cursor = encode_cursor(&selector, &boundary_key)?;
   Ok(cursor)
 }
 
+fn check_write_sizes(
+  checks: &[KvCheck],
+  mutations: &[KvMutation],
+  enqueues: &[Enqueue],
+) -> Result<(), AnyError> {
+  let mut sizes = WriteSizes::new(KvWriteLimits::default());
+  for check in checks {
+    sizes.add_check(check)?;
+  }
+  for mutation in mutations {
+    sizes.add_mutation(mutation)?;
+  }
+  for enqueue in enqueues {
+    sizes.add_enqueue(enqueue)?;
+  }
+  sizes.finish()
+}
+
+/// Accumulates the size of a write while its parts are converted, so that
+/// they don't have to be walked again to check the limits.
+struct WriteSizes {
+  limits: KvWriteLimits,
+  total_payload_size: usize,
+  total_key_size: usize,
+}
+
+impl WriteSizes {
+  fn new(limits: KvWriteLimits) -> Self {
+    Self {
+      limits,
+      total_payload_size: 0,
+      total_key_size: 0,
+    }
+  }
+
+  /// The expected value of a value check counts against the payload size
+  /// like the value of a mutation. A queue running check has no key, only
+  /// its message id counts.
+  fn add_check(&mut self, check: &KvCheck) -> Result<(), AnyError> {
+    if let CheckKind::QueueRunning(id) = &check.kind {
+      self.total_payload_size += id.len();
+      return Ok(());
+    }
+    self.add_key(&check.key)?;
+    if let CheckKind::Value(Some(value)) = &check.kind {
+      self.total_payload_size += if self.limits.skip_individual_limits {
+        value.byte_size()
+      } else {
+        check_value_size(value)?
+      };
+    }
+    Ok(())
+  }
+
+  /// Prefix deletes are accounted for by the size of their prefix, the same
+  /// way as point deletes.
+  fn add_mutation(&mut self, mutation: &KvMutation) -> Result<(), AnyError> {
+    self.add_key(&mutation.key)?;
+    if let MutationKind::Move { to, .. } = &mutation.kind {
+      self.add_key(to)?;
+    }
+    if let Some(value) = mutation.kind.value() {
+      self.total_payload_size += if self.limits.skip_individual_limits {
+        value.byte_size()
+      } else {
+        check_value_size(value)?
+      };
+    }
+    Ok(())
+  }
+
+  fn add_enqueue(&mut self, enqueue: &Enqueue) -> Result<(), AnyError> {
+    if enqueue.keys_if_undelivered.len() > MAX_QUEUE_UNDELIVERED_KEYS {
+      return Err(type_error(format!(
+        "too many keysIfUndelivered (max {})",
+        MAX_QUEUE_UNDELIVERED_KEYS
+      )));
+    }
+    for key in &enqueue.keys_if_undelivered {
+      self.add_key(key)?;
+    }
+    if let Some(group) = &enqueue.group {
+      if !self.limits.skip_individual_limits
+        && group.len() > MAX_QUEUE_GROUP_SIZE_BYTES
+      {
+        return Err(type_error(format!(
+          "queue group too large (max {} bytes)",
+          MAX_QUEUE_GROUP_SIZE_BYTES
+        )));
+      }
+      self.total_payload_size += group.len();
+    }
+    self.total_payload_size += if self.limits.skip_individual_limits {
+      enqueue.payload.len()
+    } else {
+      check_enqueue_payload_size(&enqueue.payload)?
+    };
+    Ok(())
+  }
+
+  fn add_key(&mut self, key: &[u8]) -> Result<(), AnyError> {
+    if key.is_empty() {
+      return Err(type_error("key cannot be empty"));
+    }
+    let size = if self.limits.skip_individual_limits {
+      key.len()
+    } else {
+      check_write_key_size(key)?
+    };
+    self.total_payload_size += size;
+    self.total_key_size += size;
+    Ok(())
+  }
+
+  /// Checks the totals once every part of the write has been added.
+  fn finish(self) -> Result<(), AnyError> {
+    if self.total_payload_size > MAX_TOTAL_MUTATION_SIZE_BYTES {
+      return Err(type_error(format!(
+        "total mutation size too large (max {} bytes)",
+        MAX_TOTAL_MUTATION_SIZE_BYTES
+      )));
+    }
+
+    if self.total_key_size > MAX_TOTAL_KEY_SIZE_BYTES {
+      return Err(type_error(format!(
+        "total key size too large (max {} bytes)",
+        MAX_TOTAL_KEY_SIZE_BYTES
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+fn check_enqueue_limits(enqueue: &Enqueue, now: u64) -> Result<(), AnyError> {
+  if enqueue.delay_ms > MAX_QUEUE_DELAY_MS {
+    return Err(type_error(format!(
+      "delay cannot be greater than {} ms",
+      MAX_QUEUE_DELAY_MS
+    )));
+  }
+  if let Some(enqueue_at) = enqueue.enqueue_at_ms {
+    if enqueue_at > now.saturating_add(MAX_QUEUE_DELAY_MS) {
+      return Err(type_error(format!(
+        "enqueue time cannot be more than {} ms in the future",
+        MAX_QUEUE_DELAY_MS
+      )));
+    }
+  }
+  if let Some(backoff_schedule) = &enqueue.backoff_schedule {
+    check_backoff_schedule(backoff_schedule)?;
+  }
+  Ok(())
+}
+
+/// Also applies to the default backoff schedule of a database.
+pub(crate) fn check_backoff_schedule(
+  backoff_schedule: &[u32],
+) -> Result<(), AnyError> {
+  if backoff_schedule.len() > MAX_QUEUE_BACKOFF_INTERVALS {
+    return Err(type_error(format!(
+      "backoff schedule too long (max {} intervals)",
+      MAX_QUEUE_BACKOFF_INTERVALS
+    )));
+  }
+  if backoff_schedule
+    .iter()
+    .any(|&interval| interval as u64 > MAX_QUEUE_DELAY_MS)
+  {
+    return Err(type_error(format!(
+      "backoff interval cannot be greater than {} ms",
+      MAX_QUEUE_DELAY_MS
+    )));
+  }
+  Ok(())
+}
+
+/// Checks the limits of the ranges of a snapshot read before any of them are
+/// scanned. Each limit must fit in `MAX_READ_ENTRIES` on its own, so that a
+/// single huge limit can't be hidden by wrapping the sum, and the limits
+/// together must fit in it too.
+fn check_read_limits(
+  limits: impl IntoIterator<Item = u32>,
+) -> Result<(), AnyError> {
+  let mut total_entries = 0usize;
+  for limit in limits {
+    let limit = limit as usize;
+    if limit > MAX_READ_ENTRIES {
+      return Err(type_error(format!(
+        "too many entries (max {})",
+        MAX_READ_ENTRIES
+      )));
+    }
+    total_entries += limit;
+  }
+  if total_entries > MAX_READ_ENTRIES {
+    return Err(type_error(format!(
+      "too many entries (max {})",
+      MAX_READ_ENTRIES
+    )));
+  }
+  Ok(())
+}
+
 fn check_read_key_size(key: &[u8]) -> Result<(), AnyError> {
   if key.len() > MAX_READ_KEY_SIZE_BYTES {
     Err(type_error(format!(
@@ -737,8 +2188,13 @@ fn check_value_size(value: &Value) -> Result<usize, AnyError> {
     Value::Bytes(x) => x,
     Value::V8(x) => x,
     Value::U64(_) => return Ok(8),
+    Value::Json(x) => x.as_bytes(),
   };
+  check_encoded_value_size(payload)
+}
 
+/// Like `check_value_size`, for a value in its encoded form.
+fn check_encoded_value_size(payload: &[u8]) -> Result<usize, AnyError> {
   if payload.len() > MAX_VALUE_SIZE_BYTES {
     Err(type_error(format!(
       "value too large (max {} bytes)",
@@ -750,12 +2206,408 @@ fn check_value_size(value: &Value) -> Result<usize, AnyError> {
 }
 
 fn check_enqueue_payload_size(payload: &[u8]) -> Result<usize, AnyError> {
-  if payload.len() > MAX_VALUE_SIZE_BYTES {
+  if payload.len() > MAX_ENQUEUE_PAYLOAD_SIZE_BYTES {
     Err(type_error(format!(
       "enqueue payload too large (max {} bytes)",
-      MAX_VALUE_SIZE_BYTES
+      MAX_ENQUEUE_PAYLOAD_SIZE_BYTES
     )))
   } else {
     Ok(payload.len())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use std::sync::atomic::Ordering;
+  use std::sync::Arc;
+
+  use deno_core::error::AnyError;
+  use deno_core::serde_v8::AnyValue;
+  use deno_core::OpState;
+
+  use super::atomic_write_with_metrics;
+  use super::bytes_prefix_end;
+  use super::canonicalize_json;
+  use super::check_enqueue_limits;
+  use super::check_read_limits;
+  use super::check_write_sizes;
+  use super::encode_v8_key;
+  use super::open_database;
+  use super::DatabaseResource;
+  use super::FromV8Value;
+  use super::KvOpenLimits;
+  use super::KvWriteLimits;
+  use super::RawSelector;
+  use super::V8KvCheck;
+  use super::V8KvMutation;
+  use super::WriteSizes;
+  use super::MAX_KEY_PARTS;
+  use super::MAX_MUTATIONS;
+  use super::MAX_QUEUE_BACKOFF_INTERVALS;
+  use super::MAX_QUEUE_DELAY_MS;
+  use super::MAX_READ_ENTRIES;
+  use super::MAX_READ_RANGES;
+  use crate::sqlite::tests::AllowAll;
+  use crate::sqlite::SqliteDb;
+  use crate::sqlite::SqliteDbHandler;
+  use crate::AtomicWrite;
+  use crate::CheckKind;
+  use crate::CommitOutcome;
+  use crate::Database;
+  use crate::DatabaseHandler;
+  use crate::Enqueue;
+  use crate::InMemoryKvMetrics;
+  use crate::KvCheck;
+  use crate::KvMetricsHook;
+  use crate::KvMutation;
+  use crate::MutationKind;
+  use crate::Value;
+
+  #[test]
+  fn read_limits() {
+    let max = MAX_READ_ENTRIES as u32;
+    assert!(check_read_limits([max]).is_ok());
+    assert!(check_read_limits([max + 1]).is_err());
+    assert!(check_read_limits([u32::MAX]).is_err());
+
+    // The budget is shared between all ranges of a read.
+    let with_small_ranges = |last: u32| {
+      let mut limits = vec![1; MAX_READ_RANGES - 1];
+      limits.push(last);
+      check_read_limits(limits)
+    };
+    let rest = max - (MAX_READ_RANGES as u32 - 1);
+    assert!(with_small_ranges(rest).is_ok());
+    assert!(with_small_ranges(rest + 1).is_err());
+    assert!(with_small_ranges(u32::MAX).is_err());
+    assert!(check_read_limits([max / 2; 2]).is_ok());
+    assert!(check_read_limits([max / 2 + 1; 2]).is_err());
+  }
+
+  #[test]
+  fn key_part_limit() {
+    let key = |parts: usize| {
+      (0..parts)
+        .map(|_| AnyValue::String("a".to_string()))
+        .collect::<Vec<_>>()
+    };
+    let selector = |parts: usize| {
+      RawSelector::from_tuple(Some(key(parts)), None, None, false)
+    };
+    let mutation = |op: &str, parts: usize| {
+      let value = (op == "sum")
+        .then(|| FromV8Value::U64(num_bigint::BigInt::from(1).into()));
+      let mutation: V8KvMutation =
+        (key(parts), op.to_string(), value, None, None);
+      KvMutation::try_from((mutation, 0))
+    };
+
+    assert!(mutation("sum", MAX_KEY_PARTS).is_ok());
+    let err = mutation("sum", MAX_KEY_PARTS + 1).unwrap_err();
+    assert_eq!(err.to_string(), "too many key parts (max 64)");
+
+    // Keys with more parts can still be read and deleted.
+    assert!(encode_v8_key(key(MAX_KEY_PARTS + 1)).is_ok());
+    assert!(selector(MAX_KEY_PARTS + 1).is_ok());
+    assert!(mutation("delete", MAX_KEY_PARTS + 1).is_ok());
+  }
+
+  #[tokio::test]
+  async fn open_databases_are_shared_and_capped() {
+    let dir = std::env::temp_dir()
+      .join(format!("deno_kv_open_databases_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("kv.sqlite3").to_string_lossy().into_owned();
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    state.borrow_mut().put(AllowAll);
+    state.borrow_mut().put(KvOpenLimits {
+      max_open_databases: 2,
+    });
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let open =
+      |path: &str| open_database(state.clone(), &handler, Some(path.into()));
+    let db = |rid| {
+      state
+        .borrow()
+        .resource_table
+        .get::<DatabaseResource<SqliteDb>>(rid)
+        .unwrap()
+        .db
+        .clone()
+    };
+    let close = |rid| state.borrow_mut().resource_table.close(rid).unwrap();
+
+    // Every open of the file shares one database, and so one connection and
+    // one expiration watcher.
+    let mut rids = vec![];
+    for _ in 0..5 {
+      rids.push(open(&path).await.unwrap());
+    }
+    for rid in &rids[1..] {
+      assert!(Rc::ptr_eq(&db(rids[0]), &db(*rid)));
+    }
+
+    // The shared database counts once against the cap, and in-memory
+    // databases are never shared.
+    let memory = open(":memory:").await.unwrap();
+    assert!(!Rc::ptr_eq(&db(rids[0]), &db(memory)));
+    let err = open(":memory:").await.unwrap_err();
+    assert_eq!(err.to_string(), "too many open databases (max 2)");
+    let last = open(&path).await.unwrap();
+
+    // The database stays open until its last resource is closed.
+    for rid in rids {
+      close(rid);
+    }
+    let write = AtomicWrite {
+      checks: vec![],
+      mutations: vec![KvMutation {
+        key: b"a".to_vec(),
+        kind: MutationKind::Set(Value::U64(1)),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+      return_old: false,
+      dry_run: false,
+    };
+    let outcome = db(last).atomic_write(state.clone(), write).await.unwrap();
+    assert!(outcome.into_committed().is_some());
+    close(last);
+    let memory_2 = open(":memory:").await.unwrap();
+
+    close(memory);
+    close(memory_2);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn check_with_versionstamp_and_value() {
+    let versionstamp = || Some(b"00000000000000000001".to_vec().into());
+    let value = || Some(FromV8Value::Json("1".to_string()));
+    let key = || vec![AnyValue::String("a".to_string())];
+
+    let check: V8KvCheck = (key(), versionstamp(), None, None);
+    assert!(KvCheck::try_from(check).is_ok());
+    let check: V8KvCheck = (key(), None, value(), None);
+    assert!(KvCheck::try_from(check).is_ok());
+    let check: V8KvCheck = (key(), versionstamp(), value(), None);
+    let err = KvCheck::try_from(check).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "a check can't have both a versionstamp and a value"
+    );
+  }
+
+  #[test]
+  fn json_values_are_canonicalized() {
+    assert_eq!(
+      canonicalize_json(r#" { "b": [1, { "d": 2, "c": 3 }], "a": "x" } "#)
+        .unwrap(),
+      r#"{"a":"x","b":[1,{"c":3,"d":2}]}"#
+    );
+    assert_eq!(canonicalize_json("null").unwrap(), "null");
+    assert!(canonicalize_json("{").is_err());
+    assert!(canonicalize_json("").is_err());
+
+    let value = Value::try_from(FromV8Value::Json(r#"{"b":1,"a":2}"#.into()));
+    assert!(
+      matches!(value, Ok(Value::Json(text)) if text == r#"{"a":2,"b":1}"#)
+    );
+  }
+
+  #[test]
+  fn bytes_prefix_range() {
+    assert_eq!(bytes_prefix_end(&[1, 1, 2]), vec![1, 1, 3]);
+    assert_eq!(bytes_prefix_end(&[1, 1, 0xff, 0xff]), vec![1, 2]);
+    assert_eq!(bytes_prefix_end(&[1, 0xff, 0xff]), vec![2]);
+
+    // [bytes([1, 2])] without the terminator of the byte string.
+    let selector = RawSelector::Prefixed {
+      prefix: vec![1, 1, 2],
+      start: None,
+      end: None,
+      bytes_prefix: true,
+    };
+    assert_eq!(selector.common_prefix(), &[1, 1, 2]);
+    assert_eq!(selector.range_start_key(), vec![1, 1, 2]);
+    assert_eq!(selector.range_end_key(), vec![1, 1, 3]);
+
+    let string_prefix = vec![AnyValue::String("a".to_string())];
+    let Err(err) =
+      RawSelector::from_tuple(Some(string_prefix), None, None, true)
+    else {
+      panic!("bytesPrefix with a string part should be rejected");
+    };
+    assert_eq!(
+      err.to_string(),
+      "bytesPrefix requires a prefix whose last part is a Uint8Array"
+    );
+    assert!(RawSelector::from_tuple(Some(vec![]), None, None, true).is_err());
+    assert!(RawSelector::from_tuple(None, None, None, true).is_err());
+  }
+
+  #[test]
+  fn enqueue_limits() {
+    let enqueue = |backoff_schedule: Option<Vec<u32>>| Enqueue {
+      payload: vec![],
+      delay_ms: 0,
+      enqueue_at_ms: None,
+      group: None,
+      keys_if_undelivered: vec![],
+      backoff_schedule,
+    };
+    let check = |enqueue: Enqueue| check_enqueue_limits(&enqueue, 0);
+    let max = MAX_QUEUE_DELAY_MS as u32;
+    assert!(check(enqueue(None)).is_ok());
+    assert!(check(enqueue(Some(vec![max]))).is_ok());
+    assert!(check(enqueue(Some(vec![max + 1]))).is_err());
+    assert!(check(enqueue(Some(vec![u32::MAX]))).is_err());
+
+    let intervals = MAX_QUEUE_BACKOFF_INTERVALS;
+    assert!(check(enqueue(Some(vec![1; intervals]))).is_ok());
+    assert!(check(enqueue(Some(vec![1; intervals + 1]))).is_err());
+    assert!(check(enqueue(Some(vec![1; 10_000]))).is_err());
+
+    // Absolute times are bounded relative to the current time.
+    let now = 1_000_000;
+    let at = |enqueue_at_ms: u64| Enqueue {
+      enqueue_at_ms: Some(enqueue_at_ms),
+      ..enqueue(None)
+    };
+    let last = now + MAX_QUEUE_DELAY_MS;
+    assert!(check_enqueue_limits(&at(0), now).is_ok());
+    assert!(check_enqueue_limits(&at(last), now).is_ok());
+    assert!(check_enqueue_limits(&at(last + 1), now).is_err());
+
+    let group = |size: usize| Enqueue {
+      group: Some("g".repeat(size)),
+      ..enqueue(None)
+    };
+    let max = MAX_QUEUE_GROUP_SIZE_BYTES;
+    assert!(check_write_sizes(&[], &[], &[group(max)]).is_ok());
+    assert!(check_write_sizes(&[], &[], &[group(max + 1)]).is_err());
+  }
+
+  fn v8_mutations(key_len: usize) -> Vec<V8KvMutation> {
+    (0..MAX_MUTATIONS)
+      .map(|i| {
+        (
+          vec![
+            AnyValue::String("x".repeat(key_len)),
+            AnyValue::Number(i as f64),
+          ],
+          "sum".to_string(),
+          Some(FromV8Value::U64(num_bigint::BigInt::from(i).into())),
+          None,
+          None,
+        )
+      })
+      .collect()
+  }
+
+  fn convert_then_check(
+    mutations: Vec<V8KvMutation>,
+  ) -> Result<Vec<KvMutation>, AnyError> {
+    let mutations = mutations
+      .into_iter()
+      .map(|mutation| KvMutation::try_from((mutation, 0)))
+      .collect::<Result<Vec<_>, AnyError>>()?;
+    check_write_sizes(&[], &mutations, &[])?;
+    Ok(mutations)
+  }
+
+  fn convert_and_check(
+    mutations: Vec<V8KvMutation>,
+    limits: KvWriteLimits,
+  ) -> Result<Vec<KvMutation>, AnyError> {
+    let mut sizes = WriteSizes::new(limits);
+    let mutations = mutations
+      .into_iter()
+      .map(|mutation| {
+        let mutation = KvMutation::try_from((mutation, 0))?;
+        sizes.add_mutation(&mutation)?;
+        Ok(mutation)
+      })
+      .collect::<Result<Vec<_>, AnyError>>()?;
+    sizes.finish()?;
+    Ok(mutations)
+  }
+
+  /// Checking the sizes of a batch of the maximum number of mutations during
+  /// conversion gives the same results as checking them in a second pass.
+  #[test]
+  fn write_sizes_single_pass() {
+    let expected = convert_then_check(v8_mutations(8)).unwrap();
+    let actual =
+      convert_and_check(v8_mutations(8), KvWriteLimits::default()).unwrap();
+    assert_eq!(actual.len(), expected.len());
+
+    // Both agree on the limits.
+    let mut mutations = v8_mutations(8);
+    mutations[0].0[0] = AnyValue::String("x".repeat(3000));
+    assert!(convert_then_check(mutations).is_err());
+    let mut mutations = v8_mutations(8);
+    mutations[0].0[0] = AnyValue::String("x".repeat(3000));
+    assert!(convert_and_check(mutations, KvWriteLimits::default()).is_err());
+
+    // Trusted embedders can skip the limit on a single key, but not the
+    // limit on the total size of the keys.
+    let limits = KvWriteLimits {
+      skip_individual_limits: true,
+    };
+    let mut mutations = v8_mutations(8);
+    mutations[0].0[0] = AnyValue::String("x".repeat(3000));
+    assert!(convert_and_check(mutations, limits).is_ok());
+    assert!(convert_and_check(v8_mutations(100), limits).is_err());
+  }
+
+  #[tokio::test]
+  async fn failed_check_records_commit_conflict() {
+    let metrics = Arc::new(InMemoryKvMetrics::default());
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    state.borrow_mut().put(KvMetricsHook(metrics.clone()));
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .open(state.clone(), None)
+      .await
+      .unwrap();
+
+    let write = |checks: Vec<KvCheck>| AtomicWrite {
+      checks,
+      mutations: vec![KvMutation {
+        key: b"a".to_vec(),
+        kind: MutationKind::Set(Value::Bytes(b"1".to_vec())),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+      return_old: false,
+      dry_run: false,
+    };
+
+    let committed =
+      atomic_write_with_metrics(state.clone(), &db, write(vec![]))
+        .await
+        .unwrap();
+    assert!(committed.into_committed().is_some());
+
+    // The key exists now, so a check for its absence fails.
+    let absent = KvCheck {
+      key: b"a".to_vec(),
+      kind: CheckKind::Versionstamp(None),
+    };
+    let conflicted =
+      atomic_write_with_metrics(state.clone(), &db, write(vec![absent]))
+        .await
+        .unwrap();
+    assert!(matches!(
+      conflicted,
+      CommitOutcome::CheckFailed {
+        failed_index: Some(0)
+      }
+    ));
+
+    assert_eq!(metrics.writes.load(Ordering::SeqCst), 1);
+    assert_eq!(metrics.commit_conflicts.load(Ordering::SeqCst), 1);
+  }
+}
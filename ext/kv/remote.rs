@@ -1,16 +1,25 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::cell::RefCell;
-use std::fmt;
+use std::io::Read;
 use std::io::Write;
 use std::marker::PhantomData;
+use std::path::Path;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::rc::Weak;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
 use crate::proto::datapath as pb;
 use crate::AtomicWrite;
+use crate::CheckKind;
+use crate::CommitOutcome;
 use crate::CommitResult;
+use crate::Consistency;
 use crate::Database;
 use crate::DatabaseHandler;
 use crate::KvEntry;
@@ -19,6 +28,7 @@ use crate::QueueMessageHandle;
 use crate::ReadRange;
 use crate::ReadRangeOutput;
 use crate::SnapshotReadOptions;
+use crate::DEFAULT_DISPATCH_CONCURRENCY_LIMIT;
 use anyhow::Context;
 use async_trait::async_trait;
 use chrono::DateTime;
@@ -27,18 +37,80 @@ use deno_core::error::type_error;
 use deno_core::error::AnyError;
 use deno_core::futures::TryFutureExt;
 use deno_core::unsync::JoinHandle;
+use deno_core::CancelFuture;
+use deno_core::CancelHandle;
 use deno_core::OpState;
 use prost::Message;
 use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 use serde::Deserialize;
-use termcolor::Ansi;
-use termcolor::Color;
-use termcolor::ColorSpec;
-use termcolor::WriteColor;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
 use tokio::sync::watch;
+use tokio::sync::Notify;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
 use url::Url;
 use uuid::Uuid;
 
+/// Request bodies smaller than this are sent uncompressed.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Header carrying the idempotency key of an atomic write. The key is also
+/// sent in the body, as `AtomicWrite.idempotency_key`. Every attempt at the
+/// same write carries the same key, and the server is expected to apply a
+/// write at most once per key: when it sees a key that it has already
+/// committed, it responds with the result of that commit instead of applying
+/// the mutations again.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Minimum time between two metadata refreshes forced by a rejected token.
+const MIN_FORCED_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time to wait before polling again after a dequeue returned no message.
+const DEQUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Environment variable that opts in to caching database metadata on disk.
+const METADATA_CACHE_ENV_VAR: &str = "DENO_KV_METADATA_CACHE";
+
+/// Environment variable that [EnvTokenProvider] reads access tokens from.
+const ACCESS_TOKEN_ENV_VAR: &str = "DENO_KV_ACCESS_TOKEN";
+
+/// Supplies the access tokens used to fetch the metadata of remote databases.
+/// It is consulted when a database is opened, and again every time its
+/// metadata is refreshed, so that it can hand out rotated tokens.
+pub trait TokenProvider: std::fmt::Debug + Send + Sync {
+  /// The environment variable that tokens are read from, if any, which the
+  /// permissions are checked for when a database is opened.
+  fn env_var(&self) -> Option<&str> {
+    None
+  }
+
+  /// Returns the access token for the database at `url`.
+  fn access_token(&self, url: &str) -> Result<String, AnyError>;
+}
+
+/// The default [TokenProvider], which uses the `DENO_KV_ACCESS_TOKEN`
+/// environment variable for every database.
+#[derive(Debug, Default)]
+pub struct EnvTokenProvider;
+
+impl TokenProvider for EnvTokenProvider {
+  fn env_var(&self) -> Option<&str> {
+    Some(ACCESS_TOKEN_ENV_VAR)
+  }
+
+  fn access_token(&self, _url: &str) -> Result<String, AnyError> {
+    std::env::var(ACCESS_TOKEN_ENV_VAR)
+      .map_err(anyhow::Error::from)
+      .with_context(|| {
+        "Missing DENO_KV_ACCESS_TOKEN environment variable. Please set it to your access token from https://dash.deno.com/account."
+      })
+  }
+}
+
 pub trait RemoteDbHandlerPermissions {
   fn check_env(&mut self, var: &str) -> Result<(), AnyError>;
   fn check_net_url(
@@ -48,13 +120,219 @@ pub trait RemoteDbHandlerPermissions {
   ) -> Result<(), AnyError>;
 }
 
+/// Configuration of the HTTP client used to talk to a remote database.
+#[derive(Clone, Debug)]
+pub struct RemoteDbConfig {
+  /// Timeout for establishing a connection to an endpoint.
+  pub connect_timeout: Duration,
+  /// Timeout for a single request, from connecting until the response body
+  /// has been read.
+  pub request_timeout: Duration,
+  /// How long an idle connection is kept open for reuse. `None` keeps idle
+  /// connections open indefinitely.
+  pub pool_idle_timeout: Option<Duration>,
+  /// Maximum number of idle connections kept open per host.
+  pub pool_max_idle_per_host: usize,
+  /// Directory in which database metadata is cached so that it survives
+  /// restarts. The cache is only used if the `DENO_KV_METADATA_CACHE`
+  /// environment variable is set.
+  pub metadata_cache_dir: Option<PathBuf>,
+  /// Compression applied to large request bodies, and accepted for response
+  /// bodies. `None` sends and accepts uncompressed bodies only.
+  pub compression: Option<RemoteDbCompression>,
+  /// URL of a proxy that all requests are sent through, except those to
+  /// hosts listed in the `NO_PROXY` environment variable. `None` uses the
+  /// proxies from the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY`
+  /// environment variables, if set.
+  pub proxy: Option<String>,
+  /// PEM-encoded certificates, one per entry, that are trusted as TLS roots
+  /// in addition to the built-in ones.
+  pub ca_certs: Vec<Vec<u8>>,
+  /// Headers sent with every request, such as those required by a corporate
+  /// gateway.
+  pub default_headers: HeaderMap,
+  /// How requests that fail with a server or network error, a timeout or
+  /// rate limiting, writes rejected while writes are disabled, and fetches of
+  /// the database metadata are retried.
+  pub retry_policy: RetryPolicy,
+  /// Supplies the access tokens of the databases that are opened.
+  pub token_provider: Arc<dyn TokenProvider>,
+}
+
+/// How failed requests to a remote database are retried. The delay before
+/// each retry doubles from `base_delay` up to `max_delay`, and is increased
+/// by a random fraction of up to `jitter` of itself. A request fails once
+/// either budget is used up. Retries are at least `MIN_RETRY_DELAY` apart,
+/// even if the delays are configured to be shorter.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+  /// Maximum number of attempts at a request, including the first one.
+  /// `None` doesn't limit the number of attempts.
+  pub max_attempts: Option<u32>,
+  /// Maximum time from the first attempt at a request until it is given up.
+  /// `None` doesn't limit the time spent retrying.
+  pub max_elapsed: Option<Duration>,
+  pub base_delay: Duration,
+  pub max_delay: Duration,
+  /// Between 0 and 1.
+  pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: None,
+      max_elapsed: Some(Duration::from_secs(60)),
+      base_delay: Duration::from_millis(100),
+      max_delay: Duration::from_secs(10),
+      jitter: 0.5,
+    }
+  }
+}
+
+/// Keeps a policy with zero delays and no budget from retrying a failing
+/// request in a busy loop.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+impl RetryPolicy {
+  /// Returns how long to wait before the next attempt, after `attempts`
+  /// failed ones that took `elapsed` so far, or `None` if the budget is used
+  /// up. The wait is at least `retry_after`, as requested by the server, and
+  /// the attempt is given up if the budget doesn't allow for that.
+  fn next_delay(
+    &self,
+    attempts: u32,
+    elapsed: Duration,
+    retry_after: Option<Duration>,
+  ) -> Option<Duration> {
+    if self.max_attempts.is_some_and(|max| attempts >= max) {
+      return None;
+    }
+    let remaining = match self.max_elapsed {
+      Some(max) if elapsed >= max => return None,
+      Some(max) => max - elapsed,
+      None => Duration::MAX,
+    };
+    let retry_after = retry_after.unwrap_or_default();
+    if retry_after > remaining {
+      return None;
+    }
+    let exponent = attempts.saturating_sub(1).min(31);
+    let delay = self
+      .base_delay
+      .saturating_mul(1 << exponent)
+      .min(self.max_delay);
+    let jitter = if self.jitter > 0.0 {
+      delay.mul_f64(rand::thread_rng().gen_range(0.0..=self.jitter.min(1.0)))
+    } else {
+      Duration::ZERO
+    };
+    Some(
+      (delay + jitter)
+        .max(retry_after)
+        .max(MIN_RETRY_DELAY)
+        .min(remaining),
+    )
+  }
+}
+
+/// How a ping is retried, regardless of the configured policy. A ping checks
+/// whether the database can be reached right now, so it should report an
+/// unreachable database promptly rather than wait out the full retry budget.
+const PING_RETRY_POLICY: RetryPolicy = RetryPolicy {
+  max_attempts: Some(2),
+  max_elapsed: Some(Duration::from_secs(2)),
+  base_delay: Duration::from_millis(100),
+  max_delay: Duration::from_millis(500),
+  jitter: 0.5,
+};
+
+/// A content coding for datapath request and response bodies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RemoteDbCompression {
+  Gzip,
+  Zstd,
+}
+
+impl RemoteDbCompression {
+  fn content_encoding(self) -> &'static str {
+    match self {
+      RemoteDbCompression::Gzip => "gzip",
+      RemoteDbCompression::Zstd => "zstd",
+    }
+  }
+
+  fn compress(self, data: &[u8]) -> Result<Vec<u8>, AnyError> {
+    match self {
+      RemoteDbCompression::Gzip => {
+        let mut encoder = flate2::write::GzEncoder::new(
+          Vec::new(),
+          flate2::Compression::default(),
+        );
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+      }
+      RemoteDbCompression::Zstd => Ok(zstd::encode_all(data, 0)?),
+    }
+  }
+}
+
+impl Default for RemoteDbConfig {
+  fn default() -> Self {
+    Self {
+      connect_timeout: Duration::from_secs(10),
+      request_timeout: Duration::from_secs(30),
+      pool_idle_timeout: Some(Duration::from_secs(90)),
+      pool_max_idle_per_host: usize::MAX,
+      metadata_cache_dir: None,
+      compression: None,
+      proxy: None,
+      ca_certs: vec![],
+      default_headers: HeaderMap::new(),
+      retry_policy: RetryPolicy::default(),
+      token_provider: Arc::new(EnvTokenProvider),
+    }
+  }
+}
+
+impl RemoteDbConfig {
+  fn build_client(&self) -> Result<reqwest::Client, AnyError> {
+    let mut builder = reqwest::Client::builder()
+      .connect_timeout(self.connect_timeout)
+      .timeout(self.request_timeout)
+      .pool_idle_timeout(self.pool_idle_timeout)
+      .pool_max_idle_per_host(self.pool_max_idle_per_host)
+      .default_headers(self.default_headers.clone());
+    if let Some(proxy) = &self.proxy {
+      let proxy = reqwest::Proxy::all(proxy)
+        .with_context(|| format!("Invalid KV proxy url: {proxy}"))?
+        .no_proxy(reqwest::NoProxy::from_env());
+      builder = builder.proxy(proxy);
+    }
+    for cert in &self.ca_certs {
+      let cert = reqwest::Certificate::from_pem(cert)
+        .context("Invalid KV CA certificate")?;
+      builder = builder.add_root_certificate(cert);
+    }
+    Ok(builder.build()?)
+  }
+}
+
 pub struct RemoteDbHandler<P: RemoteDbHandlerPermissions + 'static> {
+  config: RemoteDbConfig,
   _p: std::marker::PhantomData<P>,
 }
 
 impl<P: RemoteDbHandlerPermissions> RemoteDbHandler<P> {
   pub fn new() -> Self {
-    Self { _p: PhantomData }
+    Self::with_config(RemoteDbConfig::default())
+  }
+
+  pub fn with_config(config: RemoteDbConfig) -> Self {
+    Self {
+      config,
+      _p: PhantomData,
+    }
   }
 }
 
@@ -69,9 +347,8 @@ struct VersionInfo {
   version: u64,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-#[allow(dead_code)]
 struct DatabaseMetadata {
   version: u64,
   database_id: Uuid,
@@ -80,7 +357,7 @@ struct DatabaseMetadata {
   expires_at: DateTime<Utc>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EndpointInfo {
   pub url: String,
@@ -99,8 +376,6 @@ impl<P: RemoteDbHandlerPermissions> DatabaseHandler for RemoteDbHandler<P> {
     state: Rc<RefCell<OpState>>,
     path: Option<String>,
   ) -> Result<Self::DB, AnyError> {
-    const ENV_VAR_NAME: &str = "DENO_KV_ACCESS_TOKEN";
-
     let Some(url) = path else {
       return Err(type_error("Missing database url"));
     };
@@ -109,24 +384,49 @@ impl<P: RemoteDbHandlerPermissions> DatabaseHandler for RemoteDbHandler<P> {
       return Err(type_error(format!("Invalid database url: {}", url)));
     };
 
-    {
+    let cache_enabled = {
       let mut state = state.borrow_mut();
       let permissions = state.borrow_mut::<P>();
-      permissions.check_env(ENV_VAR_NAME)?;
+      if let Some(var) = self.config.token_provider.env_var() {
+        permissions.check_env(var)?;
+      }
       permissions.check_net_url(&parsed_url, "Deno.openKv")?;
-    }
+      // Caching stays off without permission to read the opt-in variable.
+      // The permission is only checked if the variable is set, so that
+      // programs that don't use the cache are never prompted for it.
+      self.config.metadata_cache_dir.is_some()
+        && std::env::var_os(METADATA_CACHE_ENV_VAR).is_some()
+        && permissions.check_env(METADATA_CACHE_ENV_VAR).is_ok()
+    };
 
-    let access_token = std::env::var(ENV_VAR_NAME)
-      .map_err(anyhow::Error::from)
-      .with_context(|| {
-        "Missing DENO_KV_ACCESS_TOKEN environment variable. Please set it to your access token from https://dash.deno.com/account."
-      })?;
+    // Fail right away if there is no token, rather than on the first request.
+    let access_token = self.config.token_provider.access_token(&url)?;
+
+    let metadata_cache = self
+      .config
+      .metadata_cache_dir
+      .as_deref()
+      .filter(|_| cache_enabled)
+      .map(|dir| MetadataCache::new(dir, &url, &access_token));
 
-    let refresher = MetadataRefresher::new(url, access_token);
+    let client = self.config.build_client()?;
+    let refresher = MetadataRefresher::new(
+      client.clone(),
+      url,
+      self.config.token_provider.clone(),
+      metadata_cache,
+      self.config.retry_policy,
+    );
 
     let db = RemoteDb {
-      client: reqwest::Client::new(),
-      refresher,
+      client,
+      compression: self.config.compression,
+      retry_policy: self.config.retry_policy,
+      refresher: Rc::new(refresher),
+      concurrency_limiter: Arc::new(Semaphore::new(
+        DEFAULT_DISPATCH_CONCURRENCY_LIMIT,
+      )),
+      cancel_handle: CancelHandle::new_rc(),
       _p: PhantomData,
     };
     Ok(db)
@@ -135,33 +435,77 @@ impl<P: RemoteDbHandlerPermissions> DatabaseHandler for RemoteDbHandler<P> {
 
 pub struct RemoteDb<P: RemoteDbHandlerPermissions + 'static> {
   client: reqwest::Client,
-  refresher: MetadataRefresher,
+  compression: Option<RemoteDbCompression>,
+  retry_policy: RetryPolicy,
+  refresher: Rc<MetadataRefresher>,
+  concurrency_limiter: Arc<Semaphore>,
+  cancel_handle: Rc<CancelHandle>,
   _p: std::marker::PhantomData<P>,
 }
 
-pub struct DummyQueueMessageHandle {}
+pub struct RemoteQueueMessageHandle<P: RemoteDbHandlerPermissions + 'static> {
+  state: Weak<RefCell<OpState>>,
+  client: reqwest::Client,
+  compression: Option<RemoteDbCompression>,
+  retry_policy: RetryPolicy,
+  refresher: Rc<MetadataRefresher>,
+  id: String,
+  payload: Option<Vec<u8>>,
+  _permit: OwnedSemaphorePermit,
+  _p: std::marker::PhantomData<P>,
+}
 
 #[async_trait(?Send)]
-impl QueueMessageHandle for DummyQueueMessageHandle {
+impl<P: RemoteDbHandlerPermissions> QueueMessageHandle
+  for RemoteQueueMessageHandle<P>
+{
   async fn take_payload(&mut self) -> Result<Vec<u8>, AnyError> {
-    unimplemented!()
+    self
+      .payload
+      .take()
+      .ok_or_else(|| type_error("Payload already consumed"))
   }
 
-  async fn finish(&self, _success: bool) -> Result<(), AnyError> {
-    unimplemented!()
+  async fn finish(&self, success: bool) -> Result<(), AnyError> {
+    // The runtime is shutting down. The message will be redelivered by the
+    // remote once its lease expires.
+    let Some(state) = self.state.upgrade() else {
+      return Ok(());
+    };
+    let req = pb::Ack {
+      id: self.id.clone(),
+      success,
+    };
+    let _: pb::AckOutput = call_remote::<P, _, _>(
+      &state,
+      &self.refresher,
+      &self.client,
+      self.compression,
+      self.retry_policy,
+      Consistency::Strong,
+      "ack",
+      &req,
+      None,
+    )
+    .await?;
+    Ok(())
   }
 }
 
 #[async_trait(?Send)]
 impl<P: RemoteDbHandlerPermissions> Database for RemoteDb<P> {
-  type QMH = DummyQueueMessageHandle;
+  type QMH = RemoteQueueMessageHandle<P>;
 
   async fn snapshot_read(
     &self,
     state: Rc<RefCell<OpState>>,
     requests: Vec<ReadRange>,
-    _options: SnapshotReadOptions,
+    options: SnapshotReadOptions,
   ) -> Result<Vec<ReadRangeOutput>, AnyError> {
+    let limits = requests
+      .iter()
+      .map(|r| (r.limit, r.max_bytes))
+      .collect::<Vec<_>>();
     let req = pb::SnapshotRead {
       ranges: requests
         .into_iter()
@@ -178,8 +522,12 @@ impl<P: RemoteDbHandlerPermissions> Database for RemoteDb<P> {
       &state,
       &self.refresher,
       &self.client,
+      self.compression,
+      self.retry_policy,
+      options.consistency,
       "snapshot_read",
       &req,
+      None,
     )
     .await?;
 
@@ -190,67 +538,165 @@ impl<P: RemoteDbHandlerPermissions> Database for RemoteDb<P> {
     let out = res
       .ranges
       .into_iter()
-      .map(|r| {
-        Ok(ReadRangeOutput {
-          entries: r
-            .values
-            .into_iter()
-            .map(|e| {
-              let encoding = e.encoding();
-              Ok(KvEntry {
-                key: e.key,
-                value: decode_value(e.value, encoding)?,
-                versionstamp: <[u8; 10]>::try_from(&e.versionstamp[..])?,
-              })
+      .zip(limits)
+      .map(|(r, (limit, max_bytes))| {
+        let entries = r
+          .values
+          .into_iter()
+          .map(|e| {
+            let encoding = e.encoding();
+            Ok(KvEntry {
+              key: e.key,
+              value: decode_value(e.value, encoding)?,
+              versionstamp: <[u8; 10]>::try_from(&e.versionstamp[..])?,
+              // Zero means the entry has no expiration, as in `KvMutation`.
+              expire_at_ms: u64::try_from(e.expire_at_ms)
+                .ok()
+                .filter(|ms| *ms > 0),
+              commit_ms: None,
             })
-            .collect::<Result<_, AnyError>>()?,
-        })
+          })
+          .collect::<Result<_, AnyError>>()?;
+        // The data path protocol has no byte limit and doesn't report whether
+        // the range was exhausted, so both are derived here.
+        Ok(ReadRangeOutput::from_limited(entries, limit, max_bytes))
       })
       .collect::<Result<Vec<_>, AnyError>>()?;
     Ok(out)
   }
 
+  async fn ping(&self, state: Rc<RefCell<OpState>>) -> Result<(), AnyError> {
+    // Reading no ranges is enough to check that the metadata can be fetched,
+    // and that the endpoint accepts the token in it.
+    let _: pb::SnapshotReadOutput = call_remote::<P, _, _>(
+      &state,
+      &self.refresher,
+      &self.client,
+      self.compression,
+      PING_RETRY_POLICY,
+      Consistency::Strong,
+      "snapshot_read",
+      &pb::SnapshotRead { ranges: vec![] },
+      None,
+    )
+    .await?;
+    Ok(())
+  }
+
   async fn atomic_write(
     &self,
     state: Rc<RefCell<OpState>>,
     write: AtomicWrite,
-  ) -> Result<Option<CommitResult>, AnyError> {
-    if !write.enqueues.is_empty() {
-      return Err(type_error("Enqueue operations are not supported yet."));
+  ) -> Result<CommitOutcome, AnyError> {
+    if write.return_old {
+      return Err(type_error(
+        "Returning old values is not supported for remote KV databases",
+      ));
+    }
+    if write.dry_run {
+      return Err(type_error(
+        "Dry runs are not supported for remote KV databases",
+      ));
     }
 
+    // The data path protocol has no set if absent mutation, so each one is
+    // sent as a check that the key doesn't exist followed by a set.
+    let absent_checks = write
+      .mutations
+      .iter()
+      .filter(|m| matches!(m.kind, MutationKind::SetIfAbsent(_)))
+      .map(|m| crate::KvCheck {
+        key: m.key.clone(),
+        kind: CheckKind::Versionstamp(None),
+      })
+      .collect::<Vec<_>>();
+    // Generated once per write rather than per attempt, so that the server
+    // can tell a retry of a write that was already committed from a new
+    // write.
+    let idempotency_key = Uuid::new_v4().to_string();
     let req = pb::AtomicWrite {
       kv_checks: write
         .checks
         .into_iter()
+        .chain(absent_checks)
         .map(|x| {
+          let versionstamp = match x.kind {
+            CheckKind::Versionstamp(versionstamp) => versionstamp,
+            CheckKind::Value(_) => {
+              return Err(type_error(
+                "Value checks are not supported for remote KV databases",
+              ))
+            }
+            CheckKind::QueueRunning(_) => return Err(type_error(
+              "Queue running checks are not supported for remote KV databases",
+            )),
+          };
           Ok(pb::KvCheck {
             key: x.key,
-            versionstamp: x.versionstamp.unwrap_or([0u8; 10]).to_vec(),
+            versionstamp: versionstamp.unwrap_or([0u8; 10]).to_vec(),
           })
         })
         .collect::<anyhow::Result<_>>()?,
-      kv_mutations: write.mutations.into_iter().map(encode_mutation).collect(),
-      enqueues: vec![],
+      kv_mutations: write
+        .mutations
+        .into_iter()
+        .map(encode_mutation)
+        .collect::<Result<_, AnyError>>()?,
+      enqueues: write
+        .enqueues
+        .into_iter()
+        .map(encode_enqueue)
+        .collect::<Result<_, AnyError>>()?,
+      idempotency_key: idempotency_key.clone(),
     };
 
-    let res: pb::AtomicWriteOutput = call_remote::<P, _, _>(
-      &state,
-      &self.refresher,
-      &self.client,
-      "atomic_write",
-      &req,
-    )
-    .await?;
+    // Writes are disabled while the primary region of the database changes,
+    // so they are retried like server errors until that is done.
+    let start = Instant::now();
+    let mut attempts = 0u32;
+    let res = loop {
+      attempts += 1;
+      let res: pb::AtomicWriteOutput = call_remote::<P, _, _>(
+        &state,
+        &self.refresher,
+        &self.client,
+        self.compression,
+        self.retry_policy,
+        Consistency::Strong,
+        "atomic_write",
+        &req,
+        Some(&idempotency_key),
+      )
+      .await?;
+      if res.status() != pb::AtomicWriteStatus::AwWriteDisabled {
+        break res;
+      }
+      let retry_policy = self.retry_policy;
+      let Some(delay) =
+        retry_policy.next_delay(attempts, start.elapsed(), None)
+      else {
+        break res;
+      };
+      tokio::time::sleep(delay).await;
+    };
     match res.status() {
-      pb::AtomicWriteStatus::AwSuccess => Ok(Some(CommitResult {
-        versionstamp: if res.versionstamp.is_empty() {
-          Default::default()
-        } else {
-          res.versionstamp[..].try_into()?
-        },
-      })),
-      pb::AtomicWriteStatus::AwCheckFailure => Ok(None),
+      pb::AtomicWriteStatus::AwSuccess => {
+        Ok(CommitOutcome::Committed(CommitResult {
+          versionstamp: if res.versionstamp.is_empty() {
+            Default::default()
+          } else {
+            res.versionstamp[..].try_into()?
+          },
+          // The data path protocol doesn't report what the mutations did.
+          mutation_counts: None,
+          old_values: vec![],
+        }))
+      }
+      // Checks for `SetIfAbsent` mutations are sent after the explicit
+      // checks, which matches the order of `CommitOutcome::CheckFailed`.
+      pb::AtomicWriteStatus::AwCheckFailure => Ok(CommitOutcome::CheckFailed {
+        failed_index: res.failed_checks.iter().min().map(|x| *x as usize),
+      }),
       pb::AtomicWriteStatus::AwUnsupportedWrite => {
         Err(type_error("Unsupported write"))
       }
@@ -258,7 +704,6 @@ impl<P: RemoteDbHandlerPermissions> Database for RemoteDb<P> {
         Err(type_error("The database usage limit has been exceeded."))
       }
       pb::AtomicWriteStatus::AwWriteDisabled => {
-        // TODO: Auto retry
         Err(type_error("Writes are disabled for this database."))
       }
       pb::AtomicWriteStatus::AwUnspecified => {
@@ -272,28 +717,57 @@ impl<P: RemoteDbHandlerPermissions> Database for RemoteDb<P> {
 
   async fn dequeue_next_message(
     &self,
-    _state: Rc<RefCell<OpState>>,
+    state: Rc<RefCell<OpState>>,
   ) -> Result<Option<Self::QMH>, AnyError> {
-    let msg = "Deno.Kv.listenQueue is not supported for remote KV databases";
-    eprintln!("{}", yellow(msg));
-    deno_core::futures::future::pending().await
-  }
+    let dequeue = async {
+      let permit = self.concurrency_limiter.clone().acquire_owned().await?;
+      loop {
+        let res: pb::DequeueOutput = call_remote::<P, _, _>(
+          &state,
+          &self.refresher,
+          &self.client,
+          self.compression,
+          self.retry_policy,
+          Consistency::Strong,
+          "dequeue",
+          &pb::Dequeue {},
+          None,
+        )
+        .await?;
 
-  fn close(&self) {}
-}
+        // The dequeue endpoint long-polls, so an empty response only means
+        // that no message became ready before the server gave up waiting.
+        // A server that answers right away must not be polled in a hot loop
+        // though.
+        let Some(message) = res.message else {
+          tokio::time::sleep(DEQUEUE_POLL_INTERVAL).await;
+          continue;
+        };
+
+        return Ok(Some(RemoteQueueMessageHandle {
+          state: Rc::downgrade(&state),
+          client: self.client.clone(),
+          compression: self.compression,
+          retry_policy: self.retry_policy,
+          refresher: self.refresher.clone(),
+          id: message.id,
+          payload: Some(message.payload),
+          _permit: permit,
+          _p: PhantomData,
+        }));
+      }
+    };
 
-fn yellow<S: AsRef<str>>(s: S) -> impl fmt::Display {
-  if std::env::var_os("NO_COLOR").is_some() {
-    return String::from(s.as_ref());
+    match dequeue.or_cancel(self.cancel_handle.clone()).await {
+      Ok(res) => res,
+      // The database was closed.
+      Err(_) => Ok(None),
+    }
+  }
+
+  fn close(&self) {
+    self.cancel_handle.cancel();
   }
-  let mut style_spec = ColorSpec::new();
-  style_spec.set_fg(Some(Color::Yellow));
-  let mut v = Vec::new();
-  let mut ansi_writer = Ansi::new(&mut v);
-  ansi_writer.set_color(&style_spec).unwrap();
-  ansi_writer.write_all(s.as_ref().as_bytes()).unwrap();
-  ansi_writer.reset().unwrap();
-  String::from_utf8_lossy(&v).into_owned()
 }
 
 fn decode_value(
@@ -312,8 +786,8 @@ fn decode_value(
   }
 }
 
-fn encode_value(value: crate::Value) -> pb::KvValue {
-  match value {
+fn encode_value(value: crate::Value) -> Result<pb::KvValue, AnyError> {
+  Ok(match value {
     crate::Value::V8(data) => pb::KvValue {
       data,
       encoding: pb::KvValueEncoding::VeV8 as _,
@@ -326,46 +800,76 @@ fn encode_value(value: crate::Value) -> pb::KvValue {
       data: x.to_le_bytes().to_vec(),
       encoding: pb::KvValueEncoding::VeLe64 as _,
     },
-  }
+    crate::Value::Json(_) => {
+      return Err(type_error(
+        "JSON values are not supported for remote KV databases",
+      ))
+    }
+  })
 }
 
-fn encode_mutation(m: crate::KvMutation) -> pb::KvMutation {
+fn encode_mutation(m: crate::KvMutation) -> Result<pb::KvMutation, AnyError> {
   let key = m.key;
   let expire_at_ms =
     m.expire_at.and_then(|x| i64::try_from(x).ok()).unwrap_or(0);
 
-  match m.kind {
-    MutationKind::Set(x) => pb::KvMutation {
+  Ok(match m.kind {
+    MutationKind::Set(x) | MutationKind::SetIfAbsent(x) => pb::KvMutation {
       key,
-      value: Some(encode_value(x)),
+      value: Some(encode_value(x)?),
       mutation_type: pb::KvMutationType::MSet as _,
       expire_at_ms,
     },
     MutationKind::Delete => pb::KvMutation {
       key,
-      value: Some(encode_value(crate::Value::Bytes(vec![]))),
+      value: Some(encode_value(crate::Value::Bytes(vec![]))?),
       mutation_type: pb::KvMutationType::MClear as _,
       expire_at_ms,
     },
     MutationKind::Max(x) => pb::KvMutation {
       key,
-      value: Some(encode_value(x)),
+      value: Some(encode_value(x)?),
       mutation_type: pb::KvMutationType::MMax as _,
       expire_at_ms,
     },
     MutationKind::Min(x) => pb::KvMutation {
       key,
-      value: Some(encode_value(x)),
+      value: Some(encode_value(x)?),
       mutation_type: pb::KvMutationType::MMin as _,
       expire_at_ms,
     },
     MutationKind::Sum(x) => pb::KvMutation {
       key,
-      value: Some(encode_value(x)),
+      value: Some(encode_value(x)?),
       mutation_type: pb::KvMutationType::MSum as _,
       expire_at_ms,
     },
-  }
+    MutationKind::DeletePrefix => {
+      return Err(type_error(
+        "Prefix deletes are not supported for remote KV databases",
+      ))
+    }
+    MutationKind::Move { .. } => {
+      return Err(type_error(
+        "Move mutations are not supported for remote KV databases",
+      ))
+    }
+  })
+}
+
+fn encode_enqueue(e: crate::Enqueue) -> Result<pb::Enqueue, AnyError> {
+  let now = Utc::now().timestamp_millis();
+  crate::check_enqueue_limits(&e, u64::try_from(now)?)?;
+  let deadline_ms = match e.enqueue_at_ms {
+    Some(enqueue_at) => i64::try_from(enqueue_at)?,
+    None => now + i64::try_from(e.delay_ms)?,
+  };
+  Ok(pb::Enqueue {
+    payload: e.payload,
+    deadline_ms,
+    kv_keys_if_undelivered: e.keys_if_undelivered,
+    backoff_schedule: e.backoff_schedule.unwrap_or_default(),
+  })
 }
 
 #[derive(Clone)]
@@ -377,19 +881,77 @@ enum MetadataState {
 
 struct MetadataRefresher {
   metadata_rx: watch::Receiver<MetadataState>,
+  /// Wakes up the refresh task to fetch the metadata right away.
+  refresh_now: Rc<Notify>,
+  /// When `refresh` last woke up the refresh task.
+  last_forced_refresh: Cell<Option<Instant>>,
   handle: JoinHandle<()>,
 }
 
 impl MetadataRefresher {
-  pub fn new(url: String, access_token: String) -> Self {
-    let (tx, rx) = watch::channel(MetadataState::Pending);
-    let handle =
-      deno_core::unsync::spawn(metadata_refresh_task(url, access_token, tx));
+  pub fn new(
+    client: reqwest::Client,
+    url: String,
+    token_provider: Arc<dyn TokenProvider>,
+    cache: Option<MetadataCache>,
+    retry_policy: RetryPolicy,
+  ) -> Self {
+    // Seed the state from the cache, so that requests don't have to wait for
+    // the metadata to be fetched after a restart.
+    let cached = cache.as_ref().and_then(|cache| cache.load());
+    let cached_expires_at = cached.as_ref().map(|x| x.expires_at);
+    let initial_state = match cached {
+      Some(metadata) => MetadataState::Ready(Arc::new(metadata)),
+      None => MetadataState::Pending,
+    };
+    let (tx, rx) = watch::channel(initial_state);
+    let refresh_now = Rc::new(Notify::new());
+    let handle = deno_core::unsync::spawn(metadata_refresh_task(
+      client,
+      url,
+      token_provider,
+      cache,
+      cached_expires_at,
+      retry_policy,
+      refresh_now.clone(),
+      tx,
+    ));
     Self {
       handle,
+      refresh_now,
+      last_forced_refresh: Cell::new(None),
       metadata_rx: rx,
     }
   }
+
+  /// Fetches the metadata again because `stale` was rejected, typically
+  /// because its token expired, and waits until it has been replaced. Callers
+  /// that find the same stale metadata share a single fetch. A refresh within
+  /// `MIN_FORCED_REFRESH_INTERVAL` of the last one is skipped, so that a token
+  /// that keeps being rejected doesn't turn every request into a metadata
+  /// fetch.
+  async fn refresh(&self, stale: &Arc<DatabaseMetadata>) {
+    let mut metadata_rx = self.metadata_rx.clone();
+    let is_stale = matches!(
+      &*metadata_rx.borrow_and_update(),
+      MetadataState::Ready(x) if Arc::ptr_eq(x, stale)
+    );
+    if !is_stale {
+      return;
+    }
+    if self
+      .last_forced_refresh
+      .get()
+      .is_some_and(|at| at.elapsed() < MIN_FORCED_REFRESH_INTERVAL)
+    {
+      return;
+    }
+    self.last_forced_refresh.set(Some(Instant::now()));
+    self.refresh_now.notify_one();
+    // `changed()` only fails if the refresh task is gone, in which case the
+    // caller finds out from the current state.
+    let _ = metadata_rx.changed().await;
+  }
 }
 
 impl Drop for MetadataRefresher {
@@ -398,57 +960,176 @@ impl Drop for MetadataRefresher {
   }
 }
 
+/// On-disk cache of the metadata of a database, keyed by its metadata URL
+/// and the access token it was fetched with. The metadata carries a database
+/// token, so it must never be handed out to a caller with another access
+/// token. A token provider that returns a different token every time simply
+/// never hits the cache.
+struct MetadataCache {
+  path: PathBuf,
+}
+
+impl MetadataCache {
+  fn new(dir: &Path, metadata_url: &str, access_token: &str) -> Self {
+    let mut hasher = Sha256::new();
+    hasher.update(metadata_url.as_bytes());
+    hasher.update([0]);
+    hasher.update(access_token.as_bytes());
+    let hash = hex::encode(hasher.finalize());
+    Self {
+      path: dir.join(format!("{}.json", hash)),
+    }
+  }
+
+  /// Returns the cached metadata, if there is any that is well-formed and has
+  /// not expired yet.
+  fn load(&self) -> Option<DatabaseMetadata> {
+    let data = std::fs::read(&self.path).ok()?;
+    let metadata: DatabaseMetadata = match serde_json::from_slice(&data) {
+      Ok(x) => x,
+      Err(e) => {
+        log::debug!("Ignoring malformed metadata cache: {}", e);
+        return None;
+      }
+    };
+    let valid = metadata.version <= 1
+      && !metadata.token.is_empty()
+      && !metadata.endpoints.is_empty()
+      && metadata
+        .endpoints
+        .iter()
+        .all(|x| !x.consistency.is_empty() && Url::parse(&x.url).is_ok())
+      && metadata.expires_at > Utc::now();
+    valid.then_some(metadata)
+  }
+
+  fn store(&self, metadata: &DatabaseMetadata) -> Result<(), AnyError> {
+    if let Some(parent) = self.path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    // The metadata contains a token, so keep it private to the current user.
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(0o600);
+    }
+    // Write to a temporary file first so that concurrent readers never see a
+    // partially written cache.
+    let tmp_path = self.path.with_extension("json.tmp");
+    let mut file = options.open(&tmp_path)?;
+    std::io::Write::write_all(&mut file, &serde_json::to_vec(metadata)?)?;
+    drop(file);
+    std::fs::rename(&tmp_path, &self.path)?;
+    Ok(())
+  }
+}
+
+/// Returns how long to wait before refreshing metadata that expires at
+/// `expires_at`.
+fn metadata_refresh_interval(expires_at: DateTime<Utc>) -> Duration {
+  let ms_until_expire = u64::try_from(
+    expires_at
+      .timestamp_millis()
+      .saturating_sub(Utc::now().timestamp_millis()),
+  )
+  .unwrap_or_default();
+
+  // Refresh 10 minutes before expiry
+  // In case of buggy clocks, don't refresh more than once per minute
+  Duration::from_millis(ms_until_expire)
+    .saturating_sub(Duration::from_secs(600))
+    .max(Duration::from_secs(60))
+}
+
 async fn metadata_refresh_task(
+  client: reqwest::Client,
   metadata_url: String,
-  access_token: String,
+  token_provider: Arc<dyn TokenProvider>,
+  cache: Option<MetadataCache>,
+  cached_expires_at: Option<DateTime<Utc>>,
+  retry_policy: RetryPolicy,
+  refresh_now: Rc<Notify>,
   tx: watch::Sender<MetadataState>,
 ) {
-  let client = reqwest::Client::new();
+  if let Some(expires_at) = cached_expires_at {
+    tokio::select! {
+      _ = tokio::time::sleep(metadata_refresh_interval(expires_at)) => {}
+      _ = refresh_now.notified() => {}
+    }
+  }
+
   loop {
-    let mut attempt = 0u64;
+    let mut attempts = 0u32;
+    let mut start = Instant::now();
     let metadata = loop {
-      match fetch_metadata(&client, &metadata_url, &access_token).await {
-        Ok(Ok(x)) => break x,
-        Ok(Err(e)) => {
-          if tx.send(MetadataState::Invalid(e)).is_err() {
-            return;
+      attempts += 1;
+      let error =
+        match fetch_metadata(&client, &metadata_url, &*token_provider).await {
+          Ok(Ok(x)) => break x,
+          Ok(Err(e)) => {
+            if tx.send(MetadataState::Invalid(e)).is_err() {
+              return;
+            }
+            None
           }
+          Err(e) => {
+            log::error!("Failed to fetch database metadata: {}", e);
+            Some(e)
+          }
+        };
+      let elapsed = start.elapsed();
+      let delay = match retry_policy.next_delay(attempts, elapsed, None) {
+        Some(delay) => delay,
+        None => {
+          // Fail the requests waiting for the metadata, but keep trying in
+          // case the server recovers.
+          if let Some(e) = error {
+            let message = format!(
+              "Failed to fetch database metadata after {} attempts: {}",
+              attempts, e
+            );
+            if tx.send(MetadataState::Invalid(message)).is_err() {
+              return;
+            }
+          }
+          attempts = 0;
+          start = Instant::now();
+          retry_policy.max_delay.max(MIN_RETRY_DELAY)
         }
-        Err(e) => {
-          log::error!("Failed to fetch database metadata: {}", e);
-        }
-      }
-      randomized_exponential_backoff(Duration::from_secs(5), attempt).await;
-      attempt += 1;
+      };
+      tokio::time::sleep(delay).await;
     };
 
-    let ms_until_expire = u64::try_from(
-      metadata
-        .expires_at
-        .timestamp_millis()
-        .saturating_sub(Utc::now().timestamp_millis()),
-    )
-    .unwrap_or_default();
+    let interval = metadata_refresh_interval(metadata.expires_at);
 
-    // Refresh 10 minutes before expiry
-    // In case of buggy clocks, don't refresh more than once per minute
-    let interval = Duration::from_millis(ms_until_expire)
-      .saturating_sub(Duration::from_secs(600))
-      .max(Duration::from_secs(60));
+    if let Some(cache) = &cache {
+      if let Err(e) = cache.store(&metadata) {
+        log::warn!("Failed to write database metadata cache: {}", e);
+      }
+    }
 
     if tx.send(MetadataState::Ready(Arc::new(metadata))).is_err() {
       return;
     }
 
-    tokio::time::sleep(interval).await;
+    tokio::select! {
+      _ = tokio::time::sleep(interval) => {}
+      _ = refresh_now.notified() => {}
+    }
   }
 }
 
 async fn fetch_metadata(
   client: &reqwest::Client,
   metadata_url: &str,
-  access_token: &str,
+  token_provider: &dyn TokenProvider,
 ) -> anyhow::Result<Result<DatabaseMetadata, String>> {
+  let access_token = match token_provider.access_token(metadata_url) {
+    Ok(x) => x,
+    Err(e) => return Ok(Err(format!("Failed to get access token: {}", e))),
+  };
   let res = client
     .post(metadata_url)
     .header("authorization", format!("Bearer {}", access_token))
@@ -489,11 +1170,75 @@ async fn fetch_metadata(
   )
 }
 
-async fn randomized_exponential_backoff(base: Duration, attempt: u64) {
-  let attempt = attempt.min(12);
-  let delay = base.as_millis() as u64 + (2 << attempt);
-  let delay = delay + rand::thread_rng().gen_range(0..(delay / 2) + 1);
-  tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+/// Compresses an encoded request body if it is large enough to be worth it,
+/// returning the body along with its content encoding.
+fn encode_body(
+  body: Vec<u8>,
+  compression: Option<RemoteDbCompression>,
+) -> Result<(bytes::Bytes, Option<&'static str>), AnyError> {
+  match compression {
+    Some(compression) if body.len() >= COMPRESSION_THRESHOLD => {
+      let compressed = compression.compress(&body)?;
+      Ok((compressed.into(), Some(compression.content_encoding())))
+    }
+    _ => Ok((body.into(), None)),
+  }
+}
+
+/// Decompresses a response body according to its content encoding.
+fn decode_body<'a>(
+  content_encoding: Option<&str>,
+  body: &'a [u8],
+) -> Result<Cow<'a, [u8]>, AnyError> {
+  match content_encoding {
+    None | Some("identity") => Ok(Cow::Borrowed(body)),
+    Some("gzip") => {
+      let mut out = Vec::new();
+      flate2::read::GzDecoder::new(body).read_to_end(&mut out)?;
+      Ok(Cow::Owned(out))
+    }
+    Some("zstd") => Ok(Cow::Owned(zstd::decode_all(body)?)),
+    Some(x) => Err(type_error(format!("unsupported content encoding: {}", x))),
+  }
+}
+
+/// Selects the endpoint to send a request with the given consistency to.
+///
+/// Eventually consistent requests prefer an endpoint advertising `"eventual"`
+/// consistency, which is usually closer to the client, and fall back to a
+/// strongly consistent endpoint if there is none.
+fn select_endpoint(
+  metadata: &DatabaseMetadata,
+  consistency: Consistency,
+) -> Option<&EndpointInfo> {
+  let find =
+    |level: &str| metadata.endpoints.iter().find(|x| x.consistency == level);
+  match consistency {
+    Consistency::Strong => find("strong"),
+    Consistency::Eventual => find("eventual").or_else(|| find("strong")),
+  }
+}
+
+/// Whether a request that failed with `status` may succeed if it is sent
+/// again: timeouts, rate limiting and server errors. Other statuses, such as
+/// client errors or redirects that weren't followed, fail the request right
+/// away.
+fn is_retryable_status(status: StatusCode) -> bool {
+  status == StatusCode::REQUEST_TIMEOUT
+    || status == StatusCode::TOO_MANY_REQUESTS
+    || status.is_server_error()
+}
+
+/// Parses the value of a `Retry-After` header, which is either a number of
+/// seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+  let value = value.trim();
+  if let Ok(secs) = value.parse::<u64>() {
+    return Some(Duration::from_secs(secs));
+  }
+  let date = DateTime::parse_from_rfc2822(value).ok()?;
+  let ms = date.timestamp_millis() - Utc::now().timestamp_millis();
+  Some(Duration::from_millis(u64::try_from(ms).unwrap_or_default()))
 }
 
 async fn call_remote<
@@ -504,11 +1249,19 @@ async fn call_remote<
   state: &RefCell<OpState>,
   refresher: &MetadataRefresher,
   client: &reqwest::Client,
+  compression: Option<RemoteDbCompression>,
+  retry_policy: RetryPolicy,
+  consistency: Consistency,
   method: &str,
   req: &T,
+  idempotency_key: Option<&str>,
 ) -> anyhow::Result<R> {
-  let mut attempt = 0u64;
+  let (body, content_encoding) = encode_body(req.encode_to_vec(), compression)?;
+  let start = Instant::now();
+  let mut attempts = 0u32;
+  let mut refreshed_metadata = false;
   let res = loop {
+    attempts += 1;
     let mut metadata_rx = refresher.metadata_rx.clone();
     let metadata = loop {
       match &*metadata_rx.borrow() {
@@ -521,17 +1274,13 @@ async fn call_remote<
       // `unwrap()` never fails because `tx` is owned by the task held by `refresher`.
       metadata_rx.changed().await.unwrap();
     };
-    let Some(sc_endpoint) = metadata
-      .endpoints
-      .iter()
-      .find(|x| x.consistency == "strong")
-    else {
+    let Some(endpoint) = select_endpoint(&metadata, consistency) else {
       return Err(type_error(
         "No strong consistency endpoint is available for this database",
       ));
     };
 
-    let full_url = format!("{}/{}", sc_endpoint.url, method);
+    let full_url = format!("{}/{}", endpoint.url, method);
     {
       let parsed_url = Url::parse(&full_url)?;
       let mut state = state.borrow_mut();
@@ -539,39 +1288,84 @@ async fn call_remote<
       permissions.check_net_url(&parsed_url, "Deno.Kv")?;
     }
 
-    let res = client
+    let mut request = client
       .post(&full_url)
       .header("x-transaction-domain-id", metadata.database_id.to_string())
-      .header("authorization", format!("Bearer {}", metadata.token))
-      .body(req.encode_to_vec())
+      .header("authorization", format!("Bearer {}", metadata.token));
+    if let Some(content_encoding) = content_encoding {
+      request = request.header("content-encoding", content_encoding);
+    }
+    if let Some(idempotency_key) = idempotency_key {
+      request = request.header(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+    }
+    if let Some(compression) = compression {
+      request =
+        request.header("accept-encoding", compression.content_encoding());
+    }
+    let res = request
+      .body(body.clone())
       .send()
-      .map_err(anyhow::Error::from)
+      .map_err(|e| (anyhow::Error::from(e), None))
       .and_then(|x| async move {
-        if x.status().is_success() {
-          Ok(Ok(x.bytes().await?))
-        } else if x.status().is_client_error() {
-          Ok(Err((x.status(), x.text().await?)))
-        } else {
-          Err(anyhow::anyhow!(
-            "server error ({:?}): {}",
-            x.status(),
-            x.text().await?
+        let status = x.status();
+        if status.is_success() {
+          let content_encoding = x
+            .headers()
+            .get("content-encoding")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string());
+          let body = x.bytes().await.map_err(|e| (e.into(), None))?;
+          Ok(Ok((content_encoding, body)))
+        } else if is_retryable_status(status) {
+          let retry_after = x
+            .headers()
+            .get("retry-after")
+            .and_then(|x| x.to_str().ok())
+            .and_then(parse_retry_after);
+          let text = x.text().await.unwrap_or_default();
+          Err((
+            anyhow::anyhow!("retryable error ({:?}): {}", status, text),
+            retry_after,
           ))
+        } else {
+          let text = x.text().await.map_err(|e| (e.into(), None))?;
+          Ok(Err((status, text)))
         }
       })
       .await;
 
     match res {
+      // The token may have expired or been rotated, which fresh metadata
+      // fixes. That is only tried once, so that a token that is simply not
+      // allowed to access the database fails the request.
+      Ok(Err((status, _)))
+        if (status == StatusCode::UNAUTHORIZED
+          || status == StatusCode::FORBIDDEN)
+          && !refreshed_metadata =>
+      {
+        refreshed_metadata = true;
+        refresher.refresh(&metadata).await;
+      }
       Ok(x) => break x,
-      Err(e) => {
+      Err((e, retry_after)) => {
         log::error!("retryable error in {}: {}", method, e);
-        randomized_exponential_backoff(Duration::from_millis(0), attempt).await;
-        attempt += 1;
+        let Some(delay) =
+          retry_policy.next_delay(attempts, start.elapsed(), retry_after)
+        else {
+          return Err(anyhow::anyhow!(
+            "{} failed after {} attempts over {:?}: {}",
+            method,
+            attempts,
+            start.elapsed(),
+            e
+          ));
+        };
+        tokio::time::sleep(delay).await;
       }
     }
   };
 
-  let res = match res {
+  let (content_encoding, res) = match res {
     Ok(x) => x,
     Err((status, message)) => {
       return Err(type_error(format!(
@@ -580,6 +1374,12 @@ async fn call_remote<
       )))
     }
   };
+  let res = decode_body(content_encoding.as_deref(), &res).map_err(|e| {
+    type_error(format!(
+      "failed to decompress response from {}: {}",
+      method, e
+    ))
+  })?;
 
   match R::decode(&*res) {
     Ok(x) => Ok(x),
@@ -589,3 +1389,1034 @@ async fn call_remote<
     ))),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::marker::PhantomData;
+  use std::rc::Rc;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+  use std::time::Duration;
+
+  use chrono::Utc;
+  use deno_core::error::type_error;
+  use deno_core::error::AnyError;
+  use deno_core::CancelHandle;
+  use deno_core::OpState;
+  use tokio::sync::watch;
+  use tokio::sync::Notify;
+  use tokio::sync::Semaphore;
+  use url::Url;
+  use uuid::Uuid;
+
+  use crate::AtomicWrite;
+  use crate::CommitOutcome;
+  use crate::Consistency;
+  use crate::Database;
+  use crate::DatabaseHandler;
+  use crate::Enqueue;
+  use crate::KvMutation;
+  use crate::MutationKind;
+  use crate::Value;
+
+  use prost::Message;
+  use reqwest::header::HeaderMap;
+  use reqwest::StatusCode;
+
+  use super::decode_body;
+  use super::encode_body;
+  use super::is_retryable_status;
+  use super::parse_retry_after;
+  use super::select_endpoint;
+  use super::DatabaseMetadata;
+  use super::EndpointInfo;
+  use super::MetadataCache;
+  use super::MetadataRefresher;
+  use super::MetadataState;
+  use super::RemoteDb;
+  use super::RemoteDbCompression;
+  use super::RemoteDbConfig;
+  use super::RemoteDbHandler;
+  use super::RemoteDbHandlerPermissions;
+  use super::RetryPolicy;
+  use super::TokenProvider;
+  use super::COMPRESSION_THRESHOLD;
+  use super::MIN_RETRY_DELAY;
+  use crate::proto::datapath as pb;
+
+  #[tokio::test]
+  async fn request_timeout() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Accept connections, but never respond.
+    let _server = tokio::spawn(async move {
+      let mut conns = vec![];
+      loop {
+        let (conn, _) = listener.accept().await.unwrap();
+        conns.push(conn);
+      }
+    });
+
+    let config = RemoteDbConfig {
+      request_timeout: Duration::from_millis(200),
+      ..Default::default()
+    };
+    let client = config.build_client().unwrap();
+    let err = client
+      .post(format!("http://{}/snapshot_read", addr))
+      .send()
+      .await
+      .unwrap_err();
+    assert!(err.is_timeout());
+  }
+
+  #[tokio::test]
+  async fn proxy_and_default_headers() {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    // A proxy that records the head of the first request it receives.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let proxy = tokio::spawn(async move {
+      let (mut conn, _) = listener.accept().await.unwrap();
+      let mut head = Vec::new();
+      let mut buf = [0; 1024];
+      while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn.read(&mut buf).await.unwrap();
+        assert!(n > 0);
+        head.extend_from_slice(&buf[..n]);
+      }
+      conn
+        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+        .await
+        .unwrap();
+      String::from_utf8(head).unwrap()
+    });
+
+    let mut default_headers = HeaderMap::new();
+    default_headers.insert("x-gateway-token", "secret".parse().unwrap());
+    let config = RemoteDbConfig {
+      proxy: Some(format!("http://{addr}")),
+      default_headers,
+      ..Default::default()
+    };
+    let client = config.build_client().unwrap();
+    let res = client
+      .post("http://kv.invalid/snapshot_read")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let head = proxy.await.unwrap().to_lowercase();
+    assert!(head.starts_with("post http://kv.invalid/snapshot_read http/1.1"));
+    assert!(head.contains("\r\nx-gateway-token: secret\r\n"));
+  }
+
+  struct AllowAll;
+
+  impl RemoteDbHandlerPermissions for AllowAll {
+    fn check_env(&mut self, _var: &str) -> Result<(), AnyError> {
+      Ok(())
+    }
+
+    fn check_net_url(
+      &mut self,
+      _url: &Url,
+      _api_name: &str,
+    ) -> Result<(), AnyError> {
+      Ok(())
+    }
+  }
+
+  /// Reads one HTTP/1.1 request from `conn`, returning its head and body, or
+  /// `None` if the connection was closed.
+  async fn read_request(
+    conn: &mut tokio::net::TcpStream,
+  ) -> Option<(String, Vec<u8>)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = Vec::new();
+    let head_len = loop {
+      if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        break i + 4;
+      }
+      let mut chunk = [0; 1024];
+      let n = conn.read(&mut chunk).await.unwrap();
+      if n == 0 {
+        return None;
+      }
+      buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8(buf[..head_len].to_vec())
+      .unwrap()
+      .to_lowercase();
+    let content_length = head
+      .lines()
+      .find_map(|line| line.strip_prefix("content-length: "))
+      .map_or(0, |x| x.trim().parse::<usize>().unwrap());
+    let mut body = buf[head_len..].to_vec();
+    while body.len() < content_length {
+      let mut chunk = [0; 1024];
+      let n = conn.read(&mut chunk).await.unwrap();
+      assert!(n > 0);
+      body.extend_from_slice(&chunk[..n]);
+    }
+    Some((head, body))
+  }
+
+  #[tokio::test]
+  async fn atomic_write_retries_share_idempotency_key() {
+    use tokio::io::AsyncWriteExt;
+
+    // Fails the first attempt at every write with a server error, as if the
+    // response to a committed write had been lost, and records the
+    // idempotency key of every attempt.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let attempts = Arc::new(Mutex::new(Vec::<(String, String)>::new()));
+    let server_attempts = attempts.clone();
+    let _server = tokio::spawn(async move {
+      loop {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let attempts = server_attempts.clone();
+        tokio::spawn(async move {
+          while let Some((head, body)) = read_request(&mut conn).await {
+            let header = head
+              .lines()
+              .find_map(|line| line.strip_prefix("idempotency-key: "))
+              .unwrap_or_default()
+              .to_string();
+            let req = pb::AtomicWrite::decode(&*body).unwrap();
+            let first = {
+              let mut attempts = attempts.lock().unwrap();
+              attempts.push((header, req.idempotency_key.clone()));
+              attempts
+                .iter()
+                .filter(|(_, key)| *key == req.idempotency_key)
+                .count()
+                == 1
+            };
+            let response = if first {
+              b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n"
+                .to_vec()
+            } else {
+              let body = pb::AtomicWriteOutput {
+                status: pb::AtomicWriteStatus::AwSuccess as i32,
+                versionstamp: vec![0; 10],
+                primary_if_write_disabled: String::new(),
+                failed_checks: vec![],
+              }
+              .encode_to_vec();
+              let mut response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n",
+                body.len()
+              )
+              .into_bytes();
+              response.extend_from_slice(&body);
+              response
+            };
+            conn.write_all(&response).await.unwrap();
+          }
+        });
+      }
+    });
+
+    let mut metadata = test_metadata(chrono::Duration::hours(1));
+    metadata.endpoints[0].url = format!("http://{addr}");
+    let (metadata_tx, metadata_rx) =
+      watch::channel(MetadataState::Ready(Arc::new(metadata)));
+    let refresher = MetadataRefresher {
+      metadata_rx,
+      refresh_now: Rc::new(Notify::new()),
+      last_forced_refresh: Cell::new(None),
+      handle: deno_core::unsync::spawn(async move {
+        let _metadata_tx = metadata_tx;
+        std::future::pending::<()>().await
+      }),
+    };
+    let db = RemoteDb::<AllowAll> {
+      client: RemoteDbConfig::default().build_client().unwrap(),
+      compression: None,
+      retry_policy: RetryPolicy::default(),
+      refresher: Rc::new(refresher),
+      concurrency_limiter: Arc::new(Semaphore::new(1)),
+      cancel_handle: CancelHandle::new_rc(),
+      _p: PhantomData,
+    };
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    for _ in 0..2 {
+      let write = AtomicWrite {
+        checks: vec![],
+        mutations: vec![KvMutation {
+          key: b"counter".to_vec(),
+          kind: MutationKind::Sum(Value::U64(1)),
+          expire_at: None,
+        }],
+        enqueues: vec![],
+        return_old: false,
+        dry_run: false,
+      };
+      assert!(db
+        .atomic_write(state.clone(), write)
+        .await
+        .unwrap()
+        .into_committed()
+        .is_some());
+    }
+
+    let attempts = attempts.lock().unwrap();
+    assert_eq!(attempts.len(), 4);
+    for (header, key) in attempts.iter() {
+      assert_eq!(header, key);
+      assert!(Uuid::parse_str(key).is_ok());
+    }
+    // Both attempts at a write carry its key, and each write has its own.
+    assert_eq!(attempts[0].1, attempts[1].1);
+    assert_eq!(attempts[2].1, attempts[3].1);
+    assert_ne!(attempts[0].1, attempts[2].1);
+  }
+
+  #[test]
+  fn retry_policy_delays() {
+    let policy = RetryPolicy {
+      max_attempts: Some(5),
+      max_elapsed: Some(Duration::from_secs(1)),
+      base_delay: Duration::from_millis(100),
+      max_delay: Duration::from_millis(300),
+      jitter: 0.0,
+    };
+    let delay = |attempts| policy.next_delay(attempts, Duration::ZERO, None);
+    assert_eq!(delay(1), Some(Duration::from_millis(100)));
+    assert_eq!(delay(2), Some(Duration::from_millis(200)));
+    assert_eq!(delay(3), Some(Duration::from_millis(300)));
+    assert_eq!(delay(4), Some(Duration::from_millis(300)));
+    assert_eq!(delay(5), None);
+    // The last delay is cut short by the time budget.
+    assert_eq!(
+      policy.next_delay(1, Duration::from_millis(950), None),
+      Some(Duration::from_millis(50))
+    );
+    assert_eq!(policy.next_delay(1, Duration::from_secs(1), None), None);
+    // A delay asked for by the server is waited out if the budget allows it.
+    assert_eq!(
+      policy.next_delay(1, Duration::ZERO, Some(Duration::from_millis(500))),
+      Some(Duration::from_millis(500))
+    );
+    assert_eq!(
+      policy.next_delay(1, Duration::ZERO, Some(Duration::from_secs(2))),
+      None
+    );
+
+    let policy = RetryPolicy {
+      jitter: 0.5,
+      ..policy
+    };
+    for _ in 0..100 {
+      let delay = policy.next_delay(1, Duration::ZERO, None).unwrap();
+      assert!(delay >= Duration::from_millis(100));
+      assert!(delay <= Duration::from_millis(150));
+    }
+
+    // Zero delays are raised to the minimum.
+    let policy = RetryPolicy {
+      max_attempts: None,
+      max_elapsed: None,
+      base_delay: Duration::ZERO,
+      max_delay: Duration::ZERO,
+      jitter: 0.0,
+    };
+    assert_eq!(
+      policy.next_delay(1, Duration::ZERO, None),
+      Some(MIN_RETRY_DELAY)
+    );
+  }
+
+  /// Returns a database whose only endpoint is a server that fails every
+  /// request with a server error, along with the bodies of the requests it
+  /// got.
+  async fn failing_db(
+    retry_policy: RetryPolicy,
+  ) -> (RemoteDb<AllowAll>, Arc<Mutex<Vec<Vec<u8>>>>) {
+    scripted_db(
+      retry_policy,
+      vec![
+        b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n"
+          .to_vec(),
+      ],
+    )
+    .await
+  }
+
+  /// Returns a database whose only endpoint is a server that sends the given
+  /// raw responses in order, repeating the last one, along with the bodies of
+  /// the requests it got.
+  async fn scripted_db(
+    retry_policy: RetryPolicy,
+    responses: Vec<Vec<u8>>,
+  ) -> (RemoteDb<AllowAll>, Arc<Mutex<Vec<Vec<u8>>>>) {
+    use tokio::io::AsyncWriteExt;
+
+    let responses = Arc::new(responses);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let server_requests = requests.clone();
+    tokio::spawn(async move {
+      loop {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let requests = server_requests.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+          while let Some((_, body)) = read_request(&mut conn).await {
+            let index = {
+              let mut requests = requests.lock().unwrap();
+              requests.push(body);
+              (requests.len() - 1).min(responses.len() - 1)
+            };
+            conn.write_all(&responses[index]).await.unwrap();
+          }
+        });
+      }
+    });
+
+    let mut metadata = test_metadata(chrono::Duration::hours(1));
+    metadata.endpoints[0].url = format!("http://{addr}");
+    let (metadata_tx, metadata_rx) =
+      watch::channel(MetadataState::Ready(Arc::new(metadata)));
+    let refresher = MetadataRefresher {
+      metadata_rx,
+      refresh_now: Rc::new(Notify::new()),
+      last_forced_refresh: Cell::new(None),
+      handle: deno_core::unsync::spawn(async move {
+        let _metadata_tx = metadata_tx;
+        std::future::pending::<()>().await
+      }),
+    };
+    let db = RemoteDb::<AllowAll> {
+      client: RemoteDbConfig::default().build_client().unwrap(),
+      compression: None,
+      retry_policy,
+      refresher: Rc::new(refresher),
+      concurrency_limiter: Arc::new(Semaphore::new(1)),
+      cancel_handle: CancelHandle::new_rc(),
+      _p: PhantomData,
+    };
+    (db, requests)
+  }
+
+  fn atomic_write_response(status: pb::AtomicWriteStatus) -> Vec<u8> {
+    let body = pb::AtomicWriteOutput {
+      status: status as i32,
+      versionstamp: vec![0; 10],
+      primary_if_write_disabled: String::new(),
+      failed_checks: vec![],
+    }
+    .encode_to_vec();
+    let mut response =
+      format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", body.len())
+        .into_bytes();
+    response.extend_from_slice(&body);
+    response
+  }
+
+  fn counter_write() -> AtomicWrite {
+    AtomicWrite {
+      checks: vec![],
+      mutations: vec![KvMutation {
+        key: b"counter".to_vec(),
+        kind: MutationKind::Sum(Value::U64(1)),
+        expire_at: None,
+      }],
+      enqueues: vec![],
+      return_old: false,
+      dry_run: false,
+    }
+  }
+
+  #[tokio::test]
+  async fn retries_give_up_after_max_attempts() {
+    let (db, requests) = failing_db(RetryPolicy {
+      max_attempts: Some(3),
+      max_elapsed: None,
+      base_delay: Duration::from_millis(10),
+      max_delay: Duration::from_millis(10),
+      jitter: 0.0,
+    })
+    .await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(
+      Duration::from_secs(10),
+      db.atomic_write(state.clone(), counter_write()),
+    )
+    .await
+    .unwrap();
+    let Err(err) = res else {
+      panic!("the write should have failed");
+    };
+    assert!(err
+      .to_string()
+      .starts_with("atomic_write failed after 3 attempts"));
+    assert_eq!(requests.lock().unwrap().len(), 3);
+  }
+
+  #[tokio::test]
+  async fn ping_does_not_use_the_retry_policy() {
+    let (db, requests) = failing_db(RetryPolicy::default()).await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(Duration::from_secs(10), db.ping(state))
+      .await
+      .unwrap();
+    let Err(err) = res else {
+      panic!("the ping should have failed");
+    };
+    assert!(err
+      .to_string()
+      .starts_with("snapshot_read failed after 2 attempts"));
+    assert_eq!(requests.lock().unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn retries_give_up_after_max_elapsed() {
+    let (db, requests) = failing_db(RetryPolicy {
+      max_attempts: None,
+      max_elapsed: Some(Duration::from_millis(300)),
+      base_delay: Duration::from_millis(10),
+      max_delay: Duration::from_millis(50),
+      jitter: 0.5,
+    })
+    .await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(
+      Duration::from_secs(10),
+      db.atomic_write(state.clone(), counter_write()),
+    )
+    .await
+    .unwrap();
+    assert!(res.is_err());
+    assert!(requests.lock().unwrap().len() > 1);
+  }
+
+  #[tokio::test]
+  async fn retries_rate_limited_requests() {
+    let (db, requests) = scripted_db(
+      RetryPolicy::default(),
+      vec![
+        b"HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 0\r\n\r\n"
+          .to_vec(),
+        atomic_write_response(pb::AtomicWriteStatus::AwSuccess),
+      ],
+    )
+    .await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(
+      Duration::from_secs(10),
+      db.atomic_write(state.clone(), counter_write()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(res, CommitOutcome::Committed(_)));
+    assert_eq!(requests.lock().unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn client_errors_are_not_retried() {
+    let (db, requests) =
+      scripted_db(
+        RetryPolicy::default(),
+        vec![
+          b"HTTP/1.1 400 Bad Request\r\ncontent-length: 3\r\n\r\nbad".to_vec()
+        ],
+      )
+      .await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(
+      Duration::from_secs(10),
+      db.atomic_write(state.clone(), counter_write()),
+    )
+    .await
+    .unwrap();
+    assert!(res.is_err());
+    assert_eq!(requests.lock().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn retries_disabled_writes() {
+    let (db, requests) = scripted_db(
+      RetryPolicy {
+        base_delay: Duration::from_millis(10),
+        ..Default::default()
+      },
+      vec![
+        atomic_write_response(pb::AtomicWriteStatus::AwWriteDisabled),
+        atomic_write_response(pb::AtomicWriteStatus::AwSuccess),
+      ],
+    )
+    .await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(
+      Duration::from_secs(10),
+      db.atomic_write(state.clone(), counter_write()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(res, CommitOutcome::Committed(_)));
+    assert_eq!(requests.lock().unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn enqueues_are_encoded() {
+    let (db, requests) = scripted_db(
+      RetryPolicy::default(),
+      vec![atomic_write_response(pb::AtomicWriteStatus::AwSuccess)],
+    )
+    .await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let before = Utc::now().timestamp_millis();
+    let write = AtomicWrite {
+      enqueues: vec![Enqueue {
+        payload: b"msg".to_vec(),
+        delay_ms: 1000,
+        enqueue_at_ms: None,
+        group: None,
+        keys_if_undelivered: vec![b"undelivered".to_vec()],
+        backoff_schedule: Some(vec![10, 20]),
+      }],
+      ..counter_write()
+    };
+    tokio::time::timeout(
+      Duration::from_secs(10),
+      db.atomic_write(state.clone(), write),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let request = pb::AtomicWrite::decode(&requests[0][..]).unwrap();
+    assert_eq!(request.enqueues.len(), 1);
+    let enqueue = &request.enqueues[0];
+    assert_eq!(enqueue.payload, b"msg");
+    assert!(enqueue.deadline_ms >= before + 1000);
+    assert_eq!(
+      enqueue.kv_keys_if_undelivered,
+      vec![b"undelivered".to_vec()]
+    );
+    assert_eq!(enqueue.backoff_schedule, vec![10, 20]);
+  }
+
+  /// Starts a server that responds to every request with the JSON `body`,
+  /// returning its address and the heads of the requests it got.
+  async fn metadata_server(
+    body: &'static str,
+  ) -> (std::net::SocketAddr, Arc<Mutex<Vec<String>>>) {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let heads = Arc::new(Mutex::new(vec![]));
+    let server_heads = heads.clone();
+    tokio::spawn(async move {
+      loop {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let heads = server_heads.clone();
+        tokio::spawn(async move {
+          while let Some((head, _)) = read_request(&mut conn).await {
+            heads.lock().unwrap().push(head);
+            let response = format!(
+              "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
+              body.len(),
+              body
+            );
+            conn.write_all(response.as_bytes()).await.unwrap();
+          }
+        });
+      }
+    });
+    (addr, heads)
+  }
+
+  #[derive(Debug)]
+  struct StaticToken(&'static str);
+
+  impl TokenProvider for StaticToken {
+    fn access_token(&self, _url: &str) -> Result<String, AnyError> {
+      Ok(self.0.to_string())
+    }
+  }
+
+  #[tokio::test]
+  async fn ping_fails_with_invalid_metadata() {
+    let (addr, _) = metadata_server(r#"{"version":2}"#).await;
+
+    let client = RemoteDbConfig::default().build_client().unwrap();
+    let refresher = MetadataRefresher::new(
+      client.clone(),
+      format!("http://{addr}"),
+      Arc::new(StaticToken("token")),
+      None,
+      RetryPolicy::default(),
+    );
+    let db = RemoteDb::<AllowAll> {
+      client,
+      compression: None,
+      retry_policy: RetryPolicy::default(),
+      refresher: Rc::new(refresher),
+      concurrency_limiter: Arc::new(Semaphore::new(1)),
+      cancel_handle: CancelHandle::new_rc(),
+      _p: PhantomData,
+    };
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    let res = tokio::time::timeout(Duration::from_secs(10), db.ping(state))
+      .await
+      .unwrap();
+    let Err(err) = res else {
+      panic!("the ping should have failed");
+    };
+    assert!(err.to_string().contains("Unsupported metadata version: 2"));
+  }
+
+  #[tokio::test]
+  async fn token_provider_replaces_env_var() {
+    // Permissions that would fail the open if the environment variable was
+    // read.
+    struct NoEnv;
+
+    impl RemoteDbHandlerPermissions for NoEnv {
+      fn check_env(&mut self, var: &str) -> Result<(), AnyError> {
+        Err(type_error(format!("Reading {var} is not allowed")))
+      }
+
+      fn check_net_url(
+        &mut self,
+        _url: &Url,
+        _api_name: &str,
+      ) -> Result<(), AnyError> {
+        Ok(())
+      }
+    }
+
+    let (addr, heads) = metadata_server(r#"{"version":2}"#).await;
+    let handler = RemoteDbHandler::<NoEnv>::with_config(RemoteDbConfig {
+      token_provider: Arc::new(StaticToken("custom-token")),
+      ..Default::default()
+    });
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(NoEnv);
+
+    let db = handler
+      .open(state.clone(), Some(format!("http://{addr}")))
+      .await
+      .unwrap();
+    let res = tokio::time::timeout(Duration::from_secs(10), db.ping(state))
+      .await
+      .unwrap();
+    assert!(res.is_err());
+    let heads = heads.lock().unwrap();
+    assert!(!heads.is_empty());
+    assert!(heads[0].contains("\r\nauthorization: bearer custom-token\r\n"));
+  }
+
+  /// Returns a database whose metadata and endpoint are served by the same
+  /// server. Every metadata fetch hands out a new token, `token1`, `token2`
+  /// and so on, and the endpoint rejects the tokens that `rejects` returns
+  /// true for. Also returns the number of metadata fetches.
+  async fn rotating_token_db(
+    rejects: fn(&str) -> bool,
+  ) -> (RemoteDb<AllowAll>, Arc<Mutex<usize>>) {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metadata_fetches = Arc::new(Mutex::new(0));
+    let server_metadata_fetches = metadata_fetches.clone();
+    tokio::spawn(async move {
+      loop {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let metadata_fetches = server_metadata_fetches.clone();
+        tokio::spawn(async move {
+          while let Some((head, _)) = read_request(&mut conn).await {
+            let token = head
+              .split("\r\n")
+              .find_map(|line| line.strip_prefix("authorization: bearer "))
+              .unwrap_or_default()
+              .to_string();
+            let response = if head.starts_with("post /metadata ") {
+              let token = {
+                let mut metadata_fetches = metadata_fetches.lock().unwrap();
+                *metadata_fetches += 1;
+                format!("token{}", *metadata_fetches)
+              };
+              let mut metadata = test_metadata(chrono::Duration::hours(1));
+              metadata.endpoints[0].url = format!("http://{addr}");
+              metadata.token = token;
+              let body = serde_json::to_vec(&metadata).unwrap();
+              let mut response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n",
+                body.len()
+              )
+              .into_bytes();
+              response.extend_from_slice(&body);
+              response
+            } else if rejects(&token) {
+              b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n".to_vec()
+            } else {
+              let body = pb::SnapshotReadOutput::default().encode_to_vec();
+              let mut response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n",
+                body.len()
+              )
+              .into_bytes();
+              response.extend_from_slice(&body);
+              response
+            };
+            conn.write_all(&response).await.unwrap();
+          }
+        });
+      }
+    });
+
+    let client = RemoteDbConfig::default().build_client().unwrap();
+    let refresher = MetadataRefresher::new(
+      client.clone(),
+      format!("http://{addr}/metadata"),
+      Arc::new(StaticToken("token")),
+      None,
+      RetryPolicy::default(),
+    );
+    let db = RemoteDb::<AllowAll> {
+      client,
+      compression: None,
+      retry_policy: RetryPolicy::default(),
+      refresher: Rc::new(refresher),
+      concurrency_limiter: Arc::new(Semaphore::new(1)),
+      cancel_handle: CancelHandle::new_rc(),
+      _p: PhantomData,
+    };
+    (db, metadata_fetches)
+  }
+
+  #[tokio::test]
+  async fn unauthorized_requests_refresh_metadata() {
+    // The first metadata has a token that the endpoint rejects, and every
+    // later one has a token that it accepts.
+    let (db, metadata_fetches) =
+      rotating_token_db(|token| token == "token1").await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    tokio::time::timeout(Duration::from_secs(10), db.ping(state))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(*metadata_fetches.lock().unwrap(), 2);
+  }
+
+  #[tokio::test]
+  async fn unauthorized_requests_refresh_metadata_at_most_every_few_seconds() {
+    let (db, metadata_fetches) = rotating_token_db(|_| true).await;
+    let state = Rc::new(RefCell::new(OpState::new(0, None)));
+    state.borrow_mut().put(AllowAll);
+
+    for _ in 0..3 {
+      let res =
+        tokio::time::timeout(Duration::from_secs(10), db.ping(state.clone()))
+          .await
+          .unwrap();
+      assert!(res.is_err());
+    }
+    // The initial fetch, and a single refresh for the first rejection.
+    assert_eq!(*metadata_fetches.lock().unwrap(), 2);
+  }
+
+  #[test]
+  fn retryable_statuses() {
+    for status in [
+      StatusCode::REQUEST_TIMEOUT,
+      StatusCode::TOO_MANY_REQUESTS,
+      StatusCode::INTERNAL_SERVER_ERROR,
+      StatusCode::SERVICE_UNAVAILABLE,
+    ] {
+      assert!(is_retryable_status(status), "{status}");
+    }
+    for status in [
+      StatusCode::CONTINUE,
+      StatusCode::NOT_MODIFIED,
+      StatusCode::TEMPORARY_REDIRECT,
+      StatusCode::BAD_REQUEST,
+      StatusCode::NOT_FOUND,
+    ] {
+      assert!(!is_retryable_status(status), "{status}");
+    }
+  }
+
+  #[test]
+  fn parse_retry_after_values() {
+    assert_eq!(parse_retry_after("3"), Some(Duration::from_secs(3)));
+    assert_eq!(
+      parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+      Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon"), None);
+  }
+
+  #[test]
+  fn invalid_proxy_url() {
+    let config = RemoteDbConfig {
+      proxy: Some("not a url".to_string()),
+      ..Default::default()
+    };
+    assert!(config.build_client().is_err());
+  }
+
+  fn test_metadata(expires_in: chrono::Duration) -> DatabaseMetadata {
+    DatabaseMetadata {
+      version: 1,
+      database_id: Uuid::new_v4(),
+      endpoints: vec![EndpointInfo {
+        url: "http://localhost:4545/kv_blackhole".to_string(),
+        consistency: "strong".to_string(),
+      }],
+      token: "token".to_string(),
+      expires_at: Utc::now() + expires_in,
+    }
+  }
+
+  #[test]
+  fn metadata_cache() {
+    let dir = std::env::temp_dir()
+      .join(format!("deno_kv_metadata_cache_{}", Uuid::new_v4()));
+    let cache =
+      MetadataCache::new(&dir, "http://localhost:4545/kv_metadata", "a");
+    assert!(cache.load().is_none());
+
+    let metadata = test_metadata(chrono::Duration::hours(1));
+    cache.store(&metadata).unwrap();
+    let loaded = cache.load().unwrap();
+    assert_eq!(loaded.database_id, metadata.database_id);
+    assert_eq!(loaded.token, "token");
+
+    // A different metadata url does not share the cache.
+    let other = MetadataCache::new(&dir, "http://localhost:4545/other", "a");
+    assert!(other.load().is_none());
+
+    // Neither does a different access token, which must not get the
+    // database token fetched with the first one.
+    let other =
+      MetadataCache::new(&dir, "http://localhost:4545/kv_metadata", "b");
+    assert!(other.load().is_none());
+
+    // Expired metadata is ignored.
+    cache
+      .store(&test_metadata(chrono::Duration::hours(-1)))
+      .unwrap();
+    assert!(cache.load().is_none());
+
+    // Metadata without a token is ignored.
+    let mut metadata = test_metadata(chrono::Duration::hours(1));
+    metadata.token = String::new();
+    cache.store(&metadata).unwrap();
+    assert!(cache.load().is_none());
+
+    std::fs::write(&cache.path, b"{}").unwrap();
+    assert!(cache.load().is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn endpoint_selection() {
+    let endpoint = |url: &str, consistency: &str| EndpointInfo {
+      url: url.to_string(),
+      consistency: consistency.to_string(),
+    };
+    let mut metadata = test_metadata(chrono::Duration::hours(1));
+    metadata.endpoints = vec![
+      endpoint("https://eventual.example", "eventual"),
+      endpoint("https://strong.example", "strong"),
+      endpoint("https://unknown.example", "bounded"),
+    ];
+
+    let url = |consistency| {
+      select_endpoint(&metadata, consistency).map(|x| x.url.as_str())
+    };
+    assert_eq!(url(Consistency::Strong), Some("https://strong.example"));
+    assert_eq!(url(Consistency::Eventual), Some("https://eventual.example"));
+
+    // Eventual reads fall back to the strong endpoint.
+    metadata.endpoints.remove(0);
+    let url = |consistency| {
+      select_endpoint(&metadata, consistency).map(|x| x.url.as_str())
+    };
+    assert_eq!(url(Consistency::Strong), Some("https://strong.example"));
+    assert_eq!(url(Consistency::Eventual), Some("https://strong.example"));
+
+    // Strong requests never go to an eventual endpoint.
+    metadata.endpoints = vec![endpoint("https://eventual.example", "eventual")];
+    assert!(select_endpoint(&metadata, Consistency::Strong).is_none());
+  }
+
+  #[test]
+  fn body_compression() {
+    let req = pb::SnapshotRead {
+      ranges: (0..64u8)
+        .map(|i| pb::ReadRange {
+          start: vec![2, b'u', b's', b'e', b'r', b's', 0, 2, i, 0],
+          end: vec![2, b'u', b's', b'e', b'r', b's', 0, 2, i, 0xff],
+          limit: 100,
+          reverse: false,
+        })
+        .collect(),
+    };
+    let encoded = req.encode_to_vec();
+    assert!(encoded.len() > 2 * COMPRESSION_THRESHOLD);
+
+    for compression in [RemoteDbCompression::Gzip, RemoteDbCompression::Zstd] {
+      let (body, content_encoding) =
+        encode_body(encoded.clone(), Some(compression)).unwrap();
+      assert_eq!(content_encoding, Some(compression.content_encoding()));
+      assert!(body.len() < encoded.len());
+      let decoded = decode_body(content_encoding, &body).unwrap();
+      assert_eq!(pb::SnapshotRead::decode(&*decoded).unwrap(), req);
+    }
+
+    // Small bodies are sent as is.
+    let small = pb::SnapshotRead {
+      ranges: req.ranges[..1].to_vec(),
+    }
+    .encode_to_vec();
+    assert!(small.len() < COMPRESSION_THRESHOLD);
+    let (body, content_encoding) =
+      encode_body(small.clone(), Some(RemoteDbCompression::Zstd)).unwrap();
+    assert_eq!(content_encoding, None);
+    assert_eq!(&body[..], &small[..]);
+
+    // Compression is disabled by default.
+    let (_, content_encoding) = encode_body(encoded, None).unwrap();
+    assert_eq!(content_encoding, None);
+  }
+}
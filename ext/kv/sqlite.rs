@@ -1,22 +1,33 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
 use std::borrow::Cow;
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env::current_dir;
 use std::future::Future;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::ErrorKind;
+use std::io::Write;
 use std::marker::PhantomData;
+use std::num::NonZeroU32;
 use std::path::Path;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::rc::Weak;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::time::Duration;
-use std::time::SystemTime;
+use std::time::Instant;
 
 use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
 use deno_core::error::get_custom_error_class;
 use deno_core::error::type_error;
 use deno_core::error::AnyError;
@@ -29,55 +40,155 @@ use deno_core::OpState;
 use deno_node::PathClean;
 use rand::Rng;
 use rusqlite::params;
+use rusqlite::types::FromSql;
+use rusqlite::types::FromSqlError;
+use rusqlite::types::FromSqlResult;
+use rusqlite::types::ToSqlOutput;
+use rusqlite::types::ValueRef;
 use rusqlite::OpenFlags;
 use rusqlite::OptionalExtension;
 use rusqlite::Transaction;
+use rusqlite::TransactionBehavior;
+use serde::Deserialize;
+use serde::Serialize;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
 use tokio::sync::OnceCell;
 use tokio::sync::OwnedSemaphorePermit;
 use tokio::sync::Semaphore;
+use url::Url;
 use uuid::Uuid;
 
+use crate::check_backoff_schedule;
+use crate::coalesce_ranges;
+use crate::codec::decode_key;
+use crate::codec::encode_key;
 use crate::AtomicWrite;
+use crate::CheckKind;
+use crate::CheckpointResult;
+use crate::Clock;
+use crate::CommitOutcome;
 use crate::CommitResult;
+use crate::Consistency;
 use crate::Database;
 use crate::DatabaseHandler;
+use crate::DatabaseStats;
+use crate::DeadLetterMessage;
+use crate::DryRunResult;
+use crate::Enqueue;
+use crate::IntegrityProblem;
+use crate::IntegrityProblemKind;
+use crate::Key;
+use crate::KeyPart;
+use crate::KvClock;
 use crate::KvEntry;
+use crate::KvIndex;
+use crate::KvIndexExtractor;
+use crate::KvMetrics;
+use crate::KvMetricsHook;
+use crate::MaintenanceMode;
+use crate::MutationCounts;
 use crate::MutationKind;
 use crate::QueueMessageHandle;
+use crate::QueueStats;
 use crate::ReadRange;
 use crate::ReadRangeOutput;
 use crate::SnapshotReadOptions;
 use crate::Value;
+use crate::DEFAULT_DISPATCH_CONCURRENCY_LIMIT;
 
 const STATEMENT_INC_AND_GET_DATA_VERSION: &str =
   "update data_version set version = version + 1 where k = 0 returning version";
 const STATEMENT_KV_RANGE_SCAN: &str =
-  "select k, v, v_encoding, version from kv where k >= ? and k < ? order by k asc limit ?";
+  "select k, v, v_encoding, version, expiration_ms, commit_ms from kv where k >= ? and k < ? and (expiration_ms < 0 or expiration_ms > ?) order by k asc limit ?";
 const STATEMENT_KV_RANGE_SCAN_REVERSE: &str =
-  "select k, v, v_encoding, version from kv where k >= ? and k < ? order by k desc limit ?";
+  "select k, v, v_encoding, version, expiration_ms, commit_ms from kv where k >= ? and k < ? and (expiration_ms < 0 or expiration_ms > ?) order by k desc limit ?";
 const STATEMENT_KV_POINT_GET_VALUE_ONLY: &str =
-  "select v, v_encoding from kv where k = ?";
+  "select v, v_encoding from kv where k = ? and (expiration_ms < 0 or expiration_ms > ?)";
+const STATEMENT_KV_POINT_GET: &str =
+  "select v, v_encoding, version, expiration_ms, commit_ms from kv where k = ? and (expiration_ms < 0 or expiration_ms > ?)";
 const STATEMENT_KV_POINT_GET_VERSION_ONLY: &str =
-  "select version from kv where k = ?";
+  "select version from kv where k = ? and (expiration_ms < 0 or expiration_ms > ?)";
 const STATEMENT_KV_POINT_SET: &str =
-  "insert into kv (k, v, v_encoding, version, expiration_ms) values (:k, :v, :v_encoding, :version, :expiration_ms) on conflict(k) do update set v = :v, v_encoding = :v_encoding, version = :version, expiration_ms = :expiration_ms";
+  "insert into kv (k, v, v_encoding, version, expiration_ms, commit_ms) values (:k, :v, :v_encoding, :version, :expiration_ms, :commit_ms) on conflict(k) do update set v = :v, v_encoding = :v_encoding, version = :version, expiration_ms = :expiration_ms, commit_ms = :commit_ms";
+// Only overwrites an entry that has expired, so that it changes a row exactly
+// when the key is created as far as readers are concerned.
+const STATEMENT_KV_POINT_CREATE: &str =
+  "insert into kv (k, v, v_encoding, version, expiration_ms, commit_ms) values (:k, :v, :v_encoding, :version, :expiration_ms, :commit_ms) on conflict(k) do update set v = :v, v_encoding = :v_encoding, version = :version, expiration_ms = :expiration_ms, commit_ms = :commit_ms where kv.expiration_ms >= 0 and kv.expiration_ms <= :now";
 const STATEMENT_KV_POINT_DELETE: &str = "delete from kv where k = ?";
+const STATEMENT_KV_COMMIT_MS_AT_VERSION: &str = "select commit_ms from kv where version <= ? and commit_ms >= 0 order by version desc limit 1";
+const STATEMENT_KV_DELETE_EXPIRED: &str =
+  "delete from kv where expiration_ms >= 0 and expiration_ms <= ?";
+const STATEMENT_KV_RANGE_COUNT: &str =
+  "select count(*) from kv where k >= ? and k < ? and (expiration_ms < 0 or expiration_ms > ?)";
+const STATEMENT_KV_RANGE_COUNT_BOUNDED: &str =
+  "select count(*) from (select 1 from kv where k >= ? and k < ? and (expiration_ms < 0 or expiration_ms > ?) limit ?)";
+const STATEMENT_KV_RANGE_DELETE: &str = "delete from kv where k >= ? and k < ?";
+const STATEMENT_KV_RANGE_SCAN_ALL: &str =
+  "select k, v, v_encoding from kv where k >= ? and k < ?";
 
-const STATEMENT_QUEUE_ADD_READY: &str = "insert into queue (ts, id, data, backoff_schedule, keys_if_undelivered) values(?, ?, ?, ?, ?)";
-const STATEMENT_QUEUE_GET_NEXT_READY: &str = "select ts, id, data, backoff_schedule, keys_if_undelivered from queue where ts <= ? order by ts limit 100";
+const STATEMENT_INDEX_INSERT: &str =
+  "insert or ignore into kv_index (name, index_key, k) values (?, ?, ?)";
+const STATEMENT_INDEX_DELETE_KEY: &str = "delete from kv_index where k = ?";
+const STATEMENT_INDEX_DELETE_RANGE: &str =
+  "delete from kv_index where k >= ? and k < ?";
+const STATEMENT_INDEX_DELETE_NAME: &str = "delete from kv_index where name = ?";
+const STATEMENT_INDEX_DELETE_EXPIRED: &str = "delete from kv_index where k in (select k from kv where expiration_ms >= 0 and expiration_ms <= ?)";
+const STATEMENT_INDEX_DEF_LIST: &str =
+  "select name, prefix, version, stale from kv_index_def";
+const STATEMENT_INDEX_DEF_SET: &str = "insert into kv_index_def (name, prefix, version, stale) values (?, ?, ?, 0) on conflict (name) do update set prefix = excluded.prefix, version = excluded.version, stale = 0";
+const STATEMENT_INDEX_DEF_MARK_STALE: &str =
+  "update kv_index_def set stale = 1 where name = ? and stale = 0";
+const STATEMENT_INDEX_DEF_MARK_STALE_COVERING: &str = "update kv_index_def set stale = 1 where stale = 0 and length(?1) > length(prefix) and substr(?1, 1, length(prefix)) = prefix";
+const STATEMENT_INDEX_RANGE_SCAN: &str =
+  "select kv.k, kv.v, kv.v_encoding, kv.version, kv.expiration_ms, kv.commit_ms from kv_index join kv on kv.k = kv_index.k where kv_index.name = ? and kv_index.index_key >= ? and kv_index.index_key < ? and (kv.expiration_ms < 0 or kv.expiration_ms > ?) order by kv_index.index_key asc, kv_index.k asc limit ?";
+const STATEMENT_INDEX_RANGE_SCAN_REVERSE: &str =
+  "select kv.k, kv.v, kv.v_encoding, kv.version, kv.expiration_ms, kv.commit_ms from kv_index join kv on kv.k = kv_index.k where kv_index.name = ? and kv_index.index_key >= ? and kv_index.index_key < ? and (kv.expiration_ms < 0 or kv.expiration_ms > ?) order by kv_index.index_key desc, kv_index.k desc limit ?";
+
+const STATEMENT_QUEUE_ADD_READY: &str = "insert into queue (ts, id, data, backoff_schedule, keys_if_undelivered, enqueued_at, failures, group_key) values(?, ?, ?, ?, ?, ?, ?, ?)";
+const STATEMENT_QUEUE_GET_NEXT_READY: &str = "select ts, id, data, backoff_schedule, keys_if_undelivered, enqueued_at, failures, group_key from queue where ts <= ? order by ts limit 100";
+// Takes the oldest ready message of every group before the second oldest of
+// any group, and so on. Messages without a group form a group of their own.
+const STATEMENT_QUEUE_GET_NEXT_READY_FAIR: &str = "select ts, id, data, backoff_schedule, keys_if_undelivered, enqueued_at, failures, group_key from (select *, row_number() over (partition by group_key order by ts) as turn from queue where ts <= ?) order by turn, ts limit 100";
 const STATEMENT_QUEUE_GET_EARLIEST_READY: &str =
   "select ts from queue order by ts limit 1";
+const STATEMENT_QUEUE_COUNT_READY: &str = "select count(*) from queue";
+const STATEMENT_QUEUE_COUNT_RUNNING: &str =
+  "select count(*) from queue_running";
+const STATEMENT_KV_STATS: &str =
+  "select count(*), coalesce(sum(length(cast(v as blob))), 0) from kv";
+const STATEMENT_DB_FILE_BYTES: &str =
+  "select page_count * page_size from pragma_page_count(), pragma_page_size()";
 const STATEMENT_QUEUE_REMOVE_READY: &str = "delete from queue where id = ?";
-const STATEMENT_QUEUE_ADD_RUNNING: &str = "insert into queue_running (deadline, id, data, backoff_schedule, keys_if_undelivered) values(?, ?, ?, ?, ?)";
+const STATEMENT_QUEUE_ADD_RUNNING: &str = "insert into queue_running (deadline, id, data, backoff_schedule, keys_if_undelivered, enqueued_at, failures, group_key, delivery_id) values(?, ?, ?, ?, ?, ?, ?, ?, ?)";
 const STATEMENT_QUEUE_REMOVE_RUNNING: &str =
   "delete from queue_running where id = ?";
-const STATEMENT_QUEUE_GET_RUNNING_BY_ID: &str = "select deadline, id, data, backoff_schedule, keys_if_undelivered from queue_running where id = ?";
+const STATEMENT_QUEUE_REMOVE_RUNNING_DELIVERY: &str =
+  "delete from queue_running where id = ? and deadline = ?";
+const STATEMENT_QUEUE_EXTEND_RUNNING: &str =
+  "update queue_running set deadline = ? where id = ? and deadline = ?";
+const STATEMENT_QUEUE_HAND_OUT_RUNNING: &str = "update queue_running set deadline = ?, delivery_id = ? where id = ? and deadline = ? and delivery_id = ''";
+const STATEMENT_QUEUE_GET_RUNNING_BY_ID: &str = "select deadline, id, data, backoff_schedule, keys_if_undelivered, enqueued_at, failures, group_key, delivery_id from queue_running where id = ?";
+const STATEMENT_QUEUE_IS_RUNNING: &str =
+  "select 1 from queue_running where delivery_id = ? and delivery_id != ''";
 const STATEMENT_QUEUE_GET_RUNNING: &str =
   "select id from queue_running order by deadline limit 100";
+const STATEMENT_QUEUE_GET_RUNNING_PAST_DEADLINE: &str =
+  "select id from queue_running where deadline <= ? order by deadline limit 100";
+const STATEMENT_QUEUE_ADD_DEAD_LETTER: &str = "insert into queue_dead_letter (id, data, keys_if_undelivered, enqueued_at, failed_at, failure_count, group_key) values(?, ?, ?, ?, ?, ?, ?)";
+const STATEMENT_QUEUE_LIST_DEAD_LETTER: &str = "select id, data, enqueued_at, failed_at, failure_count from queue_dead_letter order by failed_at limit ?";
+const STATEMENT_QUEUE_GET_DEAD_LETTER_BY_ID: &str = "select data, keys_if_undelivered, enqueued_at, group_key from queue_dead_letter where id = ?";
+const STATEMENT_QUEUE_REMOVE_DEAD_LETTER: &str =
+  "delete from queue_dead_letter where id = ?";
+
+const STATEMENT_INTEGRITY_VERSION_AHEAD: &str = "select count(*), max(version), (select version from data_version where k = 0) from kv where version > (select version from data_version where k = 0)";
+const STATEMENT_INTEGRITY_STALE_RUNNING: &str =
+  "select count(*) from queue_running where deadline < ?";
+const STATEMENT_INTEGRITY_INVALID_EXPIRATION: &str =
+  "select count(*) from kv where expiration_ms < -1";
 
 const STATEMENT_CREATE_MIGRATION_TABLE: &str = "
 create table if not exists migration_state(
@@ -86,7 +197,7 @@ create table if not exists migration_state(
 )
 ";
 
-const MIGRATIONS: [&str; 3] = [
+const MIGRATIONS: [&str; 8] = [
   "
 create table data_version (
   k integer primary key,
@@ -125,31 +236,116 @@ alter table kv add column seq integer not null default 0;
 alter table data_version add column seq integer not null default 0;
 alter table kv add column expiration_ms integer not null default -1;
 create index kv_expiration_ms_idx on kv (expiration_ms);
+",
+  "
+alter table queue add column enqueued_at integer not null default 0;
+alter table queue add column failures integer not null default 0;
+alter table queue_running add column enqueued_at integer not null default 0;
+alter table queue_running add column failures integer not null default 0;
+create table queue_dead_letter (
+  id text not null primary key,
+  data blob not null,
+  keys_if_undelivered blob not null,
+  enqueued_at integer not null,
+  failed_at integer not null,
+  failure_count integer not null
+);
+create index queue_dead_letter_failed_at_idx on queue_dead_letter (failed_at);
+",
+  "
+alter table queue add column group_key text;
+alter table queue_running add column group_key text;
+alter table queue_dead_letter add column group_key text;
+",
+  "
+create table kv_index (
+  name text not null,
+  index_key blob not null,
+  k blob not null,
+
+  primary key (name, index_key, k)
+) without rowid;
+create index kv_index_k_idx on kv_index (k);
+create table kv_index_def (
+  name text not null primary key,
+  prefix blob not null,
+  version integer not null,
+  stale integer not null default 0
+);
+",
+  "
+alter table kv add column commit_ms integer not null default -1;
+create index kv_version_idx on kv (version);
+",
+  "
+alter table queue_running add column delivery_id text not null default '';
+create index queue_running_delivery_id_idx on queue_running (delivery_id);
 ",
 ];
 
-const DISPATCH_CONCURRENCY_LIMIT: usize = 100;
+const MAX_DELETE_PREFIX_ENTRIES: usize = 1000;
+const EXPORT_BATCH_SIZE: u32 = 1000;
 const DEFAULT_BACKOFF_SCHEDULE: [u32; 5] = [100, 1000, 5000, 30000, 60000];
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_PAGE_SIZE: u32 = 512;
+const MAX_PAGE_SIZE: u32 = 65536;
+/// The most writes that are committed together in one coalesced
+/// transaction, which is also how many may wait for the next one.
+const MAX_COALESCED_WRITES: usize = 1000;
+/// Each connection of the read pool has a page cache and file handles of its
+/// own.
+const MAX_READ_POOL_SIZE: usize = 32;
+
+const DEFAULT_EXPIRATION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_QUEUE_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_STUCK_MESSAGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// A queue message that has been running longer than this was most likely
+/// abandoned.
+const STALE_RUNNING_MESSAGE_THRESHOLD_MS: u64 = 60 * 60 * 1000;
+const MAX_INTEGRITY_CHECK_ERRORS: u32 = 100;
 
 const ERROR_USING_CLOSED_DATABASE: &str = "Attempted to use a closed database";
 
+/// Prefix of the path of a named in-memory database that is shared by all
+/// handles that open it within the process, e.g.
+/// `:memory:?cache=shared&name=foo`.
+const SHARED_MEMORY_PREFIX: &str = ":memory:?";
+
+/// Keeps a named shared in-memory database alive while any handle to it is
+/// open, since SQLite frees it as soon as its last connection closes.
+struct SharedMemoryDb {
+  _anchor: Mutex<rusqlite::Connection>,
+}
+
+type SharedMemoryDbs = Mutex<HashMap<String, std::sync::Weak<SharedMemoryDb>>>;
+
+static SHARED_MEMORY_DBS: OnceLock<SharedMemoryDbs> = OnceLock::new();
+
 #[derive(Clone)]
 struct ProtectedConn {
   guard: Rc<AsyncRefCell<()>>,
   conn: Arc<Mutex<Option<rusqlite::Connection>>>,
+  busy_timeout: Duration,
+  /// Receives the timings of the transactions on the connection, in addition
+  /// to them being logged.
+  tx_timing_sink: Option<TxTimingSink>,
 }
 
 #[derive(Clone)]
 struct WeakProtectedConn {
   guard: Weak<AsyncRefCell<()>>,
   conn: std::sync::Weak<Mutex<Option<rusqlite::Connection>>>,
+  busy_timeout: Duration,
+  tx_timing_sink: Option<TxTimingSink>,
 }
 
 impl ProtectedConn {
-  fn new(conn: rusqlite::Connection) -> Self {
+  fn new(conn: rusqlite::Connection, busy_timeout: Duration) -> Self {
     Self {
       guard: Rc::new(AsyncRefCell::new(())),
       conn: Arc::new(Mutex::new(Some(conn))),
+      busy_timeout,
+      tx_timing_sink: None,
     }
   }
 
@@ -157,6 +353,8 @@ impl ProtectedConn {
     WeakProtectedConn {
       guard: Rc::downgrade(&self.guard),
       conn: Arc::downgrade(&self.conn),
+      busy_timeout: self.busy_timeout,
+      tx_timing_sink: self.tx_timing_sink.clone(),
     }
   }
 }
@@ -165,41 +363,369 @@ impl WeakProtectedConn {
   fn upgrade(&self) -> Option<ProtectedConn> {
     let guard = self.guard.upgrade()?;
     let conn = self.conn.upgrade()?;
-    Some(ProtectedConn { guard, conn })
+    Some(ProtectedConn {
+      guard,
+      conn,
+      busy_timeout: self.busy_timeout,
+      tx_timing_sink: self.tx_timing_sink.clone(),
+    })
   }
 }
 
+/// Opens SQLite databases. The `with_*` builders that take a value with a
+/// limited range return an error for values outside of it, the others can't
+/// fail.
 pub struct SqliteDbHandler<P: SqliteDbHandlerPermissions + 'static> {
   pub default_storage_dir: Option<PathBuf>,
+  named_storage_dir: Option<PathBuf>,
+  dispatch_concurrency_limit: usize,
+  default_backoff_schedule: Option<Vec<u32>>,
+  max_delivery_attempts: Option<u64>,
+  fair_dequeue: bool,
+  queue_visibility_timeout: Duration,
+  busy_timeout: Duration,
+  expiration_sweep_interval: Duration,
+  read_only: bool,
+  indexes: Vec<KvIndex>,
+  page_size: Option<u32>,
+  cache_size_kib: Option<u64>,
+  mmap_size: Option<u64>,
+  durability: Option<SqliteDurability>,
+  read_pool_size: usize,
+  write_coalescing_window: Option<Duration>,
+  #[cfg(feature = "sqlcipher")]
+  encryption_key: Option<String>,
   _permissions: PhantomData<P>,
 }
 
+/// How hard SQLite tries to make commits survive a power loss or an
+/// operating system crash. Either way a crash of the process itself never
+/// loses a commit, and the database is never corrupted.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum SqliteDurability {
+  /// `PRAGMA synchronous = NORMAL`. In WAL mode commits are not synced to
+  /// disk, only checkpoints are, so the last commits before a power loss may
+  /// be rolled back. Commits are much cheaper in exchange.
+  Normal,
+  /// `PRAGMA synchronous = FULL`. Every commit syncs the write-ahead log to
+  /// disk before it returns.
+  Full,
+}
+
 pub trait SqliteDbHandlerPermissions {
   fn check_read(&mut self, p: &Path, api_name: &str) -> Result<(), AnyError>;
   fn check_write(&mut self, p: &Path, api_name: &str) -> Result<(), AnyError>;
 }
 
+type CheckPathFn = fn(&mut OpState, &Path, &str) -> Result<(), AnyError>;
+
+/// Filesystem permission checks for a `SqliteDb`, which unlike its handler is
+/// not generic over the permissions type.
+#[derive(Clone, Copy)]
+struct PathPermissions {
+  check_read: CheckPathFn,
+  check_write: CheckPathFn,
+}
+
 impl<P: SqliteDbHandlerPermissions> SqliteDbHandler<P> {
   pub fn new(default_storage_dir: Option<PathBuf>) -> Self {
     Self {
       default_storage_dir,
+      named_storage_dir: None,
+      dispatch_concurrency_limit: DEFAULT_DISPATCH_CONCURRENCY_LIMIT,
+      default_backoff_schedule: None,
+      max_delivery_attempts: None,
+      fair_dequeue: false,
+      queue_visibility_timeout: DEFAULT_QUEUE_VISIBILITY_TIMEOUT,
+      busy_timeout: DEFAULT_BUSY_TIMEOUT,
+      expiration_sweep_interval: DEFAULT_EXPIRATION_SWEEP_INTERVAL,
+      read_only: false,
+      indexes: Vec::new(),
+      page_size: None,
+      cache_size_kib: None,
+      mmap_size: None,
+      durability: None,
+      read_pool_size: 0,
+      write_coalescing_window: None,
+      #[cfg(feature = "sqlcipher")]
+      encryption_key: None,
       _permissions: PhantomData,
     }
   }
+
+  /// Sets the maximum number of queue messages that are dispatched to
+  /// `listenQueue` handlers concurrently. Defaults to 100.
+  pub fn with_dispatch_concurrency_limit(
+    mut self,
+    limit: usize,
+  ) -> Result<Self, AnyError> {
+    if limit == 0 {
+      return Err(type_error("Dispatch concurrency limit must be at least 1"));
+    }
+    self.dispatch_concurrency_limit = limit;
+    Ok(self)
+  }
+
+  /// Sets the backoff schedule of enqueued messages that don't specify their
+  /// own. Each entry is the delay in milliseconds before the next delivery
+  /// attempt after a failed one. `None` restores the built-in schedule. The
+  /// schedule is subject to the same limits as the one of an enqueue.
+  pub fn with_default_backoff_schedule(
+    mut self,
+    schedule: Option<Vec<u32>>,
+  ) -> Result<Self, AnyError> {
+    if let Some(schedule) = &schedule {
+      check_backoff_schedule(schedule)?;
+    }
+    self.default_backoff_schedule = schedule;
+    Ok(self)
+  }
+
+  /// Sets the maximum number of times a queue message is delivered. A message
+  /// that fails this many times is dead-lettered even if its backoff
+  /// schedule has intervals left. By default only the schedule applies.
+  pub fn with_max_delivery_attempts(
+    mut self,
+    attempts: u64,
+  ) -> Result<Self, AnyError> {
+    if attempts == 0 {
+      return Err(type_error("Max delivery attempts must be at least 1"));
+    }
+    self.max_delivery_attempts = Some(attempts);
+    Ok(self)
+  }
+
+  /// Dispatches ready queue messages round-robin across their groups instead
+  /// of strictly in the order they became ready, so that a burst of messages
+  /// in one group can't hold up the others. Messages without a group are
+  /// treated as one group. Disabled by default.
+  pub fn with_fair_dequeue(mut self, fair: bool) -> Self {
+    self.fair_dequeue = fair;
+    self
+  }
+
+  /// Sets how long a queue message may be running before it is considered
+  /// stuck, e.g. because its handler hangs or leaked the message. Stuck
+  /// messages are requeued as if their delivery had failed, so they may be
+  /// delivered again while the first delivery is still running. Defaults to
+  /// 30 minutes.
+  pub fn with_visibility_timeout(
+    mut self,
+    timeout: Duration,
+  ) -> Result<Self, AnyError> {
+    if timeout.is_zero() {
+      return Err(type_error("Visibility timeout must be greater than zero"));
+    }
+    self.queue_visibility_timeout = timeout;
+    Ok(self)
+  }
+
+  /// Sets how long an operation waits for a lock held by another connection
+  /// to the same database file before failing. Defaults to 5 seconds.
+  pub fn with_busy_timeout(mut self, timeout: Duration) -> Self {
+    self.busy_timeout = timeout;
+    self
+  }
+
+  /// Sets the base interval between sweeps for expired keys. Each sweep is
+  /// delayed by up to half the interval again to spread out the load of
+  /// multiple databases. Writes of keys that expire before the next sweep
+  /// bring it forward. Databases that have the same file open share its
+  /// sweeps, which then run at the shortest of their intervals. Defaults to
+  /// 60 seconds.
+  pub fn with_expiration_sweep_interval(mut self, interval: Duration) -> Self {
+    self.expiration_sweep_interval = interval;
+    self
+  }
+
+  /// Sets the directory of databases that are opened by name, so that
+  /// `Deno.openKv("mystore")` opens `<dir>/mystore.sqlite3`. A name consists
+  /// of ASCII letters, digits, `-` and `_`; anything else is still treated
+  /// as a path. The usual permission checks apply to the resolved path.
+  pub fn with_named_storage_dir(mut self, dir: PathBuf) -> Self {
+    self.named_storage_dir = Some(dir);
+    self
+  }
+
+  /// Opens database files read-only, for inspecting a database or reading
+  /// one on a read-only filesystem. Migrations are not run, so the database
+  /// must already have the schema version of this build. Only read
+  /// permission is required, writes and queue operations fail, and expired
+  /// keys are not swept. In-memory databases can't be opened read-only.
+  ///
+  /// Where the shared-memory and log files of WAL mode can't be created, as
+  /// on a read-only filesystem, the file is opened as immutable. Such a
+  /// database must not be written to while it is open, and changes that
+  /// were not checkpointed into the file before its last writer closed it
+  /// are not visible.
+  pub fn with_read_only(mut self, read_only: bool) -> Self {
+    self.read_only = read_only;
+    self
+  }
+
+  /// Registers a secondary index that is maintained by every atomic write
+  /// to the entries under its prefix, and that can be read with
+  /// `Deno.Kv.prototype.queryIndex`. The database stores the name, prefix
+  /// and version of each index, and an index is only rebuilt when a handle
+  /// that registers it is opened read-write with a different definition, so
+  /// bump `KvIndex::version` when changing an extractor. Handles that don't
+  /// register an index defined by another handle remove the entries of the
+  /// keys they write under its prefix and mark it stale, so it is rebuilt on
+  /// the next open of a handle that registers it. Only definitions stored
+  /// before a handle is opened are known to it. Keys written by undelivered
+  /// queue messages are likewise not indexed until the next rebuild.
+  pub fn with_index(mut self, index: KvIndex) -> Result<Self, AnyError> {
+    if index.name.is_empty() {
+      return Err(type_error("Index name cannot be empty"));
+    }
+    if self.indexes.iter().any(|i| i.name == index.name) {
+      return Err(type_error(format!(
+        "An index named '{}' is already registered",
+        index.name
+      )));
+    }
+    self.indexes.push(index);
+    Ok(self)
+  }
+
+  /// Sets the page size of new database files, in bytes. It must be a power
+  /// of two between 512 and 65536. Larger pages suit large values and long
+  /// range scans. The page size is fixed once a file is created, so this has
+  /// no effect on existing databases. Defaults to SQLite's default of 4096.
+  pub fn with_page_size(mut self, page_size: u32) -> Result<Self, AnyError> {
+    if !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+      || !page_size.is_power_of_two()
+    {
+      return Err(type_error(format!(
+        "Page size must be a power of two between {} and {}",
+        MIN_PAGE_SIZE, MAX_PAGE_SIZE
+      )));
+    }
+    self.page_size = Some(page_size);
+    Ok(self)
+  }
+
+  /// Sets the size of the page cache of each connection, in KiB. Defaults to
+  /// SQLite's default of 2000 KiB.
+  pub fn with_cache_size(mut self, kib: u64) -> Result<Self, AnyError> {
+    if kib == 0 || kib > i64::MAX as u64 {
+      return Err(type_error("Cache size must be at least 1 KiB"));
+    }
+    self.cache_size_kib = Some(kib);
+    Ok(self)
+  }
+
+  /// Sets how many bytes of database files are memory-mapped instead of read
+  /// with system calls, which speeds up reads of large databases at the cost
+  /// of address space. SQLite caps this at its compile-time maximum. Memory
+  /// mapping is disabled by default.
+  pub fn with_mmap_size(mut self, bytes: u64) -> Result<Self, AnyError> {
+    if bytes > i64::MAX as u64 {
+      return Err(type_error("Memory-mapped size is too large"));
+    }
+    self.mmap_size = Some(bytes);
+    Ok(self)
+  }
+
+  /// Sets the durability of commits. By default the `PRAGMA synchronous`
+  /// setting SQLite was built with applies, which is usually
+  /// [SqliteDurability::Full].
+  pub fn with_durability(mut self, durability: SqliteDurability) -> Self {
+    self.durability = Some(durability);
+    self
+  }
+
+  /// Opens this many extra read-only connections to each database file, to
+  /// serve eventually consistent reads. They run in parallel with each other
+  /// and with writes on the primary connection, instead of queueing behind
+  /// them. Strongly consistent reads, and all reads of in-memory databases,
+  /// still use the primary connection. At most 32 connections can be
+  /// pooled. Disabled by default.
+  pub fn with_read_pool_size(mut self, size: usize) -> Result<Self, AnyError> {
+    if size > MAX_READ_POOL_SIZE {
+      return Err(type_error(format!(
+        "Read pool size must be at most {}",
+        MAX_READ_POOL_SIZE
+      )));
+    }
+    self.read_pool_size = size;
+    Ok(self)
+  }
+
+  /// Commits independent writes that arrive within `window` of each other
+  /// in one transaction, which saves most of the per-commit overhead, the
+  /// sync to disk in particular, under many concurrent writes. Each write
+  /// still gets a versionstamp of its own, and its promise resolves once the
+  /// shared transaction has been committed. Only writes of a single `set` or
+  /// `delete` mutation are coalesced; writes with checks or enqueues, dry
+  /// runs and writes that return old values are committed on their own, and
+  /// may commit before coalesced writes that were issued earlier. Disabled
+  /// by default.
+  pub fn with_write_coalescing(mut self, window: Duration) -> Self {
+    self.write_coalescing_window = Some(window);
+    self
+  }
+
+  /// Encrypts database files opened by this handler with SQLCipher.
+  ///
+  /// The key is passed to `PRAGMA key` verbatim. A passphrase is stretched
+  /// into the actual cipher key with PBKDF2-HMAC-SHA512 (256,000 iterations
+  /// in SQLCipher 4), which makes every open noticeably slower. Callers that
+  /// already hold 32 bytes of key material should pass it as a raw key in
+  /// the form `x'<64 hex digits>'`, which skips key derivation. The same key
+  /// must be used every time the file is opened. In-memory databases are
+  /// never encrypted.
+  #[cfg(feature = "sqlcipher")]
+  pub fn with_encryption_key(mut self, key: String) -> Self {
+    self.encryption_key = Some(key);
+    self
+  }
 }
 
-#[async_trait(?Send)]
-impl<P: SqliteDbHandlerPermissions> DatabaseHandler for SqliteDbHandler<P> {
-  type DB = SqliteDb;
+/// The path passed to `Deno.openKv`, resolved by
+/// [SqliteDbHandler::resolve_path].
+struct ResolvedPath {
+  /// The file of a database name in the named storage directory.
+  named_path: Option<PathBuf>,
+  path: Option<String>,
+  shared_memory_name: Option<String>,
+  in_memory: bool,
+}
 
-  async fn open(
+impl<P: SqliteDbHandlerPermissions> SqliteDbHandler<P> {
+  /// Resolves the path passed to `Deno.openKv`, validates it and checks the
+  /// permissions for opening it.
+  fn resolve_path(
     &self,
-    state: Rc<RefCell<OpState>>,
+    state: &mut OpState,
     path: Option<String>,
-  ) -> Result<Self::DB, AnyError> {
+  ) -> Result<ResolvedPath, AnyError> {
+    let named_path = match (&self.named_storage_dir, &path) {
+      (Some(dir), Some(path)) => resolve_named_path(dir, path),
+      _ => None,
+    };
+    let path = match &named_path {
+      Some(named_path) => Some(named_path.to_string_lossy().into_owned()),
+      None => path,
+    };
+
+    let shared_memory_name = match &path {
+      Some(path) => parse_shared_memory_name(path)?,
+      None => None,
+    };
+
+    let read_only = self.read_only;
+    let in_memory = match &path {
+      Some(path) => path == ":memory:" || shared_memory_name.is_some(),
+      None => self.default_storage_dir.is_none(),
+    };
+    if read_only && in_memory {
+      return Err(type_error("In-memory databases can't be opened read-only"));
+    }
+
     // Validate path
     if let Some(path) = &path {
-      if path != ":memory:" {
+      if path != ":memory:" && shared_memory_name.is_none() {
         if path.is_empty() {
           return Err(type_error("Filename cannot be empty"));
         }
@@ -209,96 +735,440 @@ impl<P: SqliteDbHandlerPermissions> DatabaseHandler for SqliteDbHandler<P> {
           ));
         }
         let path = Path::new(path);
-        {
-          let mut state = state.borrow_mut();
-          let permissions = state.borrow_mut::<P>();
-          permissions.check_read(path, "Deno.openKv")?;
+        let permissions = state.borrow_mut::<P>();
+        permissions.check_read(path, "Deno.openKv")?;
+        if !read_only {
           permissions.check_write(path, "Deno.openKv")?;
         }
       }
     }
 
-    let (conn, queue_waker_key) = sqlite_retry_loop(|| {
-      let path = path.clone();
-      let default_storage_dir = self.default_storage_dir.clone();
-      async move {
-        spawn_blocking(move || {
-          let (conn, queue_waker_key) =
-            match (path.as_deref(), &default_storage_dir) {
-              (Some(":memory:"), _) | (None, None) => {
+    Ok(ResolvedPath {
+      named_path,
+      path,
+      shared_memory_name,
+      in_memory,
+    })
+  }
+}
+
+#[async_trait(?Send)]
+impl<P: SqliteDbHandlerPermissions> DatabaseHandler for SqliteDbHandler<P> {
+  type DB = SqliteDb;
+
+  async fn open(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: Option<String>,
+  ) -> Result<Self::DB, AnyError> {
+    let ResolvedPath {
+      named_path,
+      path,
+      shared_memory_name,
+      ..
+    } = self.resolve_path(&mut state.borrow_mut(), path)?;
+    let read_only = self.read_only;
+
+    let busy_timeout = self.busy_timeout;
+    let page_size = self.page_size;
+    let cache_size_kib = self.cache_size_kib;
+    let mmap_size = self.mmap_size;
+    let durability = self.durability;
+    #[cfg(feature = "sqlcipher")]
+    let encryption_key = self.encryption_key.clone();
+    let (conn, queue_waker_key, shared_memory) =
+      sqlite_retry_loop(busy_timeout, || {
+        let path = path.clone();
+        let shared_memory_name = shared_memory_name.clone();
+        let default_storage_dir = self.default_storage_dir.clone();
+        let named_path = named_path.clone();
+        #[cfg(feature = "sqlcipher")]
+        let encryption_key = encryption_key.clone();
+        async move {
+          spawn_blocking(move || {
+            if let Some(dir) = named_path.as_deref().and_then(Path::parent) {
+              if !read_only {
+                std::fs::create_dir_all(dir)?;
+              }
+            }
+            let mut shared_memory = None;
+            let (conn, queue_waker_key) = match (
+              shared_memory_name.as_deref(),
+              path.as_deref(),
+              &default_storage_dir,
+            ) {
+              (Some(name), _, _) => {
+                let (conn, shared) = open_shared_memory(name)?;
+                shared_memory = Some(shared);
+                (conn, Some(PathBuf::from(shared_memory_uri(name))))
+              }
+              (None, Some(":memory:"), _) | (None, None, None) => {
                 (rusqlite::Connection::open_in_memory()?, None)
               }
-              (Some(path), _) => {
-                let flags =
-                  OpenFlags::default().difference(OpenFlags::SQLITE_OPEN_URI);
+              (None, Some(path), _) => {
                 let resolved_path = canonicalize_path(&PathBuf::from(path))?;
                 (
-                  rusqlite::Connection::open_with_flags(path, flags)?,
+                  rusqlite::Connection::open_with_flags(
+                    path,
+                    file_open_flags(read_only),
+                  )?,
                   Some(resolved_path),
                 )
               }
-              (None, Some(path)) => {
-                std::fs::create_dir_all(path)?;
+              (None, None, Some(path)) => {
+                if !read_only {
+                  std::fs::create_dir_all(path)?;
+                }
                 let path = path.join("kv.sqlite3");
-                (rusqlite::Connection::open(path.clone())?, Some(path))
+                (
+                  rusqlite::Connection::open_with_flags(
+                    path.clone(),
+                    file_open_flags(read_only),
+                  )?,
+                  Some(path),
+                )
               }
             };
 
-          conn.pragma_update(None, "journal_mode", "wal")?;
+            // The key must be applied before anything reads from the file.
+            #[cfg(feature = "sqlcipher")]
+            if let (Some(key), Some(_), None) =
+              (&encryption_key, &queue_waker_key, &shared_memory)
+            {
+              apply_encryption_key(&conn, key)?;
+            }
 
-          Ok::<_, AnyError>((conn, queue_waker_key))
-        })
-        .await
-        .unwrap()
-      }
-    })
-    .await?;
-    let conn = ProtectedConn::new(conn);
-    SqliteDb::run_tx(conn.clone(), |tx| {
-      tx.execute(STATEMENT_CREATE_MIGRATION_TABLE, [])?;
-
-      let current_version: usize = tx
-        .query_row(
-          "select version from migration_state where k = 0",
-          [],
-          |row| row.get(0),
-        )
-        .optional()?
-        .unwrap_or(0);
-
-      for (i, migration) in MIGRATIONS.iter().enumerate() {
-        let version = i + 1;
-        if version > current_version {
-          tx.execute_batch(migration)?;
-          tx.execute(
-            "replace into migration_state (k, version) values(?, ?)",
-            [&0, &version],
-          )?;
+            let conn = match (&queue_waker_key, &shared_memory) {
+              (Some(path), None) if read_only && !can_read_wal(&conn) => {
+                let conn = open_immutable(path)?;
+                #[cfg(feature = "sqlcipher")]
+                if let Some(key) = &encryption_key {
+                  apply_encryption_key(&conn, key)?;
+                }
+                conn
+              }
+              _ => conn,
+            };
+
+            // Let SQLite itself wait for locks held by other connections.
+            conn.busy_timeout(busy_timeout)?;
+            // The page size only takes effect before the first table is
+            // created, and before switching to WAL mode, which writes the
+            // file header. It is silently ignored for existing files.
+            if let (Some(page_size), false) = (page_size, read_only) {
+              conn.pragma_update(None, "page_size", page_size)?;
+            }
+            // Switching the journal mode writes to the file.
+            if !read_only {
+              conn.pragma_update(None, "journal_mode", "wal")?;
+            }
+            // A negative cache size is in KiB rather than in pages.
+            if let Some(kib) = cache_size_kib {
+              conn.pragma_update(None, "cache_size", -(kib as i64))?;
+            }
+            if let Some(mmap_size) = mmap_size {
+              conn.pragma_update(None, "mmap_size", mmap_size as i64)?;
+            }
+            // Trades durability against power loss for cheaper commits, see
+            // `SqliteDurability`.
+            match durability {
+              Some(SqliteDurability::Normal) => {
+                conn.pragma_update(None, "synchronous", "normal")?
+              }
+              Some(SqliteDurability::Full) => {
+                conn.pragma_update(None, "synchronous", "full")?
+              }
+              None => {}
+            }
+
+            Ok::<_, AnyError>((conn, queue_waker_key, shared_memory))
+          })
+          .await
+          .unwrap()
         }
+      })
+      .await?;
+    let conn = ProtectedConn::new(conn, busy_timeout);
+    let indexes = self
+      .indexes
+      .iter()
+      .map(|index| {
+        Ok(EncodedIndex {
+          name: index.name.clone(),
+          prefix: encode_key(&index.prefix)?,
+          version: index.version,
+          extract: Some(index.extract.clone()),
+        })
+      })
+      .collect::<Result<Vec<_>, AnyError>>()?;
+    let mut indexes = Arc::new(indexes);
+    if read_only {
+      SqliteDb::run_tx("check_schema_version", conn.clone(), |tx| {
+        check_schema_version(&tx)
+      })
+      .await?;
+    } else {
+      // Shared in-memory databases are migrated once, when they are created.
+      let migrate = shared_memory.is_none();
+      let own_indexes = indexes.clone();
+      let other_indexes =
+        SqliteDb::run_write_tx("migrate", conn.clone(), move |tx| {
+          if migrate {
+            run_migrations(&tx)?;
+          }
+          let other_indexes = sync_indexes(&tx, &own_indexes)?;
+          tx.commit()?;
+          Ok(other_indexes)
+        })
+        .await?;
+      if !other_indexes.is_empty() {
+        indexes =
+          Arc::new(indexes.iter().cloned().chain(other_indexes).collect());
       }
+    }
 
-      tx.commit()?;
+    // The pool is opened once the schema is up to date. Shared in-memory
+    // databases are excluded because their connections share one cache and
+    // its table locks.
+    let read_pool = match (&queue_waker_key, &shared_memory) {
+      (Some(path), None) if self.read_pool_size > 0 => {
+        let path = path.clone();
+        let size = self.read_pool_size;
+        #[cfg(feature = "sqlcipher")]
+        let encryption_key = self.encryption_key.clone();
+        let conns = spawn_blocking(move || {
+          (0..size)
+            .map(|_| {
+              let conn = rusqlite::Connection::open_with_flags(
+                &path,
+                file_open_flags(true),
+              )?;
+              #[cfg(feature = "sqlcipher")]
+              if let Some(key) = &encryption_key {
+                apply_encryption_key(&conn, key)?;
+              }
+              conn.busy_timeout(busy_timeout)?;
+              if let Some(kib) = cache_size_kib {
+                conn.pragma_update(None, "cache_size", -(kib as i64))?;
+              }
+              if let Some(mmap_size) = mmap_size {
+                conn.pragma_update(None, "mmap_size", mmap_size as i64)?;
+              }
+              Ok(conn)
+            })
+            .collect::<Result<Vec<_>, AnyError>>()
+        })
+        .await
+        .unwrap()?;
+        conns
+          .into_iter()
+          .map(|conn| ReadSlot {
+            conn: ProtectedConn::new(conn, busy_timeout),
+            in_flight: Cell::new(0),
+          })
+          .collect()
+      }
+      _ => Vec::new(),
+    };
 
-      Ok(())
-    })
-    .await?;
+    let clock = KvClock::from_state(&state.borrow());
+    let metrics = KvMetricsHook::from_state(&state.borrow());
+    let (next_sweep_tx, expiration_watcher) = if read_only {
+      (Arc::new(watch::channel(u64::MAX).0), None)
+    } else {
+      let watcher = spawn_expiration_watcher(
+        queue_waker_key.as_deref(),
+        &conn,
+        clock.clone(),
+        self.expiration_sweep_interval,
+      );
+      (watcher.sweep.next_sweep_tx.clone(), Some(watcher))
+    };
+    let write_coalescer = self
+      .write_coalescing_window
+      .filter(|_| !read_only)
+      .map(|window| {
+        WriteCoalescer::spawn(&conn, clock.clone(), indexes.clone(), window)
+      });
 
-    let expiration_watcher = spawn(watch_expiration(conn.clone()));
+    let permissions = PathPermissions {
+      check_read: |state, path, api_name| {
+        state.borrow_mut::<P>().check_read(path, api_name)
+      },
+      check_write: |state, path, api_name| {
+        state.borrow_mut::<P>().check_write(path, api_name)
+      },
+    };
 
     Ok(SqliteDb {
       conn,
+      clock,
+      metrics,
       queue: OnceCell::new(),
+      dispatch_concurrency_limit: self.dispatch_concurrency_limit,
+      default_backoff_schedule: Arc::new(
+        self
+          .default_backoff_schedule
+          .clone()
+          .unwrap_or_else(|| DEFAULT_BACKOFF_SCHEDULE.to_vec()),
+      ),
+      max_delivery_attempts: self.max_delivery_attempts,
+      fair_dequeue: self.fair_dequeue,
+      queue_visibility_timeout: self.queue_visibility_timeout,
       queue_waker_key,
-      expiration_watcher,
+      expiration_watcher: RefCell::new(expiration_watcher),
+      next_sweep_tx,
+      write_coalescer: RefCell::new(write_coalescer),
+      permissions,
+      read_only,
+      indexes,
+      read_pool,
+      _shared_memory: shared_memory,
     })
   }
+
+  /// Database files are shared by their canonical path. In-memory databases
+  /// are never shared, so that every open of `:memory:` gets a fresh one.
+  fn shared_key(
+    &self,
+    state: &mut OpState,
+    path: Option<String>,
+  ) -> Result<Option<String>, AnyError> {
+    let resolved = self.resolve_path(state, path)?;
+    if resolved.in_memory {
+      return Ok(None);
+    }
+    let path = match (resolved.path, &self.default_storage_dir) {
+      (Some(path), _) => PathBuf::from(path),
+      (None, Some(dir)) => dir.join("kv.sqlite3"),
+      (None, None) => return Ok(None),
+    };
+    let path = canonicalize_path(&path)?;
+    Ok(Some(path.to_string_lossy().into_owned()))
+  }
+}
+
+/// Resolves `path` to a database file in `dir` if it is a database name,
+/// rather than a file path, `:memory:` or a URL.
+fn resolve_named_path(dir: &Path, path: &str) -> Option<PathBuf> {
+  let is_name = !path.is_empty()
+    && path
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+  is_name.then(|| dir.join(format!("{path}.sqlite3")))
+}
+
+fn file_open_flags(read_only: bool) -> OpenFlags {
+  let flags = OpenFlags::default().difference(OpenFlags::SQLITE_OPEN_URI);
+  if read_only {
+    flags.difference(
+      OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    ) | OpenFlags::SQLITE_OPEN_READ_ONLY
+  } else {
+    flags
+  }
+}
+
+/// Whether a read-only connection can read the database. Reading a database
+/// in WAL mode needs its shared-memory and log files, which a read-only
+/// connection creates next to the database if they don't exist, and which
+/// fails on a read-only filesystem.
+fn can_read_wal(conn: &rusqlite::Connection) -> bool {
+  let res = conn.query_row("pragma schema_version", [], |_| Ok(()));
+  !matches!(
+    res.as_ref().map_err(|e| e.sqlite_error_code()),
+    Err(Some(
+      rusqlite::ErrorCode::CannotOpen | rusqlite::ErrorCode::ReadOnly
+    ))
+  )
+}
+
+/// Opens the database file at `path` as immutable, which needs neither
+/// locks nor the shared-memory and log files of WAL mode. Changes in the
+/// write-ahead log that were not checkpointed into the file are not visible,
+/// and the file must not be changed while the connection is open.
+fn open_immutable(path: &Path) -> Result<rusqlite::Connection, AnyError> {
+  let path = std::fs::canonicalize(path)?;
+  let Ok(url) = Url::from_file_path(&path) else {
+    return Err(type_error(format!(
+      "Failed to open {} as immutable",
+      path.display()
+    )));
+  };
+  Ok(rusqlite::Connection::open_with_flags(
+    format!("{url}?immutable=1"),
+    file_open_flags(true) | OpenFlags::SQLITE_OPEN_URI,
+  )?)
 }
 
 pub struct SqliteDb {
   conn: ProtectedConn,
+  clock: Arc<dyn Clock>,
+  metrics: Arc<dyn KvMetrics>,
   queue: OnceCell<SqliteQueue>,
+  dispatch_concurrency_limit: usize,
+  default_backoff_schedule: Arc<Vec<u32>>,
+  max_delivery_attempts: Option<u64>,
+  fair_dequeue: bool,
+  queue_visibility_timeout: Duration,
   queue_waker_key: Option<PathBuf>,
-  expiration_watcher: deno_core::unsync::JoinHandle<()>,
+  /// `None` for read-only databases, and once the database is closed.
+  expiration_watcher: RefCell<Option<ExpirationWatcher>>,
+  /// Unix timestamp in milliseconds of the next expiration sweep.
+  next_sweep_tx: Arc<watch::Sender<u64>>,
+  /// `None` unless write coalescing is enabled, and once the database is
+  /// closed.
+  write_coalescer: RefCell<Option<WriteCoalescer>>,
+  permissions: PathPermissions,
+  read_only: bool,
+  indexes: Arc<Vec<EncodedIndex>>,
+  read_pool: Vec<ReadSlot>,
+  _shared_memory: Option<Arc<SharedMemoryDb>>,
+}
+
+/// A read-only connection of the read pool of a `SqliteDb`.
+struct ReadSlot {
+  conn: ProtectedConn,
+  /// The number of reads that are queued or running on the connection.
+  in_flight: Cell<usize>,
+}
+
+/// Counts a read as in flight on a `ReadSlot` until it is dropped, even if
+/// the read is cancelled.
+struct InFlightRead<'a>(&'a Cell<usize>);
+
+impl<'a> InFlightRead<'a> {
+  fn new(in_flight: &'a Cell<usize>) -> Self {
+    in_flight.set(in_flight.get() + 1);
+    Self(in_flight)
+  }
+}
+
+impl Drop for InFlightRead<'_> {
+  fn drop(&mut self) {
+    self.0.set(self.0.get() - 1);
+  }
+}
+
+/// A secondary index with its key prefix encoded.
+#[derive(Clone)]
+struct EncodedIndex {
+  name: String,
+  prefix: Vec<u8>,
+  version: u32,
+  /// `None` for an index that is defined in the database but not registered
+  /// with this handle. Its entries can't be computed here, so writes to the
+  /// keys it covers remove their entries and mark it stale instead, and it
+  /// is rebuilt when a handle that registers it is opened.
+  extract: Option<KvIndexExtractor>,
+}
+
+impl EncodedIndex {
+  /// Whether the key is under the prefix of the index. Like a prefix
+  /// selector, the prefix itself is not included.
+  fn covers(&self, key: &[u8]) -> bool {
+    key.len() > self.prefix.len() && key.starts_with(&self.prefix)
+  }
 }
 
 impl Drop for SqliteDb {
@@ -307,15 +1177,291 @@ impl Drop for SqliteDb {
   }
 }
 
+#[cfg(feature = "sqlcipher")]
+fn apply_encryption_key(
+  conn: &rusqlite::Connection,
+  key: &str,
+) -> Result<(), AnyError> {
+  conn.pragma_update(None, "key", key)?;
+  // SQLCipher doesn't validate the key until the first read, which otherwise
+  // fails with a generic "file is not a database" error.
+  match conn.query_row("select count(*) from sqlite_master", [], |_| Ok(())) {
+    Ok(()) => Ok(()),
+    Err(e)
+      if e.sqlite_error_code() == Some(rusqlite::ErrorCode::NotADatabase) =>
+    {
+      Err(type_error(
+        "Failed to decrypt the database: the encryption key is incorrect or the file is not encrypted",
+      ))
+    }
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// Reads the entries of a range that have not expired at `now`, stopping
+/// early once `max_bytes` of values have been read. One row past `limit` is
+/// queried to tell whether the range has more entries.
+fn read_range(
+  tx: &Transaction,
+  request: &ReadRange,
+  now: u64,
+) -> Result<ReadRangeOutput, AnyError> {
+  let mut stmt = tx.prepare_cached(if request.reverse {
+    STATEMENT_KV_RANGE_SCAN_REVERSE
+  } else {
+    STATEMENT_KV_RANGE_SCAN
+  })?;
+  let rows = stmt.query_map(
+    (
+      request.start.as_slice(),
+      request.end.as_slice(),
+      now,
+      request.limit.get().saturating_add(1),
+    ),
+    kv_entry_from_row,
+  )?;
+  collect_range(rows, request)
+}
+
+/// Reads the entries of the secondary index `name` whose index keys are in
+/// the range, in the same way as `read_range`.
+fn read_index_range(
+  tx: &Transaction,
+  name: &str,
+  request: &ReadRange,
+  now: u64,
+) -> Result<ReadRangeOutput, AnyError> {
+  let mut stmt = tx.prepare_cached(if request.reverse {
+    STATEMENT_INDEX_RANGE_SCAN_REVERSE
+  } else {
+    STATEMENT_INDEX_RANGE_SCAN
+  })?;
+  let rows = stmt.query_map(
+    (
+      name,
+      request.start.as_slice(),
+      request.end.as_slice(),
+      now,
+      request.limit.get().saturating_add(1),
+    ),
+    kv_entry_from_row,
+  )?;
+  collect_range(rows, request)
+}
+
+fn collect_range(
+  mut rows: impl Iterator<Item = rusqlite::Result<KvEntry>>,
+  request: &ReadRange,
+) -> Result<ReadRangeOutput, AnyError> {
+  let limit = request.limit.get() as usize;
+  let mut entries = Vec::new();
+  let mut total_bytes = 0usize;
+  for entry in &mut rows {
+    let entry = entry?;
+    if entries.len() == limit {
+      return Ok(ReadRangeOutput {
+        entries,
+        has_more: true,
+      });
+    }
+    total_bytes += entry.value.byte_size();
+    entries.push(entry);
+    if let Some(max_bytes) = request.max_bytes {
+      if total_bytes >= max_bytes.get() as usize {
+        let has_more = rows.next().is_some();
+        return Ok(ReadRangeOutput { entries, has_more });
+      }
+    }
+  }
+  Ok(ReadRangeOutput {
+    entries,
+    has_more: false,
+  })
+}
+
+fn schema_version(tx: &Transaction) -> Result<usize, AnyError> {
+  Ok(
+    tx.query_row(
+      "select version from migration_state where k = 0",
+      [],
+      |row| row.get(0),
+    )
+    .optional()?
+    .unwrap_or(0),
+  )
+}
+
+/// Checks that a database that can't be migrated, because it is opened
+/// read-only, has the schema that this build expects.
+fn check_schema_version(tx: &Transaction) -> Result<(), AnyError> {
+  let has_migration_table = tx
+    .query_row(
+      "select 1 from sqlite_master where type = 'table' and name = 'migration_state'",
+      [],
+      |_| Ok(()),
+    )
+    .optional()?
+    .is_some();
+  let version = if has_migration_table {
+    schema_version(tx)?
+  } else {
+    0
+  };
+  if version != MIGRATIONS.len() {
+    return Err(type_error(format!(
+      "Database schema version {} does not match the expected version {}; open it read-write once to migrate it",
+      version,
+      MIGRATIONS.len()
+    )));
+  }
+  Ok(())
+}
+
+fn run_migrations(tx: &Transaction) -> Result<(), AnyError> {
+  tx.execute(STATEMENT_CREATE_MIGRATION_TABLE, [])?;
+
+  let current_version = schema_version(tx)?;
+
+  for (i, migration) in MIGRATIONS.iter().enumerate() {
+    let version = i + 1;
+    if version > current_version {
+      tx.execute_batch(migration)?;
+      tx.execute(
+        "replace into migration_state (k, version) values(?, ?)",
+        [&0, &version],
+      )?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Returns the name of the shared in-memory database at `path`, or `None` if
+/// the path does not refer to one.
+fn parse_shared_memory_name(path: &str) -> Result<Option<String>, AnyError> {
+  let Some(query) = path.strip_prefix(SHARED_MEMORY_PREFIX) else {
+    return Ok(None);
+  };
+  let mut shared = false;
+  let mut name = None;
+  for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+    match &*key {
+      "cache" => shared = value == "shared",
+      "name" => name = Some(value.into_owned()),
+      _ => {
+        return Err(type_error(format!(
+          "Unknown in-memory database option: {}",
+          key
+        )))
+      }
+    }
+  }
+  match name {
+    Some(name) if shared && !name.is_empty() => Ok(Some(name)),
+    _ => Err(type_error(
+      "In-memory databases with options require `cache=shared` and a non-empty `name`",
+    )),
+  }
+}
+
+fn shared_memory_uri(name: &str) -> String {
+  // The name is hex encoded so that it can't inject URI parameters.
+  format!(
+    "file:deno_kv_{}?mode=memory&cache=shared",
+    hex::encode(name.as_bytes())
+  )
+}
+
+/// Opens a connection to the named shared in-memory database, creating and
+/// migrating the database if no handle to it is currently open.
+fn open_shared_memory(
+  name: &str,
+) -> Result<(rusqlite::Connection, Arc<SharedMemoryDb>), AnyError> {
+  let uri = shared_memory_uri(name);
+  let flags = OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI;
+  let mut dbs = SHARED_MEMORY_DBS
+    .get_or_init(Default::default)
+    .lock()
+    .unwrap();
+  let shared = match dbs.get(name).and_then(|x| x.upgrade()) {
+    Some(shared) => shared,
+    None => {
+      let mut anchor = rusqlite::Connection::open_with_flags(&uri, flags)?;
+      let tx = anchor.transaction()?;
+      run_migrations(&tx)?;
+      tx.commit()?;
+      let shared = Arc::new(SharedMemoryDb {
+        _anchor: Mutex::new(anchor),
+      });
+      dbs.retain(|_, x| x.strong_count() > 0);
+      dbs.insert(name.to_string(), Arc::downgrade(&shared));
+      shared
+    }
+  };
+  let conn = rusqlite::Connection::open_with_flags(&uri, flags)?;
+  Ok((conn, shared))
+}
+
+/// Log target of the timings of transactions, logged at the trace level so
+/// that they are off by default. Enable them with
+/// `RUST_LOG=deno_kv::tx_timing=trace`.
+const TX_TIMING_LOG_TARGET: &str = "deno_kv::tx_timing";
+
+/// How long the operation `name` waited for the connection, and how long its
+/// transaction, including the commit, took on the connection.
+struct TxTiming {
+  name: &'static str,
+  lock_wait: Duration,
+  run: Duration,
+  ok: bool,
+}
+
+type TxTimingSink = Arc<dyn Fn(&TxTiming) + Send + Sync>;
+
+/// Logs `timing`, and passes it to `sink` if there is one.
+fn log_tx_timing(sink: Option<&TxTimingSink>, timing: TxTiming) {
+  log::trace!(
+    target: TX_TIMING_LOG_TARGET,
+    "op={} lock_wait_us={} run_us={} ok={}",
+    timing.name,
+    timing.lock_wait.as_micros(),
+    timing.run.as_micros(),
+    timing.ok
+  );
+  if let Some(sink) = sink {
+    sink(&timing);
+  }
+}
+
+/// Retries `f` while the database is busy, until `busy_timeout` has elapsed
+/// since the first busy error.
+///
+/// SQLite already waits for locks up to the busy timeout set on the
+/// connection, but it returns `SQLITE_BUSY` right away in cases where waiting
+/// can't help, such as a deferred transaction that can't be upgraded to a
+/// write transaction. Retrying the whole transaction covers those.
 async fn sqlite_retry_loop<R, Fut: Future<Output = Result<R, AnyError>>>(
+  busy_timeout: Duration,
   mut f: impl FnMut() -> Fut,
 ) -> Result<R, AnyError> {
+  let mut deadline = None;
   loop {
     match f().await {
       Ok(x) => return Ok(x),
       Err(e) => {
         if let Some(x) = e.downcast_ref::<rusqlite::Error>() {
-          if x.sqlite_error_code() == Some(rusqlite::ErrorCode::DatabaseBusy) {
+          let deadline =
+            *deadline.get_or_insert_with(|| Instant::now() + busy_timeout);
+          // Connections to a shared in-memory database report lock
+          // contention as `DatabaseLocked` rather than `DatabaseBusy`.
+          let busy = matches!(
+            x.sqlite_error_code(),
+            Some(
+              rusqlite::ErrorCode::DatabaseBusy
+                | rusqlite::ErrorCode::DatabaseLocked
+            )
+          );
+          if busy && Instant::now() < deadline {
             log::debug!("kv: Database is busy, retrying");
             tokio::time::sleep(Duration::from_millis(
               rand::thread_rng().gen_range(5..20),
@@ -331,7 +1477,175 @@ async fn sqlite_retry_loop<R, Fut: Future<Output = Result<R, AnyError>>>(
 }
 
 impl SqliteDb {
-  async fn run_tx<F, R>(conn: ProtectedConn, f: F) -> Result<R, AnyError>
+  fn check_writable(&self) -> Result<(), AnyError> {
+    if self.read_only {
+      return Err(type_error("database is read-only"));
+    }
+    Ok(())
+  }
+
+  /// Wakes up the dequeue loop of every handle to this database after new
+  /// messages have been added to the queue.
+  fn wake_queue(&self, state: Rc<RefCell<OpState>>) {
+    match self.queue.get() {
+      Some(queue) => {
+        let _ = queue.waker_tx.send(());
+      }
+      None => {
+        if let Some(waker_key) = &self.queue_waker_key {
+          let (waker_tx, _) = shared_queue_waker_channel(waker_key, state);
+          let _ = waker_tx.send(());
+        }
+      }
+    }
+  }
+
+  /// Makes sure the next expiration sweep runs no later than `expire_at`.
+  fn bring_sweep_forward(&self, expire_at: u64) {
+    self.next_sweep_tx.send_if_modified(|next_sweep| {
+      if expire_at < *next_sweep {
+        *next_sweep = expire_at;
+        true
+      } else {
+        false
+      }
+    });
+  }
+
+  /// Commits `write` together with other writes if write coalescing is
+  /// enabled and the write can be coalesced, see
+  /// [SqliteDbHandler::with_write_coalescing]. Returns `None` if the write
+  /// has to be committed on its own instead, which includes the case that
+  /// the shared transaction failed.
+  async fn coalesce_write(&self, write: &AtomicWrite) -> Option<CommitResult> {
+    let tx = self.write_coalescer.borrow().as_ref()?.tx.clone();
+    if !write.checks.is_empty()
+      || !write.enqueues.is_empty()
+      || write.return_old
+      || write.dry_run
+    {
+      return None;
+    }
+    let [mutation] = write.mutations.as_slice() else {
+      return None;
+    };
+    let value = match &mutation.kind {
+      MutationKind::Set(value) => {
+        let (value, encoding) = encode_value(value);
+        Some((value.into_owned(), encoding))
+      }
+      MutationKind::Delete => None,
+      _ => return None,
+    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.send(CoalescedWrite {
+      key: mutation.key.clone(),
+      value,
+      expire_at: mutation.expire_at,
+      reply: reply_tx,
+    })
+    .await
+    .ok()?;
+    reply_rx.await.ok().flatten()
+  }
+
+  /// Maps a versionstamp returned by this database back to an approximate
+  /// commit time, in milliseconds since the Unix epoch.
+  ///
+  /// Versionstamps only carry the order of commits, so this looks up the
+  /// commit time recorded for the newest entry still in the database that
+  /// was written at or before `versionstamp`. The result is a lower bound:
+  /// it is exact while an entry written by that commit is in the database.
+  /// Returns `None` if no such entry is left, or if it was written before
+  /// commit times were recorded.
+  pub async fn versionstamp_commit_ms(
+    &self,
+    versionstamp: [u8; 10],
+  ) -> Result<Option<u64>, AnyError> {
+    let version = versionstamp_to_version(&versionstamp);
+    self
+      .run_read_tx("versionstamp_commit_ms", Consistency::Strong, move |tx| {
+        let commit_ms: Option<i64> = tx
+          .prepare_cached(STATEMENT_KV_COMMIT_MS_AT_VERSION)?
+          .query_row([version], |row| row.get(0))
+          .optional()?;
+        Ok(commit_ms.and_then(|x| u64::try_from(x).ok()))
+      })
+      .await
+  }
+
+  /// Runs a single read-only SQL statement against the database and returns
+  /// its rows, with one value per column.
+  ///
+  /// This is an escape hatch for queries that KV itself can't express, like
+  /// aggregates over the `kv` table, and it bypasses KV semantics entirely:
+  /// keys and values are returned in their encoded form, entries that have
+  /// expired but haven't been swept yet are included, and the schema is an
+  /// implementation detail that may change between releases. Only queries
+  /// (`SELECT`, `WITH`, `VALUES` and `EXPLAIN`) that don't modify the
+  /// database are accepted. In particular, `PRAGMA` statements, which could
+  /// change the settings of the connection, `ATTACH` and transaction control
+  /// are rejected; pragmas can be read with their table-valued functions,
+  /// like `select * from pragma_page_count()`.
+  pub async fn query_raw(
+    &self,
+    sql: &str,
+    params: Vec<rusqlite::types::Value>,
+  ) -> Result<Vec<Vec<rusqlite::types::Value>>, AnyError> {
+    let sql = sql.to_string();
+    Self::run_tx("query_raw", self.conn.clone(), move |tx| {
+      let mut stmt = tx.prepare(&sql)?;
+      if !stmt.readonly() || !is_raw_query(&sql) {
+        return Err(type_error("Only read-only statements can be run raw"));
+      }
+      let columns = stmt.column_count();
+      let rows = stmt
+        .query_map(rusqlite::params_from_iter(&params), |row| {
+          (0..columns)
+            .map(|i| row.get::<_, rusqlite::types::Value>(i))
+            .collect::<Result<Vec<_>, _>>()
+        })?
+        .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+      Ok(rows)
+    })
+    .await
+  }
+
+  /// Runs `f` in a transaction, retrying it while the database is busy.
+  /// `name` identifies the operation in the timings logged to
+  /// [TX_TIMING_LOG_TARGET].
+  async fn run_tx<F, R>(
+    name: &'static str,
+    conn: ProtectedConn,
+    f: F,
+  ) -> Result<R, AnyError>
+  where
+    F: (FnOnce(rusqlite::Transaction<'_>) -> Result<R, AnyError>)
+      + Clone
+      + Send
+      + 'static,
+    R: Send + 'static,
+  {
+    sqlite_retry_loop(conn.busy_timeout, || {
+      Self::run_tx_inner(
+        name,
+        conn.clone(),
+        TransactionBehavior::Deferred,
+        f.clone(),
+      )
+    })
+    .await
+  }
+
+  /// Like `run_tx`, but for transactions that may write. They start with
+  /// `BEGIN IMMEDIATE`, which takes the write lock up front, so that another
+  /// connection writing to the same file makes them wait or fail before `f`
+  /// has done any work, rather than at their first write.
+  async fn run_write_tx<F, R>(
+    name: &'static str,
+    conn: ProtectedConn,
+    f: F,
+  ) -> Result<R, AnyError>
   where
     F: (FnOnce(rusqlite::Transaction<'_>) -> Result<R, AnyError>)
       + Clone
@@ -339,43 +1653,165 @@ impl SqliteDb {
       + 'static,
     R: Send + 'static,
   {
-    sqlite_retry_loop(|| Self::run_tx_inner(conn.clone(), f.clone())).await
+    sqlite_retry_loop(conn.busy_timeout, || {
+      Self::run_tx_inner(
+        name,
+        conn.clone(),
+        TransactionBehavior::Immediate,
+        f.clone(),
+      )
+    })
+    .await
   }
 
-  async fn run_tx_inner<F, R>(conn: ProtectedConn, f: F) -> Result<R, AnyError>
+  async fn run_tx_inner<F, R>(
+    name: &'static str,
+    conn: ProtectedConn,
+    behavior: TransactionBehavior,
+    f: F,
+  ) -> Result<R, AnyError>
   where
     F: (FnOnce(rusqlite::Transaction<'_>) -> Result<R, AnyError>)
       + Send
       + 'static,
     R: Send + 'static,
   {
+    let start = Instant::now();
     // `run_tx` runs in an asynchronous context. First acquire the async lock to
     // coordinate with other async invocations.
     let _guard_holder = conn.guard.borrow_mut().await;
+    let lock_wait = start.elapsed();
 
     // Then, take the synchronous lock. This operation is guaranteed to success without waiting,
     // unless the database is being closed.
+    //
+    // The async lock serializes the transactions of this connection, so
+    // checks and mutations of one atomic write never interleave with another
+    // write of the same database. Other connections to the same file are
+    // isolated by SQLite: a deferred transaction whose snapshot went stale
+    // before it could write fails with `SQLITE_BUSY` and is rerun from the
+    // start by `sqlite_retry_loop`, checks included. Write transactions are
+    // immediate, so they wait for each other before reading anything.
     let db = conn.conn.clone();
-    spawn_blocking(move || {
+    let (result, run) = spawn_blocking(move || {
+      let start = Instant::now();
       let mut db = db.try_lock().ok();
       let Some(db) = db.as_mut().and_then(|x| x.as_mut()) else {
-        return Err(type_error(ERROR_USING_CLOSED_DATABASE));
+        return (
+          Err(type_error(ERROR_USING_CLOSED_DATABASE)),
+          start.elapsed(),
+        );
       };
-      let result = match db.transaction() {
+      let result = match db.transaction_with_behavior(behavior) {
         Ok(tx) => f(tx),
         Err(e) => Err(e.into()),
       };
-      result
+      (result, start.elapsed())
     })
     .await
-    .unwrap()
+    .unwrap();
+    log_tx_timing(
+      conn.tx_timing_sink.as_ref(),
+      TxTiming {
+        name,
+        lock_wait,
+        run,
+        ok: result.is_ok(),
+      },
+    );
+    result
+  }
+
+  /// Runs a read transaction, on the least busy connection of the read pool
+  /// if the read may be eventually consistent.
+  async fn run_read_tx<F, R>(
+    &self,
+    name: &'static str,
+    consistency: Consistency,
+    f: F,
+  ) -> Result<R, AnyError>
+  where
+    F: (FnOnce(rusqlite::Transaction<'_>) -> Result<R, AnyError>)
+      + Clone
+      + Send
+      + 'static,
+    R: Send + 'static,
+  {
+    let slot = self
+      .read_pool
+      .iter()
+      .filter(|_| consistency == Consistency::Eventual)
+      .min_by_key(|slot| slot.in_flight.get());
+    match slot {
+      Some(slot) => {
+        let _in_flight = InFlightRead::new(&slot.in_flight);
+        Self::run_tx(name, slot.conn.clone(), f).await
+      }
+      None => Self::run_tx(name, self.conn.clone(), f).await,
+    }
+  }
+
+  /// Runs `f` on the connection outside of a transaction, for statements such
+  /// as `VACUUM` that can't run in one. Like `run_tx`, this holds the async
+  /// lock so that it doesn't race with transactions.
+  async fn run_conn<F, R>(
+    name: &'static str,
+    conn: ProtectedConn,
+    f: F,
+  ) -> Result<R, AnyError>
+  where
+    F: (FnOnce(&mut rusqlite::Connection) -> Result<R, AnyError>)
+      + Send
+      + 'static,
+    R: Send + 'static,
+  {
+    let start = Instant::now();
+    let _guard_holder = conn.guard.borrow_mut().await;
+    let lock_wait = start.elapsed();
+    let db = conn.conn.clone();
+    let (result, run) = spawn_blocking(move || {
+      let start = Instant::now();
+      let mut db = db.try_lock().ok();
+      let Some(db) = db.as_mut().and_then(|x| x.as_mut()) else {
+        return (
+          Err(type_error(ERROR_USING_CLOSED_DATABASE)),
+          start.elapsed(),
+        );
+      };
+      (f(db), start.elapsed())
+    })
+    .await
+    .unwrap();
+    log_tx_timing(
+      conn.tx_timing_sink.as_ref(),
+      TxTiming {
+        name,
+        lock_wait,
+        run,
+        ok: result.is_ok(),
+      },
+    );
+    result
   }
 }
 
 pub struct DequeuedMessage {
   conn: WeakProtectedConn,
+  clock: Arc<dyn Clock>,
+  max_delivery_attempts: Option<u64>,
+  visibility_timeout: Duration,
   id: String,
+  /// Identifies this delivery of the message, unlike `id` which is the same
+  /// for every delivery.
+  delivery_id: String,
   payload: Option<Vec<u8>>,
+  /// The number of failed deliveries of the message before this one.
+  failures: u64,
+  enqueued_at_ms: u64,
+  /// The deadline of this delivery in the running messages. A redelivery of
+  /// the message gets a new one, which keeps this handle from finishing or
+  /// extending the redelivery.
+  deadline: Cell<u64>,
   waker_tx: broadcast::Sender<()>,
   _permit: OwnedSemaphorePermit,
 }
@@ -387,16 +1823,25 @@ impl QueueMessageHandle for DequeuedMessage {
       return Ok(());
     };
     let id = self.id.clone();
-    let requeued = SqliteDb::run_tx(conn, move |tx| {
+    let deadline = self.deadline.get();
+    let clock = self.clock.clone();
+    let max_delivery_attempts = self.max_delivery_attempts;
+    let requeued = SqliteDb::run_write_tx("queue_finish", conn, move |tx| {
       let requeued = {
         if success {
           let changed = tx
-            .prepare_cached(STATEMENT_QUEUE_REMOVE_RUNNING)?
-            .execute([&id])?;
+            .prepare_cached(STATEMENT_QUEUE_REMOVE_RUNNING_DELIVERY)?
+            .execute(params![id, deadline])?;
           assert!(changed <= 1);
           false
         } else {
-          SqliteQueue::requeue_message(&id, &tx)?
+          SqliteQueue::requeue_message(
+            &id,
+            Some(deadline),
+            &tx,
+            clock.now_ms(),
+            max_delivery_attempts,
+          )?
         }
       };
       tx.commit()?;
@@ -427,14 +1872,78 @@ impl QueueMessageHandle for DequeuedMessage {
       .take()
       .ok_or_else(|| type_error("Payload already consumed"))
   }
+
+  fn attempt(&self) -> Option<u64> {
+    Some(self.failures + 1)
+  }
+
+  fn enqueued_at_ms(&self) -> Option<u64> {
+    Some(self.enqueued_at_ms)
+  }
+
+  fn id(&self) -> Option<String> {
+    Some(self.delivery_id.clone())
+  }
+
+  async fn extend_deadline(
+    &self,
+    timeout: Option<Duration>,
+  ) -> Result<(), AnyError> {
+    let timeout = timeout.unwrap_or(self.visibility_timeout);
+    if timeout.is_zero() {
+      return Err(type_error("Deadline extension must be greater than zero"));
+    }
+    let timeout = timeout.min(self.visibility_timeout);
+    let Some(conn) = self.conn.upgrade() else {
+      return Ok(());
+    };
+    let id = self.id.clone();
+    let deadline = self.deadline.get();
+    let clock = self.clock.clone();
+    let res =
+      SqliteDb::run_write_tx("queue_extend_deadline", conn, move |tx| {
+        // The deadline never moves earlier, even if a shorter timeout is
+        // given than the last time.
+        let new_deadline = clock
+          .now_ms()
+          .saturating_add(timeout.as_millis() as u64)
+          .max(deadline);
+        let changed = tx
+          .prepare_cached(STATEMENT_QUEUE_EXTEND_RUNNING)?
+          .execute(params![new_deadline, id, deadline])?;
+        assert!(changed <= 1);
+        tx.commit()?;
+        Ok((changed == 1).then_some(new_deadline))
+      })
+      .await;
+    match res {
+      Ok(Some(new_deadline)) => {
+        self.deadline.set(new_deadline);
+        Ok(())
+      }
+      Ok(None) => Err(type_error("Queue message is no longer running")),
+      // The message is requeued when the database is next opened.
+      Err(e) if is_conn_closed_error(&e) => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
 }
 
-type DequeueReceiver = mpsc::Receiver<(Vec<u8>, String)>;
+/// (payload, id, failures, enqueued_at_ms, deadline)
+type DequeuedItem = (Vec<u8>, String, u64, u64, u64);
+type DequeueReceiver = mpsc::Receiver<DequeuedItem>;
 
 struct SqliteQueue {
   conn: ProtectedConn,
+  clock: Arc<dyn Clock>,
+  max_delivery_attempts: Option<u64>,
+  visibility_timeout: Duration,
   dequeue_rx: Rc<AsyncRefCell<DequeueReceiver>>,
   concurrency_limiter: Arc<Semaphore>,
+  concurrency_limit: usize,
+  /// Set once the database starts closing gracefully; no more messages are
+  /// handed out after that.
+  draining: Cell<bool>,
   waker_tx: broadcast::Sender<()>,
   shutdown_tx: watch::Sender<()>,
 }
@@ -442,16 +1951,38 @@ struct SqliteQueue {
 impl SqliteQueue {
   fn new(
     conn: ProtectedConn,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<dyn KvMetrics>,
+    max_delivery_attempts: Option<u64>,
+    fair_dequeue: bool,
+    visibility_timeout: Duration,
     waker_tx: broadcast::Sender<()>,
     waker_rx: broadcast::Receiver<()>,
+    concurrency_limit: usize,
   ) -> Self {
     let conn_clone = conn.clone();
+    let clock_clone = clock.clone();
     let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
-    let (dequeue_tx, dequeue_rx) = mpsc::channel::<(Vec<u8>, String)>(64);
+    let (dequeue_tx, dequeue_rx) = mpsc::channel::<DequeuedItem>(64);
+
+    spawn(Self::watch_stuck_messages(
+      conn.clone(),
+      clock.clone(),
+      max_delivery_attempts,
+      visibility_timeout,
+      waker_tx.clone(),
+      shutdown_rx.clone(),
+    ));
 
     spawn(async move {
       // Oneshot requeue of all inflight messages.
-      if let Err(e) = Self::requeue_inflight_messages(conn.clone()).await {
+      if let Err(e) = Self::requeue_inflight_messages(
+        conn.clone(),
+        clock.clone(),
+        max_delivery_attempts,
+      )
+      .await
+      {
         // Exit the dequeue loop cleanly if the database has been closed.
         if is_conn_closed_error(&e) {
           return;
@@ -460,9 +1991,17 @@ impl SqliteQueue {
       }
 
       // Continuous dequeue loop.
-      if let Err(e) =
-        Self::dequeue_loop(conn.clone(), dequeue_tx, shutdown_rx, waker_rx)
-          .await
+      if let Err(e) = Self::dequeue_loop(
+        conn.clone(),
+        clock,
+        metrics,
+        fair_dequeue,
+        visibility_timeout,
+        dequeue_tx,
+        shutdown_rx,
+        waker_rx,
+      )
+      .await
       {
         // Exit the dequeue loop cleanly if the database has been closed.
         if is_conn_closed_error(&e) {
@@ -474,93 +2013,214 @@ impl SqliteQueue {
 
     Self {
       conn: conn_clone,
+      clock: clock_clone,
+      max_delivery_attempts,
+      visibility_timeout,
       dequeue_rx: Rc::new(AsyncRefCell::new(dequeue_rx)),
       waker_tx,
       shutdown_tx,
-      concurrency_limiter: Arc::new(Semaphore::new(DISPATCH_CONCURRENCY_LIMIT)),
+      concurrency_limiter: Arc::new(Semaphore::new(concurrency_limit)),
+      concurrency_limit,
+      draining: Cell::new(false),
     }
   }
 
   async fn dequeue(&self) -> Result<Option<DequeuedMessage>, AnyError> {
-    // Wait for the next message to be available from dequeue_rx.
-    let (payload, id) = {
-      let mut queue_rx = self.dequeue_rx.borrow_mut().await;
-      let Some(msg) = queue_rx.recv().await else {
+    if self.draining.get() {
+      return Ok(None);
+    }
+
+    loop {
+      // Wait for the next message to be available from dequeue_rx.
+      let (payload, id, failures, enqueued_at_ms, buffered_deadline) = {
+        let mut queue_rx = self.dequeue_rx.borrow_mut().await;
+        let Some(msg) = queue_rx.recv().await else {
+          return Ok(None);
+        };
+        msg
+      };
+
+      let permit = self.concurrency_limiter.clone().acquire_owned().await?;
+
+      // Messages that were buffered when draining started stay in the running
+      // state and are requeued when the database is next opened.
+      if self.draining.get() {
         return Ok(None);
+      }
+
+      let delivery_id = Uuid::new_v4().to_string();
+      let deadline = match self
+        .hand_out(id.clone(), buffered_deadline, delivery_id.clone())
+        .await
+      {
+        Ok(Some(deadline)) => deadline,
+        // The message was requeued while it was buffered.
+        Ok(None) => continue,
+        Err(e) if is_conn_closed_error(&e) => return Ok(None),
+        Err(e) => return Err(e),
       };
-      msg
-    };
 
-    let permit = self.concurrency_limiter.clone().acquire_owned().await?;
+      return Ok(Some(DequeuedMessage {
+        conn: self.conn.downgrade(),
+        clock: self.clock.clone(),
+        max_delivery_attempts: self.max_delivery_attempts,
+        visibility_timeout: self.visibility_timeout,
+        id,
+        delivery_id,
+        payload: Some(payload),
+        failures,
+        enqueued_at_ms,
+        deadline: Cell::new(deadline),
+        waker_tx: self.waker_tx.clone(),
+        _permit: permit,
+      }));
+    }
+  }
 
-    Ok(Some(DequeuedMessage {
-      conn: self.conn.downgrade(),
-      id,
-      payload: Some(payload),
-      waker_tx: self.waker_tx.clone(),
-      _permit: permit,
-    }))
+  /// Hands out the buffered delivery of the message `id`, giving it a new
+  /// `delivery_id` and a deadline of the visibility timeout from now, rather
+  /// than from when it was moved to the running state. Returns the deadline,
+  /// or `None` if the message was requeued while it was buffered.
+  async fn hand_out(
+    &self,
+    id: String,
+    buffered_deadline: u64,
+    delivery_id: String,
+  ) -> Result<Option<u64>, AnyError> {
+    let clock = self.clock.clone();
+    let visibility_timeout = self.visibility_timeout;
+    SqliteDb::run_write_tx("queue_hand_out", self.conn.clone(), move |tx| {
+      let deadline = clock
+        .now_ms()
+        .saturating_add(visibility_timeout.as_millis() as u64);
+      let changed = tx
+        .prepare_cached(STATEMENT_QUEUE_HAND_OUT_RUNNING)?
+        .execute(params![deadline, delivery_id, id, buffered_deadline])?;
+      assert!(changed <= 1);
+      tx.commit()?;
+      Ok((changed == 1).then_some(deadline))
+    })
+    .await
   }
 
   fn shutdown(&self) {
     let _ = self.shutdown_tx.send(());
   }
 
+  /// Stops handing out messages and waits for the handles of the messages
+  /// that were handed out to be dropped, which releases their permits.
+  /// Returns whether that happened before `timeout`.
+  async fn drain(&self, timeout: Duration) -> bool {
+    self.draining.set(true);
+    self.shutdown();
+    let all_permits = self
+      .concurrency_limiter
+      .acquire_many(self.concurrency_limit as u32);
+    matches!(tokio::time::timeout(timeout, all_permits).await, Ok(Ok(_)))
+  }
+
   async fn dequeue_loop(
     conn: ProtectedConn,
-    dequeue_tx: mpsc::Sender<(Vec<u8>, String)>,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<dyn KvMetrics>,
+    fair_dequeue: bool,
+    visibility_timeout: Duration,
+    dequeue_tx: mpsc::Sender<DequeuedItem>,
     mut shutdown_rx: watch::Receiver<()>,
     mut waker_rx: broadcast::Receiver<()>,
   ) -> Result<(), AnyError> {
+    let next_ready_statement = if fair_dequeue {
+      STATEMENT_QUEUE_GET_NEXT_READY_FAIR
+    } else {
+      STATEMENT_QUEUE_GET_NEXT_READY
+    };
     loop {
-      let messages = SqliteDb::run_tx(conn.clone(), move |tx| {
-        let now = SystemTime::now()
-          .duration_since(SystemTime::UNIX_EPOCH)
-          .unwrap()
-          .as_millis() as u64;
-
-        let messages = tx
-          .prepare_cached(STATEMENT_QUEUE_GET_NEXT_READY)?
-          .query_map([now], |row| {
-            let ts: u64 = row.get(0)?;
-            let id: String = row.get(1)?;
-            let data: Vec<u8> = row.get(2)?;
-            let backoff_schedule: String = row.get(3)?;
-            let keys_if_undelivered: String = row.get(4)?;
-            Ok((ts, id, data, backoff_schedule, keys_if_undelivered))
-          })?
-          .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+      let tx_clock = clock.clone();
+      let messages =
+        SqliteDb::run_write_tx("dequeue", conn.clone(), move |tx| {
+          let now = tx_clock.now_ms();
+          let deadline = now + visibility_timeout.as_millis() as u64;
 
-        for (ts, id, data, backoff_schedule, keys_if_undelivered) in &messages {
-          let changed = tx
-            .prepare_cached(STATEMENT_QUEUE_REMOVE_READY)?
-            .execute(params![id])?;
-          assert_eq!(changed, 1);
+          // Messages are only handed out once they are taken from the
+          // buffer, see `hand_out`. Until then, this deadline lets them be
+          // requeued if they are never taken from it.
+          let messages = tx
+            .prepare_cached(next_ready_statement)?
+            .query_map([now], |row| {
+              let ts: u64 = row.get(0)?;
+              let id: String = row.get(1)?;
+              let data: Vec<u8> = row.get(2)?;
+              let backoff_schedule: String = row.get(3)?;
+              let keys_if_undelivered: String = row.get(4)?;
+              let enqueued_at: u64 = row.get(5)?;
+              let failures: u64 = row.get(6)?;
+              let group: Option<String> = row.get(7)?;
+              Ok((
+                ts,
+                id,
+                data,
+                backoff_schedule,
+                keys_if_undelivered,
+                enqueued_at,
+                failures,
+                group,
+              ))
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
 
-          let changed =
-            tx.prepare_cached(STATEMENT_QUEUE_ADD_RUNNING)?.execute(
-              params![ts, id, &data, &backoff_schedule, &keys_if_undelivered],
-            )?;
-          assert_eq!(changed, 1);
-        }
-        tx.commit()?;
+          for (
+            ts,
+            id,
+            data,
+            backoff_schedule,
+            keys_if_undelivered,
+            enqueued_at,
+            failures,
+            group,
+          ) in &messages
+          {
+            let changed = tx
+              .prepare_cached(STATEMENT_QUEUE_REMOVE_READY)?
+              .execute(params![id])?;
+            assert_eq!(changed, 1);
 
-        Ok(
-          messages
-            .into_iter()
-            .map(|(_, id, data, _, _)| (id, data))
-            .collect::<Vec<_>>(),
-        )
-      })
-      .await?;
+            let changed = tx
+              .prepare_cached(STATEMENT_QUEUE_ADD_RUNNING)?
+              .execute(params![
+                deadline,
+                id,
+                &data,
+                &backoff_schedule,
+                &keys_if_undelivered,
+                enqueued_at,
+                failures,
+                group,
+                ""
+              ])?;
+            assert_eq!(changed, 1);
+          }
+          tx.commit()?;
+
+          Ok(
+            messages
+              .into_iter()
+              .map(|(ts, id, data, _, _, enqueued_at, failures, _)| {
+                (ts, (data, id, failures, enqueued_at, deadline))
+              })
+              .collect::<Vec<_>>(),
+          )
+        })
+        .await?;
 
       let busy = !messages.is_empty();
 
-      for (id, data) in messages {
-        if dequeue_tx.send((data, id)).await.is_err() {
+      for (ts, item) in messages {
+        if dequeue_tx.send(item).await.is_err() {
           // Queue receiver was dropped. Stop the dequeue loop.
           return Ok(());
         }
+        let waited = clock.now_ms().saturating_sub(ts);
+        metrics.record_queue_dispatch(Duration::from_millis(waited));
       }
 
       if !busy {
@@ -572,10 +2232,7 @@ impl SqliteQueue {
         let sleep_fut = {
           match Self::get_earliest_ready_ts(conn.clone()).await? {
             Some(ts) => {
-              let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64;
+              let now = clock.now_ms();
               if ts <= now {
                 continue;
               }
@@ -598,7 +2255,7 @@ impl SqliteQueue {
   async fn get_earliest_ready_ts(
     conn: ProtectedConn,
   ) -> Result<Option<u64>, AnyError> {
-    SqliteDb::run_tx(conn.clone(), move |tx| {
+    SqliteDb::run_tx("earliest_ready_ts", conn.clone(), move |tx| {
       let ts = tx
         .prepare_cached(STATEMENT_QUEUE_GET_EARLIEST_READY)?
         .query_row([], |row| {
@@ -613,34 +2270,102 @@ impl SqliteQueue {
 
   async fn requeue_inflight_messages(
     conn: ProtectedConn,
+    clock: Arc<dyn Clock>,
+    max_delivery_attempts: Option<u64>,
   ) -> Result<(), AnyError> {
     loop {
-      let done = SqliteDb::run_tx(conn.clone(), move |tx| {
-        let entries = tx
-          .prepare_cached(STATEMENT_QUEUE_GET_RUNNING)?
-          .query_map([], |row| {
-            let id: String = row.get(0)?;
-            Ok(id)
-          })?
-          .collect::<Result<Vec<_>, rusqlite::Error>>()?;
-        for id in &entries {
-          Self::requeue_message(id, &tx)?;
-        }
-        tx.commit()?;
-        Ok(entries.is_empty())
-      })
-      .await?;
+      let clock = clock.clone();
+      let done =
+        SqliteDb::run_write_tx("requeue_inflight", conn.clone(), move |tx| {
+          let now = clock.now_ms();
+          let entries = tx
+            .prepare_cached(STATEMENT_QUEUE_GET_RUNNING)?
+            .query_map([], |row| {
+              let id: String = row.get(0)?;
+              Ok(id)
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+          for id in &entries {
+            Self::requeue_message(id, None, &tx, now, max_delivery_attempts)?;
+          }
+          tx.commit()?;
+          Ok(entries.is_empty())
+        })
+        .await?;
       if done {
         return Ok(());
       }
     }
   }
 
+  /// Periodically requeues running messages whose deadline has passed, until
+  /// the queue is shut down.
+  async fn watch_stuck_messages(
+    conn: ProtectedConn,
+    clock: Arc<dyn Clock>,
+    max_delivery_attempts: Option<u64>,
+    visibility_timeout: Duration,
+    waker_tx: broadcast::Sender<()>,
+    mut shutdown_rx: watch::Receiver<()>,
+  ) {
+    let interval =
+      (visibility_timeout / 2).min(MAX_STUCK_MESSAGE_CHECK_INTERVAL);
+    loop {
+      tokio::select! {
+        _ = tokio::time::sleep(interval) => {}
+        _ = shutdown_rx.changed() => return,
+      }
+      let clock = clock.clone();
+      let res =
+        SqliteDb::run_write_tx("requeue_stuck", conn.clone(), move |tx| {
+          let now = clock.now_ms();
+          let ids = tx
+            .prepare_cached(STATEMENT_QUEUE_GET_RUNNING_PAST_DEADLINE)?
+            .query_map([now], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+          let mut requeued = false;
+          for id in &ids {
+            requeued |=
+              Self::requeue_message(id, None, &tx, now, max_delivery_attempts)?;
+          }
+          tx.commit()?;
+          Ok(requeued)
+        })
+        .await;
+      match res {
+        Ok(true) => {
+          let _ = waker_tx.send(());
+        }
+        Ok(false) => {}
+        Err(e) if is_conn_closed_error(&e) => return,
+        Err(e) => eprintln!("kv: Error in stuck message watcher: {}", e),
+      }
+    }
+  }
+
+  /// Requeues the running message `id`, or dead-letters it if it has no
+  /// attempts left. If `deadline` is given, only a delivery of the message
+  /// with that deadline is requeued. A message that was buffered but never
+  /// handed out is requeued without counting an attempt. Returns whether the
+  /// message was requeued.
   fn requeue_message(
     id: &str,
+    deadline: Option<u64>,
     tx: &rusqlite::Transaction<'_>,
+    now: u64,
+    max_delivery_attempts: Option<u64>,
   ) -> Result<bool, AnyError> {
-    let Some((_, id, data, backoff_schedule, keys_if_undelivered)) = tx
+    let Some((
+      running_deadline,
+      id,
+      data,
+      backoff_schedule,
+      keys_if_undelivered,
+      enqueued_at,
+      failures,
+      group,
+      delivery_id,
+    )) = tx
       .prepare_cached(STATEMENT_QUEUE_GET_RUNNING_BY_ID)?
       .query_row([id], |row| {
         let deadline: u64 = row.get(0)?;
@@ -648,12 +2373,55 @@ impl SqliteQueue {
         let data: Vec<u8> = row.get(2)?;
         let backoff_schedule: String = row.get(3)?;
         let keys_if_undelivered: String = row.get(4)?;
-        Ok((deadline, id, data, backoff_schedule, keys_if_undelivered))
+        let enqueued_at: u64 = row.get(5)?;
+        let failures: u64 = row.get(6)?;
+        let group: Option<String> = row.get(7)?;
+        let delivery_id: String = row.get(8)?;
+        Ok((
+          deadline,
+          id,
+          data,
+          backoff_schedule,
+          keys_if_undelivered,
+          enqueued_at,
+          failures,
+          group,
+          delivery_id,
+        ))
       })
       .optional()?
     else {
       return Ok(false);
     };
+    if deadline.is_some_and(|deadline| deadline != running_deadline) {
+      return Ok(false);
+    }
+
+    if delivery_id.is_empty() {
+      // The message was never handed out, so this is not a failed attempt.
+      // It is made ready again right away, without using up its backoff
+      // schedule.
+      let changed =
+        tx.prepare_cached(STATEMENT_QUEUE_ADD_READY)?
+          .execute(params![
+            now,
+            id,
+            &data,
+            &backoff_schedule,
+            &keys_if_undelivered,
+            enqueued_at,
+            failures,
+            group
+          ])?;
+      assert_eq!(changed, 1);
+      let changed = tx
+        .prepare_cached(STATEMENT_QUEUE_REMOVE_RUNNING)?
+        .execute(params![id])?;
+      assert_eq!(changed, 1);
+      return Ok(true);
+    }
+
+    let failures = failures + 1;
 
     let backoff_schedule = {
       let backoff_schedule =
@@ -661,13 +2429,12 @@ impl SqliteQueue {
       backoff_schedule.unwrap_or_default()
     };
 
+    let attempts_left =
+      max_delivery_attempts.map_or(true, |max| failures < max);
+
     let mut requeued = false;
-    if !backoff_schedule.is_empty() {
+    if !backoff_schedule.is_empty() && attempts_left {
       // Requeue based on backoff schedule
-      let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
       let new_ts = now + backoff_schedule[0];
       let new_backoff_schedule = serde_json::to_string(&backoff_schedule[1..])?;
       let changed = tx
@@ -677,26 +2444,56 @@ impl SqliteQueue {
           id,
           &data,
           &new_backoff_schedule,
-          &keys_if_undelivered
+          &keys_if_undelivered,
+          enqueued_at,
+          failures,
+          group
         ])
         .unwrap();
       assert_eq!(changed, 1);
       requeued = true;
-    } else if !keys_if_undelivered.is_empty() {
-      // No more requeues. Insert the message into the undelivered queue.
-      let keys_if_undelivered =
+    } else {
+      // No more requeues. Write the message to the undelivered keys, if any.
+      let undelivered_keys =
         serde_json::from_str::<Vec<Vec<u8>>>(&keys_if_undelivered)?;
+      if !undelivered_keys.is_empty() {
+        let version: i64 = tx
+          .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
+          .query_row([], |row| row.get(0))?;
 
-      let version: i64 = tx
-        .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
-        .query_row([], |row| row.get(0))?;
-
-      for key in keys_if_undelivered {
-        let changed = tx
-          .prepare_cached(STATEMENT_KV_POINT_SET)?
-          .execute(params![key, &data, &VALUE_ENCODING_V8, &version, -1i64])?;
-        assert_eq!(changed, 1);
+        for key in undelivered_keys {
+          let changed =
+            tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
+              key,
+              &data,
+              &VALUE_ENCODING_V8,
+              &version,
+              -1i64,
+              now
+            ])?;
+          assert_eq!(changed, 1);
+          // The extractors of the indexes are not known here, so the entries
+          // of the key are removed until the indexes are rebuilt.
+          tx.prepare_cached(STATEMENT_INDEX_DELETE_KEY)?
+            .execute([&key])?;
+          tx.prepare_cached(STATEMENT_INDEX_DEF_MARK_STALE_COVERING)?
+            .execute([&key])?;
+        }
       }
+
+      // Keep a record of the message so that it can be inspected and retried.
+      let changed = tx
+        .prepare_cached(STATEMENT_QUEUE_ADD_DEAD_LETTER)?
+        .execute(params![
+          id,
+          &data,
+          &keys_if_undelivered,
+          enqueued_at,
+          now,
+          failures,
+          group
+        ])?;
+      assert_eq!(changed, 1);
     }
 
     // Remove from running
@@ -709,222 +2506,748 @@ impl SqliteQueue {
   }
 }
 
-async fn watch_expiration(db: ProtectedConn) {
-  loop {
-    // Scan for expired keys
-    let res = SqliteDb::run_tx(db.clone(), move |tx| {
-      let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-      tx.prepare_cached(
-        "delete from kv where expiration_ms >= 0 and expiration_ms <= ?",
-      )?
-      .execute(params![now])?;
-      tx.commit()?;
-      Ok(())
-    })
-    .await;
-    if let Err(e) = res {
-      eprintln!("kv: Error in expiration watcher: {}", e);
-    }
-    let sleep_duration =
-      Duration::from_secs_f64(60.0 + rand::thread_rng().gen_range(0.0..30.0));
-    tokio::time::sleep(sleep_duration).await;
-  }
-}
+/// The expiration sweeps of database files, by their resolved path. Each file
+/// is swept by a single watcher of the process at a time, no matter how often
+/// and from how many isolates it is opened.
+type ExpirationSweeps =
+  Mutex<HashMap<PathBuf, std::sync::Weak<ExpirationSweep>>>;
 
-#[async_trait(?Send)]
-impl Database for SqliteDb {
-  type QMH = DequeuedMessage;
+static EXPIRATION_SWEEPS: OnceLock<ExpirationSweeps> = OnceLock::new();
 
-  async fn snapshot_read(
-    &self,
-    _state: Rc<RefCell<OpState>>,
-    requests: Vec<ReadRange>,
-    _options: SnapshotReadOptions,
-  ) -> Result<Vec<ReadRangeOutput>, AnyError> {
-    let requests = Arc::new(requests);
-    Self::run_tx(self.conn.clone(), move |tx| {
-      let mut responses = Vec::with_capacity(requests.len());
-      for request in &*requests {
-        let mut stmt = tx.prepare_cached(if request.reverse {
-          STATEMENT_KV_RANGE_SCAN_REVERSE
-        } else {
-          STATEMENT_KV_RANGE_SCAN
-        })?;
-        let entries = stmt
-          .query_map(
-            (
-              request.start.as_slice(),
-              request.end.as_slice(),
-              request.limit.get(),
-            ),
-            |row| {
-              let key: Vec<u8> = row.get(0)?;
-              let value: Vec<u8> = row.get(1)?;
-              let encoding: i64 = row.get(2)?;
-
-              let value = decode_value(value, encoding);
-
-              let version: i64 = row.get(3)?;
-              Ok(KvEntry {
-                key,
-                value,
-                versionstamp: version_to_versionstamp(version),
-              })
-            },
-          )?
-          .collect::<Result<Vec<_>, rusqlite::Error>>()?;
-        responses.push(ReadRangeOutput { entries });
-      }
+static NEXT_EXPIRATION_WATCHER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The schedule of the expiration sweeps of a database file, shared by the
+/// watchers of the `SqliteDb`s that have the file open with the same clock.
+/// Only one of the watchers sweeps, the others wait to take over once it
+/// stops.
+struct ExpirationSweep {
+  clock: Arc<dyn Clock>,
+  /// The shortest sweep interval of the databases sharing the sweep, in
+  /// milliseconds.
+  interval_ms: AtomicU64,
+  /// Unix timestamp in milliseconds of the next expiration sweep.
+  next_sweep_tx: Arc<watch::Sender<u64>>,
+  /// The id of the watcher that sweeps the file.
+  sweeper: Mutex<Option<u64>>,
+}
 
-      Ok(responses)
+impl ExpirationSweep {
+  fn new(clock: Arc<dyn Clock>, interval: Duration) -> Arc<Self> {
+    Arc::new(Self {
+      clock,
+      interval_ms: AtomicU64::new(interval.as_millis() as u64),
+      next_sweep_tx: Arc::new(watch::channel(u64::MAX).0),
+      sweeper: Mutex::new(None),
     })
-    .await
   }
 
-  async fn atomic_write(
-    &self,
-    state: Rc<RefCell<OpState>>,
-    write: AtomicWrite,
-  ) -> Result<Option<CommitResult>, AnyError> {
-    let write = Arc::new(write);
-    let (has_enqueues, commit_result) =
-      Self::run_tx(self.conn.clone(), move |tx| {
-        for check in &write.checks {
-          let real_versionstamp = tx
-            .prepare_cached(STATEMENT_KV_POINT_GET_VERSION_ONLY)?
-            .query_row([check.key.as_slice()], |row| row.get(0))
-            .optional()?
-            .map(version_to_versionstamp);
-          if real_versionstamp != check.versionstamp {
-            return Ok((false, None));
-          }
+  fn interval(&self) -> Duration {
+    Duration::from_millis(self.interval_ms.load(Ordering::Relaxed))
+  }
+
+  /// Makes the file be swept at least every `interval`, bringing the next
+  /// sweep forward if it's further out.
+  fn join(&self, interval: Duration) {
+    let interval_ms = interval.as_millis() as u64;
+    if interval_ms < self.interval_ms.fetch_min(interval_ms, Ordering::Relaxed)
+    {
+      let sweep_at = self.clock.now_ms() + interval_ms;
+      self.next_sweep_tx.send_if_modified(|next_sweep| {
+        if sweep_at < *next_sweep {
+          *next_sweep = sweep_at;
+          true
+        } else {
+          false
         }
+      });
+    }
+  }
 
-        let version: i64 = tx
-          .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
-          .query_row([], |row| row.get(0))?;
+  /// Makes the watcher with `id` the sweeper, unless another one already is.
+  fn claim(&self, id: u64) -> bool {
+    *self.sweeper.lock().unwrap().get_or_insert(id) == id
+  }
 
-        for mutation in &write.mutations {
-          match &mutation.kind {
-            MutationKind::Set(value) => {
-              let (value, encoding) = encode_value(value);
-              let changed =
-                tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
-                  mutation.key,
-                  value,
-                  &encoding,
-                  &version,
-                  mutation
-                    .expire_at
-                    .and_then(|x| i64::try_from(x).ok())
-                    .unwrap_or(-1i64)
-                ])?;
-              assert_eq!(changed, 1)
-            }
+  /// Wakes the other watchers to take over if the one with `id` is the
+  /// sweeper.
+  fn release(&self, id: u64) {
+    let mut sweeper = self.sweeper.lock().unwrap();
+    if *sweeper == Some(id) {
+      *sweeper = None;
+      self.next_sweep_tx.send_modify(|_| {});
+    }
+  }
+}
+
+/// Takes part in the expiration sweeps of the database file of a `SqliteDb`,
+/// sweeping through its connection while it is the sweeper. Stops once the
+/// database is closed or dropped.
+struct ExpirationWatcher {
+  id: u64,
+  task: deno_core::unsync::JoinHandle<()>,
+  sweep: Arc<ExpirationSweep>,
+}
+
+impl Drop for ExpirationWatcher {
+  fn drop(&mut self) {
+    self.task.abort();
+    self.sweep.release(self.id);
+  }
+}
+
+/// Spawns the expiration watcher of the database at `key`, which shares the
+/// sweeps of the file with the other databases of the process that have it
+/// open with the same clock. Their clocks may disagree on which keys have
+/// expired, so databases with other clocks, and those without a key, which
+/// are in memory, are swept on their own.
+fn spawn_expiration_watcher(
+  key: Option<&Path>,
+  conn: &ProtectedConn,
+  clock: Arc<dyn Clock>,
+  interval: Duration,
+) -> ExpirationWatcher {
+  let sweep = match key {
+    Some(key) => {
+      let mut sweeps = EXPIRATION_SWEEPS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap();
+      match sweeps.get(key).and_then(std::sync::Weak::upgrade) {
+        Some(sweep)
+          if Arc::as_ptr(&sweep.clock) as *const ()
+            == Arc::as_ptr(&clock) as *const () =>
+        {
+          sweep.join(interval);
+          sweep
+        }
+        Some(_) => ExpirationSweep::new(clock, interval),
+        None => {
+          let sweep = ExpirationSweep::new(clock, interval);
+          sweeps.retain(|_, sweep| sweep.strong_count() > 0);
+          sweeps.insert(key.to_path_buf(), Arc::downgrade(&sweep));
+          sweep
+        }
+      }
+    }
+    None => ExpirationSweep::new(clock, interval),
+  };
+  let id = NEXT_EXPIRATION_WATCHER_ID.fetch_add(1, Ordering::Relaxed);
+  let task = spawn(watch_expiration(id, conn.clone(), sweep.clone()));
+  ExpirationWatcher { id, task, sweep }
+}
+
+async fn watch_expiration(
+  id: u64,
+  db: ProtectedConn,
+  sweep: Arc<ExpirationSweep>,
+) {
+  let mut next_sweep_rx = sweep.next_sweep_tx.subscribe();
+  // Wait for the current sweeper to stop, if there is one.
+  while !sweep.claim(id) {
+    if next_sweep_rx.changed().await.is_err() {
+      return;
+    }
+  }
+
+  loop {
+    // Scan for expired keys
+    let now = sweep.clock.now_ms();
+    let res =
+      SqliteDb::run_write_tx("expiration_sweep", db.clone(), move |tx| {
+        tx.prepare_cached(STATEMENT_INDEX_DELETE_EXPIRED)?
+          .execute(params![now])?;
+        let deleted = tx
+          .prepare_cached(STATEMENT_KV_DELETE_EXPIRED)?
+          .execute(params![now])?;
+        // Deletions must be visible to versionstamp checks and watchers, just
+        // like explicit ones.
+        if deleted > 0 {
+          tx.prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
+            .query_row([], |row| row.get::<_, i64>(0))?;
+        }
+        tx.commit()?;
+        Ok(())
+      })
+      .await;
+    if let Err(e) = res {
+      eprintln!("kv: Error in expiration watcher: {}", e);
+    }
+
+    let interval = sweep.interval();
+    let jitter = interval.mul_f64(rand::thread_rng().gen_range(0.0..0.5));
+    let sweep_at =
+      sweep.clock.now_ms() + (interval + jitter).as_millis() as u64;
+    // Writes during the sweep may have brought the next one forward already.
+    sweep.next_sweep_tx.send_modify(|next_sweep| {
+      if *next_sweep <= now || sweep_at < *next_sweep {
+        *next_sweep = sweep_at;
+      }
+    });
+    next_sweep_rx.borrow_and_update();
+
+    // Sleep until the next sweep, which writes of short-lived keys may bring
+    // forward.
+    loop {
+      let next_sweep = *next_sweep_rx.borrow_and_update();
+      let now = sweep.clock.now_ms();
+      let sleep_duration =
+        Duration::from_millis(next_sweep.saturating_sub(now));
+      tokio::select! {
+        _ = tokio::time::sleep(sleep_duration) => break,
+        res = next_sweep_rx.changed() => {
+          if res.is_err() {
+            return;
+          }
+        }
+      }
+    }
+  }
+}
+
+/// A `set` or `delete` of a single key waiting to be committed by a
+/// [WriteCoalescer].
+struct CoalescedWrite {
+  key: Vec<u8>,
+  /// The encoded value and its encoding, or `None` to delete the key.
+  value: Option<(Vec<u8>, i64)>,
+  expire_at: Option<u64>,
+  /// Receives the result of the write, or `None` if it couldn't be
+  /// committed with the others.
+  reply: oneshot::Sender<Option<CommitResult>>,
+}
+
+/// Commits the writes submitted within a window of each other in one
+/// transaction, see [SqliteDbHandler::with_write_coalescing].
+///
+/// Dropping it closes the channel rather than aborting the task, so that a
+/// batch whose transaction is already running still reports its outcome.
+/// Writes still waiting for their window are committed right away, which
+/// fails once the connection is gone.
+struct WriteCoalescer {
+  tx: mpsc::Sender<CoalescedWrite>,
+}
+
+impl WriteCoalescer {
+  fn spawn(
+    conn: &ProtectedConn,
+    clock: Arc<dyn Clock>,
+    indexes: Arc<Vec<EncodedIndex>>,
+    window: Duration,
+  ) -> Self {
+    let (tx, rx) = mpsc::channel(MAX_COALESCED_WRITES);
+    spawn(commit_coalesced_writes(
+      rx,
+      conn.downgrade(),
+      clock,
+      indexes,
+      window,
+    ));
+    Self { tx }
+  }
+}
+
+async fn commit_coalesced_writes(
+  mut rx: mpsc::Receiver<CoalescedWrite>,
+  conn: WeakProtectedConn,
+  clock: Arc<dyn Clock>,
+  indexes: Arc<Vec<EncodedIndex>>,
+  window: Duration,
+) {
+  while let Some(first) = rx.recv().await {
+    let mut batch = vec![first];
+    let deadline = tokio::time::sleep(window);
+    tokio::pin!(deadline);
+    while batch.len() < MAX_COALESCED_WRITES {
+      tokio::select! {
+        write = rx.recv() => match write {
+          Some(write) => batch.push(write),
+          None => break,
+        },
+        _ = &mut deadline => break,
+      }
+    }
+
+    let Some(conn) = conn.upgrade() else {
+      return;
+    };
+    let (writes, replies): (Vec<_>, Vec<_>) = batch
+      .into_iter()
+      .map(|write| ((write.key, write.value, write.expire_at), write.reply))
+      .unzip();
+    let writes = Arc::new(writes);
+    let clock = clock.clone();
+    let indexes = indexes.clone();
+    let results = SqliteDb::run_write_tx("coalesced_write", conn, move |tx| {
+      let now = clock.now_ms();
+      let mut results = Vec::with_capacity(writes.len());
+      for (key, value, expire_at) in writes.iter() {
+        // Every write is a commit of its own as far as versionstamps go.
+        let version: i64 = tx
+          .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
+          .query_row([], |row| row.get(0))?;
+        let mut counts = MutationCounts::default();
+        match value {
+          Some((value, encoding)) => {
+            let created = set_value(
+              &tx,
+              key,
+              StoredValue(value, *encoding),
+              version,
+              expire_at
+                .and_then(|x| i64::try_from(x).ok())
+                .unwrap_or(-1i64),
+              now,
+            )?;
+            counts.record(1, created as u64, 0);
+          }
+          None => {
+            let changed = tx
+              .prepare_cached(STATEMENT_KV_POINT_DELETE)?
+              .execute(params![key])?;
+            assert!(changed == 0 || changed == 1);
+            counts.record(changed as u64, 0, changed as u64);
+          }
+        }
+        if !indexes.is_empty() {
+          reindex_key(&tx, &indexes, key, now)?;
+        }
+        results.push(CommitResult {
+          versionstamp: version_to_versionstamp(version),
+          old_values: vec![],
+          mutation_counts: Some(counts),
+        });
+      }
+      tx.commit()?;
+      Ok(results)
+    })
+    .await;
+
+    match results {
+      Ok(results) => {
+        for (reply, result) in replies.into_iter().zip(results) {
+          let _ = reply.send(Some(result));
+        }
+      }
+      Err(_) => {
+        for reply in replies {
+          let _ = reply.send(None);
+        }
+      }
+    }
+  }
+}
+
+#[async_trait(?Send)]
+impl Database for SqliteDb {
+  type QMH = DequeuedMessage;
+
+  async fn snapshot_read(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    requests: Vec<ReadRange>,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<ReadRangeOutput>, AnyError> {
+    let requests = Arc::new(requests);
+    let clock = self.clock.clone();
+    self
+      .run_read_tx("snapshot_read", options.consistency, move |tx| {
+        // Entries that have expired but haven't been swept yet are not
+        // returned.
+        let now = clock.now_ms();
+        let mut responses = Vec::with_capacity(requests.len());
+        for request in &*requests {
+          responses.push(read_range(&tx, request, now)?);
+        }
+
+        Ok(responses)
+      })
+      .await
+  }
+
+  async fn snapshot_read_stream(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    range: &mut ReadRange,
+    options: SnapshotReadOptions,
+  ) -> Result<Vec<KvEntry>, AnyError> {
+    // Each batch is read in its own transaction, resuming after the last key
+    // of the previous batch, so only one batch is held in memory at a time.
+    let request = Arc::new(ReadRange {
+      start: range.start.clone(),
+      end: range.end.clone(),
+      limit: range.limit,
+      reverse: range.reverse,
+      max_bytes: None,
+    });
+    let clock = self.clock.clone();
+    let entries = self
+      .run_read_tx("snapshot_read_stream", options.consistency, move |tx| {
+        let now = clock.now_ms();
+        Ok(read_range(&tx, &request, now)?.entries)
+      })
+      .await?;
+    range.advance(&entries);
+    Ok(entries)
+  }
+
+  async fn count_range(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    options: SnapshotReadOptions,
+  ) -> Result<u64, AnyError> {
+    let clock = self.clock.clone();
+    self
+      .run_read_tx("count_range", options.consistency, move |tx| {
+        // Like reads, the count leaves out entries that have expired but
+        // haven't been swept yet.
+        let count = tx
+          .prepare_cached(STATEMENT_KV_RANGE_COUNT)?
+          .query_row(params![start, end, clock.now_ms()], |row| row.get(0))?;
+        Ok(count)
+      })
+      .await
+  }
+
+  async fn read_union(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    limit: NonZeroU32,
+    options: SnapshotReadOptions,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    let ranges = Arc::new(coalesce_ranges(ranges));
+    let clock = self.clock.clone();
+    self
+      .run_read_tx("read_union", options.consistency, move |tx| {
+        // The ranges are disjoint and sorted, so reading them one after the
+        // other yields the entries in key order, and reading stops as soon as
+        // `limit` is reached.
+        let now = clock.now_ms();
+        let mut entries = Vec::new();
+        for (start, end) in &*ranges {
+          let remaining = limit.get() - entries.len() as u32;
+          let request = ReadRange {
+            start: start.clone(),
+            end: end.clone(),
+            // Once `limit` is reached, a single entry tells whether there are
+            // more.
+            limit: NonZeroU32::new(remaining)
+              .unwrap_or(NonZeroU32::new(1).unwrap()),
+            reverse: false,
+            max_bytes: None,
+          };
+          let output = read_range(&tx, &request, now)?;
+          if remaining == 0 {
+            if output.entries.is_empty() {
+              continue;
+            }
+            return Ok(ReadRangeOutput {
+              entries,
+              has_more: true,
+            });
+          }
+          entries.extend(output.entries);
+          if output.has_more {
+            return Ok(ReadRangeOutput {
+              entries,
+              has_more: true,
+            });
+          }
+        }
+        Ok(ReadRangeOutput {
+          entries,
+          has_more: false,
+        })
+      })
+      .await
+  }
+
+  async fn atomic_write(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    write: AtomicWrite,
+  ) -> Result<CommitOutcome, AnyError> {
+    self.check_writable()?;
+    let earliest_expire_at = write
+      .mutations
+      .iter()
+      .filter_map(|m| m.expire_at)
+      .min()
+      .filter(|_| !write.dry_run);
+    if let Some(commit_result) = self.coalesce_write(&write).await {
+      if let Some(expire_at) = earliest_expire_at {
+        self.bring_sweep_forward(expire_at);
+      }
+      return Ok(CommitOutcome::Committed(commit_result));
+    }
+    let write = Arc::new(write);
+    let default_backoff_schedule = self.default_backoff_schedule.clone();
+    let clock = self.clock.clone();
+    let indexes = self.indexes.clone();
+    let (has_enqueues, commit_result) =
+      Self::run_write_tx("atomic_write", self.conn.clone(), move |tx| {
+        let now = clock.now_ms();
+
+        let check_failed = |index: usize| CommitOutcome::CheckFailed {
+          failed_index: Some(index),
+        };
+        for (i, check) in write.checks.iter().enumerate() {
+          let passed = match &check.kind {
+            CheckKind::Versionstamp(versionstamp) => {
+              let real_versionstamp = tx
+                .prepare_cached(STATEMENT_KV_POINT_GET_VERSION_ONLY)?
+                .query_row(params![check.key, now], |row| row.get(0))
+                .optional()?
+                .map(version_to_versionstamp);
+              real_versionstamp == *versionstamp
+            }
+            CheckKind::Value(value) => {
+              let real_value = tx
+                .prepare_cached(STATEMENT_KV_POINT_GET_VALUE_ONLY)?
+                .query_row(params![check.key, now], |row| {
+                  let StoredBytes(value) = row.get(0)?;
+                  let encoding: i64 = row.get(1)?;
+                  Ok(decode_value(value, encoding))
+                })
+                .optional()?;
+              match (real_value, value) {
+                (Some(real_value), Some(value)) => {
+                  encode_value(&real_value) == encode_value(value)
+                }
+                (None, None) => true,
+                _ => false,
+              }
+            }
+            CheckKind::QueueRunning(id) => tx
+              .prepare_cached(STATEMENT_QUEUE_IS_RUNNING)?
+              .exists([id])?,
+          };
+          if !passed {
+            return Ok((false, check_failed(i)));
+          }
+        }
+        // `SetIfAbsent` and `Move` mutations carry implicit checks, which are
+        // numbered after the explicit ones.
+        let mut implicit_checks = write.checks.len()..;
+        let implicit_check_indexes = write
+          .mutations
+          .iter()
+          .map(|mutation| match mutation.kind {
+            MutationKind::SetIfAbsent(_) | MutationKind::Move { .. } => {
+              implicit_checks.next()
+            }
+            _ => None,
+          })
+          .collect::<Vec<_>>();
+        // A `Move` is only checked while it's applied, so a failed
+        // `SetIfAbsent` is reported once the mutations before it have been
+        // applied, in case the check of a `Move` among them fails first.
+        let mut failed_set_if_absent = None;
+        for (mutation, check_index) in
+          write.mutations.iter().zip(&implicit_check_indexes)
+        {
+          if let MutationKind::SetIfAbsent(_) = mutation.kind {
+            let exists = tx
+              .prepare_cached(STATEMENT_KV_POINT_GET_VERSION_ONLY)?
+              .query_row(params![mutation.key, now], |_| Ok(()))
+              .optional()?
+              .is_some();
+            if exists {
+              failed_set_if_absent = *check_index;
+              break;
+            }
+          }
+        }
+
+        let version: i64 = tx
+          .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
+          .query_row([], |row| row.get(0))?;
+
+        let mut old_values = Vec::new();
+        let mut counts = MutationCounts::default();
+        for (mutation, check_index) in
+          write.mutations.iter().zip(&implicit_check_indexes)
+        {
+          if check_index.is_some() && *check_index == failed_set_if_absent {
+            return Ok((false, check_failed(check_index.unwrap())));
+          }
+          if write.return_old {
+            old_values.push(match mutation.kind {
+              MutationKind::Set(_)
+              | MutationKind::SetIfAbsent(_)
+              | MutationKind::Delete => point_get(&tx, &mutation.key, now)?,
+              _ => None,
+            });
+          }
+
+          match &mutation.kind {
+            MutationKind::Set(value) | MutationKind::SetIfAbsent(value) => {
+              let (value, encoding) = encode_value(value);
+              let created = set_value(
+                &tx,
+                &mutation.key,
+                StoredValue(&value, encoding),
+                version,
+                mutation
+                  .expire_at
+                  .and_then(|x| i64::try_from(x).ok())
+                  .unwrap_or(-1i64),
+                now,
+              )?;
+              counts.record(1, created as u64, 0);
+            }
             MutationKind::Delete => {
               let changed = tx
                 .prepare_cached(STATEMENT_KV_POINT_DELETE)?
                 .execute(params![mutation.key])?;
-              assert!(changed == 0 || changed == 1)
+              assert!(changed == 0 || changed == 1);
+              counts.record(changed as u64, 0, changed as u64);
+            }
+            MutationKind::DeletePrefix => {
+              let deleted = delete_prefix(&tx, &mutation.key, now)? as u64;
+              counts.record((deleted > 0) as u64, 0, deleted);
+            }
+            MutationKind::Move { to, overwrite } => {
+              let Some(created) =
+                move_key(&tx, &mutation.key, to, *overwrite, version, now)?
+              else {
+                return Ok((false, check_failed(check_index.unwrap())));
+              };
+              counts.record(1, created as u64, 1);
             }
             MutationKind::Sum(operand) => {
-              mutate_le64(
+              let created = mutate_le64(
                 &tx,
                 &mutation.key,
                 "sum",
                 operand,
                 version,
+                now,
                 |a, b| a.wrapping_add(b),
               )?;
+              counts.record(1, created as u64, 0);
             }
             MutationKind::Min(operand) => {
-              mutate_le64(
+              let created = mutate_le64(
                 &tx,
                 &mutation.key,
                 "min",
                 operand,
                 version,
+                now,
                 |a, b| a.min(b),
               )?;
+              counts.record(1, created as u64, 0);
             }
             MutationKind::Max(operand) => {
-              mutate_le64(
+              let created = mutate_le64(
                 &tx,
                 &mutation.key,
                 "max",
                 operand,
                 version,
+                now,
                 |a, b| a.max(b),
               )?;
+              counts.record(1, created as u64, 0);
             }
           }
         }
 
-        let now = SystemTime::now()
-          .duration_since(SystemTime::UNIX_EPOCH)
-          .unwrap()
-          .as_millis() as u64;
+        // Index entries are derived from the final value of each key, once
+        // all mutations have been applied.
+        if !indexes.is_empty() {
+          for mutation in &write.mutations {
+            match &mutation.kind {
+              MutationKind::DeletePrefix => {
+                let start: Vec<u8> =
+                  mutation.key.iter().copied().chain(Some(0)).collect();
+                let end: Vec<u8> =
+                  mutation.key.iter().copied().chain(Some(0xff)).collect();
+                tx.prepare_cached(STATEMENT_INDEX_DELETE_RANGE)?
+                  .execute(params![start, end])?;
+              }
+              MutationKind::Move { to, .. } => {
+                reindex_key(&tx, &indexes, &mutation.key, now)?;
+                reindex_key(&tx, &indexes, to, now)?;
+              }
+              _ => reindex_key(&tx, &indexes, &mutation.key, now)?,
+            }
+          }
+        }
 
         let has_enqueues = !write.enqueues.is_empty();
-        for enqueue in &write.enqueues {
-          let id = Uuid::new_v4().to_string();
-          let backoff_schedule = serde_json::to_string(
-            &enqueue
-              .backoff_schedule
-              .as_deref()
-              .or_else(|| Some(&DEFAULT_BACKOFF_SCHEDULE[..])),
-          )?;
-          let keys_if_undelivered =
-            serde_json::to_string(&enqueue.keys_if_undelivered)?;
+        add_enqueues(&tx, &write.enqueues, &default_backoff_schedule, now)?;
 
-          let changed =
-            tx.prepare_cached(STATEMENT_QUEUE_ADD_READY)?
-              .execute(params![
-                now + enqueue.delay_ms,
-                id,
-                &enqueue.payload,
-                &backoff_schedule,
-                &keys_if_undelivered
-              ])?;
-          assert_eq!(changed, 1)
+        // Dropping the transaction rolls it back, version bump included, so
+        // a dry run has no versionstamp of its own.
+        if write.dry_run {
+          drop(tx);
+          return Ok((
+            false,
+            CommitOutcome::DryRun(DryRunResult {
+              old_values,
+              mutation_counts: Some(counts),
+            }),
+          ));
         }
-
         tx.commit()?;
         let new_versionstamp = version_to_versionstamp(version);
 
         Ok((
           has_enqueues,
-          Some(CommitResult {
+          CommitOutcome::Committed(CommitResult {
             versionstamp: new_versionstamp,
+            old_values,
+            mutation_counts: Some(counts),
           }),
         ))
       })
       .await?;
 
     if has_enqueues {
-      match self.queue.get() {
-        Some(queue) => {
-          let _ = queue.waker_tx.send(());
-        }
-        None => {
-          if let Some(waker_key) = &self.queue_waker_key {
-            let (waker_tx, _) =
-              shared_queue_waker_channel(waker_key, state.clone());
-            let _ = waker_tx.send(());
-          }
-        }
-      }
+      self.wake_queue(state);
+    }
+    if let (Some(expire_at), CommitOutcome::Committed(_)) =
+      (earliest_expire_at, &commit_result)
+    {
+      self.bring_sweep_forward(expire_at);
     }
     Ok(commit_result)
   }
 
+  async fn enqueue(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    enqueues: Vec<Enqueue>,
+  ) -> Result<[u8; 10], AnyError> {
+    self.check_writable()?;
+    let enqueues = Arc::new(enqueues);
+    let default_backoff_schedule = self.default_backoff_schedule.clone();
+    let clock = self.clock.clone();
+    let versionstamp =
+      Self::run_write_tx("enqueue", self.conn.clone(), move |tx| {
+        let now = clock.now_ms();
+        let version: i64 = tx
+          .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
+          .query_row([], |row| row.get(0))?;
+        add_enqueues(&tx, &enqueues, &default_backoff_schedule, now)?;
+        tx.commit()?;
+        Ok(version_to_versionstamp(version))
+      })
+      .await?;
+
+    self.wake_queue(state);
+    Ok(versionstamp)
+  }
+
   async fn dequeue_next_message(
     &self,
     state: Rc<RefCell<OpState>>,
   ) -> Result<Option<Self::QMH>, AnyError> {
+    self.check_writable()?;
     let queue = self
       .queue
       .get_or_init(|| async move {
@@ -936,146 +3259,1122 @@ impl Database for SqliteDb {
             None => broadcast::channel(1),
           }
         };
-        SqliteQueue::new(self.conn.clone(), waker_tx, waker_rx)
+        SqliteQueue::new(
+          self.conn.clone(),
+          self.clock.clone(),
+          self.metrics.clone(),
+          self.max_delivery_attempts,
+          self.fair_dequeue,
+          self.queue_visibility_timeout,
+          waker_tx,
+          waker_rx,
+          self.dispatch_concurrency_limit,
+        )
       })
       .await;
     let handle = queue.dequeue().await?;
     Ok(handle)
   }
 
-  fn close(&self) {
-    if let Some(queue) = self.queue.get() {
-      queue.shutdown();
+  async fn export(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+  ) -> Result<u64, AnyError> {
+    let path = PathBuf::from(path);
+    {
+      let mut state = state.borrow_mut();
+      (self.permissions.check_write)(&mut state, &path, "Deno.Kv.export")?;
     }
 
-    self.expiration_watcher.abort();
+    let clock = self.clock.clone();
+    Self::run_tx("export", self.conn.clone(), move |tx| {
+      let now = clock.now_ms();
+      let mut writer = BufWriter::new(std::fs::File::create(&path)?);
+      let mut start = vec![];
+      let end = vec![0xff];
+      let mut count = 0u64;
+      loop {
+        let entries = tx
+          .prepare_cached(STATEMENT_KV_RANGE_SCAN)?
+          .query_map(
+            params![start, end, now, EXPORT_BATCH_SIZE],
+            kv_entry_from_row,
+          )?
+          .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        for entry in &entries {
+          serde_json::to_writer(&mut writer, &ExportedEntry::try_from(entry)?)?;
+          writer.write_all(b"\n")?;
+        }
+        count += entries.len() as u64;
 
-    // The above `abort()` operation is asynchronous. It's not
-    // guaranteed that the sqlite connection will be closed immediately.
-    // So here we synchronously take the conn mutex and drop the connection.
-    //
-    // This blocks the event loop if the connection is still being used,
-    // but ensures correctness - deleting the database file after calling
-    // the `close` method will always work.
-    self.conn.conn.lock().unwrap().take();
+        match entries.last() {
+          Some(last) if entries.len() == EXPORT_BATCH_SIZE as usize => {
+            start = last.key.iter().copied().chain(Some(0)).collect();
+          }
+          _ => break,
+        }
+      }
+      writer.flush()?;
+      Ok(count)
+    })
+    .await
   }
-}
 
-/// Mutates a LE64 value in the database, defaulting to setting it to the
-/// operand if it doesn't exist.
-fn mutate_le64(
-  tx: &Transaction,
-  key: &[u8],
-  op_name: &str,
-  operand: &Value,
-  new_version: i64,
-  mutate: impl FnOnce(u64, u64) -> u64,
-) -> Result<(), AnyError> {
-  let Value::U64(operand) = *operand else {
-    return Err(type_error(format!(
-      "Failed to perform '{op_name}' mutation on a non-U64 operand"
-    )));
-  };
+  async fn import(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    path: String,
+    overwrite: bool,
+  ) -> Result<u64, AnyError> {
+    self.check_writable()?;
+    let path = PathBuf::from(path);
+    {
+      let mut state = state.borrow_mut();
+      (self.permissions.check_read)(&mut state, &path, "Deno.Kv.import")?;
+    }
 
-  let old_value = tx
-    .prepare_cached(STATEMENT_KV_POINT_GET_VALUE_ONLY)?
-    .query_row([key], |row| {
-      let value: Vec<u8> = row.get(0)?;
-      let encoding: i64 = row.get(1)?;
+    let clock = self.clock.clone();
+    let indexes = self.indexes.clone();
+    let (count, earliest_expire_at) =
+      Self::run_write_tx("import", self.conn.clone(), move |tx| {
+      let now = clock.now_ms();
+      let reader = BufReader::new(std::fs::File::open(&path)?);
+      let version: i64 = tx
+        .prepare_cached(STATEMENT_INC_AND_GET_DATA_VERSION)?
+        .query_row([], |row| row.get(0))?;
 
-      let value = decode_value(value, encoding);
-      Ok(value)
-    })
-    .optional()?;
+      let mut count = 0u64;
+      let mut earliest_expire_at: Option<u64> = None;
+      for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+          continue;
+        }
+        let entry: ExportedEntry = serde_json::from_str(&line)?;
+        let (key, value, encoding, expire_at) = entry.into_raw()?;
+        crate::check_write_key_size(&key)?;
+        crate::check_encoded_value_size(&value)?;
+        if expire_at.is_some_and(|expire_at| expire_at <= now) {
+          continue;
+        }
 
-  let new_value = match old_value {
-    Some(Value::U64(old_value) ) => mutate(old_value, operand),
-    Some(_) => return Err(type_error(format!("Failed to perform '{op_name}' mutation on a non-U64 value in the database"))),
-    None => operand,
-  };
+        if !overwrite {
+          let exists = tx
+            .prepare_cached(STATEMENT_KV_POINT_GET_VERSION_ONLY)?
+            .query_row(params![key, now], |row| row.get::<_, i64>(0))
+            .optional()?
+            .is_some();
+          if exists {
+            return Err(type_error(
+              "Import would overwrite an existing key; pass `overwrite` to replace existing keys",
+            ));
+          }
+        }
 
-  let new_value = Value::U64(new_value);
-  let (new_value, encoding) = encode_value(&new_value);
+        let expiration_ms = expire_at.map_or(-1, |expire_at| expire_at as i64);
+        let changed = tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(
+          params![
+            key,
+            StoredValue(&value, encoding),
+            &encoding,
+            &version,
+            expiration_ms,
+            now
+          ],
+        )?;
+        assert_eq!(changed, 1);
+        reindex_key(&tx, &indexes, &key, now)?;
+        count += 1;
+        if let Some(expire_at) = expire_at {
+          earliest_expire_at = Some(
+            earliest_expire_at.map_or(expire_at, |earliest| earliest.min(expire_at)),
+          );
+        }
+      }
 
-  let changed = tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
-    key,
+      tx.commit()?;
+      Ok((count, earliest_expire_at))
+    })
+    .await?;
+    if let Some(expire_at) = earliest_expire_at {
+      self.bring_sweep_forward(expire_at);
+    }
+    Ok(count)
+  }
+
+  async fn list_dead_letters(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    limit: u32,
+  ) -> Result<Vec<DeadLetterMessage>, AnyError> {
+    Self::run_tx("list_dead_letters", self.conn.clone(), move |tx| {
+      let messages = tx
+        .prepare_cached(STATEMENT_QUEUE_LIST_DEAD_LETTER)?
+        .query_map([limit], |row| {
+          Ok(DeadLetterMessage {
+            id: row.get(0)?,
+            payload: row.get(1)?,
+            enqueued_at_ms: row.get(2)?,
+            failed_at_ms: row.get(3)?,
+            failure_count: row.get(4)?,
+          })
+        })?
+        .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+      Ok(messages)
+    })
+    .await
+  }
+
+  async fn retry_dead_letter(
+    &self,
+    state: Rc<RefCell<OpState>>,
+    id: String,
+  ) -> Result<bool, AnyError> {
+    self.check_writable()?;
+    let default_backoff_schedule = self.default_backoff_schedule.clone();
+    let clock = self.clock.clone();
+    let retried =
+      Self::run_write_tx("retry_dead_letter", self.conn.clone(), move |tx| {
+        let Some((data, keys_if_undelivered, enqueued_at, group)) = tx
+          .prepare_cached(STATEMENT_QUEUE_GET_DEAD_LETTER_BY_ID)?
+          .query_row([&id], |row| {
+            let data: Vec<u8> = row.get(0)?;
+            let keys_if_undelivered: String = row.get(1)?;
+            let enqueued_at: u64 = row.get(2)?;
+            let group: Option<String> = row.get(3)?;
+            Ok((data, keys_if_undelivered, enqueued_at, group))
+          })
+          .optional()?
+        else {
+          return Ok(false);
+        };
+
+        let changed = tx
+          .prepare_cached(STATEMENT_QUEUE_REMOVE_DEAD_LETTER)?
+          .execute([&id])?;
+        assert_eq!(changed, 1);
+
+        // The message is delivered again right away, with a fresh default
+        // backoff schedule.
+        let now = clock.now_ms();
+        let backoff_schedule =
+          serde_json::to_string(&*default_backoff_schedule)?;
+        let changed =
+          tx.prepare_cached(STATEMENT_QUEUE_ADD_READY)?
+            .execute(params![
+              now,
+              id,
+              &data,
+              &backoff_schedule,
+              &keys_if_undelivered,
+              enqueued_at,
+              0,
+              group
+            ])?;
+        assert_eq!(changed, 1);
+
+        tx.commit()?;
+        Ok(true)
+      })
+      .await?;
+
+    if retried {
+      self.wake_queue(state);
+    }
+    Ok(retried)
+  }
+
+  async fn queue_stats(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+  ) -> Result<QueueStats, AnyError> {
+    Self::run_tx("queue_stats", self.conn.clone(), move |tx| {
+      let ready = tx
+        .prepare_cached(STATEMENT_QUEUE_COUNT_READY)?
+        .query_row([], |row| row.get(0))?;
+      let running = tx
+        .prepare_cached(STATEMENT_QUEUE_COUNT_RUNNING)?
+        .query_row([], |row| row.get(0))?;
+      let earliest_ready_ms = tx
+        .prepare_cached(STATEMENT_QUEUE_GET_EARLIEST_READY)?
+        .query_row([], |row| row.get(0))
+        .optional()?;
+      Ok(QueueStats {
+        ready,
+        running,
+        earliest_ready_ms,
+      })
+    })
+    .await
+  }
+
+  async fn stats(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+  ) -> Result<DatabaseStats, AnyError> {
+    Self::run_tx("stats", self.conn.clone(), move |tx| {
+      let (key_count, total_value_bytes) = tx
+        .prepare_cached(STATEMENT_KV_STATS)?
+        .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+      let db_file_bytes = tx
+        .prepare_cached(STATEMENT_DB_FILE_BYTES)?
+        .query_row([], |row| row.get(0))?;
+      Ok(DatabaseStats {
+        key_count,
+        total_value_bytes,
+        db_file_bytes,
+      })
+    })
+    .await
+  }
+
+  async fn ping(&self, _state: Rc<RefCell<OpState>>) -> Result<(), AnyError> {
+    Self::run_conn("ping", self.conn.clone(), move |conn| {
+      conn.query_row("select 1", [], |_| Ok(()))?;
+      Ok(())
+    })
+    .await
+  }
+
+  async fn checkpoint(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    mode: MaintenanceMode,
+  ) -> Result<CheckpointResult, AnyError> {
+    self.check_writable()?;
+    Self::run_conn("checkpoint", self.conn.clone(), move |conn| {
+      let checkpoint_mode = match mode {
+        MaintenanceMode::Passive => "PASSIVE",
+        MaintenanceMode::Truncate | MaintenanceMode::Vacuum => "TRUNCATE",
+      };
+      if mode == MaintenanceMode::Vacuum {
+        conn.execute_batch("vacuum")?;
+      }
+      let result = conn.query_row(
+        &format!("pragma wal_checkpoint({})", checkpoint_mode),
+        [],
+        |row| {
+          let busy: i64 = row.get(0)?;
+          Ok(CheckpointResult {
+            busy: busy != 0,
+            log_frames: row.get(1)?,
+            checkpointed_frames: row.get(2)?,
+          })
+        },
+      )?;
+      Ok(result)
+    })
+    .await
+  }
+
+  async fn check_integrity(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+  ) -> Result<Vec<IntegrityProblem>, AnyError> {
+    // The checks run in separate read transactions, on the read pool if
+    // there is one, so that other operations can be interleaved with them.
+    // `quick_check` skips verifying that indexes match their tables, which
+    // keeps the time that it holds a connection linear in the size of the
+    // database.
+    let mut problems = self
+      .run_read_tx("integrity_check", Consistency::Eventual, |tx| {
+        let mut stmt = tx.prepare(&format!(
+          "pragma quick_check({})",
+          MAX_INTEGRITY_CHECK_ERRORS
+        ))?;
+        let problems = stmt
+          .query_map([], |row| row.get::<_, String>(0))?
+          .filter(|x| !matches!(x.as_deref(), Ok("ok")))
+          .map(|message| {
+            Ok(IntegrityProblem {
+              kind: IntegrityProblemKind::Corruption,
+              message: message?,
+            })
+          })
+          .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        Ok(problems)
+      })
+      .await?;
+
+    let clock = self.clock.clone();
+    let visibility_timeout = self.queue_visibility_timeout;
+    let schema_problems = self
+      .run_read_tx("schema_check", Consistency::Eventual, move |tx| {
+        let mut problems = Vec::new();
+
+        let (count, max_version, data_version): (u64, Option<i64>, i64) = tx
+          .query_row(STATEMENT_INTEGRITY_VERSION_AHEAD, [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+          })?;
+        if count > 0 {
+          problems.push(IntegrityProblem {
+            kind: IntegrityProblemKind::VersionAhead,
+            message: format!(
+              "{} entries have a version newer than the database version {} \
+               (up to {})",
+              count,
+              data_version,
+              max_version.unwrap_or_default()
+            ),
+          });
+        }
+
+        // The deadline of a running message is the time it started running
+        // plus the visibility timeout.
+        let stale_deadline = (clock.now_ms()
+          + visibility_timeout.as_millis() as u64)
+          .saturating_sub(STALE_RUNNING_MESSAGE_THRESHOLD_MS);
+        let count: u64 = tx.query_row(
+          STATEMENT_INTEGRITY_STALE_RUNNING,
+          [stale_deadline],
+          |row| row.get(0),
+        )?;
+        if count > 0 {
+          problems.push(IntegrityProblem {
+            kind: IntegrityProblemKind::StaleRunningMessage,
+            message: format!(
+              "{} queue messages have been running for more than {} seconds",
+              count,
+              STALE_RUNNING_MESSAGE_THRESHOLD_MS / 1000
+            ),
+          });
+        }
+
+        let count: u64 =
+          tx.query_row(STATEMENT_INTEGRITY_INVALID_EXPIRATION, [], |row| {
+            row.get(0)
+          })?;
+        if count > 0 {
+          problems.push(IntegrityProblem {
+            kind: IntegrityProblemKind::InvalidExpiration,
+            message: format!(
+              "{} entries have an invalid expiration time",
+              count
+            ),
+          });
+        }
+
+        Ok(problems)
+      })
+      .await?;
+
+    problems.extend(schema_problems);
+    Ok(problems)
+  }
+
+  async fn read_index(
+    &self,
+    _state: Rc<RefCell<OpState>>,
+    name: String,
+    range: ReadRange,
+  ) -> Result<ReadRangeOutput, AnyError> {
+    if !self
+      .indexes
+      .iter()
+      .any(|index| index.extract.is_some() && index.name == name)
+    {
+      return Err(type_error(format!("Unknown index '{name}'")));
+    }
+    let name = Arc::new(name);
+    let range = Arc::new(range);
+    let clock = self.clock.clone();
+    Self::run_tx("read_index", self.conn.clone(), move |tx| {
+      read_index_range(&tx, &name, &range, clock.now_ms())
+    })
+    .await
+  }
+
+  async fn close_graceful(&self, timeout: Duration) -> Result<bool, AnyError> {
+    let drained = match self.queue.get() {
+      Some(queue) => queue.drain(timeout).await,
+      None => true,
+    };
+    self.close();
+    Ok(drained)
+  }
+
+  fn close(&self) {
+    if let Some(queue) = self.queue.get() {
+      queue.shutdown();
+    }
+
+    // Stops the watcher, letting one of another database of the same file
+    // take over the sweeps.
+    self.expiration_watcher.take();
+    // Writes waiting to be coalesced fail once the connection is gone, while
+    // a batch that is already committing still reports its outcome.
+    self.write_coalescer.take();
+
+    // The above `abort()` operation is asynchronous. It's not
+    // guaranteed that the sqlite connection will be closed immediately.
+    // So here we synchronously take the conn mutex and drop the connection.
+    //
+    // This blocks the event loop if the connection is still being used,
+    // but ensures correctness - deleting the database file after calling
+    // the `close` method will always work.
+    self.conn.conn.lock().unwrap().take();
+    for slot in &self.read_pool {
+      slot.conn.conn.lock().unwrap().take();
+    }
+  }
+}
+
+fn kv_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<KvEntry> {
+  let key: Vec<u8> = row.get(0)?;
+  let StoredBytes(value) = row.get(1)?;
+  let encoding: i64 = row.get(2)?;
+
+  let value = decode_value(value, encoding);
+
+  let version: i64 = row.get(3)?;
+  let expiration_ms: i64 = row.get(4)?;
+  let commit_ms: i64 = row.get(5)?;
+  Ok(KvEntry {
+    key,
+    value,
+    versionstamp: version_to_versionstamp(version),
+    expire_at_ms: expiration_ms_to_expire_at(expiration_ms),
+    commit_ms: u64::try_from(commit_ms).ok(),
+  })
+}
+
+/// Converts the `expiration_ms` column, where `-1` means no expiration, to
+/// the `expire_at_ms` of a `KvEntry`.
+fn expiration_ms_to_expire_at(expiration_ms: i64) -> Option<u64> {
+  u64::try_from(expiration_ms).ok()
+}
+
+/// Reads the current entry for a key, if it exists and has not expired.
+fn point_get(
+  tx: &Transaction,
+  key: &[u8],
+  now: u64,
+) -> Result<Option<KvEntry>, AnyError> {
+  let entry = tx
+    .prepare_cached(STATEMENT_KV_POINT_GET)?
+    .query_row(params![key, now], |row| {
+      let StoredBytes(value) = row.get(0)?;
+      let encoding: i64 = row.get(1)?;
+      let version: i64 = row.get(2)?;
+      let expiration_ms: i64 = row.get(3)?;
+      let commit_ms: i64 = row.get(4)?;
+      Ok(KvEntry {
+        key: key.to_vec(),
+        value: decode_value(value, encoding),
+        versionstamp: version_to_versionstamp(version),
+        expire_at_ms: expiration_ms_to_expire_at(expiration_ms),
+        commit_ms: u64::try_from(commit_ms).ok(),
+      })
+    })
+    .optional()?;
+  Ok(entry)
+}
+
+/// Sets the value of `key`, and returns whether the key was created: whether
+/// it had no live entry before. Overwriting an expired entry that hasn't been
+/// swept yet creates the key as far as readers are concerned. Only a write to
+/// a live key takes a second statement.
+fn set_value(
+  tx: &Transaction,
+  key: &[u8],
+  value: StoredValue,
+  version: i64,
+  expiration_ms: i64,
+  now: u64,
+) -> Result<bool, AnyError> {
+  let encoding = value.1;
+  let changed =
+    tx.prepare_cached(STATEMENT_KV_POINT_CREATE)?
+      .execute(params![
+        key,
+        value,
+        encoding,
+        version,
+        expiration_ms,
+        now,
+        now
+      ])?;
+  if changed == 1 {
+    return Ok(true);
+  }
+  let changed = tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
+    key,
+    value,
+    encoding,
+    version,
+    expiration_ms,
+    now,
+  ])?;
+  assert_eq!(changed, 1);
+  Ok(false)
+}
+
+/// Mutates a LE64 value in the database, defaulting to setting it to the
+/// operand if it doesn't exist or has expired. Returns whether the key was
+/// created, in the same sense as `set_value`.
+fn mutate_le64(
+  tx: &Transaction,
+  key: &[u8],
+  op_name: &str,
+  operand: &Value,
+  new_version: i64,
+  now: u64,
+  mutate: impl FnOnce(u64, u64) -> u64,
+) -> Result<bool, AnyError> {
+  let Value::U64(operand) = *operand else {
+    return Err(type_error(format!(
+      "Failed to perform '{op_name}' mutation on a non-U64 operand"
+    )));
+  };
+
+  let old_value = tx
+    .prepare_cached(STATEMENT_KV_POINT_GET_VALUE_ONLY)?
+    .query_row(params![key, now], |row| {
+      let StoredBytes(value) = row.get(0)?;
+      let encoding: i64 = row.get(1)?;
+
+      let value = decode_value(value, encoding);
+      Ok(value)
+    })
+    .optional()?;
+
+  let created = old_value.is_none();
+  let new_value = match old_value {
+    Some(Value::U64(old_value) ) => mutate(old_value, operand),
+    Some(_) => return Err(type_error(format!("Failed to perform '{op_name}' mutation on a non-U64 value in the database"))),
+    None => operand,
+  };
+
+  let new_value = Value::U64(new_value);
+  let (new_value, encoding) = encode_value(&new_value);
+
+  let changed = tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
+    key,
     &new_value[..],
     encoding,
     new_version,
     -1i64,
+    now,
   ])?;
   assert_eq!(changed, 1);
 
-  Ok(())
+  Ok(created)
 }
 
-fn version_to_versionstamp(version: i64) -> [u8; 10] {
-  let mut versionstamp = [0; 10];
-  versionstamp[..8].copy_from_slice(&version.to_be_bytes());
-  versionstamp
+/// Moves the live entry at `from` to `to` with a new version and commit
+/// time, keeping its expiration. Returns `None` without changing anything if `from` doesn't
+/// exist, or if `to` exists and `overwrite` is not set. Otherwise returns
+/// whether `to` was created.
+fn move_key(
+  tx: &Transaction,
+  from: &[u8],
+  to: &[u8],
+  overwrite: bool,
+  new_version: i64,
+  now: u64,
+) -> Result<Option<bool>, AnyError> {
+  let source = tx
+    .prepare_cached(STATEMENT_KV_POINT_GET)?
+    .query_row(params![from, now], |row| {
+      let StoredBytes(value) = row.get(0)?;
+      let encoding: i64 = row.get(1)?;
+      let expiration_ms: i64 = row.get(3)?;
+      Ok((value, encoding, expiration_ms))
+    })
+    .optional()?;
+  let Some((value, encoding, expiration_ms)) = source else {
+    return Ok(None);
+  };
+
+  let exists = tx
+    .prepare_cached(STATEMENT_KV_POINT_GET_VERSION_ONLY)?
+    .query_row(params![to, now], |_| Ok(()))
+    .optional()?
+    .is_some();
+  if exists && !overwrite {
+    return Ok(None);
+  }
+
+  let changed = tx.prepare_cached(STATEMENT_KV_POINT_SET)?.execute(params![
+    to,
+    StoredValue(&value, encoding),
+    encoding,
+    new_version,
+    expiration_ms,
+    now,
+  ])?;
+  assert_eq!(changed, 1);
+  let changed = tx
+    .prepare_cached(STATEMENT_KV_POINT_DELETE)?
+    .execute([from])?;
+  assert_eq!(changed, 1);
+
+  Ok(Some(!exists))
 }
 
-const VALUE_ENCODING_V8: i64 = 1;
-const VALUE_ENCODING_LE64: i64 = 2;
-const VALUE_ENCODING_BYTES: i64 = 3;
+/// Deletes all keys under the given prefix. Fails if more than
+/// `MAX_DELETE_PREFIX_ENTRIES` keys would be deleted, so that a single write
+/// transaction can not hold the database lock for an unbounded amount of time.
+/// Returns the number of keys deleted, not counting expired entries that
+/// hadn't been swept yet, which are deleted along with them.
+fn delete_prefix(
+  tx: &Transaction,
+  prefix: &[u8],
+  now: u64,
+) -> Result<usize, AnyError> {
+  let start: Vec<u8> = prefix.iter().copied().chain(Some(0)).collect();
+  let end: Vec<u8> = prefix.iter().copied().chain(Some(0xff)).collect();
 
-fn decode_value(value: Vec<u8>, encoding: i64) -> crate::Value {
-  match encoding {
-    VALUE_ENCODING_V8 => crate::Value::V8(value),
-    VALUE_ENCODING_BYTES => crate::Value::Bytes(value),
-    VALUE_ENCODING_LE64 => {
-      let mut buf = [0; 8];
-      buf.copy_from_slice(&value);
-      crate::Value::U64(u64::from_le_bytes(buf))
-    }
-    _ => todo!(),
+  let count: usize = tx
+    .prepare_cached(STATEMENT_KV_RANGE_COUNT_BOUNDED)?
+    .query_row(
+      params![start, end, now, MAX_DELETE_PREFIX_ENTRIES + 1],
+      |row| row.get(0),
+    )?;
+  if count > MAX_DELETE_PREFIX_ENTRIES {
+    return Err(type_error(format!(
+      "too many keys to delete under prefix (max {})",
+      MAX_DELETE_PREFIX_ENTRIES
+    )));
   }
+
+  tx.prepare_cached(STATEMENT_KV_RANGE_DELETE)?
+    .execute(params![start, end])?;
+  Ok(count)
 }
 
-fn encode_value(value: &crate::Value) -> (Cow<'_, [u8]>, i64) {
-  match value {
-    crate::Value::V8(value) => (Cow::Borrowed(value), VALUE_ENCODING_V8),
-    crate::Value::Bytes(value) => (Cow::Borrowed(value), VALUE_ENCODING_BYTES),
-    crate::Value::U64(value) => {
-      let mut buf = [0; 8];
-      buf.copy_from_slice(&value.to_le_bytes());
-      (Cow::Owned(buf.to_vec()), VALUE_ENCODING_LE64)
+/// Whether the first keyword of `sql`, after any comments, starts a query.
+/// `Statement::readonly` alone also accepts statements that change the
+/// connection rather than the database, like `ATTACH` or `BEGIN`.
+fn is_raw_query(sql: &str) -> bool {
+  let mut rest = sql;
+  loop {
+    rest = rest.trim_start();
+    if let Some(comment) = rest.strip_prefix("--") {
+      rest = comment.split_once('\n').map_or("", |(_, rest)| rest);
+    } else if let Some(comment) = rest.strip_prefix("/*") {
+      rest = comment.split_once("*/").map_or("", |(_, rest)| rest);
+    } else {
+      break;
     }
   }
+  let keyword = rest
+    .split(|c: char| !c.is_ascii_alphabetic())
+    .next()
+    .unwrap_or_default();
+  ["select", "with", "values", "explain"]
+    .iter()
+    .any(|query| keyword.eq_ignore_ascii_case(query))
 }
 
-pub struct QueueWaker {
-  wakers_tx: HashMap<PathBuf, broadcast::Sender<()>>,
-}
-
-fn shared_queue_waker_channel(
-  waker_key: &Path,
-  state: Rc<RefCell<OpState>>,
-) -> (broadcast::Sender<()>, broadcast::Receiver<()>) {
-  let mut state = state.borrow_mut();
-  let waker = {
-    let waker = state.try_borrow_mut::<QueueWaker>();
-    match waker {
-      Some(waker) => waker,
-      None => {
-        let waker = QueueWaker {
-          wakers_tx: HashMap::new(),
-        };
-        state.put::<QueueWaker>(waker);
-        state.borrow_mut::<QueueWaker>()
-      }
-    }
+/// Adds the index entries of a key with the given value to every index whose
+/// prefix contains the key.
+fn index_entry(
+  tx: &Transaction,
+  indexes: &[EncodedIndex],
+  key: &[u8],
+  value: &Value,
+) -> Result<(), AnyError> {
+  let mut covering = indexes
+    .iter()
+    .filter(|index| index.covers(key))
+    .filter_map(|index| Some((&index.name, index.extract.as_ref()?)))
+    .peekable();
+  if covering.peek().is_none() {
+    return Ok(());
+  }
+  // Keys that weren't written through `Deno.Kv` may not decode, and are not
+  // indexed.
+  let Ok(decoded_key) = decode_key(key) else {
+    return Ok(());
   };
-
-  let waker_tx = waker
-    .wakers_tx
-    .entry(waker_key.to_path_buf())
-    .or_insert_with(|| {
-      let (waker_tx, _) = broadcast::channel(1);
-      waker_tx
-    });
-
-  (waker_tx.clone(), waker_tx.subscribe())
+  for (name, extract) in covering {
+    if let Some(index_key) = extract(&decoded_key, value) {
+      tx.prepare_cached(STATEMENT_INDEX_INSERT)?.execute(params![
+        name,
+        encode_key(&index_key)?,
+        key
+      ])?;
+    }
+  }
+  Ok(())
+}
+
+/// Replaces the index entries of a key with ones for its current value, or
+/// removes them if the key no longer exists. Indexes that are not registered
+/// with this handle lose the entries of the key and are marked stale. Must be
+/// called after the key is written, in the same transaction.
+fn reindex_key(
+  tx: &Transaction,
+  indexes: &[EncodedIndex],
+  key: &[u8],
+  now: u64,
+) -> Result<(), AnyError> {
+  if !indexes.iter().any(|index| index.covers(key)) {
+    return Ok(());
+  }
+  tx.prepare_cached(STATEMENT_INDEX_DELETE_KEY)?
+    .execute([key])?;
+  let value = tx
+    .prepare_cached(STATEMENT_KV_POINT_GET_VALUE_ONLY)?
+    .query_row(params![key, now], |row| {
+      let StoredBytes(value) = row.get(0)?;
+      let encoding: i64 = row.get(1)?;
+      Ok(decode_value(value, encoding))
+    })
+    .optional()?;
+  let Some(value) = value else {
+    return Ok(());
+  };
+  index_entry(tx, indexes, key, &value)?;
+  for index in indexes {
+    if index.extract.is_none() && index.covers(key) {
+      tx.prepare_cached(STATEMENT_INDEX_DEF_MARK_STALE)?
+        .execute([&index.name])?;
+    }
+  }
+  Ok(())
+}
+
+/// Rebuilds the registered `indexes` whose stored definition is missing,
+/// differs, or is stale, and stores their definitions. Definitions stored by
+/// other handles are kept, and returned as indexes without an extractor so
+/// that writes through this handle can mark them stale.
+fn sync_indexes(
+  tx: &Transaction,
+  indexes: &[EncodedIndex],
+) -> Result<Vec<EncodedIndex>, AnyError> {
+  let mut stored = tx
+    .prepare_cached(STATEMENT_INDEX_DEF_LIST)?
+    .query_map([], |row| {
+      let name: String = row.get(0)?;
+      let prefix: Vec<u8> = row.get(1)?;
+      let version: u32 = row.get(2)?;
+      let stale: bool = row.get(3)?;
+      Ok((name, (prefix, version, stale)))
+    })?
+    .collect::<Result<HashMap<_, _>, rusqlite::Error>>()?;
+  for index in indexes {
+    let up_to_date =
+      stored
+        .remove(&index.name)
+        .is_some_and(|(prefix, version, stale)| {
+          !stale && prefix == index.prefix && version == index.version
+        });
+    if up_to_date {
+      continue;
+    }
+    tx.prepare_cached(STATEMENT_INDEX_DELETE_NAME)?
+      .execute([&index.name])?;
+    rebuild_index(tx, index)?;
+    tx.prepare_cached(STATEMENT_INDEX_DEF_SET)?
+      .execute(params![index.name, index.prefix, index.version])?;
+  }
+  Ok(
+    stored
+      .into_iter()
+      .map(|(name, (prefix, version, _))| EncodedIndex {
+        name,
+        prefix,
+        version,
+        extract: None,
+      })
+      .collect(),
+  )
+}
+
+/// Indexes the existing entries under the prefix of `index`.
+fn rebuild_index(
+  tx: &Transaction,
+  index: &EncodedIndex,
+) -> Result<(), AnyError> {
+  let start: Vec<u8> = index.prefix.iter().copied().chain(Some(0)).collect();
+  let end: Vec<u8> = index.prefix.iter().copied().chain(Some(0xff)).collect();
+  let mut stmt = tx.prepare_cached(STATEMENT_KV_RANGE_SCAN_ALL)?;
+  let mut rows = stmt.query(params![start, end])?;
+  while let Some(row) = rows.next()? {
+    let key: Vec<u8> = row.get(0)?;
+    let StoredBytes(value) = row.get(1)?;
+    let encoding: i64 = row.get(2)?;
+    index_entry(
+      tx,
+      std::slice::from_ref(index),
+      &key,
+      &decode_value(value, encoding),
+    )?;
+  }
+  Ok(())
+}
+
+/// Adds messages to the queue, using the default backoff schedule for those
+/// that don't specify their own.
+fn add_enqueues(
+  tx: &Transaction,
+  enqueues: &[Enqueue],
+  default_backoff_schedule: &[u32],
+  now: u64,
+) -> Result<(), AnyError> {
+  for enqueue in enqueues {
+    let id = Uuid::new_v4().to_string();
+    let backoff_schedule = serde_json::to_string(
+      &enqueue
+        .backoff_schedule
+        .as_deref()
+        .or(Some(default_backoff_schedule)),
+    )?;
+    let keys_if_undelivered =
+      serde_json::to_string(&enqueue.keys_if_undelivered)?;
+
+    let changed =
+      tx.prepare_cached(STATEMENT_QUEUE_ADD_READY)?
+        .execute(params![
+          enqueue.enqueue_at_ms.unwrap_or(now + enqueue.delay_ms),
+          id,
+          &enqueue.payload,
+          &backoff_schedule,
+          &keys_if_undelivered,
+          now,
+          0,
+          &enqueue.group
+        ])?;
+    assert_eq!(changed, 1)
+  }
+  Ok(())
+}
+
+fn version_to_versionstamp(version: i64) -> [u8; 10] {
+  let mut versionstamp = [0; 10];
+  versionstamp[..8].copy_from_slice(&version.to_be_bytes());
+  versionstamp
+}
+
+/// Decodes a versionstamp written by the SQLite backend into the value of
+/// the database's commit counter, which is big-endian in the first 8 bytes.
+/// The counter only orders commits; use
+/// [`SqliteDb::versionstamp_commit_ms`] to get an approximate wall-clock
+/// time.
+pub fn versionstamp_to_version(versionstamp: &[u8; 10]) -> i64 {
+  i64::from_be_bytes(versionstamp[..8].try_into().unwrap())
+}
+
+const VALUE_ENCODING_V8: i64 = 1;
+const VALUE_ENCODING_LE64: i64 = 2;
+const VALUE_ENCODING_BYTES: i64 = 3;
+/// UTF-8 encoded canonical JSON text, stored as a blob. It can be inspected
+/// with `cast(v as text)`.
+const VALUE_ENCODING_JSON: i64 = 4;
+
+/// Binds an encoded value to the `v` column: as TEXT for JSON values, so
+/// that they can be read with SQLite's JSON functions, and as a BLOB for
+/// every other encoding.
+#[derive(Clone, Copy)]
+struct StoredValue<'a>(&'a [u8], i64);
+
+impl rusqlite::ToSql for StoredValue<'_> {
+  fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+    let StoredValue(value, encoding) = *self;
+    Ok(ToSqlOutput::Borrowed(if encoding == VALUE_ENCODING_JSON {
+      ValueRef::Text(value)
+    } else {
+      ValueRef::Blob(value)
+    }))
+  }
+}
+
+/// The bytes of the `v` column, which may be TEXT or a BLOB depending on the
+/// value encoding (and on the version that wrote the row).
+struct StoredBytes(Vec<u8>);
+
+impl FromSql for StoredBytes {
+  fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+    match value {
+      ValueRef::Text(bytes) | ValueRef::Blob(bytes) => {
+        Ok(StoredBytes(bytes.to_vec()))
+      }
+      _ => Err(FromSqlError::InvalidType),
+    }
+  }
+}
+
+fn decode_value(value: Vec<u8>, encoding: i64) -> crate::Value {
+  match encoding {
+    VALUE_ENCODING_V8 => crate::Value::V8(value),
+    VALUE_ENCODING_BYTES => crate::Value::Bytes(value),
+    VALUE_ENCODING_LE64 => {
+      let mut buf = [0; 8];
+      buf.copy_from_slice(&value);
+      crate::Value::U64(u64::from_le_bytes(buf))
+    }
+    VALUE_ENCODING_JSON => {
+      crate::Value::Json(String::from_utf8_lossy(&value).into_owned())
+    }
+    _ => todo!(),
+  }
+}
+
+fn encode_value(value: &crate::Value) -> (Cow<'_, [u8]>, i64) {
+  match value {
+    crate::Value::V8(value) => (Cow::Borrowed(value), VALUE_ENCODING_V8),
+    crate::Value::Bytes(value) => (Cow::Borrowed(value), VALUE_ENCODING_BYTES),
+    crate::Value::U64(value) => {
+      let mut buf = [0; 8];
+      buf.copy_from_slice(&value.to_le_bytes());
+      (Cow::Owned(buf.to_vec()), VALUE_ENCODING_LE64)
+    }
+    crate::Value::Json(value) => {
+      (Cow::Borrowed(value.as_bytes()), VALUE_ENCODING_JSON)
+    }
+  }
+}
+
+/// A single line of a database export.
+#[derive(Serialize, Deserialize)]
+struct ExportedEntry {
+  key: Vec<ExportedKeyPart>,
+  /// Base64 encoded value.
+  value: String,
+  encoding: ExportedValueEncoding,
+  versionstamp: String,
+  /// Unix timestamp in milliseconds after which the entry expires.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  expire_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+enum ExportedKeyPart {
+  /// Base64 encoded bytes.
+  Bytes(String),
+  String(String),
+  Bigint(String),
+  // NaN and infinities can not be represented as JSON numbers.
+  Number(String),
+  Boolean(bool),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExportedValueEncoding {
+  V8,
+  Le64,
+  Bytes,
+  Json,
+}
+
+impl TryFrom<&KvEntry> for ExportedEntry {
+  type Error = AnyError;
+  fn try_from(entry: &KvEntry) -> Result<Self, AnyError> {
+    let key = decode_key(&entry.key)?
+      .0
+      .into_iter()
+      .map(|part| match part {
+        KeyPart::Bytes(b) => ExportedKeyPart::Bytes(BASE64_STANDARD.encode(b)),
+        KeyPart::String(s) => ExportedKeyPart::String(s),
+        KeyPart::Int(n) => ExportedKeyPart::Bigint(n.to_string()),
+        KeyPart::Float(n) => ExportedKeyPart::Number(n.to_string()),
+        KeyPart::False => ExportedKeyPart::Boolean(false),
+        KeyPart::True => ExportedKeyPart::Boolean(true),
+      })
+      .collect();
+    let (value, encoding) = encode_value(&entry.value);
+    let encoding = match encoding {
+      VALUE_ENCODING_V8 => ExportedValueEncoding::V8,
+      VALUE_ENCODING_LE64 => ExportedValueEncoding::Le64,
+      VALUE_ENCODING_BYTES => ExportedValueEncoding::Bytes,
+      VALUE_ENCODING_JSON => ExportedValueEncoding::Json,
+      _ => unreachable!(),
+    };
+    Ok(ExportedEntry {
+      key,
+      value: BASE64_STANDARD.encode(value),
+      encoding,
+      versionstamp: hex::encode(entry.versionstamp),
+      expire_at: entry.expire_at_ms,
+    })
+  }
+}
+
+impl ExportedEntry {
+  /// Returns the encoded key, value, value encoding and expiration time of
+  /// the entry.
+  fn into_raw(self) -> Result<(Vec<u8>, Vec<u8>, i64, Option<u64>), AnyError> {
+    let invalid = || type_error("Invalid entry in import file");
+    let key = self
+      .key
+      .into_iter()
+      .map(|part| {
+        Ok(match part {
+          ExportedKeyPart::Bytes(b) => {
+            KeyPart::Bytes(BASE64_STANDARD.decode(b).map_err(|_| invalid())?)
+          }
+          ExportedKeyPart::String(s) => KeyPart::String(s),
+          ExportedKeyPart::Bigint(n) => {
+            KeyPart::Int(n.parse().map_err(|_| invalid())?)
+          }
+          ExportedKeyPart::Number(n) => {
+            KeyPart::Float(n.parse().map_err(|_| invalid())?)
+          }
+          ExportedKeyPart::Boolean(false) => KeyPart::False,
+          ExportedKeyPart::Boolean(true) => KeyPart::True,
+        })
+      })
+      .collect::<Result<Vec<_>, AnyError>>()?;
+    crate::check_write_key_parts(&key)?;
+    let key = encode_key(&Key(key))?;
+    if key.is_empty() {
+      return Err(invalid());
+    }
+
+    let value = BASE64_STANDARD.decode(self.value).map_err(|_| invalid())?;
+    let encoding = match self.encoding {
+      ExportedValueEncoding::V8 => VALUE_ENCODING_V8,
+      ExportedValueEncoding::Le64 if value.len() == 8 => VALUE_ENCODING_LE64,
+      ExportedValueEncoding::Le64 => return Err(invalid()),
+      ExportedValueEncoding::Bytes => VALUE_ENCODING_BYTES,
+      ExportedValueEncoding::Json
+        if serde_json::from_slice::<serde_json::Value>(&value).is_ok() =>
+      {
+        VALUE_ENCODING_JSON
+      }
+      ExportedValueEncoding::Json => return Err(invalid()),
+    };
+    Ok((key, value, encoding, self.expire_at))
+  }
+}
+
+pub struct QueueWaker {
+  wakers_tx: HashMap<PathBuf, broadcast::Sender<()>>,
+}
+
+fn shared_queue_waker_channel(
+  waker_key: &Path,
+  state: Rc<RefCell<OpState>>,
+) -> (broadcast::Sender<()>, broadcast::Receiver<()>) {
+  let mut state = state.borrow_mut();
+  let waker = {
+    let waker = state.try_borrow_mut::<QueueWaker>();
+    match waker {
+      Some(waker) => waker,
+      None => {
+        let waker = QueueWaker {
+          wakers_tx: HashMap::new(),
+        };
+        state.put::<QueueWaker>(waker);
+        state.borrow_mut::<QueueWaker>()
+      }
+    }
+  };
+
+  let waker_tx = waker
+    .wakers_tx
+    .entry(waker_key.to_path_buf())
+    .or_insert_with(|| {
+      let (waker_tx, _) = broadcast::channel(1);
+      waker_tx
+    });
+
+  (waker_tx.clone(), waker_tx.subscribe())
 }
 
 /// Same as Path::canonicalize, but also handles non-existing paths.
@@ -1111,3 +4410,2571 @@ fn is_conn_closed_error(e: &AnyError) -> bool {
   get_custom_error_class(e) == Some("TypeError")
     && e.to_string() == ERROR_USING_CLOSED_DATABASE
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use std::cell::RefCell;
+  use std::num::NonZeroU32;
+  use std::path::Path;
+  use std::path::PathBuf;
+  use std::rc::Rc;
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+  use std::time::Duration;
+  use std::time::SystemTime;
+
+  use deno_core::error::type_error;
+  use deno_core::error::AnyError;
+  use deno_core::futures;
+  use deno_core::OpState;
+  use rusqlite::types::Value as SqlValue;
+
+  use super::resolve_named_path;
+  use super::version_to_versionstamp;
+  use super::versionstamp_to_version;
+  use super::SqliteDb;
+  use super::SqliteDbHandler;
+  use super::SqliteDbHandlerPermissions;
+  use super::SqliteDurability;
+  use super::TX_TIMING_LOG_TARGET;
+  use crate::coalesce_ranges;
+  use crate::AtomicWrite;
+  use crate::CheckKind;
+  use crate::CommitOutcome;
+  use crate::Consistency;
+  use crate::Database;
+  use crate::DatabaseHandler;
+  use crate::Enqueue;
+  use crate::FixedClock;
+  use crate::IntegrityProblemKind;
+  use crate::Key;
+  use crate::KeyPart;
+  use crate::KvCheck;
+  use crate::KvClock;
+  use crate::KvEntry;
+  use crate::KvIndex;
+  use crate::KvMutation;
+  use crate::MutationKind;
+  use crate::QueueMessageHandle;
+  use crate::ReadRange;
+  use crate::SnapshotReadOptions;
+  use crate::Value;
+
+  /// Permissions that allow opening any database, shared with the tests of
+  /// the other modules.
+  pub(crate) struct AllowAll;
+
+  impl SqliteDbHandlerPermissions for AllowAll {
+    fn check_read(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+      Ok(())
+    }
+
+    fn check_write(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+      Ok(())
+    }
+  }
+
+  /// An `OpState` that allows opening any database.
+  fn test_state() -> Rc<RefCell<OpState>> {
+    let state = Rc::new(RefCell::new(OpState::new(1, None)));
+    state.borrow_mut().put(AllowAll);
+    state
+  }
+
+  /// Like [`test_state`], with a clock that only moves when it is advanced.
+  fn test_state_with_clock() -> (Rc<RefCell<OpState>>, Arc<FixedClock>) {
+    let clock = Arc::new(FixedClock::new(1_000_000));
+    let state = test_state();
+    state.borrow_mut().put(KvClock(clock.clone()));
+    (state, clock)
+  }
+
+  /// Opens an in-memory database with the default options.
+  async fn open_test_db() -> (Rc<RefCell<OpState>>, SqliteDb) {
+    let state = test_state();
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .open(state.clone(), None)
+      .await
+      .unwrap();
+    (state, db)
+  }
+
+  /// A directory that is removed when it is dropped, and the path of a
+  /// database file in it.
+  fn temp_db_path() -> (tempfile::TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kv.sqlite3").to_string_lossy().into_owned();
+    (dir, path)
+  }
+
+  fn mutation(key: &[u8], kind: MutationKind) -> KvMutation {
+    KvMutation {
+      key: key.to_vec(),
+      kind,
+      expire_at: None,
+    }
+  }
+
+  fn set(key: &[u8], value: Value) -> KvMutation {
+    mutation(key, MutationKind::Set(value))
+  }
+
+  fn versionstamp_check(key: &[u8], versionstamp: Option<[u8; 10]>) -> KvCheck {
+    KvCheck {
+      key: key.to_vec(),
+      kind: CheckKind::Versionstamp(versionstamp),
+    }
+  }
+
+  fn value_check(key: &[u8], value: Option<Value>) -> KvCheck {
+    KvCheck {
+      key: key.to_vec(),
+      kind: CheckKind::Value(value),
+    }
+  }
+
+  fn enqueue(payload: &[u8]) -> Enqueue {
+    Enqueue {
+      payload: payload.to_vec(),
+      delay_ms: 0,
+      enqueue_at_ms: None,
+      group: None,
+      keys_if_undelivered: vec![],
+      backoff_schedule: None,
+    }
+  }
+
+  /// An atomic write that is committed and doesn't return old values.
+  fn checked_write(
+    checks: Vec<KvCheck>,
+    mutations: Vec<KvMutation>,
+  ) -> AtomicWrite {
+    AtomicWrite {
+      checks,
+      mutations,
+      enqueues: vec![],
+      return_old: false,
+      dry_run: false,
+    }
+  }
+
+  fn write(mutations: Vec<KvMutation>) -> AtomicWrite {
+    checked_write(vec![], mutations)
+  }
+
+  fn enqueue_write(enqueues: Vec<Enqueue>) -> AtomicWrite {
+    AtomicWrite {
+      enqueues,
+      ..write(vec![])
+    }
+  }
+
+  #[test]
+  fn dispatch_concurrency_limit_validation() {
+    assert!(SqliteDbHandler::<AllowAll>::new(None)
+      .with_dispatch_concurrency_limit(0)
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn dispatch_concurrency_limit() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_dispatch_concurrency_limit(1)
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    db.atomic_write(
+      state.clone(),
+      enqueue_write(vec![enqueue(b"1"), enqueue(b"2")]),
+    )
+    .await
+    .unwrap();
+
+    let mut first = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+
+    // The only permit is held by the first message, so the second dequeue
+    // can't complete until it has been finished and dropped.
+    let mut second = Box::pin(db.dequeue_next_message(state.clone()));
+    let res =
+      tokio::time::timeout(Duration::from_millis(200), &mut second).await;
+    assert!(res.is_err());
+
+    assert_eq!(first.take_payload().await.unwrap(), b"1");
+    first.finish(true).await.unwrap();
+    drop(first);
+
+    let mut second = tokio::time::timeout(Duration::from_secs(5), second)
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap();
+    assert_eq!(second.take_payload().await.unwrap(), b"2");
+    second.finish(true).await.unwrap();
+    drop(second);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn concurrent_writes_from_two_handlers() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let handler_a = SqliteDbHandler::<AllowAll>::new(None)
+      .with_busy_timeout(Duration::from_secs(10));
+    let handler_b = SqliteDbHandler::<AllowAll>::new(None)
+      .with_busy_timeout(Duration::from_secs(10));
+    let db_a = handler_a
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    let db_b = handler_b
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+
+    let writes = (0..50u32).map(|i| {
+      let db = if i % 2 == 0 { &db_a } else { &db_b };
+      db.atomic_write(state.clone(), set_u64(i, i as u64))
+    });
+    for result in futures::future::join_all(writes).await {
+      assert!(result.unwrap().into_committed().is_some());
+    }
+
+    db_a.close();
+    db_b.close();
+  }
+
+  #[test]
+  fn named_path_resolution() {
+    let dir = Path::new("/var/kv");
+    assert_eq!(
+      resolve_named_path(dir, "mystore"),
+      Some(dir.join("mystore.sqlite3"))
+    );
+    assert_eq!(
+      resolve_named_path(dir, "my-store_2"),
+      Some(dir.join("my-store_2.sqlite3"))
+    );
+    for path in [
+      "",
+      ":memory:",
+      "./mystore",
+      "mystore.db",
+      "/tmp/mystore",
+      "dir/mystore",
+      "https://kv.example.com",
+      ":memory:?cache=shared&name=a",
+    ] {
+      assert_eq!(resolve_named_path(dir, path), None, "{path}");
+    }
+  }
+
+  /// Allows everything, remembering the paths that were checked.
+  #[derive(Default)]
+  struct RecordingPermissions(Vec<(&'static str, PathBuf)>);
+
+  impl SqliteDbHandlerPermissions for RecordingPermissions {
+    fn check_read(&mut self, p: &Path, _api: &str) -> Result<(), AnyError> {
+      self.0.push(("read", p.to_path_buf()));
+      Ok(())
+    }
+
+    fn check_write(&mut self, p: &Path, _api: &str) -> Result<(), AnyError> {
+      self.0.push(("write", p.to_path_buf()));
+      Ok(())
+    }
+  }
+
+  struct DenyWrite;
+
+  impl SqliteDbHandlerPermissions for DenyWrite {
+    fn check_read(&mut self, _p: &Path, _api: &str) -> Result<(), AnyError> {
+      Ok(())
+    }
+
+    fn check_write(&mut self, p: &Path, _api: &str) -> Result<(), AnyError> {
+      Err(type_error(format!("no write access to {}", p.display())))
+    }
+  }
+
+  #[tokio::test]
+  async fn open_by_name() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().join("stores");
+    let expected_path = dir.join("mystore.sqlite3");
+
+    let state = test_state();
+    state.borrow_mut().put(RecordingPermissions::default());
+    let handler = SqliteDbHandler::<RecordingPermissions>::new(None)
+      .with_named_storage_dir(dir.clone());
+    let db = handler
+      .open(state.clone(), Some("mystore".to_string()))
+      .await
+      .unwrap();
+    db.close();
+
+    assert!(expected_path.exists());
+    assert_eq!(
+      state.borrow().borrow::<RecordingPermissions>().0,
+      vec![("read", expected_path.clone()), ("write", expected_path)]
+    );
+
+    // Paths are not affected by the named storage directory.
+    let err = match handler.open(state.clone(), Some(String::new())).await {
+      Ok(_) => panic!("opened an empty path"),
+      Err(err) => err,
+    };
+    assert_eq!(err.to_string(), "Filename cannot be empty");
+
+    // Permission is checked against the resolved path.
+    let handler = SqliteDbHandler::<DenyWrite>::new(None)
+      .with_named_storage_dir(dir.clone());
+    state.borrow_mut().put(DenyWrite);
+    let err = match handler.open(state.clone(), Some("other".to_string())).await
+    {
+      Ok(_) => panic!("permission was not checked"),
+      Err(err) => err,
+    };
+    assert_eq!(
+      err.to_string(),
+      format!("no write access to {}", dir.join("other.sqlite3").display())
+    );
+    assert!(!dir.join("other.sqlite3").exists());
+  }
+
+  #[tokio::test]
+  async fn read_only_open() {
+    let (dir, path) = temp_db_path();
+    let state = test_state();
+
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db = handler
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    db.atomic_write(state.clone(), write(vec![set(b"a", Value::U64(1))]))
+      .await
+      .unwrap();
+    db.close();
+
+    let handler = SqliteDbHandler::<AllowAll>::new(None).with_read_only(true);
+    let db = handler
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    let output = db
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"a".to_vec(),
+          end: b"a\x00".to_vec(),
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap();
+    assert!(matches!(output[0].entries[0].value, Value::U64(1)));
+
+    let update = write(vec![set(b"a", Value::U64(2))]);
+    let err = match db.atomic_write(state.clone(), update).await {
+      Ok(_) => panic!("wrote to a read-only database"),
+      Err(err) => err,
+    };
+    assert_eq!(err.to_string(), "database is read-only");
+    let err = db
+      .enqueue(state.clone(), vec![enqueue(b"")])
+      .await
+      .unwrap_err();
+    assert_eq!(err.to_string(), "database is read-only");
+    db.close();
+
+    // A database without the expected schema can't be opened read-only.
+    let empty_path = dir.path().join("empty.sqlite3");
+    rusqlite::Connection::open(&empty_path)
+      .unwrap()
+      .execute_batch("create table t (x integer)")
+      .unwrap();
+    let err = match handler
+      .open(
+        state.clone(),
+        Some(empty_path.to_string_lossy().into_owned()),
+      )
+      .await
+    {
+      Ok(_) => panic!("opened a database with an unexpected schema"),
+      Err(err) => err,
+    };
+    assert!(err
+      .to_string()
+      .contains("does not match the expected version"));
+
+    // Neither can an in-memory database.
+    assert!(handler.open(state.clone(), None).await.is_err());
+
+    // The database can be read where the shared-memory and log files can't
+    // be created. This is only simulated when the test doesn't run as root,
+    // which can write to the directory regardless.
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+
+      // A read-only handle leaves empty shared-memory and log files behind
+      // when it is closed, because it can't checkpoint.
+      std::fs::remove_file(&empty_path).unwrap();
+      let _ = std::fs::remove_file(dir.path().join("kv.sqlite3-wal"));
+      let _ = std::fs::remove_file(dir.path().join("kv.sqlite3-shm"));
+      std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o555))
+        .unwrap();
+      let db = handler
+        .open(state.clone(), Some(path.clone()))
+        .await
+        .unwrap();
+      let output = db
+        .snapshot_read(
+          state.clone(),
+          vec![ReadRange {
+            start: b"a".to_vec(),
+            end: b"a\x00".to_vec(),
+            limit: NonZeroU32::new(1).unwrap(),
+            reverse: false,
+            max_bytes: None,
+          }],
+          SnapshotReadOptions {
+            consistency: Consistency::Strong,
+          },
+        )
+        .await
+        .unwrap();
+      assert!(matches!(output[0].entries[0].value, Value::U64(1)));
+      db.close();
+      std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755))
+        .unwrap();
+    }
+  }
+
+  #[cfg(feature = "sqlcipher")]
+  #[tokio::test]
+  async fn open_with_wrong_encryption_key() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_encryption_key("correct horse".to_string());
+    let db = handler
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    db.close();
+
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_encryption_key("battery staple".to_string());
+    let err = match handler.open(state.clone(), Some(path.clone())).await {
+      Ok(_) => panic!("opened database with the wrong key"),
+      Err(err) => err,
+    };
+    assert!(err.to_string().contains("encryption key is incorrect"));
+
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_encryption_key("correct horse".to_string());
+    let db = handler
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn expiration_sweep_is_brought_forward() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_expiration_sweep_interval(Duration::from_secs(3600));
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    // Let the initial sweep run and schedule the next one an hour out.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let expire_at = now_ms() + 1000;
+    let expiring = KvMutation {
+      expire_at: Some(expire_at),
+      ..set(b"a", Value::U64(1))
+    };
+    let result = db
+      .atomic_write(state.clone(), write(vec![expiring]))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+
+    // Count rows directly, since reads already hide expired entries.
+    let count = || {
+      SqliteDb::run_conn("count", db.conn.clone(), |conn| {
+        Ok(conn.query_row("select count(*) from kv", [], |row| {
+          row.get::<_, i64>(0)
+        })?)
+      })
+    };
+    assert_eq!(count().await.unwrap(), 1);
+    // The sweep should run within moments of the key expiring.
+    let until_expiry = expire_at.saturating_sub(now_ms());
+    tokio::time::sleep(Duration::from_millis(until_expiry + 300)).await;
+    assert_eq!(count().await.unwrap(), 0);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn expiration_watcher_is_shared() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kv.sqlite3").to_string_lossy().into_owned();
+    let clock = Arc::new(FixedClock::new(1_000_000));
+    // Databases of different isolates share the sweeps as well.
+    let new_state = || {
+      let state = test_state();
+      state.borrow_mut().put(KvClock(clock.clone()));
+      state
+    };
+    let (state_a, state_b) = (new_state(), new_state());
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_expiration_sweep_interval(Duration::from_secs(3600));
+    let db_a = handler.open(state_a, Some(path.clone())).await.unwrap();
+    let db_b = handler
+      .open(state_b.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+
+    let sweep = db_a
+      .expiration_watcher
+      .borrow()
+      .as_ref()
+      .unwrap()
+      .sweep
+      .clone();
+    assert!(Arc::ptr_eq(
+      &sweep,
+      &db_b.expiration_watcher.borrow().as_ref().unwrap().sweep
+    ));
+    let mut next_sweep_rx = sweep.next_sweep_tx.subscribe();
+    let sweep = Arc::downgrade(&sweep);
+
+    // The watcher of the other database takes over the sweeps once one of
+    // them is closed.
+    db_a.close();
+    let expiring = KvMutation {
+      expire_at: Some(clock.now_ms() + 500),
+      ..set(b"a", Value::U64(1))
+    };
+    let result = db_b
+      .atomic_write(state_b, write(vec![expiring]))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+    clock.advance(1000);
+    // Once the key has been swept, the next sweep is scheduled an interval
+    // out.
+    let now = clock.now_ms();
+    next_sweep_rx
+      .wait_for(|next_sweep| *next_sweep > now)
+      .await
+      .unwrap();
+    let count =
+      SqliteDb::run_conn("count", db_b.conn.clone(), |conn| {
+        Ok(conn.query_row("select count(*) from kv", [], |row| {
+          row.get::<_, i64>(0)
+        })?)
+      })
+      .await
+      .unwrap();
+    assert_eq!(count, 0);
+
+    // Closing the last database stops the sweeps.
+    db_b.close();
+    tokio::task::yield_now().await;
+    assert!(sweep.upgrade().is_none());
+  }
+
+  #[tokio::test]
+  async fn expiration_sweep_is_shared_by_the_same_clock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kv.sqlite3").to_string_lossy().into_owned();
+    let open = |clock: Arc<FixedClock>, interval: u64| {
+      let state = test_state();
+      state.borrow_mut().put(KvClock(clock));
+      let path = path.clone();
+      async move {
+        SqliteDbHandler::<AllowAll>::new(None)
+          .with_expiration_sweep_interval(Duration::from_secs(interval))
+          .open(state, Some(path))
+          .await
+      }
+    };
+    let sweep = |db: &SqliteDb| {
+      db.expiration_watcher
+        .borrow()
+        .as_ref()
+        .unwrap()
+        .sweep
+        .clone()
+    };
+    let clock = Arc::new(FixedClock::new(1_000_000));
+    let db_a = open(clock.clone(), 3600).await.unwrap();
+    let db_b = open(clock.clone(), 60).await.unwrap();
+    let db_c = open(Arc::new(FixedClock::new(1_000_000)), 60)
+      .await
+      .unwrap();
+
+    // The shortest interval of the databases sharing a sweep is used.
+    assert!(Arc::ptr_eq(&sweep(&db_a), &sweep(&db_b)));
+    assert_eq!(sweep(&db_a).interval(), Duration::from_secs(60));
+    // Another clock may disagree on which keys have expired.
+    assert!(!Arc::ptr_eq(&sweep(&db_a), &sweep(&db_c)));
+
+    db_a.close();
+    db_b.close();
+    db_c.close();
+  }
+
+  fn set_if_absent(key: &[u8], value: u64) -> AtomicWrite {
+    write(vec![mutation(
+      key,
+      MutationKind::SetIfAbsent(Value::U64(value)),
+    )])
+  }
+
+  #[tokio::test]
+  async fn concurrent_set_if_absent() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db_a = handler
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    // A second database of the same file has a connection and a write guard
+    // of its own, so its transactions race with those of the first one in
+    // SQLite rather than queueing behind them.
+    let db_b = handler.open(state.clone(), Some(path)).await.unwrap();
+
+    for round in 0..50u32 {
+      let key = round.to_be_bytes();
+      let (a, b, c) = futures::join!(
+        db_a.atomic_write(state.clone(), set_if_absent(&key, 1)),
+        db_a.atomic_write(state.clone(), set_if_absent(&key, 2)),
+        db_b.atomic_write(state.clone(), set_if_absent(&key, 3)),
+      );
+      let winners = [a.unwrap(), b.unwrap(), c.unwrap()]
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, outcome)| match outcome {
+          CommitOutcome::Committed(_) => Some(i as u64 + 1),
+          CommitOutcome::CheckFailed { failed_index } => {
+            assert_eq!(failed_index, Some(0));
+            None
+          }
+          CommitOutcome::DryRun(_) => panic!("write was a dry run"),
+        })
+        .collect::<Vec<_>>();
+      assert_eq!(winners.len(), 1, "round {round}: {winners:?}");
+
+      // The key holds the value of the only write that was committed.
+      let entries = db_b
+        .snapshot_read(
+          state.clone(),
+          vec![ReadRange {
+            start: key.to_vec(),
+            end: (round + 1).to_be_bytes().to_vec(),
+            limit: NonZeroU32::new(10).unwrap(),
+            reverse: false,
+            max_bytes: None,
+          }],
+          SnapshotReadOptions {
+            consistency: Consistency::Strong,
+          },
+        )
+        .await
+        .unwrap()
+        .remove(0)
+        .entries;
+      assert_eq!(entries.len(), 1);
+      assert!(matches!(entries[0].value, Value::U64(x) if x == winners[0]));
+    }
+
+    db_a.close();
+    db_b.close();
+  }
+
+  #[tokio::test]
+  async fn concurrent_sums_are_not_lost() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let dbs = [
+      handler
+        .open(state.clone(), Some(path.clone()))
+        .await
+        .unwrap(),
+      handler.open(state.clone(), Some(path)).await.unwrap(),
+    ];
+
+    // Each sum reads the current value in its transaction. Sums racing on
+    // separate connections must still see each other's results.
+    let sum = |db: &SqliteDb| {
+      let sum = mutation(b"counter", MutationKind::Sum(Value::U64(1)));
+      db.atomic_write(state.clone(), write(vec![sum]))
+    };
+    let results =
+      futures::future::join_all((0..100).map(|i| sum(&dbs[i % 2]))).await;
+    for result in results {
+      result.unwrap().into_committed().unwrap();
+    }
+
+    let entries = dbs[0]
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"counter".to_vec(),
+          end: b"counter\x00".to_vec(),
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap()
+      .remove(0)
+      .entries;
+    assert!(matches!(entries[0].value, Value::U64(100)));
+
+    for db in &dbs {
+      db.close();
+    }
+  }
+
+  #[tokio::test]
+  async fn write_transactions_are_immediate() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let dbs = [
+      handler
+        .open(state.clone(), Some(path.clone()))
+        .await
+        .unwrap(),
+      handler.open(state.clone(), Some(path)).await.unwrap(),
+    ];
+
+    // Increments a counter with a read followed by a write, like the checks
+    // and mutations of an atomic write, on both connections at once. Returns
+    // how often the transactions had to be rerun after doing their read.
+    let insert =
+      "insert into kv (k, v, v_encoding, version) values (?, x'', 1, 0)";
+    let increment_concurrently = |immediate: bool| {
+      let runs = Arc::new(AtomicUsize::new(0));
+      let increments = (0..40)
+        .map(|i| {
+          let conn = dbs[i % 2].conn.clone();
+          let runs = runs.clone();
+          let f = move |tx: rusqlite::Transaction| -> Result<(), AnyError> {
+            runs.fetch_add(1, Ordering::SeqCst);
+            let count: i64 =
+              tx.query_row("select count(*) from kv", [], |row| row.get(0))?;
+            std::thread::sleep(Duration::from_millis(1));
+            // The key is unique, so this fails if another transaction
+            // inserted the same count since the read.
+            tx.execute(insert, [count.to_be_bytes().to_vec()])?;
+            tx.commit()?;
+            Ok(())
+          };
+          async move {
+            if immediate {
+              SqliteDb::run_write_tx("increment", conn, f).await
+            } else {
+              SqliteDb::run_tx("increment", conn, f).await
+            }
+          }
+        })
+        .collect::<Vec<_>>();
+      async move {
+        for result in futures::future::join_all(increments).await {
+          result.unwrap();
+        }
+        runs.load(Ordering::SeqCst) - 40
+      }
+    };
+
+    // Deferred transactions are rerun whenever the other connection wrote
+    // between their read and their write, which is timing dependent.
+    increment_concurrently(false).await;
+    // Immediate transactions wait for the write lock before they start, so
+    // they never have to be rerun after doing work.
+    assert_eq!(increment_concurrently(true).await, 0);
+
+    // Every increment saw all of the previous ones.
+    let count =
+      SqliteDb::run_tx("count", dbs[0].conn.clone(), |tx| {
+        Ok(tx.query_row("select count(*) from kv", [], |row| {
+          row.get::<_, i64>(0)
+        })?)
+      })
+      .await
+      .unwrap();
+    assert_eq!(count, 80);
+
+    for db in &dbs {
+      db.close();
+    }
+  }
+
+  #[tokio::test]
+  async fn slow_transaction_timing_is_reported() {
+    let (state, db) = open_test_db().await;
+    let timings = Arc::new(Mutex::new(vec![]));
+    let sink_timings = timings.clone();
+    let conn = ProtectedConn {
+      tx_timing_sink: Some(Arc::new(move |timing: &TxTiming| {
+        sink_timings.lock().unwrap().push((
+          timing.name,
+          timing.lock_wait,
+          timing.run,
+          timing.ok,
+        ));
+      })),
+      ..db.conn.clone()
+    };
+
+    let slow = SqliteDb::run_tx("slow_test_tx", conn.clone(), |_tx| {
+      std::thread::sleep(Duration::from_millis(50));
+      Ok(())
+    });
+    // Queues behind the slow transaction for the connection.
+    let waiting = SqliteDb::run_tx("waiting_test_tx", conn, |_tx| Ok(()));
+    let (slow, waiting) = futures::join!(slow, waiting);
+    slow.unwrap();
+    waiting.unwrap();
+
+    let timings = timings.lock().unwrap();
+    assert_eq!(timings.len(), 2);
+    let (slow_name, _, slow_run, slow_ok) = timings[0];
+    assert_eq!((slow_name, slow_ok), ("slow_test_tx", true));
+    assert!(slow_run >= Duration::from_millis(50));
+    // The waiting transaction only got the connection once the slow one
+    // was done with it.
+    let (waiting_name, waiting_lock_wait, _, waiting_ok) = timings[1];
+    assert_eq!((waiting_name, waiting_ok), ("waiting_test_tx", true));
+    assert!(waiting_lock_wait >= Duration::from_millis(40));
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn check_integrity() {
+    let (state, clock) = test_state_with_clock();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db = handler.open(state.clone(), None).await.unwrap();
+    let result = db
+      .atomic_write(state.clone(), write(vec![set(b"a", Value::U64(1))]))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+    let _message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(db.check_integrity(state.clone()).await.unwrap().is_empty());
+
+    // The message has been running for two hours, which the stuck message
+    // watcher would have caught if it had been running.
+    clock.advance(2 * 3_600_000);
+    SqliteDb::run_conn("corrupt", db.conn.clone(), |conn| {
+      conn.execute_batch(
+        "update kv set version = version + 10, expiration_ms = -5",
+      )?;
+      Ok(())
+    })
+    .await
+    .unwrap();
+    let kinds = db
+      .check_integrity(state.clone())
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|problem| problem.kind)
+      .collect::<Vec<_>>();
+    assert_eq!(
+      kinds,
+      vec![
+        IntegrityProblemKind::VersionAhead,
+        IntegrityProblemKind::StaleRunningMessage,
+        IntegrityProblemKind::InvalidExpiration
+      ]
+    );
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn check_integrity_of_corrupted_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kv.sqlite3");
+    let open = || {
+      let state = test_state();
+      let path = path.to_string_lossy().into_owned();
+      async move {
+        let db = SqliteDbHandler::<AllowAll>::new(None)
+          .open(state.clone(), Some(path))
+          .await
+          .unwrap();
+        (state, db)
+      }
+    };
+
+    // A few values large enough to spill into overflow pages, which make up
+    // the end of the file.
+    let (state, db) = open().await;
+    let mutations = (0..4u8)
+      .map(|i| set(&[b'a', i], Value::Bytes(vec![i; 60_000])))
+      .collect();
+    db.atomic_write(state.clone(), write(mutations))
+      .await
+      .unwrap();
+    assert!(db.check_integrity(state.clone()).await.unwrap().is_empty());
+    // Closing the last connection checkpoints the write-ahead log into the
+    // database file.
+    db.close();
+
+    // Overwrite the last quarter of the file, which breaks the links between
+    // the overflow pages.
+    let mut data = std::fs::read(&path).unwrap();
+    let len = data.len();
+    data[len - len / 4..].fill(0xa5);
+    std::fs::write(&path, data).unwrap();
+
+    let (state, db) = open().await;
+    let problems = db.check_integrity(state.clone()).await.unwrap();
+    assert!(!problems.is_empty());
+    assert!(problems
+      .iter()
+      .all(|problem| problem.kind == IntegrityProblemKind::Corruption));
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn expiration_follows_injected_clock() {
+    let (state, clock) = test_state_with_clock();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    let expiring = KvMutation {
+      expire_at: Some(1_000_000 + 60_000),
+      ..set(b"a", Value::U64(1))
+    };
+    let result = db
+      .atomic_write(state.clone(), write(vec![expiring]))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+
+    let read = || {
+      db.snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"a".to_vec(),
+          end: b"b".to_vec(),
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+    };
+    let entries = read().await.unwrap().remove(0).entries;
+    assert_eq!(entries[0].expire_at_ms, Some(1_060_000));
+
+    // No sleeping: the key expires as soon as the clock says so.
+    clock.advance(59_999);
+    assert_eq!(read().await.unwrap()[0].entries.len(), 1);
+    clock.advance(1);
+    assert_eq!(read().await.unwrap()[0].entries.len(), 0);
+
+    db.close();
+  }
+
+  fn score_key(name: &str) -> Vec<u8> {
+    crate::codec::encode_key(&Key(vec![
+      KeyPart::String("scores".into()),
+      KeyPart::String(name.into()),
+    ]))
+    .unwrap()
+  }
+
+  fn score_index() -> KvIndex {
+    KvIndex::by_value("by_score", Key(vec![KeyPart::String("scores".into())]))
+  }
+
+  async fn read_index_keys(
+    db: &SqliteDb,
+    state: &Rc<RefCell<OpState>>,
+  ) -> Vec<Vec<u8>> {
+    db.read_index(
+      state.clone(),
+      "by_score".into(),
+      ReadRange {
+        start: vec![],
+        end: vec![0xff],
+        limit: NonZeroU32::new(1000).unwrap(),
+        reverse: false,
+        max_bytes: None,
+      },
+    )
+    .await
+    .unwrap()
+    .entries
+    .into_iter()
+    .map(|entry| entry.key)
+    .collect()
+  }
+
+  #[tokio::test]
+  async fn index_is_maintained_by_writes() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_index(score_index())
+      .unwrap();
+    assert!(SqliteDbHandler::<AllowAll>::new(None)
+      .with_index(score_index())
+      .unwrap()
+      .with_index(score_index())
+      .is_err());
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    db.atomic_write(
+      state.clone(),
+      write(vec![
+        set(&score_key("a"), Value::U64(3)),
+        set(&score_key("b"), Value::U64(1)),
+        set(&score_key("c"), Value::U64(2)),
+        set(&score_key("d"), Value::V8(vec![1, 2])),
+        set(b"unrelated", Value::U64(0)),
+      ]),
+    )
+    .await
+    .unwrap()
+    .into_committed()
+    .unwrap();
+    assert_eq!(
+      read_index_keys(&db, &state).await,
+      vec![score_key("b"), score_key("c"), score_key("a")]
+    );
+
+    db.atomic_write(
+      state.clone(),
+      write(vec![
+        set(&score_key("a"), Value::U64(0)),
+        mutation(&score_key("b"), MutationKind::Delete),
+        mutation(
+          &score_key("c"),
+          MutationKind::Move {
+            to: score_key("e"),
+            overwrite: false,
+          },
+        ),
+        mutation(&score_key("f"), MutationKind::Sum(Value::U64(5))),
+      ]),
+    )
+    .await
+    .unwrap()
+    .into_committed()
+    .unwrap();
+    assert_eq!(
+      read_index_keys(&db, &state).await,
+      vec![score_key("a"), score_key("e"), score_key("f")]
+    );
+
+    let mut dry_run =
+      write(vec![mutation(&score_key("a"), MutationKind::Delete)]);
+    dry_run.dry_run = true;
+    let outcome = db.atomic_write(state.clone(), dry_run).await.unwrap();
+    assert!(matches!(outcome, CommitOutcome::DryRun(_)));
+    assert_eq!(read_index_keys(&db, &state).await.len(), 3);
+
+    let prefix =
+      crate::codec::encode_key(&Key(vec![KeyPart::String("scores".into())]))
+        .unwrap();
+    db.atomic_write(
+      state.clone(),
+      write(vec![mutation(&prefix, MutationKind::DeletePrefix)]),
+    )
+    .await
+    .unwrap()
+    .into_committed()
+    .unwrap();
+    assert!(read_index_keys(&db, &state).await.is_empty());
+
+    let err = db
+      .read_index(
+        state.clone(),
+        "missing".into(),
+        ReadRange {
+          start: vec![],
+          end: vec![0xff],
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        },
+      )
+      .await
+      .err()
+      .unwrap();
+    assert_eq!(err.to_string(), "Unknown index 'missing'");
+  }
+
+  #[tokio::test]
+  async fn index_is_consistent_under_concurrent_writes() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let open = || async {
+      SqliteDbHandler::<AllowAll>::new(None)
+        .with_busy_timeout(Duration::from_secs(10))
+        .with_index(score_index())
+        .unwrap()
+        .open(state.clone(), Some(path.clone()))
+        .await
+        .unwrap()
+    };
+    let db_a = open().await;
+    let db_b = open().await;
+
+    // Both handles race to overwrite and delete the same few keys.
+    let writes = (0..100u64).map(|i| {
+      let db = if i % 2 == 0 { &db_a } else { &db_b };
+      let key = score_key(&format!("k{}", i % 7));
+      let kind = if i % 11 == 0 {
+        MutationKind::Delete
+      } else {
+        MutationKind::Set(Value::U64((i * 37) % 13))
+      };
+      db.atomic_write(state.clone(), write(vec![mutation(&key, kind)]))
+    });
+    for result in futures::future::join_all(writes).await {
+      assert!(result.unwrap().into_committed().is_some());
+    }
+
+    let entries = db_a
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: vec![],
+          end: vec![0xff],
+          limit: NonZeroU32::new(1000).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap()
+      .remove(0)
+      .entries;
+    let mut expected = entries
+      .into_iter()
+      .map(|entry| {
+        let Value::U64(n) = entry.value else {
+          unreachable!()
+        };
+        (n, entry.key)
+      })
+      .collect::<Vec<_>>();
+    expected.sort();
+    let expected = expected.into_iter().map(|(_, key)| key).collect::<Vec<_>>();
+    assert_eq!(read_index_keys(&db_a, &state).await, expected);
+    assert_eq!(read_index_keys(&db_b, &state).await, expected);
+
+    // The stored index is kept when the database is opened again.
+    db_a.close();
+    db_b.close();
+    let db = open().await;
+    assert_eq!(read_index_keys(&db, &state).await, expected);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn index_definitions_are_shared_by_handles() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let (state_ref, path_ref) = (&state, &path);
+    let open = move |indexes: Vec<KvIndex>| async move {
+      let mut handler = SqliteDbHandler::<AllowAll>::new(None);
+      for index in indexes {
+        handler = handler.with_index(index).unwrap();
+      }
+      handler
+        .open(state_ref.clone(), Some(path_ref.clone()))
+        .await
+    };
+    let score =
+      |name: &str, n: u64| write(vec![set(&score_key(name), Value::U64(n))]);
+
+    let db = open(vec![score_index()]).await.unwrap();
+    db.atomic_write(state.clone(), score("a", 3)).await.unwrap();
+    db.atomic_write(state.clone(), score("b", 1)).await.unwrap();
+
+    // Opening a handle with other indexes keeps the existing ones.
+    let other_index =
+      KvIndex::by_value("other", Key(vec![KeyPart::String("misc".into())]));
+    let db_other = open(vec![other_index]).await.unwrap();
+    assert_eq!(
+      read_index_keys(&db, &state).await,
+      vec![score_key("b"), score_key("a")]
+    );
+
+    // A handle without the index can't compute its entries, so the entries
+    // of the keys it writes are dropped until the index is rebuilt.
+    let db_plain = open(vec![]).await.unwrap();
+    db_plain
+      .atomic_write(state.clone(), score("c", 2))
+      .await
+      .unwrap();
+    db_plain
+      .atomic_write(state.clone(), score("a", 0))
+      .await
+      .unwrap();
+    assert_eq!(read_index_keys(&db, &state).await, vec![score_key("b")]);
+    db.close();
+    db_other.close();
+    db_plain.close();
+
+    let db = open(vec![score_index()]).await.unwrap();
+    assert_eq!(
+      read_index_keys(&db, &state).await,
+      vec![score_key("a"), score_key("b"), score_key("c")]
+    );
+    db.close();
+
+    // A new version of the extractor rebuilds the index.
+    let by_key = KvIndex::new(
+      "by_score",
+      Key(vec![KeyPart::String("scores".into())]),
+      |key, _| Some(Key(vec![key.0[1].clone()])),
+    )
+    .with_version(1);
+    let db = open(vec![by_key]).await.unwrap();
+    db.atomic_write(state.clone(), score("d", 5)).await.unwrap();
+    assert_eq!(
+      read_index_keys(&db, &state).await,
+      vec![
+        score_key("a"),
+        score_key("b"),
+        score_key("c"),
+        score_key("d")
+      ]
+    );
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn writes_ignore_expired_entries() {
+    let (state, clock) = test_state_with_clock();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    let commit = |mutations: Vec<(&[u8], MutationKind, Option<u64>)>| {
+      let mutations = mutations
+        .into_iter()
+        .map(|(key, kind, expire_at)| KvMutation {
+          expire_at,
+          ..mutation(key, kind)
+        })
+        .collect();
+      db.atomic_write(
+        state.clone(),
+        AtomicWrite {
+          return_old: true,
+          ..write(mutations)
+        },
+      )
+    };
+    // Expire an hour out, so that the expiration watcher doesn't sweep the
+    // entries before the test is done with them.
+    let expire_at = Some(1_000_000 + 3_600_000);
+    commit(vec![
+      (b"n", MutationKind::Set(Value::U64(5)), expire_at),
+      (b"o", MutationKind::Set(Value::U64(0)), expire_at),
+      (b"p\x00a", MutationKind::Set(Value::U64(0)), expire_at),
+      (b"p\x00b", MutationKind::Set(Value::U64(0)), None),
+    ])
+    .await
+    .unwrap()
+    .into_committed()
+    .unwrap();
+    clock.advance(2 * 3_600_000);
+
+    // Old values are not returned for expired entries, sums start over, and
+    // prefix deletes only count the entries that were still live.
+    let result = commit(vec![
+      (b"o", MutationKind::Set(Value::U64(1)), None),
+      (b"n", MutationKind::Sum(Value::U64(1)), None),
+      (b"p", MutationKind::DeletePrefix, None),
+    ])
+    .await
+    .unwrap()
+    .into_committed()
+    .unwrap();
+    assert!(result.old_values[0].is_none());
+    let counts = result.mutation_counts.unwrap();
+    assert_eq!(counts.keys_created, 2);
+    assert_eq!(counts.keys_deleted, 1);
+
+    let entries = db
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"n".to_vec(),
+          end: b"n\x00".to_vec(),
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap()
+      .remove(0)
+      .entries;
+    assert!(matches!(entries[0].value, Value::U64(1)));
+
+    // Overwriting a live entry doesn't create the key.
+    let result = commit(vec![(b"o", MutationKind::Set(Value::U64(2)), None)])
+      .await
+      .unwrap()
+      .into_committed()
+      .unwrap();
+    assert_eq!(result.mutation_counts.unwrap().keys_created, 0);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn checks_treat_expired_entries_as_absent() {
+    let (state, clock) = test_state_with_clock();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    let commit = |checks: Vec<KvCheck>| {
+      db.atomic_write(state.clone(), checked_write(checks, vec![]))
+    };
+    let expiring = KvMutation {
+      // An hour out, so that the expiration watcher doesn't sweep it.
+      expire_at: Some(1_000_000 + 3_600_000),
+      ..set(b"a", Value::U64(5))
+    };
+    db.atomic_write(state.clone(), write(vec![expiring]))
+      .await
+      .unwrap()
+      .into_committed()
+      .unwrap();
+    clock.advance(2 * 3_600_000);
+
+    // The entry has expired but hasn't been swept. Value checks agree with
+    // versionstamp checks that it doesn't exist.
+    let outcome = commit(vec![value_check(b"a", Some(Value::U64(5)))])
+      .await
+      .unwrap();
+    assert!(matches!(
+      outcome,
+      CommitOutcome::CheckFailed {
+        failed_index: Some(0)
+      }
+    ));
+    let outcome = commit(vec![
+      value_check(b"a", None),
+      versionstamp_check(b"a", None),
+    ])
+    .await
+    .unwrap();
+    assert!(outcome.into_committed().is_some());
+
+    db.close();
+  }
+
+  #[test]
+  fn coalesce_overlapping_ranges() {
+    let range = |start: &[u8], end: &[u8]| (start.to_vec(), end.to_vec());
+    assert_eq!(
+      coalesce_ranges(vec![
+        range(b"c", b"d"),
+        range(b"a", b"b"),
+        range(b"b", b"bb"),
+        range(b"a1", b"a2"),
+        range(b"x", b"x"),
+      ]),
+      vec![range(b"a", b"bb"), range(b"c", b"d")]
+    );
+  }
+
+  #[tokio::test]
+  async fn commit_time_round_trip() {
+    let (state, clock) = test_state_with_clock();
+    let handler = SqliteDbHandler::<AllowAll>::new(None);
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    async fn commit(
+      db: &SqliteDb,
+      state: &Rc<RefCell<OpState>>,
+      key: &[u8],
+      kind: MutationKind,
+    ) -> [u8; 10] {
+      db.atomic_write(state.clone(), write(vec![mutation(key, kind)]))
+        .await
+        .unwrap()
+        .into_committed()
+        .unwrap()
+        .versionstamp
+    }
+    async fn read(
+      db: &SqliteDb,
+      state: &Rc<RefCell<OpState>>,
+      key: &[u8],
+    ) -> KvEntry {
+      let range = ReadRange {
+        start: key.to_vec(),
+        end: key.iter().copied().chain(Some(0)).collect(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+        max_bytes: None,
+      };
+      let mut output = db
+        .snapshot_read(
+          state.clone(),
+          vec![range],
+          SnapshotReadOptions {
+            consistency: Consistency::Strong,
+          },
+        )
+        .await
+        .unwrap();
+      output.remove(0).entries.remove(0)
+    }
+
+    let first =
+      commit(&db, &state, b"a", MutationKind::Set(Value::U64(1))).await;
+    clock.advance(5_000);
+    let second =
+      commit(&db, &state, b"b", MutationKind::Sum(Value::U64(1))).await;
+    assert_eq!(
+      versionstamp_to_version(&first) + 1,
+      versionstamp_to_version(&second)
+    );
+    assert_eq!(
+      version_to_versionstamp(versionstamp_to_version(&second)),
+      second
+    );
+
+    let entry = read(&db, &state, b"a").await;
+    assert_eq!(entry.versionstamp, first);
+    assert_eq!(entry.commit_ms, Some(1_000_000));
+    let entry = read(&db, &state, b"b").await;
+    assert_eq!(entry.versionstamp, second);
+    assert_eq!(entry.commit_ms, Some(1_005_000));
+
+    assert_eq!(
+      db.versionstamp_commit_ms(first).await.unwrap(),
+      Some(1_000_000)
+    );
+    assert_eq!(
+      db.versionstamp_commit_ms(second).await.unwrap(),
+      Some(1_005_000)
+    );
+
+    // Once the entries of a commit are gone, the newest earlier commit gives
+    // a lower bound.
+    clock.advance(5_000);
+    commit(&db, &state, b"b", MutationKind::Delete).await;
+    assert_eq!(
+      db.versionstamp_commit_ms(second).await.unwrap(),
+      Some(1_000_000)
+    );
+    assert_eq!(
+      db.versionstamp_commit_ms(version_to_versionstamp(0))
+        .await
+        .unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn tuning_validation() {
+    let handler = || SqliteDbHandler::<AllowAll>::new(None);
+    assert!(handler().with_page_size(8192).is_ok());
+    assert!(handler().with_page_size(256).is_err());
+    assert!(handler().with_page_size(131072).is_err());
+    assert!(handler().with_page_size(5000).is_err());
+    assert!(handler().with_cache_size(0).is_err());
+    assert!(handler().with_cache_size(u64::MAX).is_err());
+    assert!(handler().with_mmap_size(0).is_ok());
+    assert!(handler().with_mmap_size(u64::MAX).is_err());
+    assert!(handler().with_read_pool_size(0).is_ok());
+    assert!(handler().with_read_pool_size(32).is_ok());
+    assert!(handler().with_read_pool_size(33).is_err());
+  }
+
+  /// Writes to a fresh database file with and without tuning, and checks
+  /// that the pragmas took effect. The `sqlite_tuning` bench compares the
+  /// timings.
+  #[tokio::test]
+  async fn tuning_pragmas_take_effect() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state();
+
+    let pragma = |db: &SqliteDb, name: &'static str| {
+      SqliteDb::run_tx("pragmas", db.conn.clone(), move |tx| {
+        Ok(tx.query_row(&format!("pragma {name}"), [], |row| {
+          row.get::<_, i64>(0)
+        })?)
+      })
+    };
+    async fn insert(db: &SqliteDb, state: &Rc<RefCell<OpState>>) {
+      let mutations = (0..500u32)
+        .map(|i| set(&i.to_be_bytes(), Value::Bytes(vec![0; 256])))
+        .collect();
+      db.atomic_write(state.clone(), write(mutations))
+        .await
+        .unwrap()
+        .into_committed()
+        .unwrap();
+    }
+
+    let path = dir
+      .path()
+      .join("default.sqlite3")
+      .to_string_lossy()
+      .into_owned();
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .open(state.clone(), Some(path))
+      .await
+      .unwrap();
+    insert(&db, &state).await;
+    assert_eq!(pragma(&db, "page_size").await.unwrap(), 4096);
+    db.close();
+
+    let path = dir
+      .path()
+      .join("tuned.sqlite3")
+      .to_string_lossy()
+      .into_owned();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_page_size(16384)
+      .unwrap()
+      .with_cache_size(64 * 1024)
+      .unwrap()
+      .with_mmap_size(64 * 1024 * 1024)
+      .unwrap();
+    let db = handler
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    insert(&db, &state).await;
+    assert_eq!(pragma(&db, "page_size").await.unwrap(), 16384);
+    assert_eq!(pragma(&db, "cache_size").await.unwrap(), -64 * 1024);
+    let mmap_size = pragma(&db, "mmap_size").await.unwrap();
+    // Builds without memory mapping support report 0.
+    assert!(mmap_size == 64 * 1024 * 1024 || mmap_size == 0);
+    db.close();
+
+    // The page size of an existing file can't change.
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_page_size(1024)
+      .unwrap()
+      .open(state.clone(), Some(path))
+      .await
+      .unwrap();
+    assert_eq!(pragma(&db, "page_size").await.unwrap(), 16384);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn normal_durability() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_durability(SqliteDurability::Normal)
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    let synchronous = SqliteDb::run_tx("synchronous", db.conn.clone(), |tx| {
+      Ok(tx.query_row("pragma synchronous", [], |row| row.get::<_, i64>(0))?)
+    })
+    .await
+    .unwrap();
+    assert_eq!(synchronous, 1);
+
+    for i in 0..500u32 {
+      db.atomic_write(state.clone(), set_u64(i, i as u64))
+        .await
+        .unwrap()
+        .into_committed()
+        .unwrap();
+    }
+
+    // Every commit is visible to a separate connection, which only reads
+    // what reached the write-ahead log.
+    let other = SqliteDbHandler::<AllowAll>::new(None)
+      .open(state.clone(), Some(path))
+      .await
+      .unwrap();
+    let count = other
+      .count_range(
+        state.clone(),
+        vec![],
+        vec![0xff],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap();
+    assert_eq!(count, 500);
+
+    db.close();
+    other.close();
+  }
+
+  #[tokio::test]
+  async fn read_pool_serves_eventual_reads() {
+    let (_dir, path) = temp_db_path();
+    let state = test_state();
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_read_pool_size(4)
+      .unwrap()
+      .open(state.clone(), Some(path))
+      .await
+      .unwrap();
+    assert_eq!(db.read_pool.len(), 4);
+
+    db.atomic_write(state.clone(), write(vec![set(b"a", Value::U64(1))]))
+      .await
+      .unwrap()
+      .into_committed()
+      .unwrap();
+
+    let read = |consistency| {
+      db.snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"a".to_vec(),
+          end: b"b".to_vec(),
+          limit: NonZeroU32::new(10).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions { consistency },
+      )
+    };
+
+    // Hold the primary connection, as a long write would, and every pool
+    // connection, so that the reads queue up where they were routed.
+    let primary = db.conn.guard.borrow_mut().await;
+    let mut pool_guards = vec![];
+    for slot in &db.read_pool {
+      pool_guards.push(slot.conn.guard.borrow_mut().await);
+    }
+    let reads =
+      futures::future::join_all((0..4).map(|_| read(Consistency::Eventual)));
+    futures::pin_mut!(reads);
+    assert!(futures::poll!(&mut reads).is_pending());
+    // Each read went to a connection of its own, so they run in parallel.
+    for slot in &db.read_pool {
+      assert_eq!(slot.in_flight.get(), 1);
+    }
+
+    // The reads complete without the primary connection.
+    drop(pool_guards);
+    let results = tokio::time::timeout(Duration::from_secs(10), reads)
+      .await
+      .unwrap();
+    for result in results {
+      assert_eq!(result.unwrap()[0].entries.len(), 1);
+    }
+    for slot in &db.read_pool {
+      assert_eq!(slot.in_flight.get(), 0);
+    }
+
+    // Strongly consistent reads wait for the primary connection.
+    let strong = read(Consistency::Strong);
+    futures::pin_mut!(strong);
+    assert!(futures::poll!(&mut strong).is_pending());
+    drop(primary);
+    assert_eq!(strong.await.unwrap()[0].entries.len(), 1);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn ping() {
+    let (state, db) = open_test_db().await;
+    db.ping(state.clone()).await.unwrap();
+    db.close();
+  }
+
+  fn set_u64(key: u32, value: u64) -> AtomicWrite {
+    write(vec![set(&key.to_be_bytes(), Value::U64(value))])
+  }
+
+  #[tokio::test]
+  async fn write_coalescing() {
+    let state = test_state();
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_write_coalescing(Duration::from_millis(10))
+      .open(state.clone(), None)
+      .await
+      .unwrap();
+
+    let results = futures::future::join_all(
+      (0..100).map(|i| db.atomic_write(state.clone(), set_u64(i, i as u64))),
+    )
+    .await;
+    let mut versionstamps = results
+      .into_iter()
+      .map(|result| result.unwrap().into_committed().unwrap().versionstamp)
+      .collect::<Vec<_>>();
+    // All writes were committed in one transaction, but each of them got a
+    // versionstamp of its own.
+    versionstamps.sort();
+    versionstamps.dedup();
+    assert_eq!(versionstamps.len(), 100);
+    assert_eq!(
+      versionstamps[99],
+      version_to_versionstamp(versionstamp_to_version(&versionstamps[0]) + 99)
+    );
+
+    // Writes with checks are committed on their own, and see the coalesced
+    // writes.
+    let mut write = set_u64(0, 1000);
+    write
+      .checks
+      .push(value_check(&0u32.to_be_bytes(), Some(Value::U64(0))));
+    db.atomic_write(state.clone(), write)
+      .await
+      .unwrap()
+      .into_committed()
+      .unwrap();
+    let entries = db
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: vec![],
+          end: vec![0xff],
+          limit: NonZeroU32::new(1000).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap()
+      .remove(0)
+      .entries;
+    assert_eq!(entries.len(), 100);
+    assert!(matches!(entries[0].value, Value::U64(1000)));
+
+    // Writes waiting to be coalesced when the database is closed fail like
+    // any other write.
+    let write = db.atomic_write(state.clone(), set_u64(0, 0));
+    futures::pin_mut!(write);
+    assert!(futures::poll!(&mut write).is_pending());
+    db.close();
+    assert!(write.await.is_err());
+  }
+
+  #[tokio::test]
+  async fn write_coalescing_is_durable() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kv.sqlite3").to_string_lossy().into_owned();
+    let state = test_state();
+
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_durability(SqliteDurability::Full)
+      .with_write_coalescing(Duration::from_millis(2))
+      .open(state.clone(), Some(path.clone()))
+      .await
+      .unwrap();
+    let results = futures::future::join_all(
+      (0..500).map(|i| db.atomic_write(state.clone(), set_u64(i, i as u64))),
+    )
+    .await;
+    for result in results {
+      result.unwrap().into_committed().unwrap();
+    }
+    db.close();
+
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .open(state.clone(), Some(path))
+      .await
+      .unwrap();
+    let rows = db
+      .query_raw("select count(*) from kv", vec![])
+      .await
+      .unwrap();
+    assert_eq!(rows, vec![vec![SqlValue::Integer(500)]]);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn query_raw() {
+    let (state, db) = open_test_db().await;
+    let mutations = (0..3u8)
+      .map(|i| set(&[b'a', i], Value::U64(i as u64)))
+      .collect();
+    let result = db
+      .atomic_write(state.clone(), write(mutations))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+
+    let rows = db
+      .query_raw(
+        "select count(*) from kv where k >= ?",
+        vec![SqlValue::Blob(vec![b'a', 1])],
+      )
+      .await
+      .unwrap();
+    assert_eq!(rows, vec![vec![SqlValue::Integer(2)]]);
+
+    for sql in [
+      "delete from kv",
+      "attach database ':memory:' as other",
+      "begin",
+      "savepoint raw",
+      "commit",
+      "pragma query_only = 0",
+      "/* select */ pragma synchronous = off",
+    ] {
+      let err = db.query_raw(sql, vec![]).await.unwrap_err();
+      assert_eq!(
+        err.to_string(),
+        "Only read-only statements can be run raw",
+        "{sql}"
+      );
+    }
+    let rows = db
+      .query_raw(
+        "-- pages\nselect count(*) > 0 from pragma_page_count()",
+        vec![],
+      )
+      .await
+      .unwrap();
+    assert_eq!(rows, vec![vec![SqlValue::Integer(1)]]);
+    let rows = db
+      .query_raw("select count(*) from kv", vec![])
+      .await
+      .unwrap();
+    assert_eq!(rows, vec![vec![SqlValue::Integer(3)]]);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn json_value_round_trip() {
+    let (state, db) = open_test_db().await;
+    let text = r#"{"a":[1,"two",null],"b":true}"#;
+    let json = set(b"a", Value::Json(text.to_string()));
+    let result = db
+      .atomic_write(state.clone(), write(vec![json]))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+
+    let output = db
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"a".to_vec(),
+          end: b"b".to_vec(),
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap();
+    let Value::Json(read) = &output[0].entries[0].value else {
+      panic!("expected a JSON value");
+    };
+    assert_eq!(read, text);
+
+    // The value is readable as text without Deno.
+    let (encoding, raw) =
+      SqliteDb::run_conn("read_raw", db.conn.clone(), |conn| {
+        Ok(conn.query_row(
+          "select v_encoding, typeof(v), v from kv where k = ?",
+          [b"a".to_vec()],
+          |row| {
+            Ok((
+              row.get::<_, i64>(0)?,
+              (row.get::<_, String>(1)?, row.get::<_, String>(2)?),
+            ))
+          },
+        )?)
+      })
+      .await
+      .unwrap();
+    assert_eq!(encoding, super::VALUE_ENCODING_JSON);
+    assert_eq!(raw, ("text".to_string(), text.to_string()));
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn read_range_has_more() {
+    let (state, db) = open_test_db().await;
+    let mutations = (0..3u8)
+      .map(|i| set(&[b'a', i], Value::Bytes(vec![0; 10])))
+      .collect();
+    let result = db
+      .atomic_write(state.clone(), write(mutations))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+
+    let read = |limit: u32, max_bytes: Option<u32>| {
+      db.snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"a".to_vec(),
+          end: b"b".to_vec(),
+          limit: NonZeroU32::new(limit).unwrap(),
+          reverse: false,
+          max_bytes: max_bytes.and_then(NonZeroU32::new),
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+    };
+    let outputs = [
+      read(2, None).await.unwrap(),
+      read(3, None).await.unwrap(),
+      read(4, None).await.unwrap(),
+      read(4, Some(15)).await.unwrap(),
+      read(4, Some(25)).await.unwrap(),
+    ];
+    let summary = outputs
+      .iter()
+      .map(|output| (output[0].entries.len(), output[0].has_more))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      summary,
+      vec![(2, true), (3, false), (3, false), (2, true), (3, false)]
+    );
+
+    db.close();
+  }
+
+  #[test]
+  fn default_backoff_schedule_validation() {
+    let schedule = |schedule: Vec<u32>| {
+      SqliteDbHandler::<AllowAll>::new(None)
+        .with_default_backoff_schedule(Some(schedule))
+    };
+    assert!(schedule(vec![100; 6]).is_err());
+    assert!(schedule(vec![u32::MAX]).is_err());
+    assert!(schedule(vec![100; 5]).is_ok());
+    assert!(schedule(vec![]).is_ok());
+    assert!(SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(None)
+      .is_ok());
+  }
+
+  #[tokio::test]
+  async fn default_backoff_schedule() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(Some(vec![60_000]))
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    let message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    let before = now_ms();
+    message.finish(false).await.unwrap();
+    let after = now_ms();
+    drop(message);
+
+    // The message is requeued with the first delay of the custom schedule.
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!(stats.ready, 1);
+    let ready_at = stats.earliest_ready_ms.unwrap();
+    assert!(ready_at >= before + 60_000);
+    assert!(ready_at <= after + 60_000);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn fair_dequeue() {
+    let (state, clock) = test_state_with_clock();
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_fair_dequeue(true)
+      .open(state.clone(), None)
+      .await
+      .unwrap();
+
+    let grouped = |group: &str| Enqueue {
+      group: Some(group.to_string()),
+      ..enqueue(group.as_bytes())
+    };
+    // A burst in group "a" that became ready before anything in group "b".
+    // Every message becomes ready at a time of its own, so that the order
+    // doesn't depend on how ties are broken.
+    for group in ["a"; 20].into_iter().chain(["b"; 2]) {
+      db.enqueue(state.clone(), vec![grouped(group)])
+        .await
+        .unwrap();
+      clock.advance(1);
+    }
+
+    let mut groups = vec![];
+    for _ in 0..22 {
+      let mut message = db
+        .dequeue_next_message(state.clone())
+        .await
+        .unwrap()
+        .unwrap();
+      groups.push(message.take_payload().await.unwrap());
+      message.finish(true).await.unwrap();
+    }
+    assert_eq!(groups[..4], [b"a", b"b", b"a", b"b"]);
+    assert!(groups[4..].iter().all(|group| group == b"a"));
+
+    db.close();
+  }
+
+  #[test]
+  fn max_delivery_attempts_validation() {
+    assert!(SqliteDbHandler::<AllowAll>::new(None)
+      .with_max_delivery_attempts(0)
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn max_delivery_attempts() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(Some(vec![0; 5]))
+      .unwrap()
+      .with_max_delivery_attempts(2)
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    for attempt in 1..=2 {
+      let message = db
+        .dequeue_next_message(state.clone())
+        .await
+        .unwrap()
+        .unwrap();
+      assert_eq!(message.attempt(), Some(attempt));
+      assert!(message.enqueued_at_ms().is_some());
+      message.finish(false).await.unwrap();
+    }
+
+    // The schedule has intervals left, but the cap was reached.
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!(stats.ready, 0);
+    assert_eq!(stats.running, 0);
+    let dead_letters = db.list_dead_letters(state.clone(), 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].failure_count, 2);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn dead_letters_record_undeliverable_messages() {
+    let (state, clock) = test_state_with_clock();
+    // Every delivery, including that of a retried message, is given up on
+    // when it fails.
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_max_delivery_attempts(1)
+      .unwrap()
+      .open(state.clone(), None)
+      .await
+      .unwrap();
+
+    let undeliverable = Enqueue {
+      keys_if_undelivered: vec![b"undelivered".to_vec()],
+      ..enqueue(b"msg")
+    };
+    db.enqueue(state.clone(), vec![undeliverable])
+      .await
+      .unwrap();
+    let (db_ref, state_ref, clock_ref) = (&db, &state, &clock);
+    let fail_next = move || async move {
+      let message = db_ref
+        .dequeue_next_message(state_ref.clone())
+        .await
+        .unwrap()
+        .unwrap();
+      clock_ref.advance(1_000);
+      message.finish(false).await.unwrap();
+    };
+    fail_next().await;
+
+    let dead_letters = db.list_dead_letters(state.clone(), 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].payload, b"msg");
+    assert_eq!(dead_letters[0].enqueued_at_ms, 1_000_000);
+    assert_eq!(dead_letters[0].failed_at_ms, 1_001_000);
+    assert_eq!(dead_letters[0].failure_count, 1);
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!((stats.ready, stats.running), (0, 0));
+    let entries = db
+      .snapshot_read(
+        state.clone(),
+        vec![ReadRange {
+          start: b"undelivered".to_vec(),
+          end: b"undelivered\x00".to_vec(),
+          limit: NonZeroU32::new(1).unwrap(),
+          reverse: false,
+          max_bytes: None,
+        }],
+        SnapshotReadOptions {
+          consistency: Consistency::Strong,
+        },
+      )
+      .await
+      .unwrap()
+      .remove(0)
+      .entries;
+    assert_eq!(entries.len(), 1);
+
+    // Unknown messages can't be retried, and a retried message that fails
+    // again is dead-lettered again.
+    assert!(!db
+      .retry_dead_letter(state.clone(), "unknown".to_string())
+      .await
+      .unwrap());
+    let id = dead_letters[0].id.clone();
+    assert!(db
+      .retry_dead_letter(state.clone(), id.clone())
+      .await
+      .unwrap());
+    assert!(db
+      .list_dead_letters(state.clone(), 10)
+      .await
+      .unwrap()
+      .is_empty());
+    assert!(!db
+      .retry_dead_letter(state.clone(), id.clone())
+      .await
+      .unwrap());
+    fail_next().await;
+    let dead_letters = db.list_dead_letters(state.clone(), 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].id, id);
+    assert_eq!(dead_letters[0].enqueued_at_ms, 1_000_000);
+    assert_eq!(dead_letters[0].failed_at_ms, 1_002_000);
+
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn retried_dead_letter_keeps_its_group() {
+    let state = test_state();
+    let db = SqliteDbHandler::<AllowAll>::new(None)
+      .with_max_delivery_attempts(1)
+      .unwrap()
+      .open(state.clone(), None)
+      .await
+      .unwrap();
+
+    let grouped = Enqueue {
+      group: Some("g".to_string()),
+      ..enqueue(b"msg")
+    };
+    db.enqueue(state.clone(), vec![grouped]).await.unwrap();
+    let message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    message.finish(false).await.unwrap();
+
+    let dead_letters = db.list_dead_letters(state.clone(), 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert!(db
+      .retry_dead_letter(state.clone(), dead_letters[0].id.clone())
+      .await
+      .unwrap());
+
+    let group = SqliteDb::run_conn("read_group", db.conn.clone(), |conn| {
+      Ok(conn.query_row("select group_key from queue", [], |row| {
+        row.get::<_, Option<String>>(0)
+      })?)
+    })
+    .await
+    .unwrap();
+    assert_eq!(group.as_deref(), Some("g"));
+
+    db.close();
+  }
+
+  #[test]
+  fn visibility_timeout_validation() {
+    assert!(SqliteDbHandler::<AllowAll>::new(None)
+      .with_visibility_timeout(Duration::ZERO)
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn stuck_message_is_redelivered() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(Some(vec![0]))
+      .unwrap()
+      .with_visibility_timeout(Duration::from_millis(200))
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    // The handle of the first delivery is leaked, as by a handler that never
+    // finishes.
+    let leaked = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(leaked.attempt(), Some(1));
+
+    let mut message = tokio::time::timeout(
+      Duration::from_secs(5),
+      db.dequeue_next_message(state.clone()),
+    )
+    .await
+    .unwrap()
+    .unwrap()
+    .unwrap();
+    assert_eq!(message.attempt(), Some(2));
+    assert_eq!(message.take_payload().await.unwrap(), b"msg");
+    message.finish(true).await.unwrap();
+
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!(stats.ready, 0);
+    assert_eq!(stats.running, 0);
+
+    // The first delivery can't touch the message anymore.
+    assert!(leaked.extend_deadline(None).await.is_err());
+    drop(leaked);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn finished_message_is_not_redelivered() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(Some(vec![0]))
+      .unwrap()
+      .with_visibility_timeout(Duration::from_millis(200))
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    let message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    message.finish(true).await.unwrap();
+
+    let res = tokio::time::timeout(
+      Duration::from_millis(600),
+      db.dequeue_next_message(state.clone()),
+    )
+    .await;
+    assert!(res.is_err());
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!(stats.ready, 0);
+    assert_eq!(stats.running, 0);
+
+    drop(message);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn extended_deadline_prevents_redelivery() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(Some(vec![0]))
+      .unwrap()
+      .with_visibility_timeout(Duration::from_millis(400))
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    let message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(message.extend_deadline(Some(Duration::ZERO)).await.is_err());
+
+    // Runs for well past the visibility timeout, extending the deadline
+    // along the way.
+    for _ in 0..5 {
+      let res = tokio::time::timeout(
+        Duration::from_millis(150),
+        db.dequeue_next_message(state.clone()),
+      )
+      .await;
+      assert!(res.is_err());
+      message.extend_deadline(None).await.unwrap();
+    }
+    message.finish(true).await.unwrap();
+
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!(stats.ready, 0);
+    assert_eq!(stats.running, 0);
+
+    drop(message);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn extended_deadline_is_capped_and_never_earlier() {
+    let (state, clock) = test_state_with_clock();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_visibility_timeout(Duration::from_secs(60))
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+    let message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+
+    message
+      .extend_deadline(Some(Duration::from_millis(u64::MAX)))
+      .await
+      .unwrap();
+    assert_eq!(message.deadline.get(), 1_060_000);
+
+    clock.advance(1_000);
+    message
+      .extend_deadline(Some(Duration::from_secs(1)))
+      .await
+      .unwrap();
+    assert_eq!(message.deadline.get(), 1_060_000);
+    message.extend_deadline(None).await.unwrap();
+    assert_eq!(message.deadline.get(), 1_061_000);
+
+    message.finish(true).await.unwrap();
+    drop(message);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn buffered_messages_are_delivered_once() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_visibility_timeout(Duration::from_millis(300))
+      .unwrap()
+      .with_max_delivery_attempts(1)
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+    let payloads = [b"a", b"b", b"c", b"d", b"e"];
+    let enqueues = payloads.iter().map(|payload| enqueue(*payload)).collect();
+    db.enqueue(state.clone(), enqueues).await.unwrap();
+
+    // Each message is finished within the visibility timeout, but the last
+    // ones wait in the buffer for longer than that before they are handed
+    // out.
+    let mut delivered = vec![];
+    for _ in 0..payloads.len() {
+      let mut message = tokio::time::timeout(
+        Duration::from_secs(5),
+        db.dequeue_next_message(state.clone()),
+      )
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap();
+      // Waiting in the buffer doesn't count as an attempt.
+      assert_eq!(message.attempt(), Some(1));
+      delivered.push(message.take_payload().await.unwrap());
+      tokio::time::sleep(Duration::from_millis(150)).await;
+      message.finish(true).await.unwrap();
+    }
+    delivered.sort();
+    assert_eq!(delivered, payloads.map(|payload| payload.to_vec()));
+
+    let res = tokio::time::timeout(
+      Duration::from_millis(600),
+      db.dequeue_next_message(state.clone()),
+    )
+    .await;
+    assert!(res.is_err());
+    let stats = db.queue_stats(state.clone()).await.unwrap();
+    assert_eq!(stats.ready, 0);
+    assert_eq!(stats.running, 0);
+    let dead_letters = db.list_dead_letters(state.clone(), 10).await.unwrap();
+    assert!(dead_letters.is_empty());
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn queue_running_check() {
+    let (state, db) = open_test_db().await;
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    let message = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    let id = message.id().unwrap();
+    let mark_done = || {
+      checked_write(
+        vec![KvCheck {
+          key: vec![],
+          kind: CheckKind::QueueRunning(id.clone()),
+        }],
+        vec![set(b"done", Value::U64(1))],
+      )
+    };
+
+    // The message is still running, so the write goes through.
+    let result = db.atomic_write(state.clone(), mark_done()).await.unwrap();
+    assert!(result.into_committed().is_some());
+
+    // Once the message is finished, it is no longer owned by the handler.
+    message.finish(true).await.unwrap();
+    let result = db.atomic_write(state.clone(), mark_done()).await.unwrap();
+    assert!(matches!(
+      result,
+      CommitOutcome::CheckFailed {
+        failed_index: Some(0)
+      }
+    ));
+
+    drop(message);
+    db.close();
+  }
+
+  #[tokio::test]
+  async fn queue_running_check_fails_after_redelivery() {
+    let state = test_state();
+    let handler = SqliteDbHandler::<AllowAll>::new(None)
+      .with_default_backoff_schedule(Some(vec![0]))
+      .unwrap()
+      .with_visibility_timeout(Duration::from_millis(200))
+      .unwrap();
+    let db = handler.open(state.clone(), None).await.unwrap();
+    db.enqueue(state.clone(), vec![enqueue(b"msg")])
+      .await
+      .unwrap();
+
+    let check_running = |message: &dyn QueueMessageHandle| {
+      let check = KvCheck {
+        key: vec![],
+        kind: CheckKind::QueueRunning(message.id().unwrap()),
+      };
+      checked_write(vec![check], vec![])
+    };
+
+    // The first handler lets its deadline pass, so the message is delivered
+    // again, under the same message id.
+    let first = db
+      .dequeue_next_message(state.clone())
+      .await
+      .unwrap()
+      .unwrap();
+    let second = tokio::time::timeout(
+      Duration::from_secs(5),
+      db.dequeue_next_message(state.clone()),
+    )
+    .await
+    .unwrap()
+    .unwrap()
+    .unwrap();
+    assert_eq!(first.id, second.id);
+    assert_ne!(first.id(), second.id());
+
+    // Only the handler of the current delivery still owns the message.
+    let result = db
+      .atomic_write(state.clone(), check_running(&first))
+      .await
+      .unwrap();
+    assert!(matches!(
+      result,
+      CommitOutcome::CheckFailed {
+        failed_index: Some(0)
+      }
+    ));
+    let result = db
+      .atomic_write(state.clone(), check_running(&second))
+      .await
+      .unwrap();
+    assert!(result.into_committed().is_some());
+
+    drop(first);
+    drop(second);
+    db.close();
+  }
+
+  fn now_ms() -> u64 {
+    SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap()
+      .as_millis() as u64
+  }
+}
@@ -124,6 +124,8 @@ pub struct WorkerOptions {
   pub get_error_class_fn: Option<GetErrorClassFn>,
   pub cache_storage_dir: Option<std::path::PathBuf>,
   pub origin_storage_dir: Option<std::path::PathBuf>,
+  /// Directory in which the metadata of remote KV databases is cached.
+  pub kv_metadata_cache_dir: Option<std::path::PathBuf>,
   pub blob_store: Arc<BlobStore>,
   pub broadcast_channel: InMemoryBroadcastChannel,
 
@@ -164,6 +166,7 @@ impl Default for WorkerOptions {
       get_error_class_fn: Default::default(),
       origin_storage_dir: Default::default(),
       cache_storage_dir: Default::default(),
+      kv_metadata_cache_dir: Default::default(),
       broadcast_channel: Default::default(),
       source_map_getter: Default::default(),
       root_cert_store_provider: Default::default(),
@@ -261,6 +264,7 @@ impl MainWorker {
       deno_kv::deno_kv::init_ops_and_esm(
         MultiBackendDbHandler::remote_or_sqlite::<PermissionsContainer>(
           options.origin_storage_dir.clone(),
+          options.kv_metadata_cache_dir.clone(),
         ),
       ),
       deno_napi::deno_napi::init_ops_and_esm::<PermissionsContainer>(),
@@ -18,9 +18,11 @@ use hyper::Body;
 use hyper::Request;
 use hyper::Response;
 use hyper::StatusCode;
+use kv_remote::datapath::AckOutput;
 use kv_remote::datapath::AtomicWrite;
 use kv_remote::datapath::AtomicWriteOutput;
 use kv_remote::datapath::AtomicWriteStatus;
+use kv_remote::datapath::DequeueOutput;
 use kv_remote::datapath::ReadRangeOutput;
 use kv_remote::datapath::SnapshotRead;
 use kv_remote::datapath::SnapshotReadOutput;
@@ -1296,7 +1298,7 @@ async fn main_server(
       let body = hyper::body::to_bytes(req.into_body())
         .await
         .unwrap_or_default();
-      let Ok(_body): Result<AtomicWrite, _> = prost::Message::decode(&body[..])
+      let Ok(body): Result<AtomicWrite, _> = prost::Message::decode(&body[..])
       else {
         return Ok(
           Response::builder()
@@ -1305,6 +1307,20 @@ async fn main_server(
             .unwrap(),
         );
       };
+      // Reject malformed enqueues, so that tests can verify that the client
+      // encodes them.
+      if body.enqueues.iter().any(|enqueue| {
+        enqueue.payload.is_empty()
+          || enqueue.deadline_ms <= 0
+          || enqueue.kv_keys_if_undelivered.iter().any(|k| k.is_empty())
+      }) {
+        return Ok(
+          Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::empty())
+            .unwrap(),
+        );
+      }
       Ok(
         Response::builder()
           .body(Body::from(
@@ -1312,12 +1328,59 @@ async fn main_server(
               status: AtomicWriteStatus::AwSuccess.into(),
               versionstamp: vec![0u8; 10],
               primary_if_write_disabled: "".into(),
+              failed_checks: vec![],
             }
             .encode_to_vec(),
           ))
           .unwrap(),
       )
     }
+    (&hyper::Method::POST, "/kv_blackhole/dequeue") => {
+      if req
+        .headers()
+        .get("authorization")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        != format!("Bearer {}", KV_DATABASE_TOKEN)
+      {
+        return Ok(
+          Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())
+            .unwrap(),
+        );
+      }
+
+      // Emulate a long-poll that never finds a message.
+      tokio::time::sleep(Duration::from_millis(100)).await;
+      Ok(
+        Response::builder()
+          .body(Body::from(DequeueOutput { message: None }.encode_to_vec()))
+          .unwrap(),
+      )
+    }
+    (&hyper::Method::POST, "/kv_blackhole/ack") => {
+      if req
+        .headers()
+        .get("authorization")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        != format!("Bearer {}", KV_DATABASE_TOKEN)
+      {
+        return Ok(
+          Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())
+            .unwrap(),
+        );
+      }
+
+      Ok(
+        Response::builder()
+          .body(Body::from(AckOutput {}.encode_to_vec()))
+          .unwrap(),
+      )
+    }
     _ => {
       let mut file_path = testdata_path().to_path_buf();
       file_path.push(&req.uri().path()[1..].replace("%2f", "/"));